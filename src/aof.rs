@@ -0,0 +1,632 @@
+/* Append-only file */
+//
+// With `appendonly yes` every write command is appended to `appendfilename`
+// as a RESP multibulk request and the file is replayed through
+// execute_command at startup, in place of the snapshot. Commands whose effect
+// depends on when they run are logged by their effect: the EXPIRE family
+// and RESTORE with absolute unix times, XADD with the id it generated, MIGRATE as the DEL
+// it did locally.
+//
+// Commands collect in a buffer that flush() appends to the file once per
+// event loop iteration, before polling. A write that fails part way (the disk
+// filling up) leaves the rest to the next flush, which goes on from where it
+// stopped rather than writing the whole buffer again. `appendfsync` sets how
+// often the file is fsynced: after every flush (always), once a second on the
+// thread pool (everysec), or never, leaving it to the kernel (no).
+//
+// The file only grows, so BGREWRITEAOF replaces it with the shortest stream
+// of commands that recreates the dataset: FUNCTION LOAD per library, then per
+// key a SET, or RPUSH/ZADD with up to REWRITE_ITEMS_PER_CMD elements each, or
+// RESTORE for streams (no command rebuilds their consumer groups), and a
+// PEXPIREAT for its TTL. A snapshot of the dataset is taken under the lock,
+// which shares the values rather than copying them (see rdb.rs); formatting
+// and writing the new file happen on the thread pool while commands keep
+// going to the old file and to a rewrite buffer. Once the thread is done the event
+// loop appends that buffer to the new file and renames it over the old one.
+//
+// With `aof-use-rdb-preamble yes` the rewrite writes the dataset in the
+// snapshot format instead, which loads much faster than replaying commands,
+// and the commands that come after follow it as usual:
+//
+//   RRDB ... OP_EOF  *3\r\n$3\r\nSET\r\n...  *2\r\n$3\r\nDEL\r\n...
+//
+// Loading tells the two apart by the snapshot magic at the start of the file.
+//
+// A rewrite also starts on its own when the file has grown by
+// `auto-aof-rewrite-percentage` since the last one and is at least
+// `auto-aof-rewrite-min-size`, and when appendonly is switched on, to write
+// the initial file.
+//
+// FSYNC is for clients that need a durability checkpoint whatever
+// appendfsync says: it writes out the buffer and fsyncs the file before
+// replying, blocking the event loop meanwhile. Commands run one at a time and
+// are fed as they run, so by then everything the client wrote before, and
+// everything any other client did, is in the buffer; the OK means it's all on
+// disk.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::sync::{Arc, Mutex};
+
+use redis::protocol::{FrameLimits, RedisValue, Tag, MAX_ARGS};
+
+use crate::command::{self, CmdResult, Command, CMD_WRITE};
+use crate::events::ServerEvent;
+use crate::logging::{log_error, log_notice, log_warning};
+use crate::rdb::{self, bytes_to_chars, dump_value, Snapshot};
+use crate::{
+    get_current_time_ms, get_monotonic_time_ms, info, out_err, out_str, resp, run_command,
+    scripting, store, with_global_data, Buffer, GData, TtlSpec, Value,
+};
+
+// Elements per RPUSH/ZADD in a rewritten file
+const REWRITE_ITEMS_PER_CMD: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fsync {
+    Always,
+    EverySec,
+    No,
+}
+
+impl Fsync {
+    pub fn parse(s: &str) -> Option<Fsync> {
+        match s.to_lowercase().as_str() {
+            "always" => Some(Fsync::Always),
+            "everysec" => Some(Fsync::EverySec),
+            "no" => Some(Fsync::No),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Fsync::Always => "always",
+            Fsync::EverySec => "everysec",
+            Fsync::No => "no",
+        }
+    }
+}
+
+// Where the rewrite thread leaves the outcome for the event loop
+type RewriteResult = Arc<Mutex<Option<io::Result<()>>>>;
+
+#[derive(Debug)]
+struct Rewrite {
+    temp_path: String,
+    // Commands fed since the dataset was copied
+    buf: Vec<u8>,
+    result: RewriteResult,
+}
+
+#[derive(Debug, Default)]
+pub struct Aof {
+    // Open while appendonly is on and the file exists
+    file: Option<File>,
+    // Fed commands not written yet, the first `written` bytes of them excepted
+    buf: Vec<u8>,
+    written: usize,
+    // Bytes in the file, and what it had after the last rewrite or at startup
+    size: u64,
+    base_size: u64,
+    last_fsync_ms: u64,
+    rewrite: Option<Rewrite>,
+    // Replaying the file: its commands aren't logged again
+    loading: bool,
+}
+
+impl Aof {
+    pub fn is_open(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.loading
+    }
+
+    // Write out the buffer, going on from where a failed write stopped
+    fn write_buf(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => store::write_buf(file, &mut self.buf, &mut self.written, &mut self.size),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Whether a command that ran without error goes to the file
+pub fn should_log(command: &Command, args: &[String]) -> bool {
+    match command.name {
+        "function" => matches!(args[1].to_uppercase().as_str(), "LOAD" | "DELETE" | "FLUSH"),
+        _ => command.flags & CMD_WRITE != 0,
+    }
+}
+
+// The command as it should be replayed, None if it needs no replaying
+pub fn effect(command: &Command, args: &[String], reply: &RedisValue) -> Option<Vec<String>> {
+    // Logged under the built-in name, whatever rename-command made of it
+    let mut args = args.to_vec();
+    args[0] = command.name.to_uppercase();
+    match command.name {
+        "expire" | "pexpire" | "expireat" | "pexpireat" => {
+            // Only a TTL that got set, at the time it came to; the condition
+            // held when it ran, so it isn't logged
+            if !matches!(reply, RedisValue::Int(1)) {
+                return None;
+            }
+            let name = command.name;
+            let spec = TtlSpec::parse(&args, name.starts_with('p'), name.ends_with("at")).ok()?;
+            return Some(vec!["PEXPIREAT".to_string(), args.swap_remove(1), spec.at_ms.to_string()]);
+        }
+        "restore" => {
+            let ttl_ms: u64 = args[2].parse().ok()?;
+            if ttl_ms > 0 && !args[4..].iter().any(|arg| arg.eq_ignore_ascii_case("ABSTTL")) {
+                args[2] = (get_current_time_ms() + ttl_ms).to_string();
+                args.push("ABSTTL".to_string());
+            }
+        }
+        "xadd" => {
+            // Log the id the entry got instead of "*" or "ms-*"
+            let RedisValue::Str(id) = reply else {
+                return None;
+            };
+            let mut i = 2;
+            while i < args.len() {
+                match args[i].to_uppercase().as_str() {
+                    "NOMKSTREAM" => i += 1,
+                    "MAXLEN" if args.get(i + 1).is_some_and(|arg| arg == "=" || arg == "~") => i += 3,
+                    "MAXLEN" => i += 2,
+                    _ => break,
+                }
+            }
+            *args.get_mut(i)? = id.clone();
+        }
+        "migrate" => {
+            if args[5..].iter().any(|arg| arg.eq_ignore_ascii_case("COPY")) {
+                return None;
+            }
+            return Some(vec!["DEL".to_string(), args.swap_remove(3)]);
+        }
+        _ => {}
+    }
+    Some(args)
+}
+
+/// Log the commands that ran and should_log
+pub fn on_event(g_data: &mut GData, event: &ServerEvent) {
+    if let ServerEvent::CommandExecuted { command, args, reply, .. } = *event
+        && should_log(command, args)
+    {
+        feed(g_data, command, args, reply);
+    }
+}
+
+/// Log a write command that just ran; `reply` is the reply it wrote. Blocked
+/// commands (no reply yet) and failed ones aren't logged.
+pub fn feed(g_data: &mut GData, command: &Command, args: &[String], reply: &[u8]) {
+    let aof = &mut g_data.aof;
+    if reply.first().is_none_or(|&tag| tag == Tag::Err as u8)
+        || aof.loading
+        || (aof.file.is_none() && aof.rewrite.is_none())
+    {
+        return;
+    }
+    let reply = RedisValue::decode(reply).map_or(RedisValue::Nil, |(value, _)| value);
+    let Some(args) = effect(command, args, &reply) else {
+        return;
+    };
+
+    let mut request = Vec::new();
+    resp::encode_request(&args, &mut request);
+    if aof.file.is_some() {
+        aof.buf.extend_from_slice(&request);
+    }
+    if let Some(rewrite) = aof.rewrite.as_mut() {
+        rewrite.buf.extend_from_slice(&request);
+    }
+}
+
+/// Append the buffered commands to the file, and fsync as configured
+pub fn flush(g_data: &mut GData) {
+    let aof = &mut g_data.aof;
+    if let Err(e) = aof.write_buf() {
+        // The rest stays in the buffer, the next flush goes on with it
+        log_error!("persist", "Error writing to the AOF: {}", e);
+        return;
+    }
+    let Some(file) = aof.file.as_mut() else {
+        return;
+    };
+
+    let now_ms = get_monotonic_time_ms();
+    match g_data.config.appendfsync {
+        Fsync::Always => {
+            if let Err(e) = file.sync_data() {
+                log_error!("persist", "Can't fsync the AOF: {}", e);
+            }
+            aof.last_fsync_ms = now_ms;
+        }
+        Fsync::EverySec if now_ms >= aof.last_fsync_ms + 1000 => {
+            aof.last_fsync_ms = now_ms;
+            if let Ok(file) = file.try_clone() {
+                g_data.thread_pool.submit(move || {
+                    if let Err(e) = file.sync_data() {
+                        log_error!("persist", "Can't fsync the AOF: {}", e);
+                    }
+                });
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Milliseconds until cron() has something to do, if anything
+pub fn next_timer_ms(g_data: &GData, now_ms: u64) -> Option<u64> {
+    let aof = &g_data.aof;
+    if aof.rewrite.is_some() {
+        // Nothing wakes the event loop when the thread is done
+        Some(now_ms + 100)
+    } else if aof.file.is_some() && g_data.config.appendfsync == Fsync::EverySec {
+        Some(aof.last_fsync_ms + 1000)
+    } else {
+        None
+    }
+}
+
+/// Periodic work: finish a rewrite the thread pool is done with, follow
+/// appendonly being switched on or off, start an automatic rewrite
+pub fn cron(g_data: &mut GData) {
+    let done = g_data.aof.rewrite.as_ref().and_then(|rewrite| rewrite.result.lock().unwrap().take());
+    if let Some(result) = done {
+        let rewrite = g_data.aof.rewrite.take().unwrap();
+        if let Err(e) = result.and_then(|()| install_rewrite(g_data, &rewrite)) {
+            log_error!("persist", "Background AOF rewrite failed: {}", e);
+            let _ = fs::remove_file(&rewrite.temp_path);
+        }
+    }
+
+    let aof = &g_data.aof;
+    if !g_data.config.appendonly {
+        if aof.file.is_some() {
+            flush(g_data);
+            // What didn't make it is in the next file's initial rewrite
+            let aof = &mut g_data.aof;
+            aof.file = None;
+            aof.buf.clear();
+            aof.written = 0;
+            log_notice!("persist", "AOF turned off");
+        }
+    } else if aof.rewrite.is_none() {
+        if aof.file.is_none() {
+            // Write the initial file from the current dataset
+            start_rewrite(g_data);
+        } else {
+            let percentage = g_data.config.auto_aof_rewrite_percentage;
+            let base = aof.base_size.max(1);
+            let growth = aof.size.saturating_sub(base) * 100 / base;
+            if percentage > 0 && aof.size >= g_data.config.auto_aof_rewrite_min_size && growth >= percentage {
+                log_notice!("persist", "Starting automatic AOF rewrite on {}% growth", growth);
+                start_rewrite(g_data);
+            }
+        }
+    }
+}
+
+// Runs on the thread pool
+fn write_rewrite(path: &str, snapshot: &Snapshot, rdb_preamble: bool) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    if rdb_preamble {
+        file.write_all(&rdb::serialize(snapshot))?;
+    } else {
+        write_commands(&mut file, snapshot)?;
+    }
+    let file = file.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()
+}
+
+fn write_commands(file: &mut impl Write, snapshot: &Snapshot) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut emit = |args: Vec<String>| -> io::Result<()> {
+        request.clear();
+        resp::encode_request(&args, &mut request);
+        file.write_all(&request)
+    };
+    let cmd = |name: &str, key: &str| vec![name.to_string(), key.to_string()];
+
+    for code in &snapshot.libraries {
+        emit(vec!["FUNCTION".to_string(), "LOAD".to_string(), code.clone()])?;
+    }
+    for (key, value, expire_at) in &snapshot.keys {
+        match value {
+            Value::Init => continue,
+            Value::Str(s) => emit([cmd("SET", key), vec![s.to_string()]].concat())?,
+            Value::List(list) => {
+                let items: Vec<String> = list.iter().cloned().collect();
+                for chunk in items.chunks(REWRITE_ITEMS_PER_CMD) {
+                    emit([cmd("RPUSH", key), chunk.to_vec()].concat())?;
+                }
+            }
+            Value::ZSet(zset) => {
+                let members: Vec<(f64, &String)> =
+                    zset.name_to_node.iter().map(|(name, node)| (node.lock().unwrap().score, name)).collect();
+                for chunk in members.chunks(REWRITE_ITEMS_PER_CMD) {
+                    let mut args = cmd("ZADD", key);
+                    for (score, name) in chunk {
+                        args.push(score.to_string());
+                        args.push(name.to_string());
+                    }
+                    emit(args)?;
+                }
+            }
+            Value::Stream(_) => {
+                let payload = bytes_to_chars(&dump_value(value));
+                emit([cmd("RESTORE", key), vec!["0".to_string(), payload]].concat())?;
+            }
+        }
+        if let Some(at) = expire_at {
+            emit([cmd("PEXPIREAT", key), vec![at.to_string()]].concat())?;
+        }
+    }
+    Ok(())
+}
+
+/// Start a background rewrite; false if one is already running
+pub fn start_rewrite(g_data: &mut GData) -> bool {
+    if g_data.aof.rewrite.is_some() {
+        return false;
+    }
+    let snapshot = Snapshot::capture(g_data, scripting::function_library_codes());
+    let rdb_preamble = g_data.config.aof_use_rdb_preamble;
+    let temp_path = format!("temp-rewriteaof-bg-{}.aof", std::process::id());
+    let result: RewriteResult = Arc::default();
+
+    let (path, slot) = (temp_path.clone(), result.clone());
+    g_data.thread_pool.submit(move || {
+        let outcome = write_rewrite(&path, &snapshot, rdb_preamble);
+        *slot.lock().unwrap() = Some(outcome);
+    });
+    g_data.aof.rewrite = Some(Rewrite { temp_path, buf: Vec::new(), result });
+    true
+}
+
+// Back on the event loop: catch the new file up and swap it in
+fn install_rewrite(g_data: &mut GData, rewrite: &Rewrite) -> io::Result<()> {
+    // Whatever the old file still has buffered is in rewrite.buf too
+    g_data.aof.buf.clear();
+    g_data.aof.written = 0;
+
+    let mut file = OpenOptions::new().append(true).open(&rewrite.temp_path)?;
+    file.write_all(&rewrite.buf)?;
+    file.sync_data()?;
+    fs::rename(&rewrite.temp_path, &g_data.config.appendfilename)?;
+
+    let size = file.metadata()?.len();
+    log_notice!("persist", "Background AOF rewrite finished: {} bytes", size);
+    if g_data.config.appendonly {
+        let aof = &mut g_data.aof;
+        aof.file = Some(file);
+        aof.size = size;
+        aof.base_size = size;
+    }
+    Ok(())
+}
+
+pub struct LoadStats {
+    /// What the snapshot preamble held, if the file starts with one
+    pub preamble: Option<rdb::LoadStats>,
+    /// Commands replayed after it
+    pub commands: usize,
+}
+
+// Run the commands of the AOF in `data`, after the snapshot preamble if
+// it starts with one. Returns what the preamble held, how many commands ran
+// and where the last complete one ends.
+//
+// With load-skip-corrupt, an unknown command is skipped, and so is a
+// garbled one, up to the next line that starts a command.
+fn replay(path: &str, data: &[u8]) -> Result<(Option<rdb::LoadStats>, usize, usize), String> {
+    let (preamble, mut pos) = if data.starts_with(rdb::RDB_MAGIC) {
+        let (stats, used) = rdb::load_from(data).map_err(|e| format!("{}: bad snapshot preamble: {}", path, e))?;
+        (Some(stats), used)
+    } else {
+        (None, 0)
+    };
+
+    // Whatever the server once accepted
+    let limits = FrameLimits { max_request_size: usize::MAX, max_args: MAX_ARGS, max_arg_len: i64::MAX as usize };
+    let skip_corrupt = with_global_data(|g_data| {
+        g_data.aof.loading = true;
+        g_data.config.load_skip_corrupt
+    });
+    let mut commands = 0;
+    let mut out = Buffer::new();
+    let outcome = loop {
+        info::loading_progress(pos);
+        match resp::parse_request(&data[pos..], &limits) {
+            Ok(Some((args, used))) => {
+                let Some(command) = command::lookup_original(&args[0]) else {
+                    let e = format!("{}: unknown command '{}' at offset {}", path, args[0], pos);
+                    if !skip_corrupt {
+                        break Err(e);
+                    }
+                    log_warning!("persist", "Skipping {}", e);
+                    info::loading_skipped();
+                    pos += used;
+                    continue;
+                };
+                run_command(command, &args, &mut out);
+                out.consume(out.len());
+                pos += used;
+                commands += 1;
+            }
+            Ok(None) => break Ok(()),
+            Err(e) => {
+                let e = format!("{}: bad command at offset {}: {}", path, pos, e);
+                if !skip_corrupt {
+                    break Err(e);
+                }
+                log_warning!("persist", "Skipping {}", e);
+                info::loading_skipped();
+                // On to the next "*<argc>" at the start of a line, if any
+                match data[pos + 1..].windows(2).position(|w| w == b"\n*") {
+                    Some(at) => pos += at + 2,
+                    None => {
+                        pos = data.len();
+                        break Ok(());
+                    }
+                }
+            }
+        }
+    };
+    with_global_data(|g_data| g_data.aof.loading = false);
+    outcome.map(|()| (preamble, commands, pos))
+}
+
+/// Replay the append-only file at `path` and keep it open for appending.
+/// Returns None if there's no file. A command cut off at the end, as a crash
+/// mid-write leaves it, is dropped from the file.
+pub fn load_file(path: &str) -> Result<Option<LoadStats>, String> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("can't read {}: {}", path, e)),
+    };
+    info::loading_start(path, data.len());
+    let replayed = replay(path, &data);
+    info::loading_end();
+    let (preamble, commands, pos) = replayed?;
+
+    let file = OpenOptions::new().append(true).open(path).map_err(|e| format!("can't open {}: {}", path, e))?;
+    if pos < data.len() {
+        log_warning!("persist", "{} ends with an incomplete command, truncating {} bytes", path, data.len() - pos);
+        file.set_len(pos as u64).map_err(|e| format!("can't truncate {}: {}", path, e))?;
+    }
+    with_global_data(|g_data| {
+        let aof = &mut g_data.aof;
+        aof.file = Some(file);
+        aof.size = pos as u64;
+        aof.base_size = pos as u64;
+    });
+    Ok(Some(LoadStats { preamble, commands }))
+}
+
+/// Write out the buffer and fsync the file now, whatever appendfsync says
+fn sync_now(g_data: &mut GData) -> io::Result<()> {
+    let aof = &mut g_data.aof;
+    aof.write_buf()?;
+    let Some(file) = aof.file.as_mut() else {
+        return Ok(());
+    };
+    file.sync_data()?;
+    aof.last_fsync_ms = get_monotonic_time_ms();
+    Ok(())
+}
+
+// FSYNC
+pub fn do_fsync(_cmd: &[String], out: &mut Buffer) -> CmdResult {
+    with_global_data(|g_data| {
+        if !g_data.aof.is_open() {
+            out_err(out, "ERR FSYNC needs the append only file (appendonly yes)");
+        } else if let Err(e) = sync_now(g_data) {
+            log_error!("persist", "Can't fsync the AOF: {}", e);
+            out_err(out, &format!("ERR Can't fsync the append only file: {}", e));
+        } else {
+            out_str(out, "OK");
+        }
+    });
+    Ok(())
+}
+
+// BGREWRITEAOF
+pub fn do_bgrewriteaof(_cmd: &[String], out: &mut Buffer) -> CmdResult {
+    with_global_data(|g_data| {
+        if start_rewrite(g_data) {
+            out_str(out, "Background append only file rewriting started");
+        } else {
+            out_err(out, "ERR Background append only file rewriting already in progress");
+        }
+    });
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use redis::protocol::RedisValue;
+
+    use redis::protocol::{FrameLimits, MAX_ARGS};
+
+    use super::effect;
+    use crate::sim::testing::shared;
+    use crate::store::tests::Flaky;
+    use crate::{command, get_current_time_ms, resp, store};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(str::to_string).collect()
+    }
+
+    fn effect_of(line: &str, reply: RedisValue) -> Option<Vec<String>> {
+        let args = args(line);
+        effect(command::lookup(&args[0]).unwrap(), &args, &reply)
+    }
+
+    // Every way to set a TTL is logged as the PEXPIREAT it came to
+    #[test]
+    fn ttls_are_logged_as_unix_times() {
+        let _sim = shared();
+        let now = get_current_time_ms();
+        let set = RedisValue::Int(1);
+        assert_eq!(effect_of("expire k 10", set.clone()), Some(args(&format!("PEXPIREAT k {}", now + 10_000))));
+        assert_eq!(effect_of("PEXPIRE k 10 GT", set.clone()), Some(args(&format!("PEXPIREAT k {}", now + 10))));
+        assert_eq!(effect_of("expireat k 1700000000", set.clone()), Some(args("PEXPIREAT k 1700000000000")));
+        assert_eq!(effect_of("pexpireat k 1700000000123", set), Some(args("PEXPIREAT k 1700000000123")));
+        // A condition that failed leaves nothing to replay
+        assert_eq!(effect_of("expire k 10 NX", RedisValue::Int(0)), None);
+    }
+
+    // RESTORE's relative TTL becomes an ABSTTL one; no TTL and ABSTTL ones
+    // are kept as they are
+    #[test]
+    fn restore_is_logged_with_an_absolute_ttl() {
+        let _sim = shared();
+        let now = get_current_time_ms();
+        let ok = RedisValue::Str("OK".into());
+        assert_eq!(
+            effect_of("RESTORE k 5000 payload REPLACE", ok.clone()),
+            Some(args(&format!("RESTORE k {} payload REPLACE ABSTTL", now + 5000)))
+        );
+        assert_eq!(effect_of("RESTORE k 0 payload", ok.clone()), Some(args("RESTORE k 0 payload")));
+        assert_eq!(
+            effect_of("RESTORE k 1700000000000 payload ABSTTL", ok),
+            Some(args("RESTORE k 1700000000000 payload ABSTTL"))
+        );
+    }
+
+    // A disk that fills up mid-command, then has room again
+    #[test]
+    fn a_failed_write_leaves_no_command_twice() {
+        let commands = [args("SET aof:a 1"), args("RPUSH aof:l x y z"), args("DEL aof:a")];
+        let mut buf = Vec::new();
+        for command in &commands {
+            resp::encode_request(command, &mut buf);
+        }
+        let total = buf.len();
+        let mut file = Flaky { out: Vec::new(), fail_at: Some(total / 2) };
+        let (mut written, mut size) = (0, 0);
+
+        assert!(store::write_buf(&mut file, &mut buf, &mut written, &mut size).is_err());
+        assert!(written > 0 && written < total);
+        assert_eq!(size, file.out.len() as u64);
+
+        store::write_buf(&mut file, &mut buf, &mut written, &mut size).unwrap();
+        assert_eq!(size, total as u64);
+        let limits = FrameLimits { max_request_size: usize::MAX, max_args: MAX_ARGS, max_arg_len: i64::MAX as usize };
+        let mut replayed = Vec::new();
+        let mut rest = &file.out[..];
+        while let Some((args, used)) = resp::parse_request(rest, &limits).unwrap() {
+            replayed.push(args);
+            rest = &rest[used..];
+        }
+        assert!(rest.is_empty());
+        assert_eq!(replayed, commands);
+    }
+}
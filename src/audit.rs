@@ -0,0 +1,148 @@
+/* Audit log */
+//
+// With `audit-log` set to a file, every write command that succeeds is
+// recorded there as one line: the unix time in ms, the client (its id, as
+// CLIENT ID and CLIENT LIST show it, or "script" for a command a script
+// ran), the command and the keys it names:
+//
+//   1760512359123 id:42 SET "user:1"
+//   1760512359125 script DEL "user:1" "user:2"
+//
+// Keys are quoted and escaped, so a line always splits on spaces. Commands
+// are taken where they're propagated to the AOF, so a command is audited
+// exactly when it changes the dataset, under its built-in name. The
+// AOF replayed at startup isn't audited again.
+//
+// `audit-log-keys` keeps only the commands naming a key that matches the
+// glob; commands that name no key (FLUSHALL, FUNCTION LOAD) are always kept.
+// `audit-log-categories` keeps only commands of the groups listed, as COMMAND
+// DOCS shows them (string, list, sorted-set, ...).
+//
+// Lines collect in a buffer written once per event loop iteration, as AOF
+// commands are. When the file passes `audit-log-max-size` it's rotated:
+// audit.log becomes audit.log.1, audit.log.1 becomes audit.log.2 and so on,
+// keeping `audit-log-max-files` old files.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+
+use crate::{aof, blocking};
+use crate::command::Command;
+use crate::config::Config;
+use crate::events::ServerEvent;
+use crate::logging::{log_notice, log_warning};
+use crate::{get_current_time_ms, glob_match, logging, GData};
+
+#[derive(Debug, Default)]
+pub struct Audit {
+    file: Option<File>,
+    // The audit-log the file was opened for
+    path: String,
+    size: u64,
+    buf: Vec<u8>,
+}
+
+// Is `command`, naming `keys`, one the configuration asks to record
+fn wanted(config: &Config, command: &Command, keys: &[&str]) -> bool {
+    let categories = &config.audit_log_categories;
+    if !categories.is_empty() && !categories.iter().any(|group| group == command.group) {
+        return false;
+    }
+    keys.is_empty() || keys.iter().any(|key| glob_match(&config.audit_log_keys, key, false))
+}
+
+/// Record the commands the AOF logs that ran without an error
+pub fn on_event(g_data: &mut GData, event: &ServerEvent) {
+    let ServerEvent::CommandExecuted { command, args, failed: false, .. } = *event else {
+        return;
+    };
+    if g_data.config.audit_log.is_empty() || g_data.aof.is_loading() || !aof::should_log(command, args) {
+        return;
+    }
+    let keys = command.keys(args);
+    if !wanted(&g_data.config, command, &keys) {
+        return;
+    }
+
+    let client = match blocking::current_client().and_then(|fd| g_data.clients.id_of(fd)) {
+        Some(id) => format!("id:{}", id),
+        None => "script".to_string(),
+    };
+    let mut line = format!("{} {} {}", get_current_time_ms(), client, command.name.to_uppercase());
+    for key in keys {
+        line.push_str(&format!(" {:?}", key));
+    }
+    line.push('\n');
+    g_data.audit.buf.extend_from_slice(line.as_bytes());
+}
+
+// audit.log.n
+fn rotated_path(path: &str, n: u64) -> String {
+    format!("{}.{}", path, n)
+}
+
+// Shift the old files along, dropping the oldest, and start a new one
+fn rotate(audit: &mut Audit, max_files: u64) -> io::Result<()> {
+    audit.file = None;
+    if max_files == 0 {
+        fs::remove_file(&audit.path)?;
+    } else {
+        for n in (1..max_files).rev() {
+            let from = rotated_path(&audit.path, n);
+            if fs::exists(&from)? {
+                fs::rename(&from, rotated_path(&audit.path, n + 1))?;
+            }
+        }
+        fs::rename(&audit.path, rotated_path(&audit.path, 1))?;
+    }
+    audit.file = Some(logging::open_logfile(&audit.path)?);
+    audit.size = 0;
+    Ok(())
+}
+
+/// Write the recorded lines to the audit log, opening it when audit-log
+/// changed and rotating it once it's big enough
+pub fn flush(g_data: &mut GData) {
+    let audit = &mut g_data.audit;
+    let config = &g_data.config;
+    if audit.path != config.audit_log {
+        audit.file = None;
+        audit.path = config.audit_log.clone();
+        if !audit.path.is_empty() {
+            log_notice!("audit", "Auditing writes to {}", audit.path);
+        }
+    }
+    if audit.buf.is_empty() || audit.path.is_empty() {
+        audit.buf.clear();
+        return;
+    }
+
+    if audit.file.is_none() {
+        match logging::open_logfile(&audit.path).and_then(|file| Ok((file.metadata()?.len(), file))) {
+            Ok((size, file)) => {
+                audit.file = Some(file);
+                audit.size = size;
+            }
+            Err(e) => {
+                // Retried on the next flush, keeping what's recorded so far
+                log_warning!("audit", "Can't open audit log {}: {}", audit.path, e);
+                return;
+            }
+        }
+    }
+    let file = audit.file.as_mut().unwrap();
+    if let Err(e) = file.write_all(&audit.buf) {
+        log_warning!("audit", "Can't write to audit log {}: {}", audit.path, e);
+        audit.file = None;
+        return;
+    }
+    audit.size += audit.buf.len() as u64;
+    audit.buf.clear();
+
+    if config.audit_log_max_size > 0
+        && audit.size >= config.audit_log_max_size
+        && let Err(e) = rotate(audit, config.audit_log_max_files)
+    {
+        log_warning!("audit", "Can't rotate audit log {}: {}", audit.path, e);
+    }
+}
@@ -0,0 +1,277 @@
+/* Benchmark */
+//
+//   redis bench [-h host] [-p port] [-c clients] [-t set,get,zadd]
+//               [-r keyspace] [-d value-size] [--duration seconds] [--resp]
+//               [--in-process]
+//
+// Like redis-benchmark: each test in -t runs in turn for --duration seconds
+// with -c clients, each on its own connection and thread, sending one command
+// at a time. Keys are picked at random from -r distinct names. Throughput and
+// latency percentiles are printed per test. --resp sends RESP instead of the
+// framed protocol.
+//
+// --in-process runs no network at all: the commands go straight to the
+// handlers, through a ServerHandle on a dataset in the bench process, which
+// measures the commands and data structures on their own.
+
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use redis::client::RedisClient;
+use redis::protocol::{RedisValue, ReplyFormat};
+
+use crate::config::Config;
+use crate::handle::ServerHandle;
+use crate::histogram::Histogram;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Test {
+    Set,
+    Get,
+    Zadd,
+}
+
+impl Test {
+    fn parse(name: &str) -> Option<Test> {
+        match name.to_lowercase().as_str() {
+            "set" => Some(Test::Set),
+            "get" => Some(Test::Get),
+            "zadd" => Some(Test::Zadd),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Test::Set => "SET",
+            Test::Get => "GET",
+            Test::Zadd => "ZADD",
+        }
+    }
+}
+
+struct Options {
+    host: String,
+    port: u16,
+    clients: usize,
+    tests: Vec<Test>,
+    keyspace: u64,
+    value_size: usize,
+    duration: Duration,
+    resp: bool,
+    in_process: bool,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: redis bench [-h host] [-p port] [-c clients] [-t set,get,zadd] \
+         [-r keyspace] [-d value-size] [--duration seconds] [--resp] [--in-process]"
+    );
+    std::process::exit(1);
+}
+
+fn parse_options(args: &[String]) -> Options {
+    let mut opts = Options {
+        host: "127.0.0.1".to_string(),
+        port: 1234,
+        clients: 50,
+        tests: vec![Test::Set, Test::Get, Test::Zadd],
+        keyspace: 10_000,
+        value_size: 3,
+        duration: Duration::from_secs(5),
+        resp: false,
+        in_process: false,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        // Flags without a value
+        match arg.as_str() {
+            "--resp" => {
+                opts.resp = true;
+                continue;
+            }
+            "--in-process" => {
+                opts.in_process = true;
+                continue;
+            }
+            _ => {}
+        }
+        let value = args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "-h" => opts.host = value.clone(),
+            "-p" => opts.port = value.parse().unwrap_or_else(|_| usage()),
+            "-c" => opts.clients = value.parse().ok().filter(|&n| n > 0).unwrap_or_else(|| usage()),
+            "-t" => {
+                opts.tests = value.split(',').map(|name| Test::parse(name).unwrap_or_else(|| usage())).collect();
+            }
+            "-r" => opts.keyspace = value.parse().ok().filter(|&n| n > 0).unwrap_or_else(|| usage()),
+            "-d" => opts.value_size = value.parse().unwrap_or_else(|_| usage()),
+            "--duration" => {
+                let secs: f64 = value.parse().ok().filter(|&s: &f64| s > 0.0).unwrap_or_else(|| usage());
+                opts.duration = Duration::from_secs_f64(secs);
+            }
+            _ => usage(),
+        }
+    }
+    opts
+}
+
+// xorshift64*, plenty for picking keys
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+struct ClientResult {
+    latency_us: Histogram,
+    errors: u64,
+}
+
+// Where a bench client sends its commands
+enum Target {
+    Remote(RedisClient),
+    InProcess(ServerHandle),
+}
+
+impl Target {
+    fn command(&mut self, args: &[&str]) -> io::Result<RedisValue> {
+        match self {
+            Target::Remote(client) => client.command(args),
+            Target::InProcess(server) => Ok(server.execute(args)),
+        }
+    }
+}
+
+fn open_connection(opts: &Options) -> io::Result<RedisClient> {
+    let format = if opts.resp { ReplyFormat::Resp } else { ReplyFormat::Framed };
+    Ok(RedisClient::connect((opts.host.as_str(), opts.port))?.with_format(format))
+}
+
+fn open_target(opts: &Options, server: Option<ServerHandle>) -> io::Result<Target> {
+    match server {
+        Some(server) => Ok(Target::InProcess(server)),
+        None => open_connection(opts).map(Target::Remote),
+    }
+}
+
+/// One client: send `test` commands back to back until `deadline`
+fn run_client(
+    opts: &Options,
+    server: Option<ServerHandle>,
+    test: Test,
+    seed: u64,
+    deadline: Instant,
+) -> io::Result<ClientResult> {
+    let mut client = open_target(opts, server)?;
+    let mut rng = Rng(seed | 1);
+    let value = "x".repeat(opts.value_size);
+    let mut result = ClientResult { latency_us: Histogram::new(), errors: 0 };
+
+    while Instant::now() < deadline {
+        let n = rng.next() % opts.keyspace;
+        let key = format!("key:{:012}", n);
+        let member = format!("member:{:012}", n);
+        let score = (rng.next() % 1_000_000).to_string();
+        let args: Vec<&str> = match test {
+            Test::Set => vec!["SET", &key, &value],
+            Test::Get => vec!["GET", &key],
+            Test::Zadd => vec!["ZADD", "bench:zset", &score, &member],
+        };
+
+        let start = Instant::now();
+        let reply = client.command(&args)?;
+        result.latency_us.record(start.elapsed().as_micros() as u64);
+        if matches!(reply, RedisValue::Err(_)) {
+            result.errors += 1;
+        }
+    }
+    Ok(result)
+}
+
+fn ms(us: u64) -> f64 {
+    us as f64 / 1000.0
+}
+
+fn run_test(opts: &Options, server: Option<ServerHandle>, test: Test) {
+    let start = Instant::now();
+    let deadline = start + opts.duration;
+    let results: Vec<io::Result<ClientResult>> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..opts.clients)
+            .map(|i| {
+                let seed = start.elapsed().as_nanos() as u64 ^ ((i as u64 + 1) << 32);
+                scope.spawn(move || run_client(opts, server, test, seed, deadline))
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join().expect("bench client panicked")).collect()
+    });
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let mut latency_us = Histogram::new();
+    let mut errors = 0;
+    let mut failed_clients = 0;
+    for result in &results {
+        match result {
+            Ok(result) => {
+                latency_us.merge(&result.latency_us);
+                errors += result.errors;
+            }
+            Err(e) => {
+                if failed_clients == 0 {
+                    eprintln!("client error: {}", e);
+                }
+                failed_clients += 1;
+            }
+        }
+    }
+
+    println!("====== {} ======", test.name());
+    println!(
+        "  {} clients, {:.2} seconds, {} requests, {} error replies",
+        opts.clients,
+        elapsed,
+        latency_us.count(),
+        errors
+    );
+    if failed_clients > 0 {
+        println!("  {} clients lost their connection", failed_clients);
+    }
+    println!("  throughput: {:.2} requests per second", latency_us.count() as f64 / elapsed);
+    println!(
+        "  latency (msec): avg={:.3} min={:.3} p50={:.3} p95={:.3} p99={:.3} max={:.3}",
+        latency_us.mean() / 1000.0,
+        ms(latency_us.min()),
+        ms(latency_us.percentile(50.0)),
+        ms(latency_us.percentile(95.0)),
+        ms(latency_us.percentile(99.0)),
+        ms(latency_us.max())
+    );
+    println!();
+}
+
+/// Entry point for `redis bench ...`
+pub fn run(args: &[String]) -> io::Result<()> {
+    let opts = parse_options(args);
+
+    let server = opts.in_process.then(|| ServerHandle::new(Config::default()));
+
+    // Fail fast if the server isn't there
+    if server.is_none()
+        && let Err(e) = open_connection(&opts)
+    {
+        eprintln!("Could not connect to {}:{}: {}", opts.host, opts.port, e);
+        std::process::exit(1);
+    }
+
+    for &test in &opts.tests {
+        run_test(&opts, server, test);
+    }
+    Ok(())
+}
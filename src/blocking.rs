@@ -0,0 +1,230 @@
+/* Blocking operations */
+//
+// A blocking command (BLMOVE ...) that finds nothing to do parks its client
+// here instead of replying. Writes that add data to a key call
+// signal_key_ready; after the writing command finishes, serve_ready_keys
+// re-runs the parked commands of that key's waiters in arrival order, so the
+// first client to block is the first one served. Clients that time out get
+// a nil reply from the timer loop, which finds them on a timer wheel.
+//
+// A command with too much to do at once (KEYS on a big keyspace) suspends
+// its client the same way, waiting on no key: resume_suspended re-runs it
+// on the next event loop round, and it picks up where it left off.
+//
+// The client whose command is running is kept per thread rather than in the
+// global data, so starting and finishing a command takes no lock.
+
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::RawFd;
+
+use crate::timer::TimerWheel;
+use crate::{conn_touch, execute_command, out_nil, with_global_data, with_global_data_read, Buffer, Conn, GData};
+
+thread_local! {
+    // Client whose command is running; None inside scripts, which never block
+    static CURRENT_CLIENT: Cell<Option<RawFd>> = const { Cell::new(None) };
+    // Set by block() so the caller knows not to reply yet
+    static JUST_BLOCKED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The client whose command is running, None inside scripts
+pub fn current_client() -> Option<RawFd> {
+    CURRENT_CLIENT.get()
+}
+
+#[derive(Debug)]
+struct BlockedClient {
+    cmd: Vec<String>,
+    keys: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Blocking {
+    // Re-running a waiter: if it has to block again it keeps its place in line
+    serving: bool,
+    clients: HashMap<RawFd, BlockedClient>,
+    // Deadlines (monotonic ms) of the clients that don't wait forever
+    timeouts: TimerWheel<RawFd>,
+    // key -> clients waiting on it, oldest first
+    waiters: HashMap<String, VecDeque<RawFd>>,
+    ready_keys: Vec<String>,
+    // Suspended clients, to re-run next round
+    suspended: Vec<RawFd>,
+}
+
+impl Blocking {
+    /// Park the running client until one of `keys` gets data or `timeout_ms`
+    /// (0 = forever) passes. Returns false if the command can't block here,
+    /// e.g. inside a script; the caller then replies as if it timed out.
+    pub fn block(&mut self, cmd: &[String], keys: &[String], timeout_ms: u64, now_ms: u64) -> bool {
+        let Some(fd) = current_client() else {
+            return false;
+        };
+
+        for key in keys {
+            let queue = self.waiters.entry(key.clone()).or_default();
+            if self.serving {
+                queue.push_front(fd);
+            } else {
+                queue.push_back(fd);
+            }
+        }
+        self.clients.insert(fd, BlockedClient { cmd: cmd.to_vec(), keys: keys.to_vec() });
+        if timeout_ms > 0 {
+            self.timeouts.insert(fd, now_ms.saturating_add(timeout_ms));
+        }
+        JUST_BLOCKED.set(true);
+        true
+    }
+
+    /// Park the running client until the next event loop round, when
+    /// resume_suspended runs `cmd` again. Returns false where the command
+    /// can't wait, as block does; the caller then has to finish at once.
+    pub fn suspend(&mut self, cmd: &[String]) -> bool {
+        let Some(fd) = current_client() else {
+            return false;
+        };
+        self.clients.insert(fd, BlockedClient { cmd: cmd.to_vec(), keys: Vec::new() });
+        self.suspended.push(fd);
+        JUST_BLOCKED.set(true);
+        true
+    }
+
+    /// Whether a command is to be resumed, so the event loop mustn't wait
+    pub fn has_suspended(&self) -> bool {
+        !self.suspended.is_empty()
+    }
+
+    /// Note that `key` received data, if anyone is waiting for it
+    pub fn signal_key_ready(&mut self, key: &str) {
+        if self.waiters.contains_key(key) && !self.ready_keys.iter().any(|k| k == key) {
+            self.ready_keys.push(key.to_string());
+        }
+    }
+
+    /// Forget a blocked client (served, timed out or disconnected)
+    pub fn unblock(&mut self, fd: RawFd) -> Option<Vec<String>> {
+        let client = self.clients.remove(&fd)?;
+        self.timeouts.remove(&fd);
+        self.suspended.retain(|&suspended| suspended != fd);
+        for key in &client.keys {
+            if let Some(queue) = self.waiters.get_mut(key) {
+                queue.retain(|&waiter| waiter != fd);
+                if queue.is_empty() {
+                    self.waiters.remove(key);
+                }
+            }
+        }
+        Some(client.cmd)
+    }
+
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.timeouts.next_deadline()
+    }
+}
+
+/// Run a client's command. Returns true if the command blocked, in which case
+/// nothing was written to `out`.
+pub fn execute_for_client(fd: RawFd, parts: &[String], out: &mut Buffer) -> bool {
+    CURRENT_CLIENT.set(Some(fd));
+    JUST_BLOCKED.set(false);
+    execute_command(parts, out);
+    CURRENT_CLIENT.set(None);
+    JUST_BLOCKED.get()
+}
+
+/// Run `f` with no current client, so commands it issues can't block
+pub fn without_client<R>(f: impl FnOnce() -> R) -> R {
+    let saved = CURRENT_CLIENT.take();
+    let result = f();
+    CURRENT_CLIENT.set(saved);
+    result
+}
+
+/// Serve clients blocked on keys that received data. Serving one client can
+/// feed another key (BLMOVE pushes to its destination), so keep going until
+/// no key is ready.
+pub fn serve_ready_keys() {
+    loop {
+        if with_global_data_read(|g_data| g_data.blocking.ready_keys.is_empty()) {
+            break;
+        }
+        let ready = with_global_data(|g_data| std::mem::take(&mut g_data.blocking.ready_keys));
+
+        for key in ready {
+            loop {
+                // Take the oldest waiter and its connection out of the tables
+                let next = with_global_data(|g_data| {
+                    let fd = *g_data.blocking.waiters.get(&key)?.front()?;
+                    let cmd = g_data.blocking.unblock(fd)?;
+                    let conn = g_data.fd2conn.remove(&fd);
+                    g_data.blocking.serving = conn.is_some();
+                    Some((fd, cmd, conn, g_data.config.max_reply_size))
+                });
+                let Some((fd, cmd, conn, max_reply_size)) = next else {
+                    break;
+                };
+                let Some(conn) = conn else {
+                    continue; // Disconnected, nothing to serve
+                };
+
+                // If someone got there first, it waits again at the head of the line
+                if rerun(fd, &cmd, conn, max_reply_size) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Run the command of a client taken out of the blocked ones, replying on
+// `conn` and putting it back in the table. Returns true if it blocked again.
+fn rerun(fd: RawFd, cmd: &[String], mut conn: Conn, max_reply_size: usize) -> bool {
+    let header_pos = conn.outgoing.response_begin();
+    let blocked_again = execute_for_client(fd, cmd, &mut conn.outgoing);
+    if blocked_again {
+        conn.outgoing.truncate(header_pos);
+    } else {
+        conn.finish_command_reply(header_pos, max_reply_size);
+        conn.unblock();
+    }
+
+    with_global_data(|g_data| {
+        g_data.blocking.serving = false;
+        conn_touch(g_data, fd, &mut conn);
+        g_data.fd2conn.insert(fd, conn);
+    });
+    blocked_again
+}
+
+/// Re-run the commands suspended last round, each doing its next part
+pub fn resume_suspended() {
+    let suspended = with_global_data(|g_data| std::mem::take(&mut g_data.blocking.suspended));
+    for fd in suspended {
+        let next = with_global_data(|g_data| {
+            let cmd = g_data.blocking.unblock(fd)?;
+            let conn = g_data.fd2conn.remove(&fd)?;
+            Some((cmd, conn, g_data.config.max_reply_size))
+        });
+        if let Some((cmd, conn, max_reply_size)) = next {
+            rerun(fd, &cmd, conn, max_reply_size);
+        }
+    }
+}
+
+/// Reply nil to blocked clients whose timeout has passed
+pub fn expire_timeouts(g_data: &mut GData, now_ms: u64) {
+    for fd in g_data.blocking.timeouts.expire(now_ms, usize::MAX) {
+        g_data.blocking.unblock(fd);
+        let max_reply_size = g_data.config.max_reply_size;
+        if let Some(mut conn) = g_data.fd2conn.remove(&fd) {
+            let header_pos = conn.outgoing.response_begin();
+            out_nil(&mut conn.outgoing);
+            conn.finish_command_reply(header_pos, max_reply_size);
+            conn.unblock();
+            conn_touch(g_data, fd, &mut conn);
+            g_data.fd2conn.insert(fd, conn);
+        }
+    }
+}
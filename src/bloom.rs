@@ -0,0 +1,97 @@
+/* Negative lookup filter */
+//
+// With `lookup-filter yes` each table of the keyspace keeps a counting Bloom
+// filter of the hashes of its keys. A lookup asks it first: when the filter
+// says the key is absent, the bucket isn't walked at all, which saves
+// following a chain of entries scattered over the heap for every miss of a
+// workload that mostly asks for keys it doesn't have (a cache in front of a
+// database). When it says "maybe", the bucket is walked as before, so a false
+// positive only costs what every lookup cost without the filter.
+//
+// Each key sets PROBES counters, chosen from the hash already computed for
+// the bucket, and deleting it takes them back down, so the filter stays as
+// sharp as the keys present allow. Counters are four bits; one that reaches
+// 15 stays there for good, which can only make the filter answer "maybe" more
+// often. A table's filter is sized for the most keys it holds before it's
+// resized, COUNTERS_PER_KEY counters each, about 3% false positives at full
+// load; a resized table gets a fresh filter and entries migrating over set
+// their counters in it.
+
+/// Counters a key sets
+const PROBES: u64 = 3;
+
+/// Counters per key the filter is sized for
+const COUNTERS_PER_KEY: usize = 8;
+
+const SATURATED: u8 = 0xf;
+
+#[derive(Debug, Clone)]
+pub struct LookupFilter {
+    // Two counters to a byte, low nibble first
+    counters: Vec<u8>,
+    mask: u64,
+}
+
+impl LookupFilter {
+    /// An empty filter for up to `max_keys` keys
+    pub fn new(max_keys: usize) -> LookupFilter {
+        let n_counters = (max_keys * COUNTERS_PER_KEY).next_power_of_two().max(64);
+        LookupFilter {
+            counters: vec![0; n_counters / 2],
+            mask: n_counters as u64 - 1,
+        }
+    }
+
+    /// Count a key with hash `hcode` in
+    pub fn add(&mut self, hcode: u64) {
+        for slot in slots(self.mask, hcode) {
+            let count = self.get(slot);
+            if count < SATURATED {
+                self.set(slot, count + 1);
+            }
+        }
+    }
+
+    /// Count a key with hash `hcode` out again; it must have been added
+    pub fn remove(&mut self, hcode: u64) {
+        for slot in slots(self.mask, hcode) {
+            let count = self.get(slot);
+            if count > 0 && count < SATURATED {
+                self.set(slot, count - 1);
+            }
+        }
+    }
+
+    /// Whether a key with hash `hcode` may have been added; false means it
+    /// certainly wasn't
+    pub fn may_contain(&self, hcode: u64) -> bool {
+        slots(self.mask, hcode).all(|slot| self.get(slot) > 0)
+    }
+
+    /// Bytes the counters take
+    pub fn size_bytes(&self) -> usize {
+        self.counters.len()
+    }
+
+    fn get(&self, slot: u64) -> u8 {
+        (self.counters[(slot / 2) as usize] >> nibble_shift(slot)) & 0xf
+    }
+
+    fn set(&mut self, slot: u64, count: u8) {
+        let shift = nibble_shift(slot);
+        let byte = &mut self.counters[(slot / 2) as usize];
+        *byte = (*byte & !(0xf << shift)) | (count << shift);
+    }
+}
+
+// The counters of a hash, by double hashing. The bucket index is taken from
+// the low bits of the hash, so the probes start from the high ones.
+fn slots(mask: u64, hcode: u64) -> impl Iterator<Item = u64> {
+    let start = hcode.rotate_right(32);
+    let step = hcode.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    (0..PROBES).map(move |i| start.wrapping_add(i.wrapping_mul(step)) & mask)
+}
+
+fn nibble_shift(slot: u64) -> u32 {
+    (slot as u32 & 1) * 4
+}
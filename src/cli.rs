@@ -0,0 +1,389 @@
+/* Command line client */
+//
+//   redis client [-h host] [-p port] [-c] [-x] [--raw] [--resp] [--bigkeys] [command [arg ...]]
+//
+// With a command it runs it once and exits; -x appends all of stdin as the
+// last argument. Without one it reads commands line by line, with the same
+// quoting rules the server applies (see split_args), until EOF or "quit".
+//
+// --bigkeys walks the whole keyspace with SCAN ... TYPE instead, once per
+// type, asking MEMORY USAGE of every key, and reports the biggest key of each type and
+// how much each type takes. MEMORY BIGKEYS does the same on the server, on a
+// sample and by length rather than memory.
+//
+// -c is cluster mode: each command goes to the node serving its key and
+// MOVED and ASK redirections are followed (see cluster_client.rs), printed
+// as "-> Redirected to slot [n] located at ip:port" like redis-cli does
+// unless the output is raw.
+//
+// --resp talks RESP instead of the framed protocol, which also lets it be
+// pointed at a real Redis.
+//
+// Replies are printed like redis-cli does: quoted and annotated with their
+// type on a terminal, raw when piped or with --raw.
+
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+
+use redis::client::RedisClient;
+use redis::cluster_client::ClusterClient;
+use redis::protocol::{split_args, RedisValue, ReplyFormat};
+
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 1234;
+
+struct Options {
+    host: String,
+    port: u16,
+    cluster: bool,
+    stdin_arg: bool,
+    raw: bool,
+    resp: bool,
+    bigkeys: bool,
+    command: Vec<String>,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: redis client [-h host] [-p port] [-c] [-x] [--raw] [--resp] [--bigkeys] [command [arg ...]]");
+    std::process::exit(1);
+}
+
+fn parse_options(args: &[String]) -> Options {
+    let mut opts = Options {
+        host: DEFAULT_HOST.to_string(),
+        port: DEFAULT_PORT,
+        cluster: false,
+        stdin_arg: false,
+        raw: !io::stdout().is_terminal(),
+        resp: false,
+        bigkeys: false,
+        command: Vec::new(),
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" => opts.host = args.next().cloned().unwrap_or_else(|| usage()),
+            "-p" => opts.port = args.next().and_then(|p| p.parse().ok()).unwrap_or_else(|| usage()),
+            "-c" => opts.cluster = true,
+            "-x" => opts.stdin_arg = true,
+            "--raw" => opts.raw = true,
+            "--no-raw" => opts.raw = false,
+            "--resp" => opts.resp = true,
+            "--bigkeys" => opts.bigkeys = true,
+            _ => {
+                opts.command.push(arg.clone());
+                opts.command.extend(args.cloned());
+                break;
+            }
+        }
+    }
+    opts
+}
+
+/// Quote a string the way redis-cli shows it on a terminal
+fn repr(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\x07' => out.push_str("\\a"),
+            '\x08' => out.push_str("\\b"),
+            c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Terminal format. Nested array items are indented under their index:
+///
+///   1) 1) "a"
+///      2) "b"
+///   2) "c"
+fn format_tty(value: &RedisValue, prefix: &str, out: &mut String) {
+    match value {
+        RedisValue::Nil => out.push_str("(nil)\n"),
+        RedisValue::Err(msg) => out.push_str(&format!("(error) {}\n", msg)),
+        RedisValue::Str(s) => out.push_str(&format!("{}\n", repr(s))),
+        RedisValue::Int(n) => out.push_str(&format!("(integer) {}\n", n)),
+        RedisValue::Dbl(d) => out.push_str(&format!("(double) {}\n", d)),
+        RedisValue::Arr(items) | RedisValue::Push(items) if items.is_empty() => out.push_str("(empty array)\n"),
+        RedisValue::Arr(items) | RedisValue::Push(items) => {
+            let width = items.len().to_string().len();
+            let nested = format!("{}{}", prefix, " ".repeat(width + 2));
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(prefix);
+                }
+                out.push_str(&format!("{:>width$}) ", i + 1, width = width));
+                format_tty(item, &nested, out);
+            }
+        }
+    }
+}
+
+/// Raw format for scripts: one value per line, nil as an empty line
+fn format_raw(value: &RedisValue, out: &mut String) {
+    match value {
+        RedisValue::Nil => out.push('\n'),
+        RedisValue::Err(msg) | RedisValue::Str(msg) => {
+            out.push_str(msg);
+            out.push('\n');
+        }
+        RedisValue::Int(n) => out.push_str(&format!("{}\n", n)),
+        RedisValue::Dbl(d) => out.push_str(&format!("{}\n", d)),
+        RedisValue::Arr(items) | RedisValue::Push(items) => items.iter().for_each(|item| format_raw(item, out)),
+    }
+}
+
+/// A reply in the terminal format, continuation lines starting with `prefix`
+pub fn format_reply(value: &RedisValue, prefix: &str) -> String {
+    let mut out = String::new();
+    format_tty(value, prefix, &mut out);
+    out
+}
+
+fn print_reply(value: &RedisValue, raw: bool) {
+    let out = if raw {
+        let mut out = String::new();
+        format_raw(value, &mut out);
+        out
+    } else {
+        format_reply(value, "")
+    };
+    print!("{}", out);
+    let _ = io::stdout().flush();
+}
+
+fn as_strs(args: &[String]) -> Vec<&str> {
+    args.iter().map(String::as_str).collect()
+}
+
+fn reply_format(opts: &Options) -> ReplyFormat {
+    if opts.resp { ReplyFormat::Resp } else { ReplyFormat::Framed }
+}
+
+fn open_connection(opts: &Options) -> io::Result<RedisClient> {
+    Ok(RedisClient::connect((opts.host.as_str(), opts.port))?.with_format(reply_format(opts)))
+}
+
+// Where commands go: the one server, or with -c the cluster behind it
+enum Connection {
+    Single(RedisClient),
+    // With whether to print the redirections followed
+    Cluster(ClusterClient, bool),
+}
+
+impl Connection {
+    fn open(opts: &Options) -> io::Result<Connection> {
+        if !opts.cluster {
+            return open_connection(opts).map(Connection::Single);
+        }
+        let addr = format!("{}:{}", opts.host, opts.port);
+        let cluster = ClusterClient::connect(&addr, reply_format(opts))?;
+        Ok(Connection::Cluster(cluster, !opts.raw))
+    }
+
+    fn command(&mut self, args: &[&str]) -> io::Result<RedisValue> {
+        match self {
+            Connection::Single(client) => client.command(args),
+            Connection::Cluster(cluster, show_redirects) => {
+                let reply = cluster.command(args);
+                for redirect in cluster.take_redirects().into_iter().filter(|_| *show_redirects) {
+                    println!("-> Redirected to slot [{}] located at {}", redirect.slot, redirect.addr);
+                }
+                reply
+            }
+        }
+    }
+}
+
+fn run_once(opts: &Options) -> io::Result<()> {
+    let mut args = opts.command.clone();
+    if opts.stdin_arg {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        args.push(input);
+    }
+
+    let mut conn = Connection::open(opts)?;
+    let reply = conn.command(&as_strs(&args))?;
+    print_reply(&reply, opts.raw);
+    if matches!(reply, RedisValue::Err(_)) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_interactive(opts: &Options) -> io::Result<()> {
+    let interactive = io::stdin().is_terminal();
+    let address = format!("{}:{}", opts.host, opts.port);
+    let connect = || {
+        Connection::open(opts)
+            .inspect_err(|e| eprintln!("Could not connect to {}: {}", address, e))
+            .ok()
+    };
+    let mut conn = connect();
+
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        if interactive {
+            let prompt = if conn.is_some() { address.as_str() } else { "not connected" };
+            print!("{}> ", prompt);
+            io::stdout().flush()?;
+        }
+        let Some(line) = lines.next().transpose()? else {
+            break;
+        };
+
+        let args = match split_args(&line) {
+            Ok(args) => args,
+            Err(e) => {
+                eprintln!("Invalid argument(s): {}", e);
+                continue;
+            }
+        };
+        let Some(name) = args.first() else {
+            continue;
+        };
+        if name.eq_ignore_ascii_case("quit") || name.eq_ignore_ascii_case("exit") {
+            break;
+        }
+
+        // Reconnect lazily after the server went away
+        if conn.is_none() {
+            conn = connect();
+        }
+        let Some(c) = conn.as_mut() else {
+            continue;
+        };
+        match c.command(&as_strs(&args)) {
+            Ok(reply) => print_reply(&reply, opts.raw),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                conn = None;
+            }
+        }
+    }
+    Ok(())
+}
+
+const BIGKEYS_TYPES: [&str; 4] = ["string", "list", "zset", "stream"];
+
+// Keys of one type seen by --bigkeys
+#[derive(Default)]
+struct TypeStats {
+    keys: u64,
+    bytes: u64,
+    biggest: Option<(String, u64)>,
+}
+
+fn expect_int(reply: RedisValue) -> io::Result<Option<i64>> {
+    match reply {
+        RedisValue::Int(n) => Ok(Some(n)),
+        RedisValue::Nil => Ok(None),
+        RedisValue::Err(e) => Err(io::Error::other(e)),
+        other => Err(io::Error::other(format!("unexpected reply {:?}", other))),
+    }
+}
+
+// One SCAN ... TYPE pass over the keyspace per type
+fn run_bigkeys(opts: &Options) -> io::Result<()> {
+    let mut conn = open_connection(opts)?;
+    println!("# Scanning the entire keyspace to find the biggest keys by memory usage.");
+    println!();
+
+    let mut stats: Vec<(&str, TypeStats)> = Vec::new();
+    for type_name in BIGKEYS_TYPES {
+        let mut type_stats = TypeStats::default();
+        let mut cursor = "0".to_string();
+        loop {
+            let reply = conn.command(&["SCAN", &cursor, "COUNT", "100", "TYPE", type_name])?;
+            let RedisValue::Arr(mut parts) = reply else {
+                return Err(io::Error::other(format!("unexpected SCAN reply {:?}", reply)));
+            };
+            let (Some(RedisValue::Arr(keys)), Some(RedisValue::Str(next))) = (parts.pop(), parts.pop()) else {
+                return Err(io::Error::other("unexpected SCAN reply"));
+            };
+
+            for key in keys {
+                let RedisValue::Str(key) = key else {
+                    continue;
+                };
+                // None when deleted since SCAN returned it
+                let Some(bytes) = expect_int(conn.command(&["MEMORY", "USAGE", &key])?)? else {
+                    continue;
+                };
+                let bytes = bytes as u64;
+                type_stats.keys += 1;
+                type_stats.bytes += bytes;
+                if type_stats.biggest.as_ref().is_none_or(|(_, biggest)| bytes > *biggest) {
+                    println!("Biggest {:<6} found so far {} with {} bytes", type_name, repr(&key), bytes);
+                    type_stats.biggest = Some((key, bytes));
+                }
+            }
+
+            cursor = next;
+            if cursor == "0" {
+                break;
+            }
+        }
+        if type_stats.keys > 0 {
+            stats.push((type_name, type_stats));
+        }
+    }
+
+    let total_keys: u64 = stats.iter().map(|(_, type_stats)| type_stats.keys).sum();
+    println!();
+    println!("-------- summary -------");
+    println!();
+    println!("Sampled {} keys in the keyspace!", total_keys);
+    println!();
+    for (type_name, type_stats) in &stats {
+        if let Some((key, bytes)) = &type_stats.biggest {
+            println!("Biggest {:<6} found {} has {} bytes", type_name, repr(key), bytes);
+        }
+    }
+    println!();
+    for (type_name, type_stats) in &stats {
+        println!(
+            "{} {}s with {} bytes ({:.2}% of keys, avg size {:.2})",
+            type_stats.keys,
+            type_name,
+            type_stats.bytes,
+            type_stats.keys as f64 * 100.0 / total_keys as f64,
+            type_stats.bytes as f64 / type_stats.keys as f64
+        );
+    }
+    Ok(())
+}
+
+/// Entry point for `redis client ...`
+pub fn run(args: &[String]) -> io::Result<()> {
+    let opts = parse_options(args);
+    if opts.bigkeys {
+        if let Err(e) = run_bigkeys(&opts) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if opts.command.is_empty() && !opts.stdin_arg {
+        return run_interactive(&opts);
+    }
+    if opts.command.is_empty() {
+        usage();
+    }
+    if let Err(e) = run_once(&opts) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
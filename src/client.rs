@@ -0,0 +1,188 @@
+/* Client library */
+//
+// A blocking connection to the server:
+//
+//   let mut client = RedisClient::connect("127.0.0.1:1234")?;
+//   client.set("greeting", "hello")?;
+//   assert_eq!(client.get("greeting")?, Some("hello".to_string()));
+//   let reply = client.command(&["LRANGE", "mylist", "0", "-1"])?;
+//
+// command() returns whatever the server replied, errors included, as a
+// RedisValue. The typed helpers turn an error reply into an io::Error
+// (ErrorKind::Other) and an unexpected reply type into InvalidData. Push
+// messages that arrive before a reply (CLIENT TRACKING invalidations) are
+// set aside for take_pushes().
+//
+// Requests go out framed unless the client is switched to RESP with
+// with_format() before its first command, to talk to the server's text side
+// or to a real Redis. Either way replies are read through a ReplyDecoder, so
+// a reply split across any number of reads comes back whole.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::protocol::{RedisValue, ReplyDecoder, ReplyFormat};
+
+#[derive(Debug)]
+pub struct RedisClient {
+    stream: TcpStream,
+    pushes: Vec<RedisValue>,
+    decoder: ReplyDecoder,
+}
+
+// Bytes asked of each read
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Quote an argument so the server's argument splitter gives it back unchanged
+fn quote_arg(arg: &str) -> String {
+    let needs_quotes =
+        arg.is_empty() || arg.chars().any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == '\'');
+    if !needs_quotes {
+        return arg.to_string();
+    }
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    for c in arg.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\x7f' => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn unexpected(reply: RedisValue) -> io::Error {
+    match reply {
+        RedisValue::Err(msg) => io::Error::other(msg),
+        other => io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply: {:?}", other)),
+    }
+}
+
+impl RedisClient {
+    fn new(stream: TcpStream) -> RedisClient {
+        RedisClient { stream, pushes: Vec::new(), decoder: ReplyDecoder::new(ReplyFormat::Framed) }
+    }
+
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<RedisClient> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(RedisClient::new(stream))
+    }
+
+    /// Like connect, but give up on each address after `timeout`, and fail
+    /// any later read or write that stalls for that long
+    pub fn connect_timeout(addr: impl ToSocketAddrs, timeout: Duration) -> io::Result<RedisClient> {
+        let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to");
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    let client = RedisClient::new(stream);
+                    client.set_timeout(timeout)?;
+                    return Ok(client);
+                }
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Talk in `format` from now on; only meaningful before the first command,
+    /// as the server tells the protocols apart by the first bytes it gets
+    pub fn with_format(mut self, format: ReplyFormat) -> RedisClient {
+        self.decoder = ReplyDecoder::new(format);
+        self
+    }
+
+    /// Fail reads and writes that stall for longer than `timeout`
+    pub fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.stream.set_write_timeout(Some(timeout))
+    }
+
+    /// Send one command and wait for its reply
+    pub fn command(&mut self, args: &[&str]) -> io::Result<RedisValue> {
+        let request = match self.decoder.format() {
+            ReplyFormat::Framed => {
+                let line = args.iter().map(|arg| quote_arg(arg)).collect::<Vec<_>>().join(" ");
+                let len = u32::try_from(line.len())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "request too long"))?;
+                let mut frame = Vec::with_capacity(4 + line.len());
+                frame.extend_from_slice(&len.to_le_bytes());
+                frame.extend_from_slice(line.as_bytes());
+                frame
+            }
+            ReplyFormat::Resp => {
+                let mut multibulk = format!("*{}\r\n", args.len()).into_bytes();
+                for arg in args {
+                    multibulk.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+                    multibulk.extend_from_slice(arg.as_bytes());
+                    multibulk.extend_from_slice(b"\r\n");
+                }
+                multibulk
+            }
+        };
+        self.stream.write_all(&request)?;
+
+        loop {
+            match self.read_value()? {
+                RedisValue::Push(items) => self.pushes.push(RedisValue::Push(items)),
+                reply => return Ok(reply),
+            }
+        }
+    }
+
+    /// Push messages received so far, oldest first
+    pub fn take_pushes(&mut self) -> Vec<RedisValue> {
+        std::mem::take(&mut self.pushes)
+    }
+
+    /// Read until the decoder has a whole value
+    fn read_value(&mut self) -> io::Result<RedisValue> {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        loop {
+            let decoded =
+                self.decoder.next_value().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if let Some(value) = decoded {
+                return Ok(value);
+            }
+            let n = self.stream.read(&mut chunk)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by server"));
+            }
+            self.decoder.feed(&chunk[..n]);
+        }
+    }
+
+    /// GET key; None if the key doesn't exist
+    pub fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        match self.command(&["GET", key])? {
+            RedisValue::Str(value) => Ok(Some(value)),
+            RedisValue::Nil => Ok(None),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// SET key value
+    pub fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        match self.command(&["SET", key, value])? {
+            RedisValue::Nil | RedisValue::Str(_) => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// ZADD key score member; returns how many members were added
+    pub fn zadd(&mut self, key: &str, score: f64, member: &str) -> io::Result<i64> {
+        match self.command(&["ZADD", key, &score.to_string(), member])? {
+            RedisValue::Int(added) => Ok(added),
+            other => Err(unexpected(other)),
+        }
+    }
+}
@@ -0,0 +1,173 @@
+/* Client registry */
+//
+// Every connection gets an id when it's accepted, one more than the last,
+// never reused, so logs and the audit log tell clients apart even when the
+// kernel hands a closed client's fd to the next one. The registry maps the
+// fds in use to their client's id, address and start; the rest of a
+// connection's state is in its Conn, which is out of fd2conn while it's
+// being served, so the running client is found here.
+//
+//   CLIENT ID                  the running client's id
+//   CLIENT LIST                id=.. addr=.. fd=.. age=.. idle=.., a line each
+//   CLIENT KILL ip:port        close the client at that address
+//   CLIENT KILL [ID id] [ADDR ip:port] [SKIPME yes|no]
+//                              close the clients matching all the filters
+//                              given, the running one only with SKIPME no;
+//                              replies how many
+//
+// A client killing itself is closed once the reply is out, like QUIT.
+
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::events::ServerEvent;
+use crate::{blocking, forget_client, get_monotonic_time_ms, out_err, out_int, out_str, with_global_data, with_global_data_read, Buffer, GData};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A new client id
+pub fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Debug)]
+struct Client {
+    id: u64,
+    // ip:port, or empty for a socket without one
+    addr: String,
+    // Monotonic ms it connected at
+    created_ms: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Clients {
+    by_fd: HashMap<RawFd, Client>,
+}
+
+impl Clients {
+    fn add(&mut self, fd: RawFd, id: u64, addr: String) {
+        self.by_fd.insert(fd, Client { id, addr, created_ms: get_monotonic_time_ms() });
+    }
+
+    fn remove(&mut self, fd: RawFd) {
+        self.by_fd.remove(&fd);
+    }
+
+    /// The id of the client at `fd`
+    pub fn id_of(&self, fd: RawFd) -> Option<u64> {
+        self.by_fd.get(&fd).map(|client| client.id)
+    }
+}
+
+/// Register clients as they connect, and forget them as they go
+pub fn on_event(g_data: &mut GData, event: &ServerEvent) {
+    match *event {
+        ServerEvent::ClientConnected { fd, id, addr } => g_data.clients.add(fd, id, addr.to_string()),
+        ServerEvent::ClientDisconnected { fd } => g_data.clients.remove(fd),
+        _ => {}
+    }
+}
+
+// CLIENT ID
+pub fn do_id(cmd: &[String], out: &mut Buffer) {
+    if cmd.len() != 2 {
+        out_err(out, "ERR wrong number of arguments for 'client|id' command");
+        return;
+    }
+    let id = with_global_data_read(|g_data| blocking::current_client().and_then(|fd| g_data.clients.id_of(fd)));
+    match id {
+        Some(id) => out_int(out, id as i64),
+        None => out_err(out, "ERR CLIENT ID can't be used here"),
+    }
+}
+
+// CLIENT LIST
+pub fn do_list(cmd: &[String], out: &mut Buffer) {
+    if cmd.len() != 2 {
+        out_err(out, "ERR wrong number of arguments for 'client|list' command");
+        return;
+    }
+    let now_ms = get_monotonic_time_ms();
+    let text = with_global_data(|g_data| {
+        let mut clients: Vec<(&RawFd, &Client)> = g_data.clients.by_fd.iter().collect();
+        clients.sort_by_key(|(_, client)| client.id);
+        let mut text = String::new();
+        for (fd, client) in clients {
+            // The running client is out of fd2conn, and active now
+            let last_active_ms = g_data.fd2conn.get(fd).map_or(now_ms, |conn| conn.last_active_ms);
+            text.push_str(&format!(
+                "id={} addr={} fd={} age={} idle={}\n",
+                client.id,
+                client.addr,
+                fd,
+                now_ms.saturating_sub(client.created_ms) / 1000,
+                now_ms.saturating_sub(last_active_ms) / 1000
+            ));
+        }
+        text
+    });
+    out_str(out, &text);
+}
+
+// CLIENT KILL ip:port | CLIENT KILL [ID id] [ADDR ip:port] [SKIPME yes|no]
+pub fn do_kill(cmd: &[String], out: &mut Buffer) {
+    let (mut id, mut addr, mut skipme) = (None, None, true);
+    let old_form = cmd.len() == 3;
+    if old_form {
+        addr = Some(cmd[2].as_str());
+    } else if cmd.len() < 4 || !cmd.len().is_multiple_of(2) {
+        out_err(out, "ERR syntax error");
+        return;
+    } else {
+        for pair in cmd[2..].chunks(2) {
+            match pair[0].to_uppercase().as_str() {
+                "ID" => match pair[1].parse::<u64>() {
+                    Ok(n) if n > 0 => id = Some(n),
+                    _ => {
+                        out_err(out, "ERR client-id should be greater than 0");
+                        return;
+                    }
+                },
+                "ADDR" => addr = Some(pair[1].as_str()),
+                "SKIPME" => match pair[1].to_lowercase().as_str() {
+                    "yes" => skipme = true,
+                    "no" => skipme = false,
+                    _ => {
+                        out_err(out, "ERR syntax error");
+                        return;
+                    }
+                },
+                _ => {
+                    out_err(out, "ERR syntax error");
+                    return;
+                }
+            }
+        }
+    }
+
+    let killed = with_global_data(|g_data| {
+        let me = blocking::current_client();
+        let fds: Vec<RawFd> = g_data
+            .clients
+            .by_fd
+            .iter()
+            .filter(|(_, client)| id.is_none_or(|id| client.id == id) && addr.is_none_or(|addr| client.addr == addr))
+            .map(|(&fd, _)| fd)
+            .filter(|&fd| !(skipme && !old_form && me == Some(fd)))
+            .collect();
+        for &fd in &fds {
+            if me == Some(fd) {
+                g_data.quit_requested = true;
+            } else {
+                forget_client(g_data, fd);
+            }
+        }
+        fds.len()
+    });
+    match (old_form, killed) {
+        (true, 0) => out_err(out, "ERR No such client"),
+        (true, _) => out_str(out, "OK"),
+        (false, n) => out_int(out, n as i64),
+    }
+}
@@ -0,0 +1,651 @@
+/* Cluster */
+//
+// With cluster-enabled the keyspace is split into CLUSTER_SLOTS hash slots
+// (see slot.rs for how keys map to them). Each node serves the slots it
+// owns; a client command on a key in a slot owned by another node gets
+//
+//   MOVED slot ip:port
+//
+// and one on a slot nobody owns CLUSTERDOWN. Keys of one command must all
+// hash to the same slot (CROSSSLOT otherwise).
+//
+// Nodes are known by a random 40-character id. Slots are handed to this node
+// with CLUSTER ADDSLOTS, and CLUSTER MEET ip port introduces another node;
+// from then on the nodes gossip over the cluster bus (gossip.rs), so every
+// node learns of every other one and of the slots each serves. Clients
+// bootstrap from any node with
+//
+//   CLUSTER SLOTS    [[start, end, [ip, port, id]], ...] per slot range
+//   CLUSTER SHARDS   ["slots", [start, end, ...], "nodes", [node info]] per shard
+//   CLUSTER MYID     this node's id
+//   CLUSTER INFO     cluster_state, slot counts, ...
+//   CLUSTER NODES    one line per node, as in Redis
+//   CLUSTER KEYSLOT key
+//
+// Slot ownership is settled by epochs. The current epoch is the highest any
+// node has seen; each node has a config epoch, and a slot claimed by two
+// nodes goes to the one with the greater config epoch. A node that's handed
+// a slot it imported takes a new, greatest config epoch so its claim wins,
+// and of two nodes with the same config epoch the one with the smaller id
+// moves to a new one, so epochs end up unique.
+//
+// A node not heard from for cluster-node-timeout is PFAIL (possibly failing)
+// to the node that noticed, and is reported as such in its gossip. Once a
+// majority of the nodes serving slots have reported it within twice the
+// timeout it's FAIL, which spreads through gossip as well; it's cleared when
+// the node is heard from again.
+//
+// Resharding moves a slot while it keeps being served. The target is marked
+// CLUSTER SETSLOT slot IMPORTING source-id, the source SETSLOT slot MIGRATING
+// target-id, then the keys listed by CLUSTER GETKEYSINSLOT are moved with
+// MIGRATE, and SETSLOT slot NODE target-id on both ends hands the slot over.
+// Meanwhile the source serves the keys it still has and answers
+//
+//   ASK slot ip:port
+//
+// for the ones it doesn't (TRYAGAIN when a multi-key command finds only some),
+// and the target serves the slot to a client only for the command right after
+// ASKING; everyone else is still sent MOVED to the source. MIGRATE sends
+// ASKING before its RESTORE so the target takes the key.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, RandomState};
+use std::net::IpAddr;
+use std::os::unix::io::RawFd;
+
+use crate::command::{CmdResult, Command};
+use crate::events::ServerEvent;
+use crate::gossip::{self, Gossip, Message};
+use crate::logging::log_notice;
+use crate::{blocking, get_monotonic_time_ms, out_arr, out_err, out_int, out_str, with_global_data, with_global_data_read, Buffer, GData};
+
+pub use redis::slot::{key_hash_slot, CLUSTER_SLOTS};
+
+const TRYAGAIN: &str = "TRYAGAIN Multiple keys request during rehashing of slot";
+
+const DISABLED_ERR: &str = "ERR This instance has cluster support disabled";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeState {
+    Ok,
+    PFail,
+    Fail,
+}
+
+impl NodeState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NodeState::Ok => "ok",
+            NodeState::PFail => "pfail",
+            NodeState::Fail => "fail",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<NodeState> {
+        match s {
+            "ok" => Some(NodeState::Ok),
+            "pfail" => Some(NodeState::PFail),
+            "fail" => Some(NodeState::Fail),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Node {
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+    pub config_epoch: u64,
+    state: NodeState,
+    // When a message from it last arrived (monotonic ms)
+    last_seen_ms: u64,
+    // Reporting node id -> when it last gossiped this node as PFAIL or FAIL
+    fail_reports: HashMap<String, u64>,
+}
+
+impl Node {
+    fn new(id: String, ip: String, port: u16, now_ms: u64) -> Node {
+        Node { id, ip, port, config_epoch: 0, state: NodeState::Ok, last_seen_ms: now_ms, fail_reports: HashMap::new() }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Cluster {
+    /// Set once at startup from cluster-enabled
+    pub enabled: bool,
+    current_epoch: u64,
+    myself: String,
+    // id -> node, this one included
+    nodes: HashMap<String, Node>,
+    // Owner id of each slot
+    slots: Vec<Option<String>>,
+    // Slot -> node id it's moving to, for slots of this node
+    migrating: HashMap<usize, String>,
+    // Slot -> node id it's moving from, for slots of other nodes
+    importing: HashMap<usize, String>,
+    // Clients that sent ASKING, for their next command
+    asking: HashSet<RawFd>,
+}
+
+// 40 random hex characters, as Redis node ids
+fn random_node_id() -> String {
+    let state = RandomState::new();
+    (0..3u8).map(|i| format!("{:016x}", state.hash_one(i))).collect::<String>()[..40].to_string()
+}
+
+impl Cluster {
+    /// Turn cluster mode on, as a node reachable at ip:port owning no slots
+    pub fn enable(&mut self, ip: IpAddr, port: u16) {
+        let id = random_node_id();
+        self.nodes.insert(id.clone(), Node::new(id.clone(), ip.to_string(), port, get_monotonic_time_ms()));
+        self.myself = id;
+        self.slots = vec![None; CLUSTER_SLOTS];
+        self.enabled = true;
+    }
+
+    pub fn myself(&self) -> &Node {
+        &self.nodes[&self.myself]
+    }
+
+    /// Where a client command has to go instead of running here: the error
+    /// to reply with, or None to run it. `exists` tells whether a key is
+    /// here, which matters for a slot being migrated.
+    pub fn redirect(&self, command: &Command, args: &[String], asking: bool, exists: impl Fn(&str) -> bool) -> Option<String> {
+        let mut slot = None;
+        let keys = command.keys(args);
+        for &key in &keys {
+            let key_slot = key_hash_slot(key);
+            if slot.is_some_and(|slot| slot != key_slot) {
+                return Some("CROSSSLOT Keys in request don't hash to the same slot".to_string());
+            }
+            slot = Some(key_slot);
+        }
+        let slot = slot? as usize;
+        let (migrating, importing) = (self.migrating.get(&slot), self.importing.get(&slot));
+        if command.name == "migrate" && (migrating.is_some() || importing.is_some()) {
+            // Moving the keys of an open slot is always done locally
+            return None;
+        }
+        // Keys of a slot in flux may be on either end
+        let missing = keys.iter().filter(|key| !exists(key)).count();
+        let partial = missing > 0 && missing < keys.len();
+        match &self.slots[slot] {
+            Some(owner) if *owner == self.myself => match migrating {
+                Some(_) if partial => Some(TRYAGAIN.to_string()),
+                Some(target) if missing > 0 => {
+                    let node = &self.nodes[target];
+                    Some(format!("ASK {} {}:{}", slot, node.ip, node.port))
+                }
+                _ => None,
+            },
+            _ if asking && importing.is_some() => partial.then(|| TRYAGAIN.to_string()),
+            Some(owner) => {
+                let node = &self.nodes[owner];
+                Some(format!("MOVED {} {}:{}", slot, node.ip, node.port))
+            }
+            None => Some("CLUSTERDOWN Hash slot not served".to_string()),
+        }
+    }
+
+    /// This node's view of the cluster, as a message of `kind` to send
+    pub fn message(&self, kind: &str) -> Message {
+        let me = self.myself();
+        Message {
+            kind: kind.to_string(),
+            id: me.id.clone(),
+            ip: me.ip.clone(),
+            port: me.port,
+            current_epoch: self.current_epoch,
+            config_epoch: me.config_epoch,
+            slots: (0..CLUSTER_SLOTS).filter(|&slot| self.slots[slot].as_ref() == Some(&self.myself)).collect(),
+            gossip: self
+                .nodes
+                .values()
+                .filter(|node| node.id != self.myself)
+                .map(|node| Gossip { id: node.id.clone(), ip: node.ip.clone(), port: node.port, state: node.state })
+                .collect(),
+        }
+    }
+
+    /// The other nodes as (ip, port)
+    pub fn peers(&self) -> Vec<(String, u16)> {
+        self.nodes.values().filter(|node| node.id != self.myself).map(|node| (node.ip.clone(), node.port)).collect()
+    }
+
+    /// Take in what a message says. A sender this node doesn't know yet is
+    /// added only when `meet` is set (a MEET, or the reply to one).
+    pub fn receive(&mut self, msg: &Message, meet: bool, now_ms: u64, node_timeout_ms: u64) {
+        if msg.id == self.myself {
+            return;
+        }
+        if !self.nodes.contains_key(&msg.id) {
+            if !meet {
+                return;
+            }
+            log_notice!("cluster", "Met node {} at {}:{}", msg.id, msg.ip, msg.port);
+            self.nodes.insert(msg.id.clone(), Node::new(msg.id.clone(), msg.ip.clone(), msg.port, now_ms));
+        }
+        self.current_epoch = self.current_epoch.max(msg.current_epoch);
+
+        let sender = self.nodes.get_mut(&msg.id).unwrap();
+        sender.config_epoch = msg.config_epoch;
+        sender.last_seen_ms = now_ms;
+        if sender.state != NodeState::Ok {
+            if sender.state == NodeState::Fail {
+                log_notice!("cluster", "Clear FAIL state for node {}: it's reachable again", msg.id);
+            }
+            sender.state = NodeState::Ok;
+        }
+        self.claim_slots(&msg.id, msg.config_epoch, &msg.slots);
+        self.resolve_epoch_collision(&msg.id, msg.config_epoch);
+
+        // Only nodes serving slots have a say in failure detection
+        let reporter = !msg.slots.is_empty();
+        for gossip in &msg.gossip {
+            if gossip.id == self.myself {
+                continue;
+            }
+            let Some(node) = self.nodes.get_mut(&gossip.id) else {
+                if gossip.state != NodeState::Fail {
+                    log_notice!("cluster", "Discovered node {} at {}:{} from {}", gossip.id, gossip.ip, gossip.port, msg.id);
+                    let node = Node::new(gossip.id.clone(), gossip.ip.clone(), gossip.port, now_ms);
+                    self.nodes.insert(gossip.id.clone(), node);
+                }
+                continue;
+            };
+            if reporter && gossip.state != NodeState::Ok {
+                node.fail_reports.insert(msg.id.clone(), now_ms);
+            } else {
+                node.fail_reports.remove(&msg.id);
+            }
+            // Unless this node heard from it since
+            let silent = now_ms.saturating_sub(node.last_seen_ms) > node_timeout_ms;
+            if gossip.state == NodeState::Fail && node.state != NodeState::Fail && silent {
+                log_notice!("cluster", "FAIL of node {} reported by {}", gossip.id, msg.id);
+                node.state = NodeState::Fail;
+            }
+        }
+    }
+
+    // The sender serves `slots` as of `epoch`: take the claims that beat
+    // what this node knows
+    fn claim_slots(&mut self, id: &str, epoch: u64, slots: &[usize]) {
+        for &slot in slots {
+            // Ownership of a slot being imported is settled by SETSLOT NODE
+            if self.importing.contains_key(&slot) {
+                continue;
+            }
+            match &self.slots[slot] {
+                Some(owner) if owner == id => continue,
+                Some(owner) if self.nodes[owner].config_epoch >= epoch => continue,
+                Some(owner) if *owner == self.myself => {
+                    log_notice!("cluster", "Slot {} taken over by node {} with config epoch {}", slot, id, epoch);
+                    self.migrating.remove(&slot);
+                }
+                _ => {}
+            }
+            self.slots[slot] = Some(id.to_string());
+        }
+    }
+
+    // Two nodes with the same config epoch: the one with the smaller id
+    // moves to a new epoch
+    fn resolve_epoch_collision(&mut self, id: &str, epoch: u64) {
+        if epoch != self.myself().config_epoch || self.myself.as_str() > id {
+            return;
+        }
+        self.current_epoch += 1;
+        let epoch = self.current_epoch;
+        self.nodes.get_mut(&self.myself).unwrap().config_epoch = epoch;
+        log_notice!("cluster", "Config epoch collision with node {}: moved to config epoch {}", id, epoch);
+    }
+
+    // Take a config epoch greater than every other node's, unless this node
+    // already has one
+    fn bump_epoch(&mut self) {
+        let mine = self.myself().config_epoch;
+        if mine > 0 && self.nodes.values().all(|node| node.id == self.myself || node.config_epoch < mine) {
+            return;
+        }
+        self.current_epoch += 1;
+        let epoch = self.current_epoch;
+        self.nodes.get_mut(&self.myself).unwrap().config_epoch = epoch;
+    }
+
+    /// Mark nodes not heard from for `node_timeout_ms` PFAIL, and those
+    /// enough nodes agree about FAIL
+    pub fn check_failures(&mut self, now_ms: u64, node_timeout_ms: u64) {
+        let masters: HashSet<String> = self.slots.iter().flatten().cloned().collect();
+        let quorum = masters.len() / 2 + 1;
+        let me_master = masters.contains(&self.myself);
+        for node in self.nodes.values_mut().filter(|node| node.id != self.myself) {
+            node.fail_reports.retain(|_, at| now_ms.saturating_sub(*at) <= 2 * node_timeout_ms);
+            if node.state == NodeState::Ok && now_ms.saturating_sub(node.last_seen_ms) > node_timeout_ms {
+                log_notice!("cluster", "Node {} possibly failing: no reply for {} ms", node.id, node_timeout_ms);
+                node.state = NodeState::PFail;
+            }
+            let reports = node.fail_reports.keys().filter(|id| masters.contains(*id)).count() + me_master as usize;
+            if node.state == NodeState::PFail && reports >= quorum {
+                log_notice!("cluster", "Marking node {} as failing (quorum reached)", node.id);
+                node.state = NodeState::Fail;
+            }
+        }
+    }
+
+    // Forget a disconnected client
+    fn remove_client(&mut self, fd: RawFd) {
+        self.asking.remove(&fd);
+    }
+
+    fn node_known(&self, id: &str) -> Result<(), String> {
+        match self.nodes.contains_key(id) {
+            true => Ok(()),
+            false => Err(format!("ERR I don't know about node {}", id)),
+        }
+    }
+
+    // CLUSTER SETSLOT slot MIGRATING|IMPORTING|NODE node-id, CLUSTER SETSLOT slot STABLE
+    fn set_slot(&mut self, slot: usize, state: &str, id: Option<&String>, keys_in_slot: usize) -> Result<(), String> {
+        let owned = self.slots[slot].as_ref() == Some(&self.myself);
+        match (state, id) {
+            ("MIGRATING", Some(id)) => {
+                if !owned {
+                    return Err(format!("ERR I'm not the owner of hash slot {}", slot));
+                }
+                self.node_known(id)?;
+                self.migrating.insert(slot, id.clone());
+            }
+            ("IMPORTING", Some(id)) => {
+                if owned {
+                    return Err(format!("ERR I'm already the owner of hash slot {}", slot));
+                }
+                self.node_known(id)?;
+                self.importing.insert(slot, id.clone());
+            }
+            ("STABLE", None) => {
+                self.migrating.remove(&slot);
+                self.importing.remove(&slot);
+            }
+            ("NODE", Some(id)) => {
+                self.node_known(id)?;
+                if owned && *id != self.myself && keys_in_slot > 0 {
+                    return Err(format!(
+                        "ERR Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                        slot
+                    ));
+                }
+                let imported = *id == self.myself && self.importing.contains_key(&slot);
+                self.slots[slot] = Some(id.clone());
+                self.migrating.remove(&slot);
+                self.importing.remove(&slot);
+                if imported {
+                    self.bump_epoch();
+                }
+            }
+            _ => return Err("ERR Invalid CLUSTER SETSLOT action or number of arguments".to_string()),
+        }
+        Ok(())
+    }
+
+    // (first slot, last slot, owner id) of each run of slots with one owner
+    fn slot_ranges(&self) -> Vec<(usize, usize, &str)> {
+        let mut ranges: Vec<(usize, usize, &str)> = Vec::new();
+        for (slot, owner) in self.slots.iter().enumerate() {
+            let Some(owner) = owner else {
+                continue;
+            };
+            match ranges.last_mut() {
+                Some((_, last, id)) if *last + 1 == slot && *id == owner => *last = slot,
+                _ => ranges.push((slot, slot, owner)),
+            }
+        }
+        ranges
+    }
+
+    fn assigned_slots(&self) -> usize {
+        self.slots.iter().filter(|owner| owner.is_some()).count()
+    }
+}
+
+/// Forget the clients that go
+pub fn on_event(g_data: &mut GData, event: &ServerEvent) {
+    if let ServerEvent::ClientDisconnected { fd } = *event {
+        g_data.cluster.remove_client(fd);
+    }
+}
+
+// The slot numbers in args, or the error to reply with
+fn parse_slots(args: &[String]) -> Result<Vec<usize>, &'static str> {
+    args.iter()
+        .map(|arg| match arg.parse::<usize>() {
+            Ok(slot) if slot < CLUSTER_SLOTS => Ok(slot),
+            _ => Err("ERR Invalid or out of range slot"),
+        })
+        .collect()
+}
+
+// The keys stored here that hash to `slot`
+fn keys_in_slot(g_data: &GData, slot: usize) -> impl Iterator<Item = &str> {
+    g_data.db.iter().map(|entry| entry.key.as_str()).filter(move |key| key_hash_slot(key) as usize == slot)
+}
+
+// CLUSTER MEET ip port, talking to the node without holding the lock
+fn meet(ip: &str, port: &str, out: &mut Buffer) {
+    let Ok(port) = port.parse::<u16>() else {
+        out_err(out, &format!("ERR Invalid node address specified: {}:{}", ip, port));
+        return;
+    };
+    if !with_global_data(|g_data| g_data.cluster.enabled) {
+        out_err(out, DISABLED_ERR);
+        return;
+    }
+    match gossip::meet(ip, port) {
+        Ok(()) => out_str(out, "OK"),
+        Err(e) => out_err(out, &format!("ERR Can't meet node {}:{}: {}", ip, port, e)),
+    }
+}
+
+// CLUSTER subcommand ...
+pub fn do_cluster(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let subcommand = cmd[1].to_uppercase();
+    if subcommand == "KEYSLOT" && cmd.len() == 3 {
+        // Works without cluster mode too, as in Redis
+        out_int(out, key_hash_slot(&cmd[2]) as i64);
+        return Ok(());
+    }
+    if subcommand == "MEET" && cmd.len() == 4 {
+        meet(&cmd[2], &cmd[3], out);
+        return Ok(());
+    }
+
+    with_global_data(|g_data| {
+        let cluster = &mut g_data.cluster;
+        if !cluster.enabled {
+            out_err(out, DISABLED_ERR);
+            return;
+        }
+        match (subcommand.as_str(), cmd.len()) {
+            ("MYID", 2) => out_str(out, &cluster.myself),
+            ("INFO", 2) => {
+                let assigned = cluster.assigned_slots();
+                let in_state = |state| cluster.slots.iter().flatten().filter(|id| cluster.nodes[*id].state == state).count();
+                let (pfail, fail) = (in_state(NodeState::PFail), in_state(NodeState::Fail));
+                let state = if assigned == CLUSTER_SLOTS && fail == 0 { "ok" } else { "fail" };
+                let masters = cluster.slot_ranges().iter().map(|&(_, _, id)| id).collect::<HashSet<_>>().len();
+                let info = format!(
+                    "cluster_enabled:1\r\ncluster_state:{}\r\ncluster_slots_assigned:{}\r\ncluster_slots_ok:{}\r\n\
+                     cluster_slots_pfail:{}\r\ncluster_slots_fail:{}\r\ncluster_known_nodes:{}\r\ncluster_size:{}\r\n\
+                     cluster_current_epoch:{}\r\ncluster_my_epoch:{}\r\n",
+                    state,
+                    assigned,
+                    assigned - pfail - fail,
+                    pfail,
+                    fail,
+                    cluster.nodes.len(),
+                    masters,
+                    cluster.current_epoch,
+                    cluster.myself().config_epoch
+                );
+                out_str(out, &info);
+            }
+            ("SLOTS", 2) => {
+                let ranges = cluster.slot_ranges();
+                out_arr(out, ranges.len() as u32);
+                for (first, last, id) in ranges {
+                    let node = &cluster.nodes[id];
+                    out_arr(out, 3);
+                    out_int(out, first as i64);
+                    out_int(out, last as i64);
+                    out_arr(out, 3);
+                    out_str(out, &node.ip);
+                    out_int(out, node.port as i64);
+                    out_str(out, &node.id);
+                }
+            }
+            ("SHARDS", 2) => {
+                let ranges = cluster.slot_ranges();
+                // Every node is a master of its own shard until there are replicas
+                let mut ids: Vec<&String> = cluster.nodes.keys().collect();
+                ids.sort();
+                out_arr(out, ids.len() as u32);
+                for id in ids {
+                    let node = &cluster.nodes[id];
+                    let owned: Vec<_> = ranges.iter().filter(|&&(_, _, owner)| owner == id).collect();
+                    let offset = if *id == cluster.myself { g_data.replication.master_repl_offset } else { 0 };
+                    out_arr(out, 4);
+                    out_str(out, "slots");
+                    out_arr(out, 2 * owned.len() as u32);
+                    for &&(first, last, _) in &owned {
+                        out_int(out, first as i64);
+                        out_int(out, last as i64);
+                    }
+                    out_str(out, "nodes");
+                    out_arr(out, 1);
+                    out_arr(out, 14);
+                    for (name, value) in [("id", node.id.as_str()), ("ip", &node.ip), ("endpoint", &node.ip), ("role", "master")] {
+                        out_str(out, name);
+                        out_str(out, value);
+                    }
+                    out_str(out, "port");
+                    out_int(out, node.port as i64);
+                    out_str(out, "replication-offset");
+                    out_int(out, offset as i64);
+                    out_str(out, "health");
+                    out_str(out, if node.state == NodeState::Fail { "fail" } else { "online" });
+                }
+            }
+            ("NODES", 2) => {
+                let ranges = cluster.slot_ranges();
+                let mut ids: Vec<&String> = cluster.nodes.keys().collect();
+                ids.sort();
+                let mut lines = String::new();
+                for id in ids {
+                    let node = &cluster.nodes[id];
+                    let flags = match (*id == cluster.myself, node.state) {
+                        (true, _) => "myself,master",
+                        (false, NodeState::Ok) => "master",
+                        (false, NodeState::PFail) => "master,fail?",
+                        (false, NodeState::Fail) => "master,fail",
+                    };
+                    let link = if node.state == NodeState::Ok { "connected" } else { "disconnected" };
+                    lines += &format!(
+                        "{} {}:{}@{} {} - 0 {} {} {}",
+                        node.id,
+                        node.ip,
+                        node.port,
+                        gossip::bus_port(node.port).unwrap_or(0),
+                        flags,
+                        node.last_seen_ms,
+                        node.config_epoch,
+                        link
+                    );
+                    for &(first, last, _) in ranges.iter().filter(|&&(_, _, owner)| owner == id) {
+                        lines += &if first == last { format!(" {}", first) } else { format!(" {}-{}", first, last) };
+                    }
+                    lines.push('\n');
+                }
+                out_str(out, &lines);
+            }
+            ("ADDSLOTS", 3..) | ("DELSLOTS", 3..) => {
+                let slots = match parse_slots(&cmd[2..]) {
+                    Ok(slots) => slots,
+                    Err(e) => return out_err(out, e),
+                };
+                let adding = subcommand == "ADDSLOTS";
+                if let Some(&slot) = slots.iter().find(|&&slot| cluster.slots[slot].is_some() == adding) {
+                    let state = if adding { "busy" } else { "unassigned" };
+                    return out_err(out, &format!("ERR Slot {} is already {}", slot, state));
+                }
+                for slot in slots {
+                    cluster.slots[slot] = adding.then(|| cluster.myself.clone());
+                }
+                out_str(out, "OK");
+            }
+            ("SETSLOT", 4 | 5) => {
+                let slot = match parse_slots(&cmd[2..3]) {
+                    Ok(slots) => slots[0],
+                    Err(e) => return out_err(out, e),
+                };
+                let keys = keys_in_slot(g_data, slot).count();
+                match g_data.cluster.set_slot(slot, &cmd[3].to_uppercase(), cmd.get(4), keys) {
+                    Ok(()) => out_str(out, "OK"),
+                    Err(e) => out_err(out, &e),
+                }
+            }
+            ("COUNTKEYSINSLOT", 3) => match parse_slots(&cmd[2..3]) {
+                Ok(slots) => out_int(out, keys_in_slot(g_data, slots[0]).count() as i64),
+                Err(e) => out_err(out, e),
+            },
+            ("GETKEYSINSLOT", 4) => {
+                let slot = match parse_slots(&cmd[2..3]) {
+                    Ok(slots) => slots[0],
+                    Err(e) => return out_err(out, e),
+                };
+                let Ok(count) = cmd[3].parse::<usize>() else {
+                    return out_err(out, "ERR Invalid number of keys");
+                };
+                let keys: Vec<&str> = keys_in_slot(g_data, slot).take(count).collect();
+                out_arr(out, keys.len() as u32);
+                for key in keys {
+                    out_str(out, key);
+                }
+            }
+            _ => out_err(out, "ERR unknown CLUSTER subcommand or wrong number of arguments"),
+        }
+    });
+    Ok(())
+}
+
+/// The MOVED/ASK/CROSSSLOT/CLUSTERDOWN error for a client command that
+/// can't run on this node, None when it can (or cluster mode is off)
+pub fn check_redirect(command: &Command, args: &[String]) -> Option<String> {
+    if !with_global_data_read(|g_data| g_data.cluster.enabled) {
+        return None;
+    }
+    with_global_data(|g_data| {
+        let fd = blocking::current_client()?;
+        let cluster = &mut g_data.cluster;
+        if !cluster.enabled {
+            return None;
+        }
+        // ASKING holds for one command only
+        let asking = command.name != "asking" && cluster.asking.remove(&fd);
+        let db = &g_data.db;
+        g_data.cluster.redirect(command, args, asking, |key| db.peek_entry(key).is_some())
+    })
+}
+
+// ASKING: let the next command run on a slot being imported
+pub fn do_asking(_cmd: &[String], out: &mut Buffer) -> CmdResult {
+    with_global_data(|g_data| {
+        if !g_data.cluster.enabled {
+            return out_err(out, DISABLED_ERR);
+        }
+        if let Some(fd) = blocking::current_client() {
+            g_data.cluster.asking.insert(fd);
+        }
+        out_str(out, "OK");
+    });
+    Ok(())
+}
@@ -0,0 +1,163 @@
+/* Cluster client */
+//
+// A client for a whole cluster, started from any one node:
+//
+//   let mut cluster = ClusterClient::connect("127.0.0.1:7000", ReplyFormat::Framed)?;
+//   cluster.command(&["SET", "{user1}.name", "ann"])?;
+//
+// It keeps a map of which node serves each slot, loaded with CLUSTER SLOTS
+// when it connects, and sends a command to the node of its first argument's
+// slot: the key, for nearly every command that has one. A command sent to the
+// wrong node, because the map is out of date or the first argument isn't a
+// key, is answered with a redirection, which it follows:
+//
+//   MOVED slot ip:port   the slot is served by ip:port from now on: the map
+//                        is updated and the command sent there
+//   ASK slot ip:port     the slot is being migrated and this key already
+//                        moved: the command is sent there once, after ASKING,
+//                        and the map is left alone
+//
+// up to MAX_REDIRECTS times, after which the last redirection is returned as
+// the reply. A server without cluster support never redirects, so it's used
+// as a single node. Connections to the other nodes are opened as commands
+// are sent their way, and kept; one that fails is dropped, so the next
+// command reconnects. Redirections followed are kept for take_redirects().
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::io;
+
+use crate::client::RedisClient;
+use crate::protocol::{RedisValue, ReplyFormat};
+use crate::slot::{key_hash_slot, CLUSTER_SLOTS};
+
+/// Redirections followed for one command before giving up
+const MAX_REDIRECTS: usize = 16;
+
+/// A MOVED or ASK reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub slot: u16,
+    /// ip:port of the node to ask instead
+    pub addr: String,
+    /// ASK: for this one command only
+    pub ask: bool,
+}
+
+impl Redirect {
+    /// The redirection in error reply `msg`, if it is one
+    pub fn parse(msg: &str) -> Option<Redirect> {
+        let mut words = msg.split(' ');
+        let ask = match words.next()? {
+            "MOVED" => false,
+            "ASK" => true,
+            _ => return None,
+        };
+        let slot = words.next()?.parse::<u16>().ok().filter(|&slot| (slot as usize) < CLUSTER_SLOTS)?;
+        let addr = words.next()?.to_string();
+        words.next().is_none().then_some(Redirect { slot, addr, ask })
+    }
+}
+
+#[derive(Debug)]
+pub struct ClusterClient {
+    format: ReplyFormat,
+    // The node connected to first, for commands without a known slot
+    seed: String,
+    // Open connections, by ip:port
+    nodes: HashMap<String, RedisClient>,
+    // Slot -> ip:port of the node serving it, where known
+    slots: Vec<Option<String>>,
+    redirects: Vec<Redirect>,
+}
+
+impl ClusterClient {
+    /// Connect to the node at `addr` (host:port) and load the slot map from
+    /// it; requests go out in `format`
+    pub fn connect(addr: &str, format: ReplyFormat) -> io::Result<ClusterClient> {
+        let mut cluster = ClusterClient {
+            format,
+            seed: addr.to_string(),
+            nodes: HashMap::new(),
+            slots: vec![None; CLUSTER_SLOTS],
+            redirects: Vec::new(),
+        };
+        cluster.refresh_slots()?;
+        Ok(cluster)
+    }
+
+    /// Reload the slot map from the seed node with CLUSTER SLOTS. Without
+    /// cluster support the map stays empty.
+    pub fn refresh_slots(&mut self) -> io::Result<()> {
+        let seed = self.seed.clone();
+        let reply = self.send(&seed, &["CLUSTER", "SLOTS"])?;
+        let RedisValue::Arr(ranges) = reply else {
+            return Ok(());
+        };
+        self.slots.iter_mut().for_each(|node| *node = None);
+        for range in ranges {
+            let RedisValue::Arr(range) = range else {
+                continue;
+            };
+            let (Some(RedisValue::Int(first)), Some(RedisValue::Int(last)), Some(RedisValue::Arr(node))) =
+                (range.first(), range.get(1), range.get(2))
+            else {
+                continue;
+            };
+            let (Some(RedisValue::Str(ip)), Some(RedisValue::Int(port))) = (node.first(), node.get(1)) else {
+                continue;
+            };
+            let (first, last) = (*first as usize, (*last as usize).min(CLUSTER_SLOTS - 1));
+            for slot in first..=last {
+                self.slots[slot] = Some(format!("{}:{}", ip, port));
+            }
+        }
+        Ok(())
+    }
+
+    /// Send one command to the node serving it, following redirections, and
+    /// wait for the reply
+    pub fn command(&mut self, args: &[&str]) -> io::Result<RedisValue> {
+        let slot = args.get(1).map(|key| key_hash_slot(key) as usize);
+        let mut addr = slot.and_then(|slot| self.slots[slot].clone()).unwrap_or_else(|| self.seed.clone());
+        let mut asking = false;
+        let mut redirects = 0;
+        loop {
+            if asking {
+                self.send(&addr, &["ASKING"])?;
+            }
+            let reply = self.send(&addr, args)?;
+            let redirect = match &reply {
+                RedisValue::Err(msg) if redirects < MAX_REDIRECTS => Redirect::parse(msg),
+                _ => None,
+            };
+            let Some(redirect) = redirect else {
+                return Ok(reply);
+            };
+            if !redirect.ask {
+                self.slots[redirect.slot as usize] = Some(redirect.addr.clone());
+            }
+            asking = redirect.ask;
+            addr = redirect.addr.clone();
+            self.redirects.push(redirect);
+            redirects += 1;
+        }
+    }
+
+    /// Redirections followed so far, oldest first
+    pub fn take_redirects(&mut self) -> Vec<Redirect> {
+        std::mem::take(&mut self.redirects)
+    }
+
+    // Send `args` to the node at `addr`, connecting first if need be
+    fn send(&mut self, addr: &str, args: &[&str]) -> io::Result<RedisValue> {
+        let node = match self.nodes.entry(addr.to_string()) {
+            Entry::Occupied(node) => node.into_mut(),
+            Entry::Vacant(node) => node.insert(RedisClient::connect(addr)?.with_format(self.format)),
+        };
+        let reply = node.command(args);
+        if reply.is_err() {
+            self.nodes.remove(addr);
+        }
+        reply
+    }
+}
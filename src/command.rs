@@ -0,0 +1,1292 @@
+/* Command table */
+//
+// Every command the server understands is described here once: the handler
+// to run plus the metadata COMMAND reports (arity, flags, key specs, docs).
+// execute_command checks arity against this table before dispatching, so
+// handlers can assume they got at least the minimum number of arguments.
+//
+// A handler that finds a key of the wrong type returns CmdError::WrongType
+// (the typed accessors such as GData.db.expect_zset do) and the dispatcher
+// replies WRONGTYPE in place of anything the handler had written, so the
+// client gets exactly one reply.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::{
+    aof, cluster, do_client, do_copy, do_del, do_echo, do_expire, do_expireat, do_get, do_getset, do_keys, do_object, do_persist, do_pexpire, do_pexpireat, do_ping, do_quit, do_rename, do_renamenx, do_reset, do_scan, do_set, do_shutdown, do_ttl, do_zadd, do_zmpop, do_zquery, do_zrandmember, do_zrangebyscore, do_zrem, do_zremrangebylex, do_zremrangebyrank, do_zremrangebyscore, failover, geo, hyperloglog, info, latency,
+    config, list, memory, migrate, out_arr, out_err, out_int, out_nil, out_str, rdb, replication, scripting, setops, stream, Buffer,
+};
+
+pub const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+/// Why a handler gave up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmdError {
+    /// A key holds another type; the dispatcher replies WRONGTYPE
+    WrongType,
+    /// The handler has replied already; the reason is only logged
+    Failed(&'static str),
+}
+
+impl From<&'static str> for CmdError {
+    fn from(msg: &'static str) -> Self {
+        CmdError::Failed(msg)
+    }
+}
+
+pub type CmdResult = Result<(), CmdError>;
+
+pub type CommandProc = fn(&[String], &mut Buffer) -> CmdResult;
+
+// Command flags
+pub const CMD_WRITE: u32 = 1 << 0;
+/// Only reads the keyspace; the handler takes the global data shared
+pub const CMD_READONLY: u32 = 1 << 1;
+pub const CMD_DENYOOM: u32 = 1 << 2;
+pub const CMD_ADMIN: u32 = 1 << 3;
+pub const CMD_NOSCRIPT: u32 = 1 << 4;
+pub const CMD_BLOCKING: u32 = 1 << 5;
+pub const CMD_LOADING: u32 = 1 << 6;
+pub const CMD_STALE: u32 = 1 << 7;
+pub const CMD_FAST: u32 = 1 << 8;
+
+const FLAG_NAMES: &[(u32, &str)] = &[
+    (CMD_WRITE, "write"),
+    (CMD_READONLY, "readonly"),
+    (CMD_DENYOOM, "denyoom"),
+    (CMD_ADMIN, "admin"),
+    (CMD_NOSCRIPT, "noscript"),
+    (CMD_BLOCKING, "blocking"),
+    (CMD_LOADING, "loading"),
+    (CMD_STALE, "stale"),
+    (CMD_FAST, "fast"),
+];
+
+/// Where the search for a key spec's keys starts
+pub enum BeginSearch {
+    /// A fixed argument index
+    Index(i32),
+    /// The argument after `keyword`, searching from `start_from`
+    Keyword { keyword: &'static str, start_from: i32 },
+}
+
+/// How keys are found once the search has begun
+pub enum FindKeys {
+    /// Keys up to `last_key` (relative, negative counts from the end) every
+    /// `key_step` arguments. A non-zero `limit` takes only 1/limit of the rest.
+    Range { last_key: i32, key_step: i32, limit: i32 },
+    /// The argument at `key_num_idx` says how many keys follow, starting at
+    /// `first_key` (both relative to the begin search position)
+    KeyNum { key_num_idx: i32, first_key: i32, key_step: i32 },
+}
+
+pub struct KeySpec {
+    pub flags: &'static [&'static str],
+    pub begin_search: BeginSearch,
+    pub find_keys: FindKeys,
+}
+
+impl KeySpec {
+    /// One key at a fixed position
+    const fn single(index: i32, flags: &'static [&'static str]) -> Self {
+        Self::range(index, 0, 1, flags)
+    }
+
+    const fn range(index: i32, last_key: i32, key_step: i32, flags: &'static [&'static str]) -> Self {
+        Self {
+            flags,
+            begin_search: BeginSearch::Index(index),
+            find_keys: FindKeys::Range { last_key, key_step, limit: 0 },
+        }
+    }
+
+    /// Keys following a keyword, e.g. XREAD ... STREAMS key [key ...] id [id ...]
+    const fn keyword(keyword: &'static str, limit: i32, flags: &'static [&'static str]) -> Self {
+        Self {
+            flags,
+            begin_search: BeginSearch::Keyword { keyword, start_from: 1 },
+            find_keys: FindKeys::Range { last_key: -1, key_step: 1, limit },
+        }
+    }
+
+    /// "numkeys key [key ...]" as in EVAL and FCALL
+    const fn numkeys(index: i32, flags: &'static [&'static str]) -> Self {
+        Self {
+            flags,
+            begin_search: BeginSearch::Index(index),
+            find_keys: FindKeys::KeyNum { key_num_idx: 0, first_key: 1, key_step: 1 },
+        }
+    }
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub handler: CommandProc,
+    /// Positive: exact argument count (including the name). Negative: minimum.
+    pub arity: i32,
+    pub flags: u32,
+    pub key_specs: &'static [KeySpec],
+    pub group: &'static str,
+    pub summary: &'static str,
+    pub complexity: &'static str,
+}
+
+impl Command {
+    pub fn arity_ok(&self, argc: usize) -> bool {
+        if self.arity >= 0 {
+            argc == self.arity as usize
+        } else {
+            argc >= self.arity.unsigned_abs() as usize
+        }
+    }
+
+    pub fn has_flag(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// The keys in a call's arguments, found with the key specs. Positions
+    /// past the end of `args` are skipped rather than trusted.
+    pub fn keys<'a>(&self, args: &'a [String]) -> Vec<&'a str> {
+        let mut keys = Vec::new();
+        for spec in self.key_specs {
+            let begin = match spec.begin_search {
+                BeginSearch::Index(index) => index as usize,
+                BeginSearch::Keyword { keyword, start_from } => {
+                    let found = args.iter().skip(start_from as usize).position(|arg| arg.eq_ignore_ascii_case(keyword));
+                    match found {
+                        Some(pos) => start_from as usize + pos + 1,
+                        None => continue,
+                    }
+                }
+            };
+            // Keys are taken every `step` arguments from `first` for `span` arguments
+            let (first, span, step) = match spec.find_keys {
+                FindKeys::Range { last_key, key_step, limit } => {
+                    let end = if last_key >= 0 { begin as i32 + last_key + 1 } else { args.len() as i32 + last_key + 1 };
+                    let span = (end.max(0) as usize).saturating_sub(begin);
+                    (begin, if limit > 1 { span / limit as usize } else { span }, key_step as usize)
+                }
+                FindKeys::KeyNum { key_num_idx, first_key, key_step } => {
+                    let Some(count) = args.get(begin + key_num_idx as usize).and_then(|n| n.parse::<usize>().ok()) else {
+                        continue;
+                    };
+                    (begin + first_key as usize, count.saturating_mul(key_step as usize), key_step as usize)
+                }
+            };
+            let range = first..first.saturating_add(span);
+            keys.extend(range.step_by(step.max(1)).map_while(|i| args.get(i)).map(String::as_str));
+        }
+        keys
+    }
+
+    /// Keys can't be located from fixed positions alone
+    fn movable_keys(&self) -> bool {
+        self.key_specs
+            .iter()
+            .any(|spec| !matches!((&spec.begin_search, &spec.find_keys), (BeginSearch::Index(_), FindKeys::Range { .. })))
+    }
+
+    /// The pre-key-spec (first key, last key, step) triple, covering the
+    /// fixed-position key specs
+    fn legacy_key_range(&self) -> (i32, i32, i32) {
+        let mut range: Option<(i32, i32, i32)> = None;
+        for spec in self.key_specs {
+            if let (BeginSearch::Index(index), FindKeys::Range { last_key, key_step, .. }) =
+                (&spec.begin_search, &spec.find_keys)
+            {
+                let last = if *last_key < 0 { *last_key } else { index + last_key };
+                range = Some(match range {
+                    None => (*index, last, *key_step),
+                    Some((first, prev_last, step)) => {
+                        let last = if prev_last < 0 || last < 0 { prev_last.min(last) } else { prev_last.max(last) };
+                        (first.min(*index), last, step)
+                    }
+                });
+            }
+        }
+        range.unwrap_or((0, 0, 0))
+    }
+}
+
+const RO: &[&str] = &["RO", "ACCESS"];
+const RW: &[&str] = &["RW", "UPDATE"];
+const RW_INSERT: &[&str] = &["RW", "INSERT"];
+const RW_DELETE: &[&str] = &["RW", "DELETE"];
+const OW: &[&str] = &["OW", "UPDATE"];
+const RM: &[&str] = &["RM", "DELETE"];
+
+static COMMANDS: &[Command] = &[
+    /* Connection */
+    Command {
+        name: "ping",
+        handler: do_ping,
+        arity: -1,
+        flags: CMD_FAST | CMD_LOADING | CMD_STALE,
+        key_specs: &[],
+        group: "connection",
+        summary: "Reply PONG, or the given message",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "echo",
+        handler: do_echo,
+        arity: 2,
+        flags: CMD_FAST,
+        key_specs: &[],
+        group: "connection",
+        summary: "Reply the given message",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "quit",
+        handler: do_quit,
+        arity: -1,
+        flags: CMD_NOSCRIPT | CMD_LOADING | CMD_STALE | CMD_FAST,
+        key_specs: &[],
+        group: "connection",
+        summary: "Close the connection after replying",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "client",
+        handler: do_client,
+        arity: -2,
+        flags: CMD_NOSCRIPT | CMD_LOADING | CMD_STALE,
+        key_specs: &[],
+        group: "connection",
+        summary: "Manage the connection (ID, LIST, KILL, TRACKING, REPLY, PAUSE, UNPAUSE)",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "reset",
+        handler: do_reset,
+        arity: 1,
+        flags: CMD_NOSCRIPT | CMD_LOADING | CMD_STALE | CMD_FAST,
+        key_specs: &[],
+        group: "connection",
+        summary: "Reset the connection state",
+        complexity: "O(1)",
+    },
+    /* Strings and keys */
+    Command {
+        name: "get",
+        handler: do_get,
+        arity: 2,
+        flags: CMD_READONLY | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RO)],
+        group: "string",
+        summary: "Get the value of a key",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "set",
+        handler: do_set,
+        arity: -3,
+        flags: CMD_WRITE | CMD_DENYOOM,
+        key_specs: &[KeySpec::single(1, OW)],
+        group: "string",
+        summary: "Set the string value of a key",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "getset",
+        handler: do_getset,
+        arity: 3,
+        flags: CMD_WRITE | CMD_DENYOOM | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW)],
+        group: "string",
+        summary: "Set the string value of a key and return its old value",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "del",
+        handler: do_del,
+        arity: -2,
+        flags: CMD_WRITE,
+        key_specs: &[KeySpec::range(1, -1, 1, RM)],
+        group: "generic",
+        summary: "Delete one or more keys",
+        complexity: "O(n)",
+    },
+    Command {
+        name: "rename",
+        handler: do_rename,
+        arity: 3,
+        flags: CMD_WRITE,
+        key_specs: &[KeySpec::single(1, RW_DELETE), KeySpec::single(2, OW)],
+        group: "generic",
+        summary: "Give a key another name, replacing any key there, TTL included",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "renamenx",
+        handler: do_renamenx,
+        arity: 3,
+        flags: CMD_WRITE,
+        key_specs: &[KeySpec::single(1, RW_DELETE), KeySpec::single(2, OW)],
+        group: "generic",
+        summary: "Give a key another name, only if no key has it",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "copy",
+        handler: do_copy,
+        arity: -3,
+        flags: CMD_WRITE | CMD_DENYOOM,
+        key_specs: &[KeySpec::single(1, RO), KeySpec::single(2, OW)],
+        group: "generic",
+        summary: "Copy a key's value and TTL to another key",
+        complexity: "O(1); the value is copied when either key is next written",
+    },
+    Command {
+        name: "scan",
+        handler: do_scan,
+        arity: -2,
+        flags: CMD_READONLY,
+        key_specs: &[],
+        group: "generic",
+        summary: "Incrementally iterate over the keys",
+        complexity: "O(1) per call, O(n) for a full iteration",
+    },
+    Command {
+        name: "dump",
+        handler: rdb::do_dump,
+        arity: 2,
+        flags: CMD_READONLY,
+        key_specs: &[KeySpec::single(1, RO)],
+        group: "generic",
+        summary: "Serialize the value stored at a key",
+        complexity: "O(n)",
+    },
+    Command {
+        name: "restore",
+        handler: rdb::do_restore,
+        arity: -4,
+        flags: CMD_WRITE | CMD_DENYOOM,
+        key_specs: &[KeySpec::single(1, OW)],
+        group: "generic",
+        summary: "Create a key from a DUMP payload",
+        complexity: "O(n)",
+    },
+    Command {
+        name: "migrate",
+        handler: migrate::do_migrate,
+        arity: -5,
+        flags: CMD_WRITE,
+        key_specs: &[KeySpec::single(3, RW_DELETE)],
+        group: "generic",
+        summary: "Move a key to another instance",
+        complexity: "O(n) to serialize the value, plus a network round trip",
+    },
+    Command {
+        name: "keys",
+        handler: do_keys,
+        arity: -1,
+        flags: CMD_READONLY,
+        key_specs: &[],
+        group: "generic",
+        summary: "List the keys matching a pattern, all of them without one",
+        complexity: "O(n), spread over event loop rounds",
+    },
+    Command {
+        name: "expire",
+        handler: do_expire,
+        arity: -3,
+        flags: CMD_WRITE | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW)],
+        group: "generic",
+        summary: "Set a key's time to live in seconds",
+        complexity: "O(log n)",
+    },
+    Command {
+        name: "pexpire",
+        handler: do_pexpire,
+        arity: -3,
+        flags: CMD_WRITE | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW)],
+        group: "generic",
+        summary: "Set a key's time to live in milliseconds",
+        complexity: "O(log n)",
+    },
+    Command {
+        name: "expireat",
+        handler: do_expireat,
+        arity: -3,
+        flags: CMD_WRITE | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW)],
+        group: "generic",
+        summary: "Set a key to expire at a unix time in seconds",
+        complexity: "O(log n)",
+    },
+    Command {
+        name: "pexpireat",
+        handler: do_pexpireat,
+        arity: -3,
+        flags: CMD_WRITE | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW)],
+        group: "generic",
+        summary: "Set a key to expire at a unix time in milliseconds",
+        complexity: "O(log n)",
+    },
+    Command {
+        name: "ttl",
+        handler: do_ttl,
+        arity: 2,
+        flags: CMD_READONLY | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RO)],
+        group: "generic",
+        summary: "Get the time to live for a key in seconds",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "persist",
+        handler: do_persist,
+        arity: 2,
+        flags: CMD_WRITE | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW)],
+        group: "generic",
+        summary: "Remove the expiration from a key",
+        complexity: "O(log n)",
+    },
+    Command {
+        name: "object",
+        handler: do_object,
+        arity: -2,
+        flags: CMD_READONLY,
+        key_specs: &[KeySpec::single(2, RO)],
+        group: "generic",
+        summary: "Inspect the internals of a key (ENCODING, FREQ, IDLETIME)",
+        complexity: "O(1), O(n) for ENCODING of a sorted set or list",
+    },
+    /* Sorted sets */
+    Command {
+        name: "zadd",
+        handler: do_zadd,
+        arity: -4,
+        flags: CMD_WRITE | CMD_DENYOOM | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW)],
+        group: "sorted-set",
+        summary: "Add members to a sorted set, or update their scores",
+        complexity: "O(log n)",
+    },
+    Command {
+        name: "zrem",
+        handler: do_zrem,
+        arity: -3,
+        flags: CMD_WRITE | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW_DELETE)],
+        group: "sorted-set",
+        summary: "Remove members from a sorted set",
+        complexity: "O(log n)",
+    },
+    Command {
+        name: "zremrangebyscore",
+        handler: do_zremrangebyscore,
+        arity: 4,
+        flags: CMD_WRITE,
+        key_specs: &[KeySpec::single(1, RW_DELETE)],
+        group: "sorted-set",
+        summary: "Remove the members of a sorted set within a range of scores",
+        complexity: "O((log n + m) log n), m the members removed",
+    },
+    Command {
+        name: "zremrangebyrank",
+        handler: do_zremrangebyrank,
+        arity: 4,
+        flags: CMD_WRITE,
+        key_specs: &[KeySpec::single(1, RW_DELETE)],
+        group: "sorted-set",
+        summary: "Remove the members of a sorted set within a range of ranks",
+        complexity: "O((log n + m) log n), m the members removed",
+    },
+    Command {
+        name: "zremrangebylex",
+        handler: do_zremrangebylex,
+        arity: 4,
+        flags: CMD_WRITE,
+        key_specs: &[KeySpec::single(1, RW_DELETE)],
+        group: "sorted-set",
+        summary: "Remove the members of a sorted set within a range of names",
+        complexity: "O((log n + m) log n), m the members removed",
+    },
+    Command {
+        name: "zmpop",
+        handler: do_zmpop,
+        arity: -4,
+        flags: CMD_WRITE,
+        key_specs: &[KeySpec::numkeys(1, RW_DELETE)],
+        group: "sorted-set",
+        summary: "Pop the lowest or highest scored members of the first non-empty sorted set",
+        complexity: "O(k + m log n), k the keys and m the members popped",
+    },
+    Command {
+        name: "zintercard",
+        handler: setops::do_zintercard,
+        arity: -3,
+        flags: CMD_READONLY,
+        key_specs: &[KeySpec::numkeys(1, RO)],
+        group: "sorted-set",
+        summary: "Count the members common to several sorted sets, stopping at a limit",
+        complexity: "O(n k), n the members of the smallest set and k the keys; less with LIMIT",
+    },
+    Command {
+        name: "zrandmember",
+        handler: do_zrandmember,
+        arity: -2,
+        flags: CMD_READONLY,
+        key_specs: &[KeySpec::single(1, RO)],
+        group: "sorted-set",
+        summary: "Random members of a sorted set, distinct or with repeats",
+        complexity: "O(m log n), m the members returned",
+    },
+    Command {
+        name: "zrangebyscore",
+        handler: do_zrangebyscore,
+        arity: -4,
+        flags: CMD_READONLY,
+        key_specs: &[KeySpec::single(1, RO)],
+        group: "sorted-set",
+        summary: "Return the members of a sorted set within a range of scores",
+        complexity: "O(log n + m), m the members returned",
+    },
+    Command {
+        name: "zquery",
+        handler: do_zquery,
+        arity: 6,
+        flags: CMD_READONLY,
+        key_specs: &[KeySpec::single(1, RO)],
+        group: "sorted-set",
+        summary: "Range query by (score, name) with offset and limit",
+        complexity: "O(log n + k)",
+    },
+    /* Lists */
+    Command {
+        name: "lpush",
+        handler: list::do_lpush,
+        arity: -3,
+        flags: CMD_WRITE | CMD_DENYOOM | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW_INSERT)],
+        group: "list",
+        summary: "Prepend elements to a list",
+        complexity: "O(1) per element",
+    },
+    Command {
+        name: "rpush",
+        handler: list::do_rpush,
+        arity: -3,
+        flags: CMD_WRITE | CMD_DENYOOM | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW_INSERT)],
+        group: "list",
+        summary: "Append elements to a list",
+        complexity: "O(1) per element",
+    },
+    Command {
+        name: "lpop",
+        handler: list::do_lpop,
+        arity: -2,
+        flags: CMD_WRITE | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW_DELETE)],
+        group: "list",
+        summary: "Remove and return the first elements of a list",
+        complexity: "O(n) in the count",
+    },
+    Command {
+        name: "rpop",
+        handler: list::do_rpop,
+        arity: -2,
+        flags: CMD_WRITE | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW_DELETE)],
+        group: "list",
+        summary: "Remove and return the last elements of a list",
+        complexity: "O(n) in the count",
+    },
+    Command {
+        name: "llen",
+        handler: list::do_llen,
+        arity: 2,
+        flags: CMD_READONLY | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RO)],
+        group: "list",
+        summary: "Length of a list",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "lrange",
+        handler: list::do_lrange,
+        arity: 4,
+        flags: CMD_READONLY,
+        key_specs: &[KeySpec::single(1, RO)],
+        group: "list",
+        summary: "Elements of a list within an index range",
+        complexity: "O(k)",
+    },
+    Command {
+        name: "lmove",
+        handler: list::do_lmove,
+        arity: 5,
+        flags: CMD_WRITE | CMD_DENYOOM,
+        key_specs: &[KeySpec::single(1, RW_DELETE), KeySpec::single(2, RW_INSERT)],
+        group: "list",
+        summary: "Pop an element from one list and push it to another",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "blmove",
+        handler: list::do_blmove,
+        arity: 6,
+        flags: CMD_WRITE | CMD_DENYOOM | CMD_BLOCKING,
+        key_specs: &[KeySpec::single(1, RW_DELETE), KeySpec::single(2, RW_INSERT)],
+        group: "list",
+        summary: "LMOVE, blocking until the source has an element",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "lmpop",
+        handler: list::do_lmpop,
+        arity: -4,
+        flags: CMD_WRITE,
+        key_specs: &[KeySpec::numkeys(1, RW_DELETE)],
+        group: "list",
+        summary: "Pop elements from the first non-empty list",
+        complexity: "O(k + m), k the keys and m the elements popped",
+    },
+    Command {
+        name: "blmpop",
+        handler: list::do_blmpop,
+        arity: -5,
+        flags: CMD_WRITE | CMD_BLOCKING,
+        key_specs: &[KeySpec::numkeys(2, RW_DELETE)],
+        group: "list",
+        summary: "LMPOP, blocking until one of the lists has an element",
+        complexity: "O(k + m), k the keys and m the elements popped",
+    },
+    Command {
+        name: "rpoplpush",
+        handler: list::do_rpoplpush,
+        arity: 3,
+        flags: CMD_WRITE | CMD_DENYOOM,
+        key_specs: &[KeySpec::single(1, RW_DELETE), KeySpec::single(2, RW_INSERT)],
+        group: "list",
+        summary: "Pop the last element of a list and push it to the front of another",
+        complexity: "O(1)",
+    },
+    /* HyperLogLog */
+    Command {
+        name: "pfadd",
+        handler: hyperloglog::do_pfadd,
+        arity: -2,
+        flags: CMD_WRITE | CMD_DENYOOM | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW_INSERT)],
+        group: "hyperloglog",
+        summary: "Add elements to a HyperLogLog",
+        complexity: "O(1) per element",
+    },
+    Command {
+        name: "pfcount",
+        handler: hyperloglog::do_pfcount,
+        arity: -2,
+        flags: CMD_READONLY,
+        key_specs: &[KeySpec::range(1, -1, 1, RO)],
+        group: "hyperloglog",
+        summary: "Estimate the cardinality of the union of HyperLogLogs",
+        complexity: "O(n)",
+    },
+    Command {
+        name: "pfmerge",
+        handler: hyperloglog::do_pfmerge,
+        arity: -2,
+        flags: CMD_WRITE | CMD_DENYOOM,
+        key_specs: &[KeySpec::single(1, RW_INSERT), KeySpec::range(2, -1, 1, RO)],
+        group: "hyperloglog",
+        summary: "Merge HyperLogLogs into a destination key",
+        complexity: "O(n)",
+    },
+    /* Streams */
+    Command {
+        name: "xadd",
+        handler: stream::do_xadd,
+        arity: -5,
+        flags: CMD_WRITE | CMD_DENYOOM | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW_INSERT)],
+        group: "stream",
+        summary: "Append an entry to a stream",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "xlen",
+        handler: stream::do_xlen,
+        arity: 2,
+        flags: CMD_READONLY | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RO)],
+        group: "stream",
+        summary: "Number of entries in a stream",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "xrange",
+        handler: stream::do_xrange,
+        arity: -4,
+        flags: CMD_READONLY,
+        key_specs: &[KeySpec::single(1, RO)],
+        group: "stream",
+        summary: "Entries within a range of IDs",
+        complexity: "O(log n + k)",
+    },
+    Command {
+        name: "xrevrange",
+        handler: stream::do_xrevrange,
+        arity: -4,
+        flags: CMD_READONLY,
+        key_specs: &[KeySpec::single(1, RO)],
+        group: "stream",
+        summary: "Entries within a range of IDs, in reverse order",
+        complexity: "O(log n + k)",
+    },
+    Command {
+        name: "xread",
+        handler: stream::do_xread,
+        arity: -4,
+        flags: CMD_READONLY | CMD_BLOCKING,
+        key_specs: &[KeySpec::keyword("STREAMS", 2, RO)],
+        group: "stream",
+        summary: "Read entries after the given IDs from one or more streams",
+        complexity: "O(log n + k) per stream",
+    },
+    Command {
+        name: "xgroup",
+        handler: stream::do_xgroup,
+        arity: -2,
+        flags: CMD_WRITE,
+        key_specs: &[KeySpec::single(2, RW)],
+        group: "stream",
+        summary: "Create, destroy and manage consumer groups",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "xreadgroup",
+        handler: stream::do_xreadgroup,
+        arity: -7,
+        flags: CMD_WRITE | CMD_BLOCKING,
+        key_specs: &[KeySpec::keyword("STREAMS", 2, RW)],
+        group: "stream",
+        summary: "Read entries through a consumer group",
+        complexity: "O(log n + k) per stream",
+    },
+    Command {
+        name: "xack",
+        handler: stream::do_xack,
+        arity: -4,
+        flags: CMD_WRITE | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW)],
+        group: "stream",
+        summary: "Acknowledge pending entries of a consumer group",
+        complexity: "O(log n) per ID",
+    },
+    Command {
+        name: "xpending",
+        handler: stream::do_xpending,
+        arity: -3,
+        flags: CMD_READONLY,
+        key_specs: &[KeySpec::single(1, RO)],
+        group: "stream",
+        summary: "Inspect the pending entries list of a consumer group",
+        complexity: "O(log n + k)",
+    },
+    Command {
+        name: "xclaim",
+        handler: stream::do_xclaim,
+        arity: -6,
+        flags: CMD_WRITE | CMD_FAST,
+        key_specs: &[KeySpec::single(1, RW)],
+        group: "stream",
+        summary: "Take ownership of pending entries",
+        complexity: "O(log n) per ID",
+    },
+    /* Geo */
+    Command {
+        name: "geoadd",
+        handler: geo::do_geoadd,
+        arity: -5,
+        flags: CMD_WRITE | CMD_DENYOOM,
+        key_specs: &[KeySpec::single(1, RW)],
+        group: "geo",
+        summary: "Add members with longitude/latitude to a geo index",
+        complexity: "O(log n) per member",
+    },
+    Command {
+        name: "geopos",
+        handler: geo::do_geopos,
+        arity: -2,
+        flags: CMD_READONLY,
+        key_specs: &[KeySpec::single(1, RO)],
+        group: "geo",
+        summary: "Coordinates of members of a geo index",
+        complexity: "O(1) per member",
+    },
+    Command {
+        name: "geodist",
+        handler: geo::do_geodist,
+        arity: -4,
+        flags: CMD_READONLY,
+        key_specs: &[KeySpec::single(1, RO)],
+        group: "geo",
+        summary: "Distance between two members of a geo index",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "geosearch",
+        handler: geo::do_geosearch,
+        arity: -7,
+        flags: CMD_READONLY,
+        key_specs: &[KeySpec::single(1, RO)],
+        group: "geo",
+        summary: "Members within a radius or box",
+        complexity: "O(log n + k)",
+    },
+    /* Scripting */
+    Command {
+        name: "eval",
+        handler: scripting::do_eval,
+        arity: -3,
+        flags: CMD_NOSCRIPT | CMD_STALE,
+        key_specs: &[KeySpec::numkeys(2, RW)],
+        group: "scripting",
+        summary: "Run a Lua script",
+        complexity: "Depends on the script",
+    },
+    Command {
+        name: "evalsha",
+        handler: scripting::do_evalsha,
+        arity: -3,
+        flags: CMD_NOSCRIPT | CMD_STALE,
+        key_specs: &[KeySpec::numkeys(2, RW)],
+        group: "scripting",
+        summary: "Run a cached Lua script by its SHA1",
+        complexity: "Depends on the script",
+    },
+    Command {
+        name: "script",
+        handler: scripting::do_script,
+        arity: -2,
+        flags: CMD_NOSCRIPT,
+        key_specs: &[],
+        group: "scripting",
+        summary: "Manage the script cache (LOAD, EXISTS, FLUSH), stop a slow script (KILL)",
+        complexity: "O(n)",
+    },
+    Command {
+        name: "fcall",
+        handler: scripting::do_fcall,
+        arity: -3,
+        flags: CMD_NOSCRIPT | CMD_STALE,
+        key_specs: &[KeySpec::numkeys(2, RW)],
+        group: "scripting",
+        summary: "Call a function",
+        complexity: "Depends on the function",
+    },
+    Command {
+        name: "fcall_ro",
+        handler: scripting::do_fcall,
+        arity: -3,
+        flags: CMD_NOSCRIPT | CMD_STALE | CMD_READONLY,
+        key_specs: &[KeySpec::numkeys(2, RO)],
+        group: "scripting",
+        summary: "Call a read-only function",
+        complexity: "Depends on the function",
+    },
+    Command {
+        name: "function",
+        handler: scripting::do_function,
+        arity: -2,
+        flags: CMD_NOSCRIPT,
+        key_specs: &[],
+        group: "scripting",
+        summary: "Manage function libraries (LOAD, DELETE, FLUSH, LIST), stop a slow function (KILL)",
+        complexity: "O(n)",
+    },
+    /* Server */
+    Command {
+        name: "save",
+        handler: rdb::do_save,
+        arity: 1,
+        flags: CMD_ADMIN | CMD_NOSCRIPT,
+        key_specs: &[],
+        group: "server",
+        summary: "Synchronously save the dataset to disk",
+        complexity: "O(n)",
+    },
+    Command {
+        name: "shutdown",
+        handler: do_shutdown,
+        arity: -1,
+        flags: CMD_ADMIN | CMD_NOSCRIPT | CMD_LOADING | CMD_STALE,
+        key_specs: &[],
+        group: "server",
+        summary: "Exit the server, optionally saving a snapshot first",
+        complexity: "O(n) with SAVE",
+    },
+    Command {
+        name: "bgsave",
+        handler: rdb::do_bgsave,
+        arity: 1,
+        flags: CMD_ADMIN | CMD_NOSCRIPT,
+        key_specs: &[],
+        group: "server",
+        summary: "Asynchronously save the dataset to disk",
+        complexity: "O(n)",
+    },
+    Command {
+        name: "bgrewriteaof",
+        handler: aof::do_bgrewriteaof,
+        arity: 1,
+        flags: CMD_ADMIN | CMD_NOSCRIPT,
+        key_specs: &[],
+        group: "server",
+        summary: "Rewrite the append-only file in the background",
+        complexity: "O(n)",
+    },
+    Command {
+        name: "fsync",
+        handler: aof::do_fsync,
+        arity: 1,
+        flags: CMD_NOSCRIPT,
+        key_specs: &[],
+        group: "server",
+        summary: "Write the append-only file to disk and fsync it before replying",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "info",
+        handler: info::do_info,
+        arity: -1,
+        flags: CMD_LOADING | CMD_STALE,
+        key_specs: &[],
+        group: "server",
+        summary: "Report on the server and its counters",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "config",
+        handler: config::do_config,
+        arity: -2,
+        flags: CMD_ADMIN | CMD_NOSCRIPT | CMD_LOADING | CMD_STALE,
+        key_specs: &[],
+        group: "server",
+        summary: "Get, set or persist configuration parameters",
+        complexity: "O(n)",
+    },
+    Command {
+        name: "memory",
+        handler: memory::do_memory,
+        arity: -2,
+        flags: CMD_READONLY,
+        // Only MEMORY USAGE names a key
+        key_specs: &[KeySpec {
+            flags: RO,
+            begin_search: BeginSearch::Keyword { keyword: "USAGE", start_from: 1 },
+            find_keys: FindKeys::Range { last_key: 0, key_step: 1, limit: 0 },
+        }],
+        group: "server",
+        summary: "Estimate memory use of a key (USAGE) or the server (STATS), diagnose problems (DOCTOR), find big keys (BIGKEYS)",
+        complexity: "O(n)",
+    },
+    Command {
+        name: "cluster",
+        handler: cluster::do_cluster,
+        arity: -2,
+        flags: CMD_NOSCRIPT | CMD_LOADING | CMD_STALE,
+        key_specs: &[],
+        group: "cluster",
+        summary: "Cluster topology and slot ownership (SLOTS, SHARDS, NODES, MYID, INFO, KEYSLOT, ADDSLOTS, DELSLOTS, MEET, SETSLOT, GETKEYSINSLOT, COUNTKEYSINSLOT)",
+        complexity: "O(n) in the number of slots, O(N) in the number of keys for GETKEYSINSLOT/COUNTKEYSINSLOT",
+    },
+    Command {
+        name: "asking",
+        handler: cluster::do_asking,
+        arity: 1,
+        flags: CMD_FAST | CMD_LOADING | CMD_STALE,
+        key_specs: &[],
+        group: "cluster",
+        summary: "Let the next command run on a slot being imported",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "sync",
+        handler: replication::do_psync,
+        arity: 1,
+        flags: CMD_ADMIN | CMD_NOSCRIPT,
+        key_specs: &[],
+        group: "server",
+        summary: "Start streaming the dataset and its writes to a replica (full resync)",
+        complexity: "O(N)",
+    },
+    Command {
+        name: "psync",
+        handler: replication::do_psync,
+        arity: -3,
+        flags: CMD_ADMIN | CMD_NOSCRIPT,
+        key_specs: &[],
+        group: "server",
+        summary: "Stream writes to a replica, from its offset if the backlog has it",
+        complexity: "O(N)",
+    },
+    Command {
+        name: "replconf",
+        handler: replication::do_replconf,
+        arity: -1,
+        flags: CMD_ADMIN | CMD_NOSCRIPT | CMD_LOADING | CMD_STALE,
+        key_specs: &[],
+        group: "server",
+        summary: "Set a replica's options, or acknowledge its offset",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "replicaof",
+        handler: replication::do_replicaof,
+        arity: 3,
+        flags: CMD_ADMIN | CMD_NOSCRIPT | CMD_STALE,
+        key_specs: &[],
+        group: "server",
+        summary: "Become a master again after FAILOVER, with NO ONE",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "failover",
+        handler: failover::do_failover,
+        arity: -1,
+        flags: CMD_ADMIN | CMD_NOSCRIPT | CMD_STALE,
+        key_specs: &[],
+        group: "server",
+        summary: "Hand the master role over to a replica once it has caught up",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "latency",
+        handler: latency::do_latency,
+        arity: -2,
+        flags: CMD_ADMIN | CMD_NOSCRIPT | CMD_LOADING | CMD_STALE,
+        key_specs: &[],
+        group: "server",
+        summary: "Calls and latency percentiles of each command",
+        complexity: "O(N) commands",
+    },
+    Command {
+        name: "role",
+        handler: replication::do_role,
+        arity: 1,
+        flags: CMD_NOSCRIPT | CMD_LOADING | CMD_STALE | CMD_FAST,
+        key_specs: &[],
+        group: "server",
+        summary: "Report the replication role, offset and connected replicas",
+        complexity: "O(1)",
+    },
+    Command {
+        name: "command",
+        handler: do_command,
+        arity: -1,
+        flags: CMD_LOADING | CMD_STALE,
+        key_specs: &[],
+        group: "server",
+        summary: "Describe the commands this server supports",
+        complexity: "O(n)",
+    },
+];
+
+// The names clients call commands by, after rename-command
+static DISPATCH: OnceLock<HashMap<String, &'static Command>> = OnceLock::new();
+
+/// Apply the rename-command directives, in order. Called once at startup,
+/// before the first lookup; a command renamed to "" can't be called at all.
+pub fn install_renames(renames: &[(String, String)]) -> Result<(), String> {
+    let mut dispatch: HashMap<String, &'static Command> =
+        COMMANDS.iter().map(|command| (command.name.to_string(), command)).collect();
+    for (old, new) in renames {
+        let Some(command) = dispatch.remove(&old.to_ascii_lowercase()) else {
+            return Err(format!("rename-command: '{}' was already renamed", old));
+        };
+        if new.is_empty() {
+            continue;
+        }
+        if dispatch.insert(new.to_ascii_lowercase(), command).is_some() {
+            return Err(format!("rename-command: '{}' is already the name of a command", new));
+        }
+    }
+    DISPATCH.set(dispatch).map_err(|_| "commands are already in use".to_string())
+}
+
+/// Find a command by the name clients call it, case-insensitively
+pub fn lookup(name: &str) -> Option<&'static Command> {
+    let dispatch =
+        DISPATCH.get_or_init(|| COMMANDS.iter().map(|command| (command.name.to_string(), command)).collect());
+    dispatch.get(&name.to_ascii_lowercase()).copied()
+}
+
+/// Whether some command belongs to `group`, e.g. "sorted-set"
+pub fn is_group(group: &str) -> bool {
+    COMMANDS.iter().any(|command| command.group == group)
+}
+
+/// Find a command by its built-in name, whatever it was renamed to. The
+/// append-only file is written with these names so renames don't break it.
+pub fn lookup_original(name: &str) -> Option<&'static Command> {
+    static INDEX: OnceLock<HashMap<&'static str, &'static Command>> = OnceLock::new();
+    let index = INDEX.get_or_init(|| COMMANDS.iter().map(|command| (command.name, command)).collect());
+    index.get(name.to_ascii_lowercase().as_str()).copied()
+}
+
+fn out_key_spec(out: &mut Buffer, spec: &KeySpec) {
+    out_arr(out, 6);
+    out_str(out, "flags");
+    out_arr(out, spec.flags.len() as u32);
+    for flag in spec.flags {
+        out_str(out, flag);
+    }
+
+    out_str(out, "begin_search");
+    out_arr(out, 4);
+    out_str(out, "type");
+    match spec.begin_search {
+        BeginSearch::Index(index) => {
+            out_str(out, "index");
+            out_str(out, "spec");
+            out_arr(out, 2);
+            out_str(out, "index");
+            out_int(out, index as i64);
+        }
+        BeginSearch::Keyword { keyword, start_from } => {
+            out_str(out, "keyword");
+            out_str(out, "spec");
+            out_arr(out, 4);
+            out_str(out, "keyword");
+            out_str(out, keyword);
+            out_str(out, "startfrom");
+            out_int(out, start_from as i64);
+        }
+    }
+
+    out_str(out, "find_keys");
+    out_arr(out, 4);
+    out_str(out, "type");
+    match spec.find_keys {
+        FindKeys::Range { last_key, key_step, limit } => {
+            out_str(out, "range");
+            out_str(out, "spec");
+            out_arr(out, 6);
+            out_str(out, "lastkey");
+            out_int(out, last_key as i64);
+            out_str(out, "keystep");
+            out_int(out, key_step as i64);
+            out_str(out, "limit");
+            out_int(out, limit as i64);
+        }
+        FindKeys::KeyNum { key_num_idx, first_key, key_step } => {
+            out_str(out, "keynum");
+            out_str(out, "spec");
+            out_arr(out, 6);
+            out_str(out, "keynumidx");
+            out_int(out, key_num_idx as i64);
+            out_str(out, "firstkey");
+            out_int(out, first_key as i64);
+            out_str(out, "keystep");
+            out_int(out, key_step as i64);
+        }
+    }
+}
+
+// One COMMAND INFO entry:
+// [name, arity, flags, first key, last key, step, acl categories, tips, key specs, subcommands]
+fn out_command_info(out: &mut Buffer, command: &Command) {
+    out_arr(out, 10);
+    out_str(out, command.name);
+    out_int(out, command.arity as i64);
+
+    let mut flags: Vec<&str> = FLAG_NAMES
+        .iter()
+        .filter(|(flag, _)| command.has_flag(*flag))
+        .map(|(_, name)| *name)
+        .collect();
+    if command.movable_keys() {
+        flags.push("movablekeys");
+    }
+    out_arr(out, flags.len() as u32);
+    for flag in flags {
+        out_str(out, flag);
+    }
+
+    let (first, last, step) = command.legacy_key_range();
+    out_int(out, first as i64);
+    out_int(out, last as i64);
+    out_int(out, step as i64);
+
+    out_arr(out, 0); // ACL categories
+    out_arr(out, 0); // tips
+    out_arr(out, command.key_specs.len() as u32);
+    for spec in command.key_specs {
+        out_key_spec(out, spec);
+    }
+    out_arr(out, 0); // subcommands
+}
+
+fn out_command_docs(out: &mut Buffer, command: &Command) {
+    out_str(out, command.name);
+    out_arr(out, 6);
+    out_str(out, "summary");
+    out_str(out, command.summary);
+    out_str(out, "group");
+    out_str(out, command.group);
+    out_str(out, "complexity");
+    out_str(out, command.complexity);
+}
+
+// COMMAND | COMMAND COUNT | COMMAND LIST | COMMAND INFO [name ...] | COMMAND DOCS [name ...]
+pub fn do_command(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() == 1 {
+        out_arr(out, COMMANDS.len() as u32);
+        for command in COMMANDS {
+            out_command_info(out, command);
+        }
+        return Ok(());
+    }
+
+    match cmd[1].to_uppercase().as_str() {
+        "COUNT" if cmd.len() == 2 => out_int(out, COMMANDS.len() as i64),
+        "LIST" if cmd.len() == 2 => {
+            out_arr(out, COMMANDS.len() as u32);
+            for command in COMMANDS {
+                out_str(out, command.name);
+            }
+        }
+        "INFO" => {
+            let names = &cmd[2..];
+            if names.is_empty() {
+                out_arr(out, COMMANDS.len() as u32);
+                for command in COMMANDS {
+                    out_command_info(out, command);
+                }
+            } else {
+                out_arr(out, names.len() as u32);
+                for name in names {
+                    match lookup(name) {
+                        Some(command) => out_command_info(out, command),
+                        None => out_nil(out),
+                    }
+                }
+            }
+        }
+        "DOCS" => {
+            // A flat name -> docs map; unknown names are left out
+            let found: Vec<&Command> = if cmd.len() == 2 {
+                COMMANDS.iter().collect()
+            } else {
+                cmd[2..].iter().filter_map(|name| lookup(name)).collect()
+            };
+            out_arr(out, found.len() as u32 * 2);
+            for command in found {
+                out_command_docs(out, command);
+            }
+        }
+        _ => out_err(out, "ERR unknown COMMAND subcommand or wrong number of arguments"),
+    }
+    Ok(())
+}
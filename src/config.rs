@@ -0,0 +1,1072 @@
+/* Runtime configuration */
+//
+// Tunables live in one Config struct inside GData. Each parameter is listed
+// in CONFIG_PARAMS with how to print and parse it; that one table drives the
+// config file, `--name value` command line overrides, and CONFIG GET/SET.
+//
+//   redis [/path/to/redis.conf] [--name value ...]
+//
+// The config file has one "name value" directive per line, '#' starts a comment.
+// Besides the parameters there's `rename-command OLD NEW`, which can be given
+// any number of times, only at startup: the command is then only reachable as
+// NEW, or not at all if NEW is "".
+// CONFIG REWRITE writes the running values back into that file: directives
+// for known parameters are updated in place (duplicates dropped), comments and
+// unknown directives are kept, and parameters that differ from their default
+// but aren't in the file yet are appended at the end.
+//
+// On SIGHUP the config file is read again (reload). Its parameters are
+// applied together, or not at all if any of them is invalid; parameters no
+// longer in the file keep their running value. Those in RESTART_ONLY, and
+// rename-command, only take effect at startup: a change to them is logged
+// and left for the next restart. CONFIG SET refuses them.
+
+use std::fs;
+use std::net::IpAddr;
+use std::io::Write;
+
+use redis::protocol::{FrameLimits, MAX_ARGS};
+
+use crate::aof::Fsync;
+use crate::evict::Policy;
+use crate::info::ServerStats;
+use crate::store::Backend;
+use crate::command::{self, CmdResult};
+use crate::logging::{self, log_notice, log_warning, Level};
+use crate::{glob_match, out_arr, out_err, out_nil, out_str, with_global_data, Buffer};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Close client connections idle for this many seconds, 0 = never
+    pub timeout: u64,
+    /// What one request may ask for
+    pub limits: Limits,
+    /// Largest reply; bigger ones are replaced by an error
+    pub max_reply_size: usize,
+    /// Seconds of silence before the first keepalive probe, 0 = SO_KEEPALIVE off
+    pub tcp_keepalive: u64,
+    /// Seconds between unanswered keepalive probes
+    pub tcp_keepalive_interval: u64,
+    /// Unanswered probes before the kernel drops the connection
+    pub tcp_keepalive_probes: u32,
+    /// Disable Nagle's algorithm on client sockets
+    pub tcp_nodelay: bool,
+    /// Address to listen on, None = every interface; read at startup
+    pub bind: Option<IpAddr>,
+    /// TCP port to listen on; read at startup
+    pub port: u16,
+    /// Working directory, where the persistence files live; read at startup
+    pub dir: String,
+    /// Fork into the background; read at startup
+    pub daemonize: bool,
+    /// Write the server's pid here, empty = none unless daemonized; read at startup
+    pub pidfile: String,
+    /// Turn away clients from other hosts when listening beyond loopback
+    pub protected_mode: bool,
+    /// Most clients connected at once; more are refused
+    pub maxclients: u64,
+    pub max_open_files: u64,
+    /// Commands a second each client may send, 0 = unlimited
+    pub client_rate_limit: u64,
+    /// Least severe messages logged by default
+    pub loglevel: Level,
+    /// Per-target level overrides, "target=level,..."
+    pub log_targets: String,
+    /// Log to this file instead of stdout; empty = stdout
+    pub logfile: String,
+    /// Log every write to the append-only file and load it at startup
+    pub appendonly: bool,
+    /// Name of the append-only file
+    pub appendfilename: String,
+    /// How often the append-only file is fsynced
+    pub appendfsync: Fsync,
+    /// Rewrite the append-only file once it grew by this many percent since
+    /// the last rewrite, 0 = never on its own
+    pub auto_aof_rewrite_percentage: u64,
+    /// ... and is at least this many bytes
+    pub auto_aof_rewrite_min_size: u64,
+    /// Rewrite the append-only file as a snapshot followed by commands
+    pub aof_use_rdb_preamble: bool,
+    /// Where the keyspace is kept; read at startup
+    pub storage_backend: Backend,
+    /// File of the log backend
+    pub storage_log: String,
+    /// Bytes of values the log backend keeps in memory, 0 for all of them
+    pub storage_memory: u64,
+    /// Bytes of replication stream kept for replicas to resume from
+    pub repl_backlog_size: u64,
+    /// Skip the records of a snapshot or AOF that can't be loaded at startup
+    /// instead of refusing to start
+    pub load_skip_corrupt: bool,
+    /// Milliseconds a script runs before other clients get BUSY, 0 = never
+    pub lua_time_limit: u64,
+    /// Record write commands to this file; empty = no audit log
+    pub audit_log: String,
+    /// Only record commands naming a key that matches this glob
+    pub audit_log_keys: String,
+    /// Only record commands of these groups, none = every group
+    pub audit_log_categories: Vec<String>,
+    /// Rotate the audit log once it's this many bytes, 0 = never
+    pub audit_log_max_size: u64,
+    /// Rotated audit logs kept
+    pub audit_log_max_files: u64,
+    /// Absolute path of the config file loaded at startup, for CONFIG REWRITE
+    pub config_file: Option<String>,
+    /// rename-command directives in order, (old, new); an empty new name
+    /// disables the command
+    pub rename_commands: Vec<(String, String)>,
+    /// Run as a cluster node, serving only the hash slots it owns; read at startup
+    pub cluster_enabled: bool,
+    /// Milliseconds a cluster node may go unheard before it's suspected down
+    pub cluster_node_timeout: u64,
+    /// Sorted sets with at most this many members ...
+    pub zset_max_listpack_entries: u64,
+    /// ... none longer than this many bytes are listpack-encoded
+    pub zset_max_listpack_value: u64,
+    /// Lists up to this many elements (positive) or this size class
+    /// (-1 = 4 KB ... -5 = 64 KB) are listpack-encoded
+    pub list_max_listpack_size: i64,
+    /// Keep a negative lookup filter for each table of the keyspace
+    pub lookup_filter: bool,
+    /// Move entries to a resized keyspace table from the event loop too,
+    /// not only as keys are written
+    pub activerehashing: bool,
+    /// Evict keys to stay under this many bytes, 0 = no limit
+    pub maxmemory: u64,
+    /// Which keys go first
+    pub maxmemory_policy: Policy,
+    /// Keys sampled for each eviction
+    pub maxmemory_samples: u64,
+    /// How slowly LFU counters grow: the higher, the more accesses it takes
+    pub lfu_log_factor: u64,
+    /// Minutes for an LFU counter to decay by one, 0 = never
+    pub lfu_decay_time: u64,
+}
+
+/// Bounds on one request. The request parsers enforce the size, argument
+/// count and argument length (so the length of any value); the dispatcher
+/// checks key lengths, and ZADD and GEOADD the number of members. Requests
+/// refused are counted in INFO stats.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Largest request frame accepted; bigger ones close the connection
+    pub max_request_size: usize,
+    /// Most arguments in a request
+    pub max_args: usize,
+    /// Longest key a command may name
+    pub max_key_len: usize,
+    /// Longest single argument in a request
+    pub max_value_len: usize,
+    /// Most members one command may add to a sorted set, 0 = no limit
+    pub max_zset_members: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_request_size: 64 * 1024 * 1024,
+            max_args: MAX_ARGS,
+            max_key_len: 512 * 1024 * 1024,
+            max_value_len: 512 * 1024 * 1024,
+            max_zset_members: 0,
+        }
+    }
+}
+
+impl Limits {
+    /// The part the request parsers enforce
+    pub fn frame_limits(&self) -> FrameLimits {
+        FrameLimits { max_request_size: self.max_request_size, max_args: self.max_args, max_arg_len: self.max_value_len }
+    }
+
+    /// The error for a command naming one of `keys`, if one is longer than max_key_len
+    pub fn check_keys(&self, keys: &[&str]) -> Result<(), String> {
+        match keys.iter().find(|key| key.len() > self.max_key_len) {
+            Some(key) => Err(format!("ERR key of {} bytes exceeds the {} byte limit", key.len(), self.max_key_len)),
+            None => Ok(()),
+        }
+    }
+
+    /// The error for `command` adding `n` sorted set members, if that's too many
+    pub fn check_zset_members(&self, command: &str, n: usize) -> Result<(), String> {
+        if self.max_zset_members > 0 && n > self.max_zset_members {
+            return Err(format!(
+                "ERR {} of {} members exceeds the limit of {} per command",
+                command.to_uppercase(),
+                n,
+                self.max_zset_members
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            timeout: 0,
+            limits: Limits::default(),
+            max_reply_size: 512 * 1024 * 1024,
+            tcp_keepalive: 300,
+            tcp_keepalive_interval: 100,
+            tcp_keepalive_probes: 3,
+            tcp_nodelay: true,
+            bind: None,
+            port: 1234,
+            dir: ".".to_string(),
+            daemonize: false,
+            pidfile: String::new(),
+            protected_mode: true,
+            maxclients: 10000,
+            max_open_files: 0,
+            client_rate_limit: 0,
+            loglevel: Level::Notice,
+            log_targets: String::new(),
+            logfile: String::new(),
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: Fsync::EverySec,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            aof_use_rdb_preamble: true,
+            storage_backend: Backend::Memory,
+            storage_log: "keyspace.log".to_string(),
+            storage_memory: 0,
+            repl_backlog_size: 1024 * 1024,
+            load_skip_corrupt: false,
+            lua_time_limit: 5000,
+            audit_log: String::new(),
+            audit_log_keys: "*".to_string(),
+            audit_log_categories: Vec::new(),
+            audit_log_max_size: 64 * 1024 * 1024,
+            audit_log_max_files: 5,
+            config_file: None,
+            rename_commands: Vec::new(),
+            cluster_enabled: false,
+            cluster_node_timeout: 15000,
+            zset_max_listpack_entries: 128,
+            zset_max_listpack_value: 64,
+            list_max_listpack_size: -2,
+            lookup_filter: false,
+            activerehashing: true,
+            maxmemory: 0,
+            maxmemory_policy: Policy::NoEviction,
+            maxmemory_samples: 5,
+            lfu_log_factor: 10,
+            lfu_decay_time: 1,
+        }
+    }
+}
+
+struct ConfigParam {
+    name: &'static str,
+    get: fn(&Config) -> String,
+    set: fn(&mut Config, &str) -> Result<(), String>,
+}
+
+static CONFIG_PARAMS: &[ConfigParam] = &[
+    ConfigParam {
+        name: "timeout",
+        get: |config| config.timeout.to_string(),
+        set: |config, value| {
+            config.timeout = parse_u64(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "max-request-size",
+        get: |config| config.limits.max_request_size.to_string(),
+        set: |config, value| {
+            config.limits.max_request_size = parse_message_size(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "proto-max-multibulk-len",
+        get: |config| config.limits.max_args.to_string(),
+        set: |config, value| {
+            config.limits.max_args = match parse_nonzero(value)? {
+                n if n > MAX_ARGS as u64 => return Err(format!("must be at most {}", MAX_ARGS)),
+                n => n as usize,
+            };
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "max-key-len",
+        get: |config| config.limits.max_key_len.to_string(),
+        set: |config, value| {
+            config.limits.max_key_len = parse_message_size(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "max-zset-members-per-command",
+        get: |config| config.limits.max_zset_members.to_string(),
+        set: |config, value| {
+            config.limits.max_zset_members = parse_u64(value)? as usize;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "max-reply-size",
+        get: |config| config.max_reply_size.to_string(),
+        set: |config, value| {
+            config.max_reply_size = parse_message_size(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "proto-max-bulk-len",
+        get: |config| config.limits.max_value_len.to_string(),
+        set: |config, value| {
+            config.limits.max_value_len = parse_message_size(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "tcp-keepalive",
+        get: |config| config.tcp_keepalive.to_string(),
+        set: |config, value| {
+            config.tcp_keepalive = parse_u64(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "tcp-keepalive-interval",
+        get: |config| config.tcp_keepalive_interval.to_string(),
+        set: |config, value| {
+            config.tcp_keepalive_interval = parse_nonzero(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "tcp-keepalive-probes",
+        get: |config| config.tcp_keepalive_probes.to_string(),
+        set: |config, value| {
+            config.tcp_keepalive_probes = u32::try_from(parse_nonzero(value)?)
+                .map_err(|_| format!("argument is out of range: '{}'", value))?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "tcp-nodelay",
+        get: |config| format_bool(config.tcp_nodelay),
+        set: |config, value| {
+            config.tcp_nodelay = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "bind",
+        get: |config| config.bind.map_or_else(|| "*".to_string(), |addr| addr.to_string()),
+        set: |config, value| {
+            config.bind = match value {
+                "*" => None,
+                addr => Some(addr.parse().map_err(|_| format!("'{}' is not an IP address or *", addr))?),
+            };
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "port",
+        get: |config| config.port.to_string(),
+        set: |config, value| {
+            config.port = value.parse().map_err(|_| format!("'{}' is not a valid port", value))?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "dir",
+        get: |config| config.dir.clone(),
+        set: |config, value| {
+            if !fs::metadata(value).is_ok_and(|meta| meta.is_dir()) {
+                return Err(format!("'{}' is not a directory", value));
+            }
+            config.dir = value.to_string();
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "daemonize",
+        get: |config| format_bool(config.daemonize),
+        set: |config, value| {
+            config.daemonize = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "pidfile",
+        get: |config| config.pidfile.clone(),
+        set: |config, value| {
+            config.pidfile = value.to_string();
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "protected-mode",
+        get: |config| format_bool(config.protected_mode),
+        set: |config, value| {
+            config.protected_mode = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "maxclients",
+        get: |config| config.maxclients.to_string(),
+        set: |config, value| {
+            config.maxclients = parse_nonzero(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "max-open-files",
+        get: |config| config.max_open_files.to_string(),
+        set: |config, value| {
+            config.max_open_files = parse_u64(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "client-rate-limit",
+        get: |config| config.client_rate_limit.to_string(),
+        set: |config, value| {
+            config.client_rate_limit = parse_u64(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "loglevel",
+        get: |config| config.loglevel.name().to_string(),
+        set: |config, value| {
+            config.loglevel = Level::parse(value)
+                .ok_or_else(|| format!("argument must be one of error, warning, notice, debug, trace: '{}'", value))?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "log-targets",
+        get: |config| config.log_targets.clone(),
+        set: |config, value| {
+            logging::parse_targets(value)?;
+            config.log_targets = value.to_string();
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "logfile",
+        get: |config| config.logfile.clone(),
+        set: |config, value| {
+            if !value.is_empty() {
+                logging::open_logfile(value).map_err(|e| format!("can't open '{}': {}", value, e))?;
+            }
+            config.logfile = value.to_string();
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "appendonly",
+        get: |config| format_bool(config.appendonly),
+        set: |config, value| {
+            config.appendonly = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "appendfilename",
+        get: |config| config.appendfilename.clone(),
+        set: |config, value| {
+            if value.is_empty() || value.contains('/') {
+                return Err(format!("must be a file name without a path: '{}'", value));
+            }
+            config.appendfilename = value.to_string();
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "appendfsync",
+        get: |config| config.appendfsync.name().to_string(),
+        set: |config, value| {
+            config.appendfsync = Fsync::parse(value)
+                .ok_or_else(|| format!("argument must be one of always, everysec, no: '{}'", value))?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "auto-aof-rewrite-percentage",
+        get: |config| config.auto_aof_rewrite_percentage.to_string(),
+        set: |config, value| {
+            config.auto_aof_rewrite_percentage = parse_u64(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "auto-aof-rewrite-min-size",
+        get: |config| config.auto_aof_rewrite_min_size.to_string(),
+        set: |config, value| {
+            config.auto_aof_rewrite_min_size = parse_memory(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "aof-use-rdb-preamble",
+        get: |config| format_bool(config.aof_use_rdb_preamble),
+        set: |config, value| {
+            config.aof_use_rdb_preamble = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "storage-backend",
+        get: |config| config.storage_backend.name().to_string(),
+        set: |config, value| {
+            config.storage_backend =
+                Backend::parse(value).ok_or_else(|| format!("argument must be one of memory, log: '{}'", value))?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "storage-log",
+        get: |config| config.storage_log.clone(),
+        set: |config, value| {
+            if value.is_empty() || value.contains('/') {
+                return Err(format!("must be a file name without a path: '{}'", value));
+            }
+            config.storage_log = value.to_string();
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "storage-memory",
+        get: |config| config.storage_memory.to_string(),
+        set: |config, value| {
+            config.storage_memory = parse_memory(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "repl-backlog-size",
+        get: |config| config.repl_backlog_size.to_string(),
+        set: |config, value| {
+            config.repl_backlog_size = parse_memory(value)?.max(1);
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "load-skip-corrupt",
+        get: |config| format_bool(config.load_skip_corrupt),
+        set: |config, value| {
+            config.load_skip_corrupt = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "lua-time-limit",
+        get: |config| config.lua_time_limit.to_string(),
+        set: |config, value| {
+            config.lua_time_limit = parse_u64(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "audit-log",
+        get: |config| config.audit_log.clone(),
+        set: |config, value| {
+            config.audit_log = value.to_string();
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "audit-log-keys",
+        get: |config| config.audit_log_keys.clone(),
+        set: |config, value| {
+            config.audit_log_keys = value.to_string();
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "audit-log-categories",
+        get: |config| config.audit_log_categories.join(","),
+        set: |config, value| {
+            let groups: Vec<String> =
+                value.split([',', ' ']).filter(|group| !group.is_empty()).map(str::to_lowercase).collect();
+            if let Some(group) = groups.iter().find(|group| !command::is_group(group)) {
+                return Err(format!("unknown command group '{}'", group));
+            }
+            config.audit_log_categories = groups;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "audit-log-max-size",
+        get: |config| config.audit_log_max_size.to_string(),
+        set: |config, value| {
+            config.audit_log_max_size = parse_memory(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "audit-log-max-files",
+        get: |config| config.audit_log_max_files.to_string(),
+        set: |config, value| {
+            config.audit_log_max_files = parse_u64(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "cluster-enabled",
+        get: |config| format_bool(config.cluster_enabled),
+        set: |config, value| {
+            config.cluster_enabled = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "cluster-node-timeout",
+        get: |config| config.cluster_node_timeout.to_string(),
+        set: |config, value| {
+            config.cluster_node_timeout = parse_nonzero(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "zset-max-listpack-entries",
+        get: |config| config.zset_max_listpack_entries.to_string(),
+        set: |config, value| {
+            config.zset_max_listpack_entries = parse_u64(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "zset-max-listpack-value",
+        get: |config| config.zset_max_listpack_value.to_string(),
+        set: |config, value| {
+            config.zset_max_listpack_value = parse_u64(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "list-max-listpack-size",
+        get: |config| config.list_max_listpack_size.to_string(),
+        set: |config, value| {
+            config.list_max_listpack_size = match value.parse::<i64>() {
+                Ok(size) if size != 0 && size >= -5 => size,
+                _ => return Err(format!("argument must be a positive count or -1 to -5: '{}'", value)),
+            };
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "maxmemory",
+        get: |config| config.maxmemory.to_string(),
+        set: |config, value| {
+            config.maxmemory = parse_memory(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "maxmemory-policy",
+        get: |config| config.maxmemory_policy.name().to_string(),
+        set: |config, value| {
+            config.maxmemory_policy = Policy::parse(value).ok_or_else(|| {
+                let names = "noeviction, allkeys-lru, volatile-lru, allkeys-lfu, volatile-lfu, allkeys-random, volatile-random, volatile-ttl";
+                format!("argument must be one of {}: '{}'", names, value)
+            })?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "maxmemory-samples",
+        get: |config| config.maxmemory_samples.to_string(),
+        set: |config, value| {
+            config.maxmemory_samples = parse_nonzero(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "lfu-log-factor",
+        get: |config| config.lfu_log_factor.to_string(),
+        set: |config, value| {
+            config.lfu_log_factor = parse_u64(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "lfu-decay-time",
+        get: |config| config.lfu_decay_time.to_string(),
+        set: |config, value| {
+            config.lfu_decay_time = parse_u64(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "lookup-filter",
+        get: |config| format_bool(config.lookup_filter),
+        set: |config, value| {
+            config.lookup_filter = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    ConfigParam {
+        name: "activerehashing",
+        get: |config| format_bool(config.activerehashing),
+        set: |config, value| {
+            config.activerehashing = parse_bool(value)?;
+            Ok(())
+        },
+    },
+];
+
+fn parse_u64(value: &str) -> Result<u64, String> {
+    value.parse().map_err(|_| format!("argument couldn't be parsed into an integer: '{}'", value))
+}
+
+fn parse_nonzero(value: &str) -> Result<u64, String> {
+    match parse_u64(value)? {
+        0 => Err("must be greater than 0".to_string()),
+        n => Ok(n),
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("argument must be 'yes' or 'no': '{}'", value)),
+    }
+}
+
+fn format_bool(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+/// Parse a byte count with an optional unit as Redis does:
+/// k/m/g are powers of 1000, kb/mb/gb powers of 1024
+fn parse_memory(value: &str) -> Result<u64, String> {
+    let lower = value.to_ascii_lowercase();
+    let split = lower.find(|c: char| !c.is_ascii_digit()).unwrap_or(lower.len());
+    let (digits, unit) = lower.split_at(split);
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("argument must be a memory value: '{}'", value)),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("argument must be a memory value: '{}'", value))
+}
+
+// Frames carry a u32 length, so that's the hard ceiling
+fn parse_message_size(value: &str) -> Result<usize, String> {
+    match parse_memory(value)? {
+        0 => Err("must be greater than 0".to_string()),
+        n if n > u32::MAX as u64 => Err(format!("must be at most {} bytes", u32::MAX)),
+        n => Ok(n as usize),
+    }
+}
+
+// "name value" on its own line; an empty value leaves just the name
+fn push_directive(text: &mut String, name: &str, value: &str) {
+    text.push_str(format!("{} {}", name, value).trim_end());
+    text.push('\n');
+}
+
+// Parameters read once at startup; reload leaves them alone
+const RESTART_ONLY: &[&str] =
+    &["bind", "port", "dir", "daemonize", "pidfile", "cluster-enabled", "storage-backend", "storage-log", "storage-memory"];
+
+fn find_param(name: &str) -> Option<&'static ConfigParam> {
+    CONFIG_PARAMS.iter().find(|param| param.name.eq_ignore_ascii_case(name))
+}
+
+impl Config {
+    /// Set one parameter by name
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let param = find_param(name).ok_or_else(|| format!("unknown option '{}'", name))?;
+        (param.set)(self, value).map_err(|e| format!("invalid value for '{}': {}", param.name, e))
+    }
+
+    // A startup directive: a parameter, or one only allowed at startup
+    fn apply_directive(&mut self, name: &str, value: &str) -> Result<(), String> {
+        if !name.eq_ignore_ascii_case("rename-command") {
+            return self.set(name, value);
+        }
+        let mut words = value.split_whitespace();
+        let (Some(old), new, None) = (words.next(), words.next(), words.next()) else {
+            return Err("rename-command takes the command and its new name".to_string());
+        };
+        if command::lookup_original(old).is_none() {
+            return Err(format!("rename-command: no such command '{}'", old));
+        }
+        let new = match new {
+            None | Some("\"\"") => "",
+            Some(new) => new,
+        };
+        self.rename_commands.push((old.to_string(), new.to_string()));
+        Ok(())
+    }
+
+    fn apply_file(&mut self, path: &str) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("can't open config file '{}': {}", path, e))?;
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            self.apply_directive(name, value.trim())
+                .map_err(|e| format!("{}:{}: {}", path, lineno + 1, e))?;
+        }
+        Ok(())
+    }
+
+    /// Build the startup config from the command line: an optional config
+    /// file followed by `--name value` overrides
+    pub fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut args = args.iter().peekable();
+
+        if let Some(path) = args.next_if(|arg| !arg.starts_with("--")) {
+            config.apply_file(path)?;
+            // Absolute, so a later change of working directory doesn't matter
+            let path = fs::canonicalize(path).map_or_else(|_| path.clone(), |p| p.to_string_lossy().into_owned());
+            config.config_file = Some(path);
+        }
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                return Err(format!("unexpected argument '{}'", arg));
+            };
+            let value = args.next().ok_or_else(|| format!("missing value for '--{}'", name))?;
+            config.apply_directive(name, value)?;
+        }
+        Ok(config)
+    }
+
+    /// Whether protected mode turns away a client connecting from `peer`.
+    /// There's no password to require yet, so listening on anything but
+    /// loopback is enough to need it.
+    pub fn denies_peer(&self, peer: IpAddr) -> bool {
+        self.protected_mode && !self.bind.is_some_and(|addr| addr.is_loopback()) && !peer.to_canonical().is_loopback()
+    }
+
+    /// The config file's text with the current values written in
+    fn rewritten(&self, old_text: &str) -> String {
+        const GENERATED: &str = "# Generated by CONFIG REWRITE";
+        let defaults = Config::default();
+        let mut written = Vec::new();
+        let mut text = String::new();
+        let mut has_marker = false;
+
+        for line in old_text.lines() {
+            let trimmed = line.trim();
+            let name = trimmed.split(char::is_whitespace).next().unwrap_or("");
+            match find_param(name).filter(|_| !trimmed.starts_with('#')) {
+                Some(param) if written.contains(&param.name) => continue,
+                Some(param) => {
+                    push_directive(&mut text, param.name, &(param.get)(self));
+                    written.push(param.name);
+                }
+                None => {
+                    has_marker |= trimmed == GENERATED;
+                    text.push_str(line);
+                    text.push('\n');
+                }
+            }
+        }
+
+        // Appended directives go under the marker from an earlier rewrite
+        let mut added = has_marker;
+        for param in CONFIG_PARAMS.iter().filter(|param| !written.contains(&param.name)) {
+            let value = (param.get)(self);
+            if value == (param.get)(&defaults) {
+                continue;
+            }
+            if !added {
+                text.push_str(GENERATED);
+                text.push('\n');
+                added = true;
+            }
+            push_directive(&mut text, param.name, &value);
+        }
+        text
+    }
+
+    /// Write the current values back to the config file, replacing it atomically
+    pub fn rewrite_file(&self) -> Result<(), String> {
+        let path = self.config_file.as_deref().ok_or("The server is running without a config file")?;
+        let old_text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("can't read '{}': {}", path, e)),
+        };
+
+        let tmp_path = format!("{}.tmp-{}", path, std::process::id());
+        let write = || -> std::io::Result<()> {
+            let mut file = fs::File::create(&tmp_path)?;
+            file.write_all(self.rewritten(&old_text).as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, path)
+        };
+        write().map_err(|e| {
+            let _ = fs::remove_file(&tmp_path);
+            format!("can't write '{}': {}", path, e)
+        })
+    }
+}
+
+/// Read the config file again and apply what changed, as asked by SIGHUP
+pub fn reload() {
+    let old = with_global_data(|g_data| g_data.config.clone());
+    let Some(path) = old.config_file.clone() else {
+        log_warning!("server", "SIGHUP received, but the server is running without a config file");
+        return;
+    };
+
+    let mut config = old.clone();
+    config.rename_commands.clear();
+    if let Err(e) = config.apply_file(&path) {
+        log_warning!("server", "Config reload failed, keeping the running configuration: {}", e);
+        return;
+    }
+
+    for param in RESTART_ONLY.iter().filter_map(|name| find_param(name)) {
+        let (running, wanted) = ((param.get)(&old), (param.get)(&config));
+        if running != wanted {
+            log_warning!(
+                "server",
+                "Config reload: '{}' can't change without a restart, keeping {} (the file has {})",
+                param.name, running, wanted
+            );
+            (param.set)(&mut config, &running).expect("the running value is valid");
+        }
+    }
+    if config.rename_commands != old.rename_commands {
+        log_warning!("server", "Config reload: rename-command can't change without a restart");
+    }
+    config.rename_commands = old.rename_commands.clone();
+
+    let changed: Vec<String> = CONFIG_PARAMS
+        .iter()
+        .filter(|param| (param.get)(&config) != (param.get)(&old))
+        .map(|param| format!("{} {}", param.name, (param.get)(&config)))
+        .collect();
+    with_global_data(|g_data| {
+        g_data.config = config;
+        logging::configure(&g_data.config);
+    });
+    if changed.is_empty() {
+        log_notice!("server", "Config reloaded from {}, nothing changed", path);
+    } else {
+        log_notice!("server", "Config reloaded from {}: {}", path, changed.join(", "));
+    }
+}
+
+// CONFIG GET pattern [pattern ...] | CONFIG SET name value [name value ...] | CONFIG RESETSTAT | CONFIG REWRITE
+pub fn do_config(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 2 {
+        out_err(out, "CONFIG requires: GET|SET|RESETSTAT|REWRITE ...");
+        return Ok(());
+    }
+
+    match cmd[1].to_uppercase().as_str() {
+        "GET" if cmd.len() > 2 => with_global_data(|g_data| {
+            let matches: Vec<&ConfigParam> = CONFIG_PARAMS
+                .iter()
+                .filter(|param| cmd[2..].iter().any(|pattern| glob_match(pattern, param.name, true)))
+                .collect();
+            out_arr(out, matches.len() as u32 * 2);
+            for param in matches {
+                out_str(out, param.name);
+                out_str(out, &(param.get)(&g_data.config));
+            }
+        }),
+        "SET" if cmd.len() > 2 && cmd.len().is_multiple_of(2) => with_global_data(|g_data| {
+            // All or nothing: apply to a copy and only keep it if every pair is valid
+            let mut config = g_data.config.clone();
+            for pair in cmd[2..].chunks(2) {
+                if let Some(param) = find_param(&pair[0]).filter(|param| RESTART_ONLY.contains(&param.name)) {
+                    out_err(out, &format!("ERR CONFIG SET failed: '{}' can't change without a restart", param.name));
+                    return;
+                }
+                if let Err(e) = config.set(&pair[0], &pair[1]) {
+                    out_err(out, &format!("ERR CONFIG SET failed: {}", e));
+                    return;
+                }
+            }
+            g_data.config = config;
+            logging::configure(&g_data.config);
+            out_nil(out);
+        }),
+        "RESETSTAT" if cmd.len() == 2 => with_global_data(|g_data| {
+            g_data.stats = ServerStats::default();
+            g_data.db.table().reset_lookup_stats();
+            g_data.command_stats.reset();
+            out_str(out, "OK");
+        }),
+        "REWRITE" if cmd.len() == 2 => {
+            // Don't hold the lock across the file I/O
+            let config = with_global_data(|g_data| g_data.config.clone());
+            match config.rewrite_file() {
+                Ok(()) => out_nil(out),
+                Err(e) => out_err(out, &format!("ERR Rewriting config file: {}", e)),
+            }
+        }
+        _ => out_err(out, "ERR unknown CONFIG subcommand or wrong number of arguments"),
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use redis::protocol::RedisValue;
+
+    use crate::sim::{testing::shared, Simulation};
+
+    // The value CONFIG GET reports for one parameter
+    fn get(sim: &mut Simulation, name: &str) -> String {
+        match sim.call("config", &format!("CONFIG GET {}", name)) {
+            RedisValue::Arr(pair) => match &pair[1] {
+                RedisValue::Str(value) => value.clone(),
+                value => panic!("{:?}", value),
+            },
+            reply => panic!("{:?}", reply),
+        }
+    }
+
+    // Startup-only parameters are refused, so CONFIG REWRITE can't write out
+    // a value the server never used
+    #[test]
+    fn set_refuses_restart_only_parameters() {
+        let mut sim = shared();
+        let port = get(&mut sim, "port");
+        for line in ["CONFIG SET port 7777", "CONFIG SET DIR /tmp", "CONFIG SET storage-backend log"] {
+            match sim.call("config", line) {
+                RedisValue::Err(e) => assert!(e.contains("can't change without a restart"), "{}", e),
+                reply => panic!("{}: {:?}", line, reply),
+            }
+        }
+        assert_eq!(get(&mut sim, "port"), port);
+    }
+
+    // One refused pair fails the whole CONFIG SET
+    #[test]
+    fn set_with_a_restart_only_parameter_changes_nothing() {
+        let mut sim = shared();
+        let samples = get(&mut sim, "maxmemory-samples");
+        let reply = sim.call("config", "CONFIG SET maxmemory-samples 9 port 7777");
+        assert!(matches!(reply, RedisValue::Err(_)), "{:?}", reply);
+        assert_eq!(get(&mut sim, "maxmemory-samples"), samples);
+
+        assert_eq!(sim.call("config", "CONFIG SET maxmemory-samples 9"), RedisValue::Nil);
+        assert_eq!(get(&mut sim, "maxmemory-samples"), "9");
+        sim.call("config", &format!("CONFIG SET maxmemory-samples {}", samples));
+    }
+}
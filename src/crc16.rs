@@ -0,0 +1,31 @@
+/* CRC-16 */
+//
+// The CRC-16/XMODEM variant Redis Cluster hashes keys to slots with:
+// polynomial 0x1021, zero initial value, not reflected, no final xor.
+// crc16(b"123456789") == 0x31c3.
+
+const POLY: u16 = 0x1021;
+
+const TABLE: [u16; 256] = {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ POLY } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc = TABLE[((crc >> 8) as u8 ^ byte) as usize] ^ (crc << 8);
+    }
+    crc
+}
@@ -0,0 +1,114 @@
+/* Daemonizing */
+//
+// With `daemonize yes` the server forks into the background at startup, as
+// Redis does: the parent exits, the child starts a new session and gets
+// /dev/null for stdin. stdout and stderr go to the logfile if there is one,
+// so a panic still leaves a trace, and to /dev/null otherwise.
+//
+// The pidfile holds the server's pid while it runs. A daemonized server
+// writes one even if none is configured, DEFAULT_PIDFILE; it is removed again
+// on SHUTDOWN, SIGTERM or SIGINT.
+//
+// The server works in `dir`, where the snapshot, the AOF and their temporary
+// files live. LOCK_FILENAME there is flock()ed for as long as the server
+// runs and holds its pid, so a second server started on the same directory
+// refuses to start, naming the first, instead of both writing the same files.
+// The lock goes away with the process, however it ends; the file stays.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::sync::OnceLock;
+
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use nix::unistd::{dup2, fork, setsid, ForkResult};
+
+use crate::config::Config;
+use crate::logging;
+
+pub const DEFAULT_PIDFILE: &str = "/var/run/redis.pid";
+
+pub const LOCK_FILENAME: &str = "redis.lock";
+
+// The pidfile written at startup; the pidfile parameter may change later
+static PIDFILE: OnceLock<String> = OnceLock::new();
+
+// Open for as long as the server runs, which keeps the lock
+static DIR_LOCK: OnceLock<File> = OnceLock::new();
+
+/// Lock the data directory, by now the working directory, against other
+/// servers
+pub fn lock_dir() -> Result<(), String> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(LOCK_FILENAME)
+        .map_err(|e| format!("can't open {}: {}", LOCK_FILENAME, e))?;
+    match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => {}
+        Err(Errno::EWOULDBLOCK) => {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(format!("the data directory is in use by another server (pid {})", pid.trim()));
+        }
+        Err(e) => return Err(format!("can't lock {}: {}", LOCK_FILENAME, e)),
+    }
+    let _ = DIR_LOCK.set(file);
+    record_lock_owner().map_err(|e| format!("can't write {}: {}", LOCK_FILENAME, e))
+}
+
+/// Write our pid into the lock file; again after daemonize, which changes it
+pub fn record_lock_owner() -> io::Result<()> {
+    let Some(mut file) = DIR_LOCK.get() else {
+        return Ok(());
+    };
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    writeln!(file, "{}", std::process::id())
+}
+
+/// Fork into the background. Only the child returns; this must happen before
+/// any thread is started, as only the calling thread survives a fork.
+pub fn daemonize(config: &Config) -> io::Result<()> {
+    // SAFETY: no other thread runs yet
+    match unsafe { fork() }.map_err(io::Error::from)? {
+        ForkResult::Parent { .. } => std::process::exit(0),
+        ForkResult::Child => {}
+    }
+    setsid().map_err(io::Error::from)?;
+
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    let output = match config.logfile.as_str() {
+        "" => null.try_clone()?,
+        path => logging::open_logfile(path)?,
+    };
+    dup2(null.as_raw_fd(), 0).map_err(io::Error::from)?;
+    for fd in [1, 2] {
+        dup2(output.as_raw_fd(), fd).map_err(io::Error::from)?;
+    }
+    Ok(())
+}
+
+/// Write our pid to the configured pidfile, or the default one when
+/// daemonized; nothing to do otherwise
+pub fn write_pidfile(config: &Config) -> io::Result<()> {
+    let path = match config.pidfile.as_str() {
+        "" if config.daemonize => DEFAULT_PIDFILE,
+        "" => return Ok(()),
+        path => path,
+    };
+    let mut file = File::create(path)?;
+    writeln!(file, "{}", std::process::id())?;
+    let _ = PIDFILE.set(path.to_string());
+    Ok(())
+}
+
+/// Remove the pidfile written at startup, if any
+pub fn remove_pidfile() {
+    if let Some(path) = PIDFILE.get() {
+        let _ = fs::remove_file(path);
+    }
+}
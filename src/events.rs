@@ -0,0 +1,80 @@
+/* Server events */
+//
+// What happens to keys and clients, and every command run, is emitted as a
+// ServerEvent to the listeners in LISTENERS, each of them a subsystem that
+// reacts to it:
+//
+//   KeyModified         a key written or deleted, or evicted
+//   KeyExpired          a key deleted as its TTL passed
+//   ClientConnected     a connection accepted, with its client id
+//   ClientDisconnected  a client gone, closed by either side or killed
+//   CommandExecuted     a command ran, with the reply it wrote (empty when
+//                       it blocked) and the time it took
+//
+// so tracking invalidation, propagation to the AOF and the replicas, the
+// audit log and the statistics are fed from one place instead of calls
+// spread over the handlers. A command that writes also has its keys reported
+// by KeyModified where the keyspace changes, which for tracking is the same
+// invalidation twice. Listeners are run in order, under the lock the event
+// was emitted with, so they get the global data and mustn't take it again.
+//
+// Most listeners change the global data and need it exclusively. Those in
+// SHARED_LISTENERS only need to look at it, and keep what they record
+// behind a lock of their own; an event only they care about, a command that
+// changed nothing run while no client tracks its reads, is emitted with
+// emit_shared under the shared lock, so read-only commands don't take turns.
+
+use std::os::unix::io::RawFd;
+
+use crate::command::{Command, CMD_WRITE};
+use crate::{aof, audit, clients, cluster, info, latency, replication, tracking, GData};
+
+pub enum ServerEvent<'a> {
+    KeyModified { key: &'a str },
+    KeyExpired { key: &'a str },
+    ClientConnected { fd: RawFd, id: u64, addr: &'a str },
+    ClientDisconnected { fd: RawFd },
+    CommandExecuted { command: &'a Command, args: &'a [String], reply: &'a [u8], usec: u64, failed: bool },
+}
+
+pub type Listener = fn(&mut GData, &ServerEvent);
+pub type SharedListener = fn(&GData, &ServerEvent);
+
+/// Listeners that only need shared access, told first
+static SHARED_LISTENERS: &[SharedListener] = &[latency::on_event];
+
+/// Everything else that listens, in the order it's told
+static LISTENERS: &[Listener] = &[
+    clients::on_event,
+    info::on_event,
+    tracking::on_event,
+    cluster::on_event,
+    aof::on_event,
+    replication::on_event,
+    audit::on_event,
+];
+
+/// Tell every listener about `event`
+pub fn emit(g_data: &mut GData, event: &ServerEvent) {
+    for listener in SHARED_LISTENERS {
+        listener(g_data, event);
+    }
+    for listener in LISTENERS {
+        listener(g_data, event);
+    }
+}
+
+/// Tell the shared listeners about `event` if it's of no concern to the
+/// others; false, telling nobody, if emit is needed
+pub fn emit_shared(g_data: &GData, event: &ServerEvent) -> bool {
+    let ServerEvent::CommandExecuted { command, args, .. } = *event else {
+        return false;
+    };
+    if command.has_flag(CMD_WRITE) || aof::should_log(command, args) || g_data.tracking.tracks_reads() {
+        return false;
+    }
+    for listener in SHARED_LISTENERS {
+        listener(g_data, event);
+    }
+    true
+}
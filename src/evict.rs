@@ -0,0 +1,311 @@
+/* Eviction */
+//
+// With maxmemory set, every command first makes room: while used_memory()
+// is over the limit, keys are evicted as maxmemory-policy says.
+//
+//   noeviction       evict nothing
+//   allkeys-lru      the key idle the longest, among all keys
+//   volatile-lru     ... among the keys with a TTL
+//   allkeys-lfu      the key used the least often, among all keys
+//   volatile-lfu     ... among the keys with a TTL
+//   allkeys-random   any key
+//   volatile-random  any key with a TTL
+//   volatile-ttl     the key with the nearest TTL
+//
+// As in Redis the choice is approximate, and made the same way whatever the
+// policy: each eviction samples maxmemory-samples keys from a random point
+// of the keyspace and offers them to the Pool, which keeps the POOL_SIZE
+// best candidates seen so far, ranked by the policy's score (idle time,
+// 255 minus the LFU counter, how soon the TTL is due, or a random number).
+// The best one still in the db is evicted. As the pool outlives one
+// eviction, a good candidate that wasn't the best in its sample isn't
+// forgotten, and more samples make the choice closer to exact at the cost
+// of more CPU. used_memory() is read once per command, walking every key
+// unless the server runs on jemalloc; it is then lowered by each evicted
+// key's estimate. When nothing more can be evicted (noeviction, or no key
+// with a TTL left for a volatile policy), commands that add data fail with
+// OOM and the others still run. Evictions are writes: none happen while
+// clients are paused, and each goes to the AOF and replicas as a DEL.
+//
+// Under an LFU policy each key has an access counter, 8 bits wide and
+// logarithmic: an access increments a counter c with probability
+// 1 / ((c - LFU_INIT_VAL) * lfu-log-factor + 1), so with the default factor
+// of 10 it takes about a million accesses to reach 255. A new key starts at
+// LFU_INIT_VAL, so it isn't the first to go before it had a chance to be
+// used. The counter decays by one every lfu-decay-time minutes: next to it
+// the key keeps the minute it last decayed, on the coarse clock the event
+// loop ticks, and any read of the counter first takes off the periods
+// elapsed since. OBJECT FREQ reports it.
+
+use std::cell::Cell;
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use redis::protocol::RedisValue;
+
+use crate::command::{self, Command, CMD_DENYOOM};
+use crate::config::Config;
+use crate::events::{self, ServerEvent};
+use crate::memory::{self, MemoryUsage};
+use crate::{aof, entry_discard, log_debug, lru_clock, replication, with_global_data, with_global_data_read, Entry, GData};
+
+/// Candidates the pool keeps between evictions
+const POOL_SIZE: usize = 16;
+
+/// Counter of a new key
+const LFU_INIT_VAL: u8 = 5;
+
+pub const OOM_ERROR: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    NoEviction,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysLfu,
+    VolatileLfu,
+    AllKeysRandom,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl Policy {
+    pub fn parse(s: &str) -> Option<Policy> {
+        match s.to_lowercase().as_str() {
+            "noeviction" => Some(Policy::NoEviction),
+            "allkeys-lru" => Some(Policy::AllKeysLru),
+            "volatile-lru" => Some(Policy::VolatileLru),
+            "allkeys-lfu" => Some(Policy::AllKeysLfu),
+            "volatile-lfu" => Some(Policy::VolatileLfu),
+            "allkeys-random" => Some(Policy::AllKeysRandom),
+            "volatile-random" => Some(Policy::VolatileRandom),
+            "volatile-ttl" => Some(Policy::VolatileTtl),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Policy::NoEviction => "noeviction",
+            Policy::AllKeysLru => "allkeys-lru",
+            Policy::VolatileLru => "volatile-lru",
+            Policy::AllKeysLfu => "allkeys-lfu",
+            Policy::VolatileLfu => "volatile-lfu",
+            Policy::AllKeysRandom => "allkeys-random",
+            Policy::VolatileRandom => "volatile-random",
+            Policy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    pub fn is_lfu(self) -> bool {
+        matches!(self, Policy::AllKeysLfu | Policy::VolatileLfu)
+    }
+
+    fn volatile_only(self) -> bool {
+        matches!(self, Policy::VolatileLru | Policy::VolatileLfu | Policy::VolatileRandom | Policy::VolatileTtl)
+    }
+
+    // How much better it is to evict `entry` than a key scoring lower
+    fn score(self, entry: &Entry) -> u64 {
+        match self {
+            Policy::NoEviction => 0,
+            Policy::AllKeysLru | Policy::VolatileLru => entry.idle_secs() as u64,
+            Policy::AllKeysLfu | Policy::VolatileLfu => (u8::MAX - entry.freq()) as u64,
+            Policy::AllKeysRandom | Policy::VolatileRandom => (random_unit() * u64::MAX as f64) as u64,
+            Policy::VolatileTtl => u64::MAX - entry.ttl_deadline.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The best eviction candidates sampled so far, kept from one eviction to
+/// the next
+#[derive(Debug, Default)]
+pub struct Pool {
+    // Ascending by score: the best is last
+    candidates: Vec<Candidate>,
+    // The policy the scores are for
+    policy: Option<Policy>,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    score: u64,
+    key: String,
+    // Of the entry sampled, so a later key of the same name isn't taken for it
+    generation: u64,
+}
+
+impl Pool {
+    // Keep `entry` if it's among the POOL_SIZE best seen
+    fn offer(&mut self, score: u64, entry: &Entry) {
+        if self.candidates.iter().any(|candidate| candidate.key == entry.key) {
+            return;
+        }
+        if self.candidates.len() == POOL_SIZE && self.candidates[0].score >= score {
+            return;
+        }
+        let at = self.candidates.partition_point(|candidate| candidate.score < score);
+        let candidate = Candidate { score, key: entry.key.clone(), generation: entry.generation };
+        self.candidates.insert(at, candidate);
+        if self.candidates.len() > POOL_SIZE {
+            self.candidates.remove(0);
+        }
+    }
+}
+
+// The LFU parameters, for accesses to read without the global lock
+static LFU_ENABLED: AtomicBool = AtomicBool::new(false);
+static LFU_LOG_FACTOR: AtomicU32 = AtomicU32::new(10);
+static LFU_DECAY_TIME: AtomicU32 = AtomicU32::new(1);
+
+/// Apply the LFU parameters of `config`
+pub fn configure(config: &Config) {
+    LFU_ENABLED.store(config.maxmemory_policy.is_lfu(), Ordering::Relaxed);
+    LFU_LOG_FACTOR.store(config.lfu_log_factor.min(u32::MAX as u64) as u32, Ordering::Relaxed);
+    LFU_DECAY_TIME.store(config.lfu_decay_time.min(u32::MAX as u64) as u32, Ordering::Relaxed);
+}
+
+/// Whether accesses count towards the LFU counters
+pub fn lfu_enabled() -> bool {
+    LFU_ENABLED.load(Ordering::Relaxed)
+}
+
+// Minutes on the coarse clock, as 16 bits
+fn lfu_minutes() -> u32 {
+    (lru_clock() / 60) & 0xffff
+}
+
+/// The LFU state of a new key: the minute it last decayed (now) in the
+/// high bits, the counter in the low 8
+pub fn lfu_init() -> u32 {
+    (lfu_minutes() << 8) | LFU_INIT_VAL as u32
+}
+
+/// The counter of LFU state `lfu`, after the decay due by now
+pub fn lfu_counter(lfu: u32) -> u8 {
+    let counter = (lfu & 0xff) as u8;
+    let decay_time = LFU_DECAY_TIME.load(Ordering::Relaxed);
+    if decay_time == 0 {
+        return counter;
+    }
+    let elapsed = lfu_minutes().wrapping_sub(lfu >> 8) & 0xffff;
+    counter.saturating_sub((elapsed / decay_time).min(u8::MAX as u32) as u8)
+}
+
+/// LFU state `lfu` after one more access
+pub fn lfu_access(lfu: u32) -> u32 {
+    let mut counter = lfu_counter(lfu);
+    if counter < u8::MAX {
+        let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+        let p = 1.0 / (base * LFU_LOG_FACTOR.load(Ordering::Relaxed) as f64 + 1.0);
+        if random_unit() < p {
+            counter += 1;
+        }
+    }
+    (lfu_minutes() << 8) | counter as u32
+}
+
+thread_local! {
+    static RNG: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+}
+
+// Uniform in [0, 1), by xorshift64
+fn random_unit() -> f64 {
+    RNG.with(|rng| {
+        let mut x = rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        rng.set(x);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+/// Before running `command`: evict keys until memory use is under
+/// maxmemory. Err(OOM_ERROR) if it stays over and the command would add data.
+pub fn make_room(command: &Command) -> Result<(), &'static str> {
+    // Under the limit, the usual case, is seen under the shared lock
+    let under = with_global_data_read(|g_data| {
+        let maxmemory = g_data.config.maxmemory as usize;
+        maxmemory == 0 || memory::used_memory(g_data) <= maxmemory
+    });
+    if under {
+        return Ok(());
+    }
+    let over = with_global_data(|g_data| {
+        let maxmemory = g_data.config.maxmemory as usize;
+        if maxmemory == 0 {
+            return false;
+        }
+        let mut used = memory::used_memory(g_data);
+        while used > maxmemory && !g_data.pause.is_active() && !g_data.aof.is_loading() {
+            let Some(key) = pick(g_data) else {
+                break;
+            };
+            // Always there: the sample leaves out keys past their TTL
+            let Some(entry) = g_data.take_entry(&key) else {
+                continue;
+            };
+            used = used.saturating_sub(entry.memory_usage());
+            entry_discard(g_data, entry);
+            g_data.stats.evicted_keys += 1;
+            events::emit(g_data, &ServerEvent::KeyModified { key: &key });
+            log_debug!("evict", "Evicted key {}", key);
+            if let Some(del) = command::lookup_original("del") {
+                let args = ["DEL".to_string(), key];
+                let mut reply = Vec::new();
+                RedisValue::Int(1).encode(&mut reply);
+                aof::feed(g_data, del, &args, &reply);
+                replication::feed(g_data, del, &args, &reply);
+            }
+        }
+        used > maxmemory
+    });
+
+    if over && command.has_flag(CMD_DENYOOM) {
+        return Err(OOM_ERROR);
+    }
+    Ok(())
+}
+
+// The key to evict next, if the policy allows one
+fn pick(g_data: &mut GData) -> Option<String> {
+    let policy = g_data.config.maxmemory_policy;
+    if policy == Policy::NoEviction || (policy.volatile_only() && g_data.expires.len() == 0) {
+        return None;
+    }
+    let (pool, db) = (&mut g_data.eviction_pool, &g_data.db);
+    if pool.policy != Some(policy) {
+        // Scores of another policy don't compare
+        pool.candidates.clear();
+        pool.policy = Some(policy);
+    }
+
+    let samples = g_data.config.maxmemory_samples as usize;
+    let mut sampled = 0;
+    let mut cursor = RandomState::new().build_hasher().finish();
+    // A step visits a bucket at least; give up on a keyspace mostly without
+    // TTLs rather than walk all of it
+    for _ in 0..samples * 16 {
+        cursor = db.scan(cursor, &mut |entry| {
+            if policy.volatile_only() && !entry.has_ttl() {
+                return;
+            }
+            sampled += 1;
+            pool.offer(policy.score(entry), entry);
+        });
+        if sampled >= samples {
+            break;
+        }
+    }
+
+    // Candidates deleted, replaced or (for a volatile policy) persisted
+    // since they were sampled are dropped
+    while let Some(candidate) = pool.candidates.pop() {
+        let current = db.peek_entry(&candidate.key).filter(|entry| entry.generation == candidate.generation);
+        if current.is_some_and(|entry| entry.has_ttl() || !policy.volatile_only()) {
+            return Some(candidate.key);
+        }
+    }
+    None
+}
@@ -0,0 +1,84 @@
+/* Key expiry */
+//
+// A key's TTL is kept as the unix time in ms it expires at. That's what the
+// snapshot, the AOF, DUMP/MIGRATE and replicas carry, so a TTL means the
+// same after a restart or on another machine, and reading one back gives
+// exactly what was set.
+//
+// Finding the keys that are due is the fast path, and it runs on the
+// monotonic clock instead: each TTL also has a timer in a wheel whose
+// deadline is the unix time translated once, when the TTL is set. The event
+// loop then only compares integers, and a wall clock stepped while the
+// server runs neither fires a burst of keys nor holds them back.
+//
+// A TTL also records the generation of the entry it was set on. Keys are
+// names, and a name can be deleted and created again; the event loop only
+// expires the entry a TTL was set for, so a TTL left behind by the old one
+// can never take the new one with it.
+//
+// The deadline is copied onto the entry as well, so reads check it without
+// this table: a key is gone for clients from its deadline on, even when the
+// event loop hasn't got to deleting it yet (see HMap::peek_entry), and a
+// write to it starts from a missing key.
+
+use std::collections::HashMap;
+
+use crate::timer::TimerWheel;
+use crate::{get_current_time_ms, get_monotonic_time_ms};
+
+#[derive(Debug, Default)]
+pub struct Expires {
+    // key -> (unix ms, generation of its entry)
+    at: HashMap<String, (u64, u64)>,
+    // key -> monotonic ms
+    timers: TimerWheel<String>,
+}
+
+impl Expires {
+    pub fn len(&self) -> usize {
+        self.at.len()
+    }
+
+    /// The unix time in ms `key` expires at, None without a TTL
+    pub fn get(&self, key: &str) -> Option<u64> {
+        self.at.get(key).map(|&(at_ms, _)| at_ms)
+    }
+
+    /// Have `key`, whose entry is of `generation`, expire at the unix time
+    /// `at_ms`, replacing any TTL it had. Returns the monotonic deadline.
+    pub fn set(&mut self, key: String, at_ms: u64, generation: u64) -> u64 {
+        let deadline = get_monotonic_time_ms().saturating_add(at_ms.saturating_sub(get_current_time_ms()));
+        self.timers.insert(key.clone(), deadline);
+        self.at.insert(key, (at_ms, generation));
+        deadline
+    }
+
+    /// Drop the TTL of `key`; the unix time it had, if any
+    pub fn remove(&mut self, key: &str) -> Option<u64> {
+        let (at_ms, _) = self.at.remove(key)?;
+        self.timers.remove(&key.to_string());
+        Some(at_ms)
+    }
+
+    /// The monotonic time the next key is due, None without TTLs
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.timers.next_deadline()
+    }
+
+    /// Take out up to `max` keys due by the monotonic time `now_ms`, with
+    /// the generation of the entry each TTL was set on
+    pub fn expire(&mut self, now_ms: u64, max: usize) -> Vec<(String, u64)> {
+        let keys = self.timers.expire(now_ms, max);
+        keys.into_iter()
+            .filter_map(|key| {
+                let (_, generation) = self.at.remove(&key)?;
+                Some((key, generation))
+            })
+            .collect()
+    }
+
+    /// Every key with a TTL and the unix time it expires at
+    pub fn iter(&self) -> impl Iterator<Item = (&String, u64)> {
+        self.at.iter().map(|(key, &(at_ms, _))| (key, at_ms))
+    }
+}
@@ -0,0 +1,326 @@
+/* Coordinated failover */
+//
+//   FAILOVER [TO host port [FORCE]] [TIMEOUT milliseconds]
+//   FAILOVER ABORT
+//
+// hands the master role to a replica without losing a write, as Redis does:
+//
+//   waiting-for-sync      writes are paused (see pause) and the replicas are
+//                         asked for their offsets with REPLCONF GETACK, until
+//                         the target, or any replica without TO, has
+//                         acknowledged the whole stream
+//   failover-in-progress  we connect to it and send
+//                           PSYNC replid offset FAILOVER
+//                         on which a Redis replica promotes itself and,
+//                         answering +CONTINUE or +FULLRESYNC, takes over
+//
+// after which this server is demoted: ROLE and INFO report the new master
+// and writes are refused with READONLY, so clients go and find it. There is
+// no replica side yet, so it doesn't follow the new master's stream; REPLICAOF
+// NO ONE makes it a master again.
+//
+// Past the TIMEOUT while waiting, the failover is aborted, or with FORCE goes
+// on to the handover anyway. An aborted or failed failover lets the paused
+// writes through and leaves the server a master. The steps run between event
+// loop rounds. The handover's connect and PSYNC run on the thread pool, and
+// the event loop checks for the answer every HANDOVER_POLL_MS; without one
+// by HANDOVER_TIMEOUT, the failover is aborted.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use redis::client::RedisClient;
+use redis::protocol::{RedisValue, ReplyFormat};
+
+use crate::command::CmdResult;
+use crate::logging::{log_notice, log_warning};
+use crate::{get_monotonic_time_ms, out_err, out_str, pause, with_global_data, Buffer, GData};
+
+pub const READONLY_ERROR: &str = "READONLY You can't write against a read only replica.";
+
+// Connecting to the target and its answer to PSYNC
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(5);
+// How often the event loop looks for the answer while the handover runs
+const HANDOVER_POLL_MS: u64 = 10;
+
+// Where the handover leaves the target's answer to PSYNC for the event loop
+type HandoverResult = Arc<Mutex<Option<io::Result<RedisValue>>>>;
+
+#[derive(Debug, Default)]
+pub struct Failover {
+    // None while no failover runs
+    run: Option<Run>,
+}
+
+#[derive(Debug)]
+struct Run {
+    // TO host port
+    target: Option<(String, u16)>,
+    // Monotonic ms TIMEOUT ends at
+    deadline_ms: Option<u64>,
+    force: bool,
+    // Connecting to the replica that takes over
+    handover: Option<Handover>,
+}
+
+#[derive(Debug)]
+struct Handover {
+    replica: (String, u16),
+    // Monotonic ms it's given up at
+    deadline_ms: u64,
+    result: HandoverResult,
+}
+
+impl Failover {
+    /// master_failover_state in INFO
+    pub fn state(&self) -> &'static str {
+        match &self.run {
+            None => "no-failover",
+            Some(run) if run.handover.is_some() => "failover-in-progress",
+            Some(_) => "waiting-for-sync",
+        }
+    }
+
+    /// When cron() has something to do next: the running failover times
+    /// out, or the handover's answer is to be looked for
+    pub fn next_timer_ms(&self, now_ms: u64) -> Option<u64> {
+        let run = self.run.as_ref()?;
+        match &run.handover {
+            Some(handover) => Some((now_ms + HANDOVER_POLL_MS).min(handover.deadline_ms)),
+            None => run.deadline_ms,
+        }
+    }
+}
+
+// FAILOVER [TO host port [FORCE]] [TIMEOUT milliseconds] | FAILOVER ABORT
+pub fn do_failover(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let (mut target, mut timeout_ms, mut force, mut abort) = (None, None, false, false);
+    let mut args = cmd[1..].iter();
+    while let Some(arg) = args.next() {
+        match arg.to_uppercase().as_str() {
+            "TO" => match (args.next(), args.next().map(|port| port.parse::<u16>())) {
+                (Some(host), Some(Ok(port))) => target = Some((host.clone(), port)),
+                (Some(_), Some(Err(_))) => {
+                    out_err(out, "ERR Invalid port");
+                    return Ok(());
+                }
+                _ => {
+                    out_err(out, "ERR syntax error");
+                    return Ok(());
+                }
+            },
+            "TIMEOUT" => match args.next().map(|ms| ms.parse::<i64>()) {
+                Some(Ok(ms)) if ms > 0 => timeout_ms = Some(ms as u64),
+                Some(Ok(_)) => {
+                    out_err(out, "ERR FAILOVER timeout must be greater than 0");
+                    return Ok(());
+                }
+                _ => {
+                    out_err(out, "ERR syntax error");
+                    return Ok(());
+                }
+            },
+            "FORCE" => force = true,
+            "ABORT" => abort = true,
+            _ => {
+                out_err(out, "ERR syntax error");
+                return Ok(());
+            }
+        }
+    }
+
+    if abort {
+        if cmd.len() != 2 {
+            out_err(out, "ERR FAILOVER ABORT takes no other arguments");
+            return Ok(());
+        }
+        let aborted = with_global_data(|g_data| {
+            let running = g_data.failover.run.is_some();
+            if running {
+                end(g_data, "aborted by FAILOVER ABORT");
+            }
+            running
+        });
+        match aborted {
+            true => out_str(out, "OK"),
+            false => out_err(out, "ERR No failover in progress."),
+        }
+        return Ok(());
+    }
+    if force && (target.is_none() || timeout_ms.is_none()) {
+        out_err(out, "ERR FAILOVER with force option requires both a timeout and target HOST and IP.");
+        return Ok(());
+    }
+
+    with_global_data(|g_data| {
+        if g_data.replication.is_replica() {
+            out_err(out, "ERR FAILOVER is not valid when server is a replica.");
+            return;
+        }
+        if g_data.failover.run.is_some() {
+            out_err(out, "ERR FAILOVER already in progress.");
+            return;
+        }
+        if g_data.replication.replica_count() == 0 {
+            out_err(out, "ERR FAILOVER requires connected replicas.");
+            return;
+        }
+        if let Some((host, port)) = &target
+            && !g_data.replication.has_replica(host, *port)
+        {
+            out_err(out, "ERR FAILOVER target HOST and PORT is not a replica.");
+            return;
+        }
+
+        let deadline_ms = timeout_ms.map(|ms| get_monotonic_time_ms().saturating_add(ms));
+        log_notice!("repl", "FAILOVER requested to {}", target.as_ref().map_or("any replica".to_string(), |(host, port)| format!("{}:{}", host, port)));
+        g_data.failover.run = Some(Run { target, deadline_ms, force, handover: None });
+        pause::hold_writes(g_data);
+        let backlog_size = g_data.config.repl_backlog_size as usize;
+        g_data.replication.request_acks(backlog_size);
+        out_str(out, "OK");
+    });
+    Ok(())
+}
+
+/// Take the running failover a step further: after every event loop round
+pub fn cron(g_data: &mut GData) {
+    let now_ms = get_monotonic_time_ms();
+    let Some(run) = g_data.failover.run.as_mut() else {
+        return;
+    };
+    if let Some(handover) = &run.handover {
+        let (host, port) = handover.replica.clone();
+        let result = handover.result.lock().unwrap().take();
+        match result {
+            Some(Ok(RedisValue::Str(reply))) if reply.starts_with("CONTINUE") || reply.starts_with("FULLRESYNC") => {
+                log_notice!("repl", "FAILOVER: {}:{} took over, now following it", host, port);
+                g_data.replication.demote((host, port));
+                g_data.failover.run = None;
+                pause::resume(g_data);
+            }
+            Some(Ok(reply)) => end(g_data, &format!("{}:{} refused: {:?}", host, port, reply)),
+            Some(Err(e)) => end(g_data, &format!("can't reach {}:{}: {}", host, port, e)),
+            None if now_ms >= handover.deadline_ms => end(g_data, &format!("no answer from {}:{} in time", host, port)),
+            None => {}
+        }
+        return;
+    }
+
+    let timed_out = run.deadline_ms.is_some_and(|deadline| deadline <= now_ms);
+    let ready = match g_data.replication.caught_up_replica(run.target.as_ref()) {
+        Some(replica) => Some(replica),
+        None if timed_out && run.force => run.target.clone(),
+        None => None,
+    };
+    let Some(replica) = ready else {
+        if timed_out {
+            end(g_data, "timed out waiting for a replica to catch up");
+        }
+        return;
+    };
+
+    // Writes stay paused meanwhile, so nothing else changes the stream
+    let (replid, offset) = g_data.replication.next_psync();
+    log_notice!("repl", "FAILOVER: handing over to {}:{} at offset {}", replica.0, replica.1, offset);
+    let result = HandoverResult::default();
+    let (slot, (host, port)) = (result.clone(), replica.clone());
+    g_data.thread_pool.submit(move || {
+        let answer = RedisClient::connect_timeout((host.as_str(), port), HANDOVER_TIMEOUT).and_then(|client| {
+            let mut client = client.with_format(ReplyFormat::Resp);
+            client.command(&["PSYNC", &replid, &offset.to_string(), "FAILOVER"])
+        });
+        *slot.lock().unwrap() = Some(answer);
+    });
+    let deadline_ms = now_ms + HANDOVER_TIMEOUT.as_millis() as u64;
+    run.handover = Some(Handover { replica, deadline_ms, result });
+}
+
+// Give up on the running failover, letting the paused writes in
+fn end(g_data: &mut GData, why: &str) {
+    log_warning!("repl", "FAILOVER aborted: {}", why);
+    g_data.failover.run = None;
+    pause::resume(g_data);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    use redis::protocol::RedisValue;
+
+    use crate::sim::{testing::shared, Simulation};
+    use crate::with_global_data;
+
+    // Not a socket anything else has; the fake replica is registered under it
+    const REPLICA_FD: i32 = 1_000_000;
+
+    fn state() -> &'static str {
+        with_global_data(|g_data| g_data.failover.state())
+    }
+
+    // Ask for a failover to the replica listening at `port`
+    fn start(sim: &mut Simulation, port: u16) {
+        with_global_data(|g_data| g_data.replication.add_caught_up_replica(REPLICA_FD, "127.0.0.1", port));
+        let reply = sim.call("failover", &format!("FAILOVER TO 127.0.0.1 {} TIMEOUT 1000", port));
+        assert_eq!(reply, RedisValue::Str("OK".into()));
+        assert_eq!(state(), "failover-in-progress");
+    }
+
+    // A target that accepts and never answers doesn't hold up other clients;
+    // past HANDOVER_TIMEOUT the failover is given up and writes go on
+    #[test]
+    fn a_silent_target_does_not_block_the_event_loop() {
+        let mut sim = shared();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        start(&mut sim, listener.local_addr().unwrap().port());
+
+        let started = Instant::now();
+        assert_eq!(sim.call("other", "PING"), RedisValue::Str("PONG".into()));
+        sim.advance(1000).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(state(), "failover-in-progress");
+
+        sim.advance(super::HANDOVER_TIMEOUT.as_millis() as u64).unwrap();
+        assert_eq!(state(), "no-failover");
+        assert_eq!(sim.call("other", "SET failover:a 1"), RedisValue::Nil);
+        with_global_data(|g_data| g_data.replication.forget_replica(REPLICA_FD));
+    }
+
+    // The target's +CONTINUE, whenever it comes, demotes this server
+    #[test]
+    fn an_answer_from_the_target_completes_the_handover() {
+        let mut sim = shared();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (answer, answer_now) = std::sync::mpsc::channel::<()>();
+        let target = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 256];
+            let n = stream.read(&mut request).unwrap();
+            answer_now.recv().unwrap();
+            stream.write_all(b"+CONTINUE\r\n").unwrap();
+            String::from_utf8_lossy(&request[..n]).into_owned()
+        });
+        start(&mut sim, port);
+        assert_eq!(sim.call("other", "PING"), RedisValue::Str("PONG".into()));
+        answer.send(()).unwrap();
+
+        // The answer comes in real time; virtual time only polls for it
+        let started = Instant::now();
+        while state() == "failover-in-progress" && started.elapsed() < Duration::from_secs(5) {
+            sim.advance(super::HANDOVER_POLL_MS).unwrap();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(target.join().unwrap().contains("FAILOVER"));
+        assert_eq!(state(), "no-failover");
+        assert!(with_global_data(|g_data| g_data.replication.is_replica()));
+
+        with_global_data(|g_data| g_data.replication.forget_replica(REPLICA_FD));
+        assert_eq!(sim.call("failover", "REPLICAOF NO ONE"), RedisValue::Str("OK".into()));
+    }
+}
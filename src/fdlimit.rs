@@ -0,0 +1,125 @@
+/* Open file limit */
+//
+// A client takes two descriptors: its socket, and the duplicate the event
+// loop polls it through (see client_poll_entries). At startup the soft
+// RLIMIT_NOFILE is raised to max-open-files, or when that's 0 to what
+// maxclients needs: two a client plus RESERVED_FDS for the listener, the
+// snapshot and AOF files, the cluster bus and such. Where the hard limit
+// doesn't allow that much, the soft limit goes as high as it can and
+// maxclients is lowered to fit, with a warning.
+//
+// Should the process run out anyway (EMFILE, or ENFILE when the whole
+// system has), accept() fails, the pending connection stays in the backlog
+// and the listener stays readable, so the event loop would go round failing
+// to accept it. Instead the first failure is logged, the spare descriptor
+// kept for this is closed to accept that connection and hang up on it, so
+// its client isn't left waiting, and the listener is left out of the poll
+// for ACCEPT_BACKOFF_MS. Accepting again is logged once it works.
+
+use std::fs::File;
+use std::io;
+
+use nix::errno::Errno;
+use nix::sys::resource::{getrlimit, setrlimit, Resource};
+use socket2::Socket;
+
+use crate::config::Config;
+use crate::logging::{log_notice, log_warning};
+
+/// Descriptors a client takes
+const FDS_PER_CLIENT: u64 = 2;
+
+/// Descriptors set aside for everything but clients
+const RESERVED_FDS: u64 = 32;
+
+/// How long accepting pauses after running out of descriptors
+const ACCEPT_BACKOFF_MS: u64 = 100;
+
+/// Raise the open file limit to what `config` asks for, lowering maxclients
+/// if it can't be had
+pub fn adjust_open_files_limit(config: &mut Config) {
+    let wanted = match config.max_open_files {
+        0 => config.maxclients.saturating_mul(FDS_PER_CLIENT).saturating_add(RESERVED_FDS),
+        n => n,
+    };
+    let (soft, hard) = match getrlimit(Resource::RLIMIT_NOFILE) {
+        Ok(limits) => limits,
+        Err(e) => {
+            log_warning!("server", "Can't read the open file limit: {}", e);
+            return;
+        }
+    };
+    let mut limit = soft;
+    if soft < wanted {
+        let target = wanted.min(hard);
+        match setrlimit(Resource::RLIMIT_NOFILE, target, hard) {
+            Ok(()) => limit = target,
+            Err(e) => log_warning!("server", "Can't raise the open file limit from {} to {}: {}", soft, target, e),
+        }
+        if limit < wanted {
+            log_warning!("server", "Open file limit is {}, below the {} asked for", limit, wanted);
+        }
+    }
+
+    let fit = limit.saturating_sub(RESERVED_FDS) / FDS_PER_CLIENT;
+    if fit < config.maxclients {
+        log_warning!("server", "maxclients lowered from {} to {} to fit the open file limit of {}", config.maxclients, fit.max(1), limit);
+        config.maxclients = fit.max(1);
+    }
+}
+
+/// Whether accept() failed for want of descriptors
+pub fn is_exhausted(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(code) if code == Errno::EMFILE as i32 || code == Errno::ENFILE as i32)
+}
+
+#[derive(Debug, Default)]
+pub struct AcceptState {
+    // Given up to hang up on a connection when out of descriptors
+    spare: Option<File>,
+    // Monotonic ms accepting resumes at
+    paused_until_ms: Option<u64>,
+    // Out of descriptors since the last accept that worked
+    exhausted: bool,
+}
+
+impl AcceptState {
+    /// Take the spare descriptor, at startup
+    pub fn reserve_spare(&mut self) {
+        self.spare = File::open("/dev/null").inspect_err(|e| log_warning!("net", "Can't reserve a spare descriptor: {}", e)).ok();
+    }
+
+    /// Whether to poll the listener now, ending a pause that's over
+    pub fn accepting(&mut self, now_ms: u64) -> bool {
+        if self.paused_until_ms.is_some_and(|until| until <= now_ms) {
+            self.paused_until_ms = None;
+        }
+        self.paused_until_ms.is_none()
+    }
+
+    /// When accepting resumes, if it's paused
+    pub fn deadline(&self) -> Option<u64> {
+        self.paused_until_ms
+    }
+
+    /// A connection was accepted
+    pub fn accepted(&mut self) {
+        self.paused_until_ms = None;
+        if std::mem::take(&mut self.exhausted) {
+            log_notice!("net", "Accepting connections again");
+        }
+    }
+
+    /// accept() on `listener` failed with `e`, out of descriptors
+    pub fn exhausted(&mut self, listener: &Socket, e: &io::Error, now_ms: u64) {
+        if !std::mem::replace(&mut self.exhausted, true) {
+            log_warning!("net", "Out of file descriptors, refusing connections for now ({}); see maxclients and max-open-files", e);
+        }
+        // Dropping it frees its descriptor for the one connection
+        if self.spare.take().is_some() {
+            drop(listener.accept());
+            self.reserve_spare();
+        }
+        self.paused_until_ms = Some(now_ms + ACCEPT_BACKOFF_MS);
+    }
+}
@@ -0,0 +1,571 @@
+/* Geospatial */
+//
+// Points are stored in a regular sorted set: longitude/latitude are quantized
+// to 26 bits each and interleaved into a 52-bit geohash, which fits exactly in
+// the f64 score. Radius and box searches turn the area into at most nine
+// geohash cells and scan each cell as a contiguous score range of the zset.
+
+use crate::command::CmdResult;
+use crate::{check_zset_members, expect_zset_mut, out_arr, out_dbl, out_err, out_int, out_nil, out_str, successor, with_global_data, with_global_data_read, Buffer, ZSet};
+
+const GEO_STEP_MAX: u32 = 26; // 26 * 2 = 52 bits
+const GEO_LAT_MIN: f64 = -85.05112878;
+const GEO_LAT_MAX: f64 = 85.05112878;
+const GEO_LONG_MIN: f64 = -180.0;
+const GEO_LONG_MAX: f64 = 180.0;
+
+// Earth's quadratic mean radius for WGS-84, same constant Redis uses
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+
+// Spread the low 32 bits of x into the even bit positions
+fn spread(x: u32) -> u64 {
+    let mut x = x as u64;
+    x = (x | (x << 16)) & 0x0000FFFF0000FFFF;
+    x = (x | (x << 8)) & 0x00FF00FF00FF00FF;
+    x = (x | (x << 4)) & 0x0F0F0F0F0F0F0F0F;
+    x = (x | (x << 2)) & 0x3333333333333333;
+    x = (x | (x << 1)) & 0x5555555555555555;
+    x
+}
+
+// Inverse of spread: collect the even bits back into a u32
+fn squash(x: u64) -> u32 {
+    let mut x = x & 0x5555555555555555;
+    x = (x | (x >> 1)) & 0x3333333333333333;
+    x = (x | (x >> 2)) & 0x0F0F0F0F0F0F0F0F;
+    x = (x | (x >> 4)) & 0x00FF00FF00FF00FF;
+    x = (x | (x >> 8)) & 0x0000FFFF0000FFFF;
+    x = (x | (x >> 16)) & 0x00000000FFFFFFFF;
+    x as u32
+}
+
+/// Latitude bits go in the even positions, longitude bits in the odd ones
+fn interleave(lat_bits: u32, lon_bits: u32) -> u64 {
+    spread(lat_bits) | (spread(lon_bits) << 1)
+}
+
+fn deinterleave(hash: u64) -> (u32, u32) {
+    (squash(hash), squash(hash >> 1))
+}
+
+pub fn valid_coords(lon: f64, lat: f64) -> bool {
+    (GEO_LONG_MIN..=GEO_LONG_MAX).contains(&lon) && (GEO_LAT_MIN..=GEO_LAT_MAX).contains(&lat)
+}
+
+/// Geohash of a point with `step` bits per coordinate
+pub fn geohash_encode(lon: f64, lat: f64, step: u32) -> u64 {
+    let cells = (1u64 << step) as f64;
+    let lat_offset = (lat - GEO_LAT_MIN) / (GEO_LAT_MAX - GEO_LAT_MIN) * cells;
+    let lon_offset = (lon - GEO_LONG_MIN) / (GEO_LONG_MAX - GEO_LONG_MIN) * cells;
+    // The max edge belongs to the last cell
+    let max_cell = (1u64 << step) - 1;
+    let lat_bits = (lat_offset as u64).min(max_cell) as u32;
+    let lon_bits = (lon_offset as u64).min(max_cell) as u32;
+    interleave(lat_bits, lon_bits)
+}
+
+/// Bounds of a geohash cell as (lon_min, lon_max, lat_min, lat_max)
+fn geohash_area(hash: u64, step: u32) -> (f64, f64, f64, f64) {
+    let (lat_bits, lon_bits) = deinterleave(hash);
+    let cells = (1u64 << step) as f64;
+    let lat_scale = GEO_LAT_MAX - GEO_LAT_MIN;
+    let lon_scale = GEO_LONG_MAX - GEO_LONG_MIN;
+    (
+        GEO_LONG_MIN + (lon_bits as f64 / cells) * lon_scale,
+        GEO_LONG_MIN + ((lon_bits as f64 + 1.0) / cells) * lon_scale,
+        GEO_LAT_MIN + (lat_bits as f64 / cells) * lat_scale,
+        GEO_LAT_MIN + ((lat_bits as f64 + 1.0) / cells) * lat_scale,
+    )
+}
+
+/// Center of the 52-bit cell a zset score refers to, as (lon, lat)
+pub fn geohash_decode(score: f64) -> (f64, f64) {
+    let (lon_min, lon_max, lat_min, lat_max) = geohash_area(score as u64, GEO_STEP_MAX);
+    let lon = ((lon_min + lon_max) / 2.0).clamp(GEO_LONG_MIN, GEO_LONG_MAX);
+    let lat = ((lat_min + lat_max) / 2.0).clamp(GEO_LAT_MIN, GEO_LAT_MAX);
+    (lon, lat)
+}
+
+/// Great-circle distance in meters (haversine)
+pub fn geo_distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let lat1r = lat1.to_radians();
+    let lat2r = lat2.to_radians();
+    let u = ((lat2r - lat1r) / 2.0).sin();
+    let v = ((lon2 - lon1).to_radians() / 2.0).sin();
+    let a = u * u + lat1r.cos() * lat2r.cos() * v * v;
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+// Coarsest step whose cells are still about as large as the search radius
+fn estimate_step(radius_m: f64, lat: f64) -> u32 {
+    if radius_m == 0.0 {
+        return GEO_STEP_MAX;
+    }
+    let mut range = radius_m;
+    let mut step: i32 = 1;
+    while range < MERCATOR_MAX {
+        range *= 2.0;
+        step += 1;
+    }
+    step -= 2; // Make sure the range is included in most of the base cases
+
+    // Cells get narrower towards the poles
+    if !(-66.0..=66.0).contains(&lat) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&lat) {
+            step -= 1;
+        }
+    }
+    step.clamp(1, GEO_STEP_MAX as i32) as u32
+}
+
+/// The shape searched by GEOSEARCH
+#[derive(Debug, Clone, Copy)]
+pub enum GeoShape {
+    Radius(f64),                  // meters
+    Box { width: f64, height: f64 }, // meters
+}
+
+impl GeoShape {
+    // Radius of the circle enclosing the shape
+    fn enclosing_radius(&self) -> f64 {
+        match *self {
+            GeoShape::Radius(r) => r,
+            GeoShape::Box { width, height } => ((width / 2.0).powi(2) + (height / 2.0).powi(2)).sqrt(),
+        }
+    }
+
+    /// Distance from the center if (lon, lat) falls inside the shape
+    fn contains(&self, center: (f64, f64), lon: f64, lat: f64) -> Option<f64> {
+        let dist = geo_distance(center.0, center.1, lon, lat);
+        match *self {
+            GeoShape::Radius(r) => (dist <= r).then_some(dist),
+            GeoShape::Box { width, height } => {
+                // Measure each axis separately along the great circles through the center
+                let lat_dist = geo_distance(lon, lat, lon, center.1);
+                if lat_dist > height / 2.0 {
+                    return None;
+                }
+                let lon_dist = geo_distance(lon, lat, center.0, lat);
+                (lon_dist <= width / 2.0).then_some(dist)
+            }
+        }
+    }
+}
+
+/// Latitude/longitude bounds enclosing a circle of `radius_m` around a point
+fn bounding_box(lon: f64, lat: f64, radius_m: f64) -> (f64, f64, f64, f64) {
+    let lat_delta = (radius_m / EARTH_RADIUS_IN_METERS).to_degrees();
+    let lon_delta_top = (radius_m / EARTH_RADIUS_IN_METERS / (lat + lat_delta).to_radians().cos()).to_degrees();
+    let lon_delta_bottom = (radius_m / EARTH_RADIUS_IN_METERS / (lat - lat_delta).to_radians().cos()).to_degrees();
+    let lon_delta = if lat > 0.0 { lon_delta_bottom } else { lon_delta_top };
+    (lon - lon_delta, lon + lon_delta, lat - lat_delta, lat + lat_delta)
+}
+
+/// Geohash cells (at `step`) covering the 3x3 neighbourhood of the center cell.
+/// Longitude wraps around, cells past the poles are skipped.
+fn neighbourhood(lon: f64, lat: f64, step: u32) -> Vec<u64> {
+    let (lat_bits, lon_bits) = deinterleave(geohash_encode(lon, lat, step));
+    let cells = 1i64 << step;
+    let mut hashes = Vec::with_capacity(9);
+    for dlat in -1i64..=1 {
+        let y = lat_bits as i64 + dlat;
+        if y < 0 || y >= cells {
+            continue;
+        }
+        for dlon in -1i64..=1 {
+            let x = (lon_bits as i64 + dlon).rem_euclid(cells);
+            let hash = interleave(y as u32, x as u32);
+            if !hashes.contains(&hash) {
+                hashes.push(hash);
+            }
+        }
+    }
+    hashes
+}
+
+/// Choose a step whose 3x3 neighbourhood fully covers the search area
+fn covering_cells(lon: f64, lat: f64, radius_m: f64) -> (Vec<u64>, u32) {
+    let (min_lon, max_lon, min_lat, max_lat) = bounding_box(lon, lat, radius_m);
+    let mut step = estimate_step(radius_m, lat);
+    loop {
+        let (lon_lo, lon_hi, lat_lo, lat_hi) = geohash_area(geohash_encode(lon, lat, step), step);
+        let cell_w = lon_hi - lon_lo;
+        let cell_h = lat_hi - lat_lo;
+        let covered = lon_lo - cell_w <= min_lon
+            && lon_hi + cell_w >= max_lon
+            && lat_lo - cell_h <= min_lat
+            && lat_hi + cell_h >= max_lat;
+        if covered || step == 1 {
+            return (neighbourhood(lon, lat, step), step);
+        }
+        step -= 1;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GeoPoint {
+    pub member: String,
+    pub dist: f64,
+    pub score: f64,
+    pub lon: f64,
+    pub lat: f64,
+}
+
+/// All members of `zset` within `shape` around `center`, at most `limit` when `any`
+pub fn geo_search(zset: &ZSet, center: (f64, f64), shape: GeoShape, limit: Option<usize>) -> Vec<GeoPoint> {
+    let (cells, step) = covering_cells(center.0, center.1, shape.enclosing_radius());
+    let shift = 2 * (GEO_STEP_MAX - step);
+
+    let mut found = Vec::new();
+    for cell in cells {
+        // Every 52-bit hash inside this cell shares its prefix, so the cell is
+        // the half-open score interval [cell << shift, (cell + 1) << shift)
+        let min = (cell << shift) as f64;
+        let max = ((cell + 1) << shift) as f64;
+
+        let mut node = zset.zset_seekge(min, "");
+        while let Some(current) = node {
+            let (score, name) = {
+                let n = current.lock().unwrap();
+                (n.score, n.name.clone())
+            };
+            if score >= max {
+                break;
+            }
+            let (lon, lat) = geohash_decode(score);
+            if let Some(dist) = shape.contains(center, lon, lat) {
+                found.push(GeoPoint { member: name, dist, score, lon, lat });
+                if limit.is_some_and(|n| found.len() >= n) {
+                    return found;
+                }
+            }
+            node = successor(Some(current));
+        }
+    }
+    found
+}
+
+fn unit_to_meters(unit: &str) -> Option<f64> {
+    match unit.to_lowercase().as_str() {
+        "m" => Some(1.0),
+        "km" => Some(1000.0),
+        "ft" => Some(0.3048),
+        "mi" => Some(1609.34),
+        _ => None,
+    }
+}
+
+const ERR_UNIT: &str = "ERR unsupported unit provided. please use M, KM, FT, MI";
+
+// GEOADD key [NX | XX] [CH] longitude latitude member [longitude latitude member ...]
+pub fn do_geoadd(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let mut nx = false;
+    let mut xx = false;
+    let mut ch = false;
+
+    let mut i = 2;
+    while i < cmd.len() {
+        match cmd[i].to_uppercase().as_str() {
+            "NX" => nx = true,
+            "XX" => xx = true,
+            "CH" => ch = true,
+            _ => break,
+        }
+        i += 1;
+    }
+
+    let triples = cmd.get(i..).unwrap_or_default();
+    if cmd.len() < 2 || triples.is_empty() || triples.len() % 3 != 0 {
+        out_err(out, "GEOADD requires: key [NX|XX] [CH] longitude latitude member [longitude latitude member ...]");
+        return Ok(());
+    }
+    if nx && xx {
+        out_err(out, "ERR XX and NX options at the same time are not compatible");
+        return Ok(());
+    }
+    if let Err(e) = check_zset_members("geoadd", triples.len() / 3) {
+        out_err(out, &e);
+        return Ok(());
+    }
+
+    let mut points = Vec::with_capacity(triples.len() / 3);
+    for triple in triples.chunks(3) {
+        let (Ok(lon), Ok(lat)) = (triple[0].parse::<f64>(), triple[1].parse::<f64>()) else {
+            out_err(out, "ERR value is not a valid float");
+            return Ok(());
+        };
+        if !valid_coords(lon, lat) {
+            out_err(out, &format!("ERR invalid longitude,latitude pair {:.6},{:.6}", lon, lat));
+            return Ok(());
+        }
+        let score = geohash_encode(lon, lat, GEO_STEP_MAX) as f64;
+        points.push((score, triple[2].clone()));
+    }
+
+    let key = &cmd[1];
+
+    with_global_data(|g_data| {
+        // XX on a missing key leaves the set empty, so it isn't created
+        let (added, changed) = expect_zset_mut(g_data, key, true, |zset| {
+            let mut added = 0;
+            let mut changed = 0;
+            for (score, member) in points {
+                let existing = zset.lookup(&member).map(|node| node.lock().unwrap().score);
+                match existing {
+                    Some(_) if nx => {}
+                    None if xx => {}
+                    Some(old) => {
+                        if old != score {
+                            zset.insert(score, member);
+                            changed += 1;
+                        }
+                    }
+                    None => {
+                        zset.insert(score, member);
+                        added += 1;
+                    }
+                }
+            }
+            (added, changed)
+        })?
+        .expect("created if missing");
+        out_int(out, if ch { added + changed } else { added });
+        Ok(())
+    })
+}
+
+// GEOPOS key [member ...]
+pub fn do_geopos(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 2 {
+        out_err(out, "GEOPOS requires: key [member ...]");
+        return Ok(());
+    }
+
+    let members = &cmd[2..];
+
+    with_global_data_read(|g_data| {
+        let zset = g_data.db.expect_zset(&cmd[1])?;
+
+        out_arr(out, members.len() as u32);
+        for member in members {
+            let score = zset.and_then(|z| z.lookup(member)).map(|node| node.lock().unwrap().score);
+            match score {
+                Some(score) => {
+                    let (lon, lat) = geohash_decode(score);
+                    out_arr(out, 2);
+                    out_dbl(out, lon);
+                    out_dbl(out, lat);
+                }
+                None => out_nil(out),
+            }
+        }
+        Ok(())
+    })
+}
+
+// GEODIST key member1 member2 [M | KM | FT | MI]
+pub fn do_geodist(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() != 4 && cmd.len() != 5 {
+        out_err(out, "GEODIST requires: key member1 member2 [M|KM|FT|MI]");
+        return Ok(());
+    }
+
+    let Some(to_meters) = unit_to_meters(cmd.get(4).map_or("m", |s| s.as_str())) else {
+        out_err(out, ERR_UNIT);
+        return Ok(());
+    };
+
+    with_global_data_read(|g_data| {
+        let Some(zset) = g_data.db.expect_zset(&cmd[1])? else {
+            out_nil(out);
+            return Ok(());
+        };
+
+        let score_of = |member: &str| zset.lookup(member).map(|node| node.lock().unwrap().score);
+        match (score_of(&cmd[2]), score_of(&cmd[3])) {
+            (Some(a), Some(b)) => {
+                let (lon1, lat1) = geohash_decode(a);
+                let (lon2, lat2) = geohash_decode(b);
+                let dist = geo_distance(lon1, lat1, lon2, lat2) / to_meters;
+                out_dbl(out, (dist * 10000.0).round() / 10000.0);
+            }
+            _ => out_nil(out),
+        }
+        Ok(())
+    })
+}
+
+// GEOSEARCH key <FROMMEMBER member | FROMLONLAT longitude latitude>
+//   <BYRADIUS radius <M | KM | FT | MI> | BYBOX width height <M | KM | FT | MI>>
+//   [ASC | DESC] [COUNT count [ANY]] [WITHCOORD] [WITHDIST] [WITHHASH]
+pub fn do_geosearch(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 2 {
+        out_err(out, "GEOSEARCH requires: key <FROMMEMBER member | FROMLONLAT lon lat> <BYRADIUS r unit | BYBOX w h unit> [ASC|DESC] [COUNT n [ANY]] [WITHCOORD] [WITHDIST] [WITHHASH]");
+        return Ok(());
+    }
+
+    let key = &cmd[1];
+    let mut from_member: Option<&String> = None;
+    let mut from_lonlat: Option<(f64, f64)> = None;
+    let mut shape: Option<(GeoShape, f64)> = None; // shape in meters, unit factor
+    let mut sort: Option<bool> = None; // Some(true) = ascending
+    let mut count: Option<usize> = None;
+    let mut any = false;
+    let (mut with_coord, mut with_dist, mut with_hash) = (false, false, false);
+
+    let num = |i: usize| cmd.get(i).and_then(|s| s.parse::<f64>().ok());
+    let mut i = 2;
+    while i < cmd.len() {
+        match cmd[i].to_uppercase().as_str() {
+            "FROMMEMBER" if i + 1 < cmd.len() => {
+                from_member = Some(&cmd[i + 1]);
+                i += 2;
+            }
+            "FROMLONLAT" => {
+                let (Some(lon), Some(lat)) = (num(i + 1), num(i + 2)) else {
+                    out_err(out, "ERR value is not a valid float");
+                    return Ok(());
+                };
+                if !valid_coords(lon, lat) {
+                    out_err(out, &format!("ERR invalid longitude,latitude pair {:.6},{:.6}", lon, lat));
+                    return Ok(());
+                }
+                from_lonlat = Some((lon, lat));
+                i += 3;
+            }
+            "BYRADIUS" => {
+                let Some(radius) = num(i + 1).filter(|r| *r >= 0.0) else {
+                    out_err(out, "ERR radius cannot be negative");
+                    return Ok(());
+                };
+                let Some(factor) = cmd.get(i + 2).and_then(|u| unit_to_meters(u)) else {
+                    out_err(out, ERR_UNIT);
+                    return Ok(());
+                };
+                shape = Some((GeoShape::Radius(radius * factor), factor));
+                i += 3;
+            }
+            "BYBOX" => {
+                let (Some(w), Some(h)) = (num(i + 1), num(i + 2)) else {
+                    out_err(out, "ERR value is not a valid float");
+                    return Ok(());
+                };
+                if w < 0.0 || h < 0.0 {
+                    out_err(out, "ERR height or width cannot be negative");
+                    return Ok(());
+                }
+                let Some(factor) = cmd.get(i + 3).and_then(|u| unit_to_meters(u)) else {
+                    out_err(out, ERR_UNIT);
+                    return Ok(());
+                };
+                shape = Some((GeoShape::Box { width: w * factor, height: h * factor }, factor));
+                i += 4;
+            }
+            "ASC" => {
+                sort = Some(true);
+                i += 1;
+            }
+            "DESC" => {
+                sort = Some(false);
+                i += 1;
+            }
+            "COUNT" => {
+                match cmd.get(i + 1).and_then(|s| s.parse::<usize>().ok()).filter(|n| *n > 0) {
+                    Some(n) => count = Some(n),
+                    None => {
+                        out_err(out, "ERR COUNT must be > 0");
+                        return Ok(());
+                    }
+                }
+                i += 2;
+                if cmd.get(i).is_some_and(|s| s.eq_ignore_ascii_case("ANY")) {
+                    any = true;
+                    i += 1;
+                }
+            }
+            "WITHCOORD" => {
+                with_coord = true;
+                i += 1;
+            }
+            "WITHDIST" => {
+                with_dist = true;
+                i += 1;
+            }
+            "WITHHASH" => {
+                with_hash = true;
+                i += 1;
+            }
+            _ => {
+                out_err(out, "ERR syntax error");
+                return Ok(());
+            }
+        }
+    }
+
+    if from_member.is_some() == from_lonlat.is_some() {
+        out_err(out, "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH");
+        return Ok(());
+    }
+    let Some((shape, unit_factor)) = shape else {
+        out_err(out, "ERR exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH");
+        return Ok(());
+    };
+
+    with_global_data_read(|g_data| {
+        let Some(zset) = g_data.db.expect_zset(key)? else {
+            out_arr(out, 0);
+            return Ok(());
+        };
+
+        let center = match (from_member, from_lonlat) {
+            (Some(member), _) => match zset.lookup(member) {
+                Some(node) => geohash_decode(node.lock().unwrap().score),
+                None => {
+                    out_err(out, "ERR could not decode requested zset member");
+                    return Ok(());
+                }
+            },
+            (None, Some(lonlat)) => lonlat,
+            (None, None) => unreachable!(),
+        };
+
+        let mut points = geo_search(zset, center, shape, count.filter(|_| any));
+
+        // COUNT without an explicit order still returns the nearest matches
+        let ascending = sort.or(count.filter(|_| !any).map(|_| true));
+        if let Some(ascending) = ascending {
+            points.sort_by(|a, b| a.dist.total_cmp(&b.dist));
+            if !ascending {
+                points.reverse();
+            }
+        }
+        if let Some(n) = count {
+            points.truncate(n);
+        }
+
+        let extras = with_dist as u32 + with_hash as u32 + with_coord as u32;
+        out_arr(out, points.len() as u32);
+        for point in &points {
+            if extras == 0 {
+                out_str(out, &point.member);
+                continue;
+            }
+            out_arr(out, 1 + extras);
+            out_str(out, &point.member);
+            if with_dist {
+                out_dbl(out, (point.dist / unit_factor * 10000.0).round() / 10000.0);
+            }
+            if with_hash {
+                out_int(out, point.score as i64);
+            }
+            if with_coord {
+                out_arr(out, 2);
+                out_dbl(out, point.lon);
+                out_dbl(out, point.lat);
+            }
+        }
+        Ok(())
+    })
+}
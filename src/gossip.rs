@@ -0,0 +1,266 @@
+/* Cluster bus */
+//
+// Cluster nodes talk to each other on the bus port, the client port + 10000,
+// framed as client requests are. Every GOSSIP_PERIOD a node pings each node
+// it knows and takes in the pong it gets back; both carry the sender's view:
+//
+//   PING|PONG|MEET id ip port current-epoch config-epoch slots [id ip port state]...
+//
+// slots being the ranges the sender serves ("0-5460,5462", "-" for none),
+// followed by every other node it knows with its state as the sender sees
+// it: ok, pfail or fail. What a node does with them is in cluster.rs.
+//
+// CLUSTER MEET sends a MEET, which has the receiver add the sender; the pong
+// to it has the sender add the receiver, and gossip spreads both to the
+// rest. A ping from a node the receiver doesn't know is answered but
+// otherwise ignored.
+//
+// As with sentinels, the bus runs on its own threads with blocking sockets:
+// one accepting peers (and a thread per peer connection), one pinging.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv6Addr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use redis::client::RedisClient;
+use redis::protocol::{parse_frame, FrameLimits, RedisValue};
+
+use crate::cluster::{NodeState, CLUSTER_SLOTS};
+use crate::logging::{log_debug, log_notice};
+use crate::{get_monotonic_time_ms, with_global_data};
+
+const BUS_PORT_OFFSET: u16 = 10000;
+const GOSSIP_PERIOD: Duration = Duration::from_millis(500);
+// Connect and reply timeout when talking to other nodes
+const IO_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Room for a few thousand nodes and a fully fragmented slot list
+const LIMITS: FrameLimits = FrameLimits { max_request_size: 4 * 1024 * 1024, max_args: 16 * 1024, max_arg_len: 256 * 1024 };
+
+/// What a message says about a node other than its sender
+#[derive(Debug)]
+pub struct Gossip {
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+    pub state: NodeState,
+}
+
+#[derive(Debug)]
+pub struct Message {
+    /// PING, PONG or MEET
+    pub kind: String,
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+    pub current_epoch: u64,
+    pub config_epoch: u64,
+    /// Slots the sender serves, ascending
+    pub slots: Vec<usize>,
+    pub gossip: Vec<Gossip>,
+}
+
+/// The bus port of a node listening for clients on `port`
+pub fn bus_port(port: u16) -> Option<u16> {
+    port.checked_add(BUS_PORT_OFFSET)
+}
+
+// "0-5460,5462" for slots 0 to 5460 and 5462
+fn format_slots(slots: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for &slot in slots {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == slot => *last = slot,
+            _ => ranges.push((slot, slot)),
+        }
+    }
+    if ranges.is_empty() {
+        return "-".to_string();
+    }
+    let ranges: Vec<String> = ranges
+        .into_iter()
+        .map(|(first, last)| if first == last { first.to_string() } else { format!("{}-{}", first, last) })
+        .collect();
+    ranges.join(",")
+}
+
+fn parse_slots(s: &str) -> Option<Vec<usize>> {
+    if s == "-" {
+        return Some(Vec::new());
+    }
+    let mut slots = Vec::new();
+    for range in s.split(',') {
+        let (first, last): (usize, usize) = match range.split_once('-') {
+            Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+            None => {
+                let slot = range.parse().ok()?;
+                (slot, slot)
+            }
+        };
+        if first > last || last >= CLUSTER_SLOTS {
+            return None;
+        }
+        slots.extend(first..=last);
+    }
+    Some(slots)
+}
+
+impl Message {
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![
+            self.kind.clone(),
+            self.id.clone(),
+            self.ip.clone(),
+            self.port.to_string(),
+            self.current_epoch.to_string(),
+            self.config_epoch.to_string(),
+            format_slots(&self.slots),
+        ];
+        for gossip in &self.gossip {
+            args.extend([gossip.id.clone(), gossip.ip.clone(), gossip.port.to_string(), gossip.state.as_str().to_string()]);
+        }
+        args
+    }
+
+    /// The message in `args`, None if it isn't a well-formed one
+    pub fn parse(args: &[String]) -> Option<Message> {
+        let (header, gossip) = (args.get(..7)?, &args[7..]);
+        if !matches!(header[0].as_str(), "PING" | "PONG" | "MEET") || gossip.len() % 4 != 0 {
+            return None;
+        }
+        let gossip = gossip
+            .chunks(4)
+            .map(|g| {
+                Some(Gossip { id: g[0].clone(), ip: g[1].clone(), port: g[2].parse().ok()?, state: NodeState::parse(&g[3])? })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Message {
+            kind: header[0].clone(),
+            id: header[1].clone(),
+            ip: header[2].clone(),
+            port: header[3].parse().ok()?,
+            current_epoch: header[4].parse().ok()?,
+            config_epoch: header[5].parse().ok()?,
+            slots: parse_slots(&header[6])?,
+            gossip,
+        })
+    }
+
+    // A message sent back as a reply: an array of its args
+    fn from_reply(reply: &RedisValue) -> Option<Message> {
+        let RedisValue::Arr(items) = reply else {
+            return None;
+        };
+        let args = items
+            .iter()
+            .map(|item| match item {
+                RedisValue::Str(s) => Some(s.clone()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Message::parse(&args)
+    }
+
+    fn to_reply(&self) -> RedisValue {
+        RedisValue::Arr(self.to_args().into_iter().map(RedisValue::Str).collect())
+    }
+}
+
+// Hand a message to the cluster state; the pong to answer it with
+fn receive(msg: &Message, meet: bool) -> Message {
+    with_global_data(|g_data| {
+        let node_timeout_ms = g_data.config.cluster_node_timeout;
+        g_data.cluster.receive(msg, meet, get_monotonic_time_ms(), node_timeout_ms);
+        g_data.cluster.message("PONG")
+    })
+}
+
+// Send `msg` to the node at ip:port and take in its pong, reusing the
+// connection from last time. A connection that failed is dropped, so the
+// next call reconnects.
+fn exchange(conns: &mut HashMap<String, RedisClient>, ip: &str, port: u16, msg: &Message) -> io::Result<()> {
+    let port = bus_port(port).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no bus port"))?;
+    let addr = if ip.contains(':') { format!("[{}]:{}", ip, port) } else { format!("{}:{}", ip, port) };
+    let mut client = match conns.remove(&addr) {
+        Some(client) => client,
+        None => RedisClient::connect_timeout(&addr, IO_TIMEOUT)?,
+    };
+    let args = msg.to_args();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let reply = client.command(&args)?;
+    conns.insert(addr, client);
+    let pong = Message::from_reply(&reply).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad pong"))?;
+    receive(&pong, msg.kind == "MEET");
+    Ok(())
+}
+
+/// CLUSTER MEET: introduce this node to the one at ip:port
+pub fn meet(ip: &str, port: u16) -> io::Result<()> {
+    let msg = with_global_data(|g_data| g_data.cluster.message("MEET"));
+    exchange(&mut HashMap::new(), ip, port, &msg)
+}
+
+fn ping_loop() -> ! {
+    let mut conns = HashMap::new();
+    loop {
+        let (ping, peers) = with_global_data(|g_data| (g_data.cluster.message("PING"), g_data.cluster.peers()));
+        for (ip, port) in peers {
+            if let Err(e) = exchange(&mut conns, &ip, port, &ping) {
+                log_debug!("cluster", "No pong from {}:{}: {}", ip, port, e);
+            }
+        }
+        with_global_data(|g_data| {
+            let node_timeout_ms = g_data.config.cluster_node_timeout;
+            g_data.cluster.check_failures(get_monotonic_time_ms(), node_timeout_ms);
+        });
+        thread::sleep(GOSSIP_PERIOD);
+    }
+}
+
+fn serve_peer(mut stream: TcpStream) -> io::Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut chunk)?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        while let Some((args, used)) =
+            parse_frame(&buf, &LIMITS).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        {
+            buf.drain(..used);
+            let reply = match Message::parse(&args) {
+                Some(msg) => receive(&msg, msg.kind == "MEET").to_reply(),
+                None => RedisValue::Err("ERR malformed cluster bus message".to_string()),
+            };
+            let mut body = Vec::new();
+            reply.encode(&mut body);
+            let mut frame = (body.len() as u32).to_le_bytes().to_vec();
+            frame.extend_from_slice(&body);
+            stream.write_all(&frame)?;
+        }
+    }
+}
+
+/// Listen on the bus port for the client port `port` and start gossiping
+pub fn start(bind: Option<IpAddr>, port: u16) -> io::Result<()> {
+    let port = bus_port(port).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "port too high for a cluster bus port"))?;
+    let listener = TcpListener::bind((bind.unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED)), port))?;
+    log_notice!("cluster", "Cluster bus listening on port {}", port);
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                if let Err(e) = serve_peer(stream) {
+                    log_debug!("cluster", "Cluster bus peer error: {}", e);
+                }
+            });
+        }
+    });
+    thread::spawn(|| ping_loop());
+    Ok(())
+}
@@ -0,0 +1,57 @@
+/* In-process server */
+//
+// ServerHandle runs commands on this process's dataset without a socket:
+//
+//   let server = ServerHandle::new(Config::default());
+//   server.execute(&["SET", "greeting", "hello"]);
+//   assert_eq!(server.execute(&["GET", "greeting"]), RedisValue::Str("hello".into()));
+//
+// Commands go through execute_command, the entry point requests from the
+// network and from scripts take too, so limits, cluster redirects, the AOF
+// and client tracking apply the same way, and the reply is the value a
+// client would decode. There's no client behind a handle: a blocking command
+// acts as if it timed out, as it does in a script.
+//
+// `redis bench --in-process` drives the command handlers this way, to measure
+// them and the data structures under them without the network stack.
+
+use redis::protocol::RedisValue;
+
+use crate::config::Config;
+use crate::{blocking, evict, execute_command, logging, with_global_data, Buffer};
+
+/// The server in this process. Every handle shares the one dataset and can
+/// be used from any thread; commands are serialized on the global lock.
+#[derive(Debug, Clone, Copy)]
+pub struct ServerHandle(());
+
+impl ServerHandle {
+    /// Start serving in-process with `config`, replacing the running one
+    pub fn new(config: Config) -> ServerHandle {
+        with_global_data(|g_data| {
+            g_data.config = config;
+            logging::configure(&g_data.config);
+            g_data.db.table_mut().set_lookup_filter(g_data.config.lookup_filter);
+            evict::configure(&g_data.config);
+        });
+        ServerHandle(())
+    }
+
+    /// Run one command and return its reply, errors included
+    pub fn execute(&self, args: &[&str]) -> RedisValue {
+        let parts: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        execute_value(&parts)
+    }
+}
+
+/// Run one command outside of any client and decode its reply
+pub fn execute_value(parts: &[String]) -> RedisValue {
+    if parts.is_empty() {
+        return RedisValue::Err("ERR empty command".to_string());
+    }
+    let mut buf = Buffer::new();
+    blocking::without_client(|| execute_command(parts, &mut buf));
+    RedisValue::decode(&buf)
+        .map(|(value, _)| value)
+        .unwrap_or_else(|| RedisValue::Err("ERR malformed reply".to_string()))
+}
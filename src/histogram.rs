@@ -0,0 +1,105 @@
+/* Latency histogram */
+//
+// Log-linear buckets in the style of HdrHistogram: values below 32 get a
+// bucket each, and every power of two above that is split into 32 equal
+// sub-buckets. Any recorded value is off by at most 1/32 (~3%) when read
+// back, and the whole u64 range fits in under 2000 counters.
+
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let exponent = 63 - value.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let sub = (value >> shift) as usize & (SUB_BUCKETS - 1);
+    ((shift as usize + 1) << SUB_BUCKET_BITS) + sub
+}
+
+// Smallest value that lands in bucket `index`
+fn bucket_low(index: usize) -> u64 {
+    let group = index >> SUB_BUCKET_BITS;
+    let sub = (index & (SUB_BUCKETS - 1)) as u64;
+    if group == 0 {
+        sub
+    } else {
+        (SUB_BUCKETS as u64 + sub) << (group - 1)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; BUCKETS],
+            total: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.counts[bucket_index(value)] += 1;
+        self.total += 1;
+        self.sum += value as u128;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Add all of `other`'s samples to this histogram
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other_count) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other_count;
+        }
+        self.total += other.total;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    pub fn min(&self) -> u64 {
+        if self.total == 0 { 0 } else { self.min }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.total == 0 { 0.0 } else { self.sum as f64 / self.total as f64 }
+    }
+
+    /// Value at or below which `percentile`% of the samples fall, rounded up
+    /// to the top of its bucket (but never past the largest sample)
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+        let rank = ((percentile / 100.0) * self.total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let high = if index + 1 < BUCKETS { bucket_low(index + 1) - 1 } else { u64::MAX };
+                return high.min(self.max);
+            }
+        }
+        self.max
+    }
+}
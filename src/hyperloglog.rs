@@ -0,0 +1,265 @@
+/* HyperLogLog */
+//
+// Dense HyperLogLog with 2^14 registers. The registers are stored one per byte
+// behind a "HYLL" magic prefix, so the whole sketch is plain ASCII and lives in
+// an ordinary `Value::Str` (GET/SET/DEL treat it like any other string).
+
+use crate::command::{CmdResult, WRONGTYPE};
+use crate::{out_err, out_int, out_nil, with_global_data, with_global_data_read, Buffer, Value};
+
+const HLL_P: u32 = 14;
+const HLL_Q: u32 = 64 - HLL_P;
+const HLL_REGISTERS: usize = 1 << HLL_P;
+const HLL_MAGIC: &str = "HYLL";
+const HLL_SEED: u64 = 0xadc83b19;
+
+const WRONGTYPE_HLL: &str = "WRONGTYPE Key is not a valid HyperLogLog string value.";
+
+#[derive(Debug, Clone)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        Self {
+            registers: vec![0; HLL_REGISTERS],
+        }
+    }
+
+    /// Decode a sketch previously produced by `to_blob`.
+    /// Returns None if the string is not a valid dense HyperLogLog.
+    pub fn from_blob(blob: &str) -> Option<Self> {
+        let registers = blob.strip_prefix(HLL_MAGIC)?.as_bytes();
+        if registers.len() != HLL_REGISTERS {
+            return None;
+        }
+        if registers.iter().any(|&r| r as u32 > HLL_Q + 1) {
+            return None;
+        }
+        Some(Self {
+            registers: registers.to_vec(),
+        })
+    }
+
+    pub fn to_blob(&self) -> String {
+        let mut blob = String::with_capacity(HLL_MAGIC.len() + HLL_REGISTERS);
+        blob.push_str(HLL_MAGIC);
+        // Every register is <= 51, so each byte is a valid ASCII char
+        blob.extend(self.registers.iter().map(|&r| r as char));
+        blob
+    }
+
+    /// Add an element, returns true if a register changed
+    pub fn add(&mut self, element: &[u8]) -> bool {
+        let (index, count) = hll_pattern(element);
+        if count > self.registers[index] {
+            self.registers[index] = count;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Register-wise max, i.e. the sketch of the union of both sets
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (mine, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            if *theirs > *mine {
+                *mine = *theirs;
+            }
+        }
+    }
+
+    /// Cardinality estimate using Ertl's improved estimator (no bias tables needed)
+    pub fn count(&self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        let mut histogram = [0u32; HLL_Q as usize + 2];
+        for &r in &self.registers {
+            histogram[r as usize] += 1;
+        }
+
+        let mut z = m * hll_tau((m - histogram[HLL_Q as usize + 1] as f64) / m);
+        for j in (1..=HLL_Q as usize).rev() {
+            z += histogram[j] as f64;
+            z *= 0.5;
+        }
+        z += m * hll_sigma(histogram[0] as f64 / m);
+
+        let alpha_inf = 0.5 / std::f64::consts::LN_2;
+        (alpha_inf * m * m / z).round() as u64
+    }
+}
+
+// Returns (register index, run length of zeros + 1) for an element
+fn hll_pattern(element: &[u8]) -> (usize, u8) {
+    let hash = murmurhash64a(element, HLL_SEED);
+    let index = (hash as usize) & (HLL_REGISTERS - 1);
+    // Sentinel bit guarantees termination within HLL_Q bits
+    let rest = (hash >> HLL_P) | (1u64 << HLL_Q);
+    (index, rest.trailing_zeros() as u8 + 1)
+}
+
+fn hll_sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let z_prev = z;
+        z += x * y;
+        y += y;
+        if z_prev == z {
+            return z;
+        }
+    }
+}
+
+fn hll_tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let z_prev = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z_prev == z {
+            return z / 3.0;
+        }
+    }
+}
+
+/// MurmurHash64A, kept separate from the keyspace hash so that sketches stay
+/// valid across restarts regardless of how the hash table is seeded.
+fn murmurhash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, &b) in tail.iter().enumerate() {
+            h ^= (b as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+// Load the sketch stored in `value`, or the error to reply with
+fn hll_from_value(value: &Value) -> Result<HyperLogLog, &'static str> {
+    match value {
+        Value::Str(s) => HyperLogLog::from_blob(s).ok_or(WRONGTYPE_HLL),
+        _ => Err(WRONGTYPE),
+    }
+}
+
+// PFADD key [element ...]
+pub fn do_pfadd(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 2 {
+        out_err(out, "PFADD requires: key [element ...]");
+        return Ok(());
+    }
+
+    let key = &cmd[1];
+
+    with_global_data(|g_data| {
+        let (mut hll, created) = match g_data.db.peek_entry(key).map(|entry| hll_from_value(&entry.value)) {
+            Some(Ok(hll)) => (hll, false),
+            Some(Err(msg)) => {
+                out_err(out, msg);
+                return;
+            }
+            None => (HyperLogLog::new(), true),
+        };
+
+        let mut changed = created;
+        for element in &cmd[2..] {
+            changed |= hll.add(element.as_bytes());
+        }
+
+        if changed {
+            g_data.with_entry_mut(key, |slot| *slot = Some(Value::Str(hll.to_blob().into())));
+        }
+        out_int(out, changed as i64);
+    });
+
+    Ok(())
+}
+
+// PFCOUNT key [key ...]
+pub fn do_pfcount(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 2 {
+        out_err(out, "PFCOUNT requires at least one key");
+        return Ok(());
+    }
+
+    with_global_data_read(|g_data| {
+        // Counting several keys estimates the cardinality of their union
+        let mut union = HyperLogLog::new();
+        for key in &cmd[1..] {
+            if let Some(entry) = g_data.db.lookup_entry(key) {
+                match hll_from_value(&entry.value) {
+                    Ok(hll) => union.merge(&hll),
+                    Err(msg) => {
+                        out_err(out, msg);
+                        return;
+                    }
+                }
+            }
+        }
+        out_int(out, union.count() as i64);
+    });
+
+    Ok(())
+}
+
+// PFMERGE destkey [sourcekey ...]
+pub fn do_pfmerge(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 2 {
+        out_err(out, "PFMERGE requires: destkey [sourcekey ...]");
+        return Ok(());
+    }
+
+    let dest = &cmd[1];
+
+    with_global_data(|g_data| {
+        // The destination takes part in the union if it already exists
+        let mut merged = HyperLogLog::new();
+        for key in &cmd[1..] {
+            if let Some(entry) = g_data.db.lookup_entry(key) {
+                match hll_from_value(&entry.value) {
+                    Ok(hll) => merged.merge(&hll),
+                    Err(msg) => {
+                        out_err(out, msg);
+                        return;
+                    }
+                }
+            }
+        }
+
+        g_data.with_entry_mut(dest, |slot| *slot = Some(Value::Str(merged.to_blob().into())));
+        out_nil(out); // Like SET, returns nil on success
+    });
+
+    Ok(())
+}
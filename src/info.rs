@@ -0,0 +1,241 @@
+/* INFO */
+//
+// INFO [section ...] reports on the server as "name:value" lines grouped
+// under "# Section" headers, as Redis does:
+//
+//   # Server
+//   redis_version:0.1.0
+//   ...
+//
+// Without arguments, or with "default", "all" or "everything", every section
+// is included; an unknown section adds nothing. Counters that commands and
+// the event loop keep along the way are in ServerStats, inside GData; the
+// commandstats and latencystats sections come from latency.rs. CONFIG
+// RESETSTAT starts them all over, with the keyspace hits and misses.
+//
+// The loading section follows the snapshot or AOF loaded at startup: how far
+// it got and how many of its records were skipped as corrupt. The same
+// progress is logged once a second while it loads.
+
+use redis::protocol::ProtocolError;
+
+use crate::command::CmdResult;
+use crate::events::ServerEvent;
+use crate::logging::{log_notice, log_warning};
+use crate::{get_current_time_ms, get_monotonic_time_ms, out_str, with_global_data, with_global_data_read, Buffer, GData};
+
+const SECTIONS: &[&str] = &["server", "clients", "stats", "replication", "loading", "commandstats", "latencystats"];
+
+// Load progress is logged at most this often
+const LOADING_LOG_INTERVAL_MS: u64 = 1000;
+
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    /// Requests refused for going over max-request-size
+    pub rejected_request_size: u64,
+    /// ... over proto-max-multibulk-len
+    pub rejected_args: u64,
+    /// ... over proto-max-bulk-len
+    pub rejected_value_len: u64,
+    /// Commands refused for naming a key over max-key-len
+    pub rejected_key_len: u64,
+    /// Commands refused for adding more than max-zset-members-per-command
+    pub rejected_zset_members: u64,
+    /// Keys removed by DEL, or moved away by MIGRATE
+    pub deleted_keys: u64,
+    /// Keys removed as their TTL passed
+    pub expired_keys: u64,
+    /// Keys removed to stay under maxmemory
+    pub evicted_keys: u64,
+    /// Keys whose value SET or RESTORE REPLACE overwrote
+    pub replaced_keys: u64,
+}
+
+impl ServerStats {
+    /// Count a request the parser refused for one of the limits
+    pub fn count_protocol_error(&mut self, e: &ProtocolError) {
+        match e {
+            ProtocolError::RequestTooLarge { .. } => self.rejected_request_size += 1,
+            ProtocolError::TooManyArgs { .. } => self.rejected_args += 1,
+            ProtocolError::ArgTooLong { .. } => self.rejected_value_len += 1,
+            _ => {}
+        }
+    }
+}
+
+/// Count the keys that expire
+pub fn on_event(g_data: &mut GData, event: &ServerEvent) {
+    if let ServerEvent::KeyExpired { .. } = event {
+        g_data.stats.expired_keys += 1;
+    }
+}
+
+/// The snapshot or AOF being loaded, or the last one loaded
+#[derive(Debug, Default)]
+pub struct Loading {
+    active: bool,
+    file: String,
+    /// Unix time in seconds
+    start_time: u64,
+    /// Monotonic ms
+    started_ms: u64,
+    duration_ms: u64,
+    total_bytes: u64,
+    loaded_bytes: u64,
+    keys: u64,
+    skipped: u64,
+    last_log_ms: u64,
+}
+
+impl Loading {
+    fn percent(&self) -> f64 {
+        if self.total_bytes == 0 { 100.0 } else { self.loaded_bytes as f64 * 100.0 / self.total_bytes as f64 }
+    }
+}
+
+/// Note that `file`, `total_bytes` long, starts loading
+pub fn loading_start(file: &str, total_bytes: usize) {
+    let now_ms = get_monotonic_time_ms();
+    with_global_data(|g_data| {
+        g_data.loading = Loading {
+            active: true,
+            file: file.to_string(),
+            start_time: get_current_time_ms() / 1000,
+            started_ms: now_ms,
+            total_bytes: total_bytes as u64,
+            last_log_ms: now_ms,
+            ..Loading::default()
+        };
+    });
+    log_notice!("persist", "Loading {} ({} bytes)", file, total_bytes);
+}
+
+/// Note that the load got to `loaded_bytes`, logging it now and then
+pub fn loading_progress(loaded_bytes: usize) {
+    let now_ms = get_monotonic_time_ms();
+    with_global_data(|g_data| {
+        let keys = g_data.db.size() as u64;
+        let loading = &mut g_data.loading;
+        loading.loaded_bytes = loaded_bytes as u64;
+        loading.keys = keys;
+        if now_ms - loading.last_log_ms >= LOADING_LOG_INTERVAL_MS {
+            loading.last_log_ms = now_ms;
+            log_notice!(
+                "persist",
+                "Loading {}: {:.1}% ({} of {} bytes), {} keys",
+                loading.file, loading.percent(), loading.loaded_bytes, loading.total_bytes, loading.keys
+            );
+        }
+    });
+}
+
+/// Note a record skipped for being corrupt, under load-skip-corrupt
+pub fn loading_skipped() {
+    with_global_data(|g_data| g_data.loading.skipped += 1);
+}
+
+/// Note that the load is over, whether it finished or failed
+pub fn loading_end() {
+    let now_ms = get_monotonic_time_ms();
+    with_global_data(|g_data| {
+        let keys = g_data.db.size() as u64;
+        let loading = &mut g_data.loading;
+        loading.active = false;
+        loading.keys = keys;
+        loading.duration_ms = now_ms - loading.started_ms;
+        if loading.skipped > 0 {
+            log_warning!(
+                "persist",
+                "Skipped {} corrupt records of {}, {} keys loaded",
+                loading.skipped, loading.file, loading.keys
+            );
+        }
+    });
+}
+
+fn section(g_data: &GData, name: &str) -> Vec<(&'static str, String)> {
+    let stats = &g_data.stats;
+    let (hits, misses, filtered) = g_data.db.table().lookup_stats();
+    match name {
+        "server" => vec![
+            ("redis_version", env!("CARGO_PKG_VERSION").to_string()),
+            ("process_id", std::process::id().to_string()),
+            ("tcp_port", g_data.config.port.to_string()),
+            ("uptime_in_seconds", (get_monotonic_time_ms() / 1000).to_string()),
+        ],
+        // The client asking is out of the table while it is served
+        "clients" => vec![("connected_clients", (g_data.fd2conn.len() + 1).to_string())],
+        "stats" => vec![
+            ("rejected_request_size", stats.rejected_request_size.to_string()),
+            ("rejected_args", stats.rejected_args.to_string()),
+            ("rejected_value_len", stats.rejected_value_len.to_string()),
+            ("rejected_key_len", stats.rejected_key_len.to_string()),
+            ("rejected_zset_members", stats.rejected_zset_members.to_string()),
+            ("deleted_keys", stats.deleted_keys.to_string()),
+            ("expired_keys", stats.expired_keys.to_string()),
+            ("evicted_keys", stats.evicted_keys.to_string()),
+            ("replaced_keys", stats.replaced_keys.to_string()),
+            ("keyspace_hits", hits.to_string()),
+            ("keyspace_misses", misses.to_string()),
+            ("keyspace_hit_rate", format!("{:.2}", hits as f64 * 100.0 / (hits + misses).max(1) as f64)),
+            ("lookup_filter_skips", filtered.to_string()),
+        ],
+        "replication" => {
+            let mut fields = g_data.replication.info();
+            fields.push(("repl_backlog_size", g_data.config.repl_backlog_size.to_string()));
+            fields.push(("master_failover_state", g_data.failover.state().to_string()));
+            fields
+        }
+        "loading" => {
+            let loading = &g_data.loading;
+            let duration_ms =
+                if loading.active { get_monotonic_time_ms() - loading.started_ms } else { loading.duration_ms };
+            vec![
+                ("loading", (loading.active as u8).to_string()),
+                ("loading_file", loading.file.clone()),
+                ("loading_start_time", loading.start_time.to_string()),
+                ("loading_duration_ms", duration_ms.to_string()),
+                ("loading_total_bytes", loading.total_bytes.to_string()),
+                ("loading_loaded_bytes", loading.loaded_bytes.to_string()),
+                ("loading_loaded_perc", format!("{:.2}", loading.percent())),
+                ("loading_loaded_keys", loading.keys.to_string()),
+                ("loading_skipped_records", loading.skipped.to_string()),
+            ]
+        }
+        _ => Vec::new(),
+    }
+}
+
+// INFO [section ...]
+pub fn do_info(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let asked: Vec<String> = cmd[1..].iter().map(|arg| arg.to_lowercase()).collect();
+    let everything = asked.is_empty() || asked.iter().any(|s| matches!(s.as_str(), "default" | "all" | "everything"));
+
+    let text = with_global_data_read(|g_data| {
+        let mut text = String::new();
+        for &name in SECTIONS.iter().filter(|&&name| everything || asked.iter().any(|s| s == name)) {
+            if !text.is_empty() {
+                text.push_str("\r\n");
+            }
+            // "# Server"
+            text.push_str(&format!("# {}{}\r\n", name[..1].to_uppercase(), &name[1..]));
+            for (field, value) in section(g_data, name) {
+                text.push_str(&format!("{}:{}\r\n", field, value));
+            }
+            // Numbered or named after commands, so not among the fields
+            let lines = match name {
+                "replication" => g_data.replication.replica_info().iter().enumerate().map(|(i, line)| format!("slave{}:{}", i, line)).collect(),
+                "commandstats" => g_data.command_stats.info(),
+                "latencystats" => g_data.command_stats.latency_info(),
+                _ => Vec::new(),
+            };
+            for line in lines {
+                text.push_str(&line);
+                text.push_str("\r\n");
+            }
+        }
+        text
+    });
+    out_str(out, &text);
+    Ok(())
+}
@@ -0,0 +1,151 @@
+/* Command statistics */
+//
+// Every command run is counted under its name, with the time its handler
+// took recorded in a latency histogram (see histogram.rs), kept for each
+// command from its first call. They're reported as Redis does, by
+//
+//   INFO commandstats   cmdstat_<name>:calls=..,usec=..,usec_per_call=..,failed_calls=..
+//   INFO latencystats   latency_percentiles_usec_<name>:p50=..,p99=..,p99.9=..
+//
+// and by LATENCY PERCENTILES [command ...], which gives for each command
+// asked for (all those called, without arguments) its calls and the same
+// percentiles. Times are in microseconds, and a percentile is off by at most
+// the histogram's ~3%. A call that replied with an error counts as failed;
+// commands refused before running (wrong arity, OOM, READONLY, redirected)
+// aren't counted at all. CONFIG RESETSTAT starts everything over.
+//
+// The statistics have a lock of their own, so read-only commands are
+// counted under the shared lock of the global data (see events.rs).
+
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::command::{self, CmdResult};
+use crate::events::ServerEvent;
+use crate::histogram::Histogram;
+use crate::{out_arr, out_err, out_int, out_str, with_global_data_read, Buffer, GData};
+
+/// Percentiles reported, with their names
+const PERCENTILES: [(&str, f64); 3] = [("p50", 50.0), ("p99", 99.0), ("p99.9", 99.9)];
+
+#[derive(Debug, Default)]
+pub struct CommandStats {
+    // By command name
+    commands: Mutex<HashMap<&'static str, CommandStat>>,
+}
+
+#[derive(Debug)]
+struct CommandStat {
+    calls: u64,
+    failed_calls: u64,
+    usec: u64,
+    latency_usec: Histogram,
+}
+
+impl CommandStats {
+    /// Count a call of `name` that took `usec`
+    pub fn record(&self, name: &'static str, usec: u64, failed: bool) {
+        let mut commands = self.lock();
+        let stat = commands.entry(name).or_insert_with(|| CommandStat {
+            calls: 0,
+            failed_calls: 0,
+            usec: 0,
+            latency_usec: Histogram::new(),
+        });
+        stat.calls += 1;
+        stat.failed_calls += failed as u64;
+        stat.usec += usec;
+        stat.latency_usec.record(usec);
+    }
+
+    /// Forget every call, for CONFIG RESETSTAT
+    pub fn reset(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<&'static str, CommandStat>> {
+        self.commands.lock().unwrap()
+    }
+}
+
+/// The commands called, by name
+fn sorted<'a>(commands: &'a HashMap<&'static str, CommandStat>) -> Vec<(&'static str, &'a CommandStat)> {
+    let mut commands: Vec<_> = commands.iter().map(|(&name, stat)| (name, stat)).collect();
+    commands.sort_by_key(|&(name, _)| name);
+    commands
+}
+
+impl CommandStats {
+
+    /// The lines of INFO commandstats
+    pub fn info(&self) -> Vec<String> {
+        sorted(&self.lock())
+            .into_iter()
+            .map(|(name, stat)| {
+                format!(
+                    "cmdstat_{}:calls={},usec={},usec_per_call={:.2},failed_calls={}",
+                    name,
+                    stat.calls,
+                    stat.usec,
+                    stat.usec as f64 / stat.calls as f64,
+                    stat.failed_calls
+                )
+            })
+            .collect()
+    }
+
+    /// The lines of INFO latencystats
+    pub fn latency_info(&self) -> Vec<String> {
+        sorted(&self.lock())
+            .into_iter()
+            .map(|(name, stat)| {
+                let percentiles: Vec<String> = PERCENTILES
+                    .iter()
+                    .map(|&(label, percentile)| format!("{}={}", label, stat.latency_usec.percentile(percentile)))
+                    .collect();
+                format!("latency_percentiles_usec_{}:{}", name, percentiles.join(","))
+            })
+            .collect()
+    }
+}
+
+// LATENCY PERCENTILES [command ...]
+pub fn do_latency(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if !cmd[1].eq_ignore_ascii_case("PERCENTILES") {
+        out_err(out, &format!("ERR unknown subcommand '{}'. Try LATENCY PERCENTILES.", cmd[1]));
+        return Ok(());
+    }
+    with_global_data_read(|g_data| {
+        let stats = g_data.command_stats.lock();
+        let wanted: Vec<(&'static str, &CommandStat)> = if cmd.len() == 2 {
+            sorted(&stats)
+        } else {
+            // Unknown and never called commands are left out
+            cmd[2..]
+                .iter()
+                .filter_map(|name| command::lookup(name))
+                .filter_map(|command| Some((command.name, stats.get(command.name)?)))
+                .collect()
+        };
+        // name => [calls, n, p50, usec, p99, usec, p99.9, usec]
+        out_arr(out, wanted.len() as u32 * 2);
+        for (name, stat) in wanted {
+            out_str(out, name);
+            out_arr(out, 2 + PERCENTILES.len() as u32 * 2);
+            out_str(out, "calls");
+            out_int(out, stat.calls as i64);
+            for (label, percentile) in PERCENTILES {
+                out_str(out, label);
+                out_int(out, stat.latency_usec.percentile(percentile) as i64);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Count every command that ran
+pub fn on_event(g_data: &GData, event: &ServerEvent) {
+    if let ServerEvent::CommandExecuted { command, usec, failed, .. } = *event {
+        g_data.command_stats.record(command.name, usec, failed);
+    }
+}
@@ -0,0 +1,11 @@
+/* Library */
+//
+// The server is the `redis` binary (main.rs). The library side holds what a
+// program needs to talk to it: the wire protocol types, a client, and a
+// cluster client that finds the node serving each key.
+
+pub mod client;
+pub mod cluster_client;
+mod crc16;
+pub mod protocol;
+pub mod slot;
@@ -0,0 +1,368 @@
+/* Lists */
+//
+// Lists are a VecDeque of strings, so pushes and pops at either end are O(1).
+// An emptied list is deleted, as in Redis. Every push signals the key ready
+// so clients blocked in BLMOVE or BLMPOP on it get served.
+
+use std::collections::VecDeque;
+
+use crate::command::{CmdError, CmdResult};
+use crate::{
+    expect_list_mut, get_monotonic_time_ms, out_arr, out_err, out_int, out_nil, out_str, with_global_data, with_global_data_read, Buffer,
+    GData,
+};
+
+#[derive(Debug, Clone, Copy)]
+enum End {
+    Left,
+    Right,
+}
+
+impl End {
+    fn parse(s: &str) -> Option<End> {
+        match s.to_uppercase().as_str() {
+            "LEFT" => Some(End::Left),
+            "RIGHT" => Some(End::Right),
+            _ => None,
+        }
+    }
+}
+
+fn list_push(list: &mut VecDeque<String>, end: End, element: String) {
+    match end {
+        End::Left => list.push_front(element),
+        End::Right => list.push_back(element),
+    }
+}
+
+fn list_pop(list: &mut VecDeque<String>, end: End) -> Option<String> {
+    match end {
+        End::Left => list.pop_front(),
+        End::Right => list.pop_back(),
+    }
+}
+
+/// Pop up to `count` elements from `key`, deleting the list once it's empty.
+/// Ok(None) if the key doesn't exist.
+fn pop_generic(g_data: &mut GData, key: &str, end: End, count: usize) -> Result<Option<Vec<String>>, CmdError> {
+    expect_list_mut(g_data, key, false, |list| {
+        let mut popped = Vec::with_capacity(count.min(list.len()));
+        while popped.len() < count {
+            match list_pop(list, end) {
+                Some(element) => popped.push(element),
+                None => break,
+            }
+        }
+        popped
+    })
+}
+
+/// Move one element from `src` to `dst`, returning it. Ok(None) if `src` doesn't exist.
+fn move_generic(g_data: &mut GData, src: &str, dst: &str, from: End, to: End) -> Result<Option<String>, CmdError> {
+    // Both types are checked before anything is popped
+    g_data.db.expect_list(src)?;
+    g_data.db.expect_list(dst)?;
+
+    let Some(mut popped) = pop_generic(g_data, src, from, 1)? else {
+        return Ok(None);
+    };
+    let element = popped.pop().expect("lists are never stored empty");
+    expect_list_mut(g_data, dst, true, |list| list_push(list, to, element.clone()))?;
+
+    Ok(Some(element))
+}
+
+fn push_generic(cmd: &[String], out: &mut Buffer, end: End) -> CmdResult {
+    if cmd.len() < 3 {
+        out_err(out, "PUSH requires: key element [element ...]");
+        return Ok(());
+    }
+
+    let key = &cmd[1];
+
+    with_global_data(|g_data| {
+        let len = expect_list_mut(g_data, key, true, |list| {
+            for element in &cmd[2..] {
+                list_push(list, end, element.clone());
+            }
+            list.len()
+        })?;
+        out_int(out, len.unwrap_or(0) as i64);
+        Ok(())
+    })
+}
+
+// LPUSH key element [element ...]
+pub fn do_lpush(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    push_generic(cmd, out, End::Left)
+}
+
+// RPUSH key element [element ...]
+pub fn do_rpush(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    push_generic(cmd, out, End::Right)
+}
+
+fn pop_command(cmd: &[String], out: &mut Buffer, end: End) -> CmdResult {
+    if cmd.len() < 2 || cmd.len() > 3 {
+        out_err(out, "POP requires: key [count]");
+        return Ok(());
+    }
+
+    // Without COUNT the reply is a single element rather than an array
+    let count = match cmd.get(2) {
+        None => None,
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(count) => Some(count),
+            Err(_) => {
+                out_err(out, "ERR value is out of range, must be positive");
+                return Ok(());
+            }
+        },
+    };
+
+    with_global_data(|g_data| {
+        match pop_generic(g_data, &cmd[1], end, count.unwrap_or(1))? {
+            None => out_nil(out),
+            Some(popped) => match count {
+                None => out_str(out, &popped[0]),
+                Some(_) => {
+                    out_arr(out, popped.len() as u32);
+                    for element in &popped {
+                        out_str(out, element);
+                    }
+                }
+            },
+        }
+        Ok(())
+    })
+}
+
+// LPOP key [count]
+pub fn do_lpop(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    pop_command(cmd, out, End::Left)
+}
+
+// RPOP key [count]
+pub fn do_rpop(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    pop_command(cmd, out, End::Right)
+}
+
+/// Parse "numkeys key [key ...] <where> [COUNT count]" as LMPOP and ZMPOP
+/// take it, into the keys, the <where> argument and the count (1 without COUNT)
+pub fn parse_mpop(args: &[String]) -> Result<(&[String], &str, usize), &'static str> {
+    let numkeys = match args.first().map(|arg| arg.parse::<usize>()) {
+        Some(Ok(numkeys)) if numkeys > 0 => numkeys,
+        _ => return Err("ERR numkeys should be greater than 0"),
+    };
+    let (Some(keys), Some(end)) = (args.get(1..=numkeys), args.get(numkeys + 1)) else {
+        return Err("ERR syntax error");
+    };
+    let count = match &args[numkeys + 2..] {
+        [] => 1,
+        [keyword, count] if keyword.eq_ignore_ascii_case("COUNT") => match count.parse::<usize>() {
+            Ok(count) if count > 0 => count,
+            _ => return Err("ERR count should be greater than 0"),
+        },
+        _ => return Err("ERR syntax error"),
+    };
+    Ok((keys, end, count))
+}
+
+/// Pop up to `count` elements from the first of `keys` holding a list, and
+/// reply [key, [element ...]], or nil if none does. Returns whether it
+/// replied.
+fn mpop_generic(g_data: &mut GData, out: &mut Buffer, keys: &[String], end: End, count: usize) -> Result<bool, CmdError> {
+    for key in keys {
+        if let Some(popped) = pop_generic(g_data, key, end, count)? {
+            out_arr(out, 2);
+            out_str(out, key);
+            out_arr(out, popped.len() as u32);
+            for element in &popped {
+                out_str(out, element);
+            }
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// LMPOP numkeys key [key ...] LEFT|RIGHT [COUNT count]
+pub fn do_lmpop(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let (keys, end, count) = match parse_mpop(&cmd[1..]) {
+        Ok(parsed) => parsed,
+        Err(msg) => {
+            out_err(out, msg);
+            return Ok(());
+        }
+    };
+    let Some(end) = End::parse(end) else {
+        out_err(out, "ERR syntax error");
+        return Ok(());
+    };
+
+    with_global_data(|g_data| {
+        if !mpop_generic(g_data, out, keys, end, count)? {
+            out_nil(out);
+        }
+        Ok(())
+    })
+}
+
+// BLMPOP timeout numkeys key [key ...] LEFT|RIGHT [COUNT count]
+//
+// Waits on all the keys at once; whichever gets data first is popped from.
+pub fn do_blmpop(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let timeout_ms = match parse_timeout(&cmd[1]) {
+        Ok(ms) => ms,
+        Err(msg) => {
+            out_err(out, msg);
+            return Ok(());
+        }
+    };
+    let (keys, end, count) = match parse_mpop(&cmd[2..]) {
+        Ok(parsed) => parsed,
+        Err(msg) => {
+            out_err(out, msg);
+            return Ok(());
+        }
+    };
+    let Some(end) = End::parse(end) else {
+        out_err(out, "ERR syntax error");
+        return Ok(());
+    };
+
+    with_global_data(|g_data| {
+        if !mpop_generic(g_data, out, keys, end, count)? {
+            let now_ms = get_monotonic_time_ms();
+            if !g_data.blocking.block(cmd, keys, timeout_ms, now_ms) {
+                out_nil(out);
+            }
+        }
+        Ok(())
+    })
+}
+
+// LLEN key
+pub fn do_llen(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() != 2 {
+        out_err(out, "LLEN requires a key");
+        return Ok(());
+    }
+
+    with_global_data_read(|g_data| {
+        out_int(out, g_data.db.expect_list(&cmd[1])?.map_or(0, |list| list.len()) as i64);
+        Ok(())
+    })
+}
+
+// LRANGE key start stop
+pub fn do_lrange(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() != 4 {
+        out_err(out, "LRANGE requires: key start stop");
+        return Ok(());
+    }
+
+    let (Ok(start), Ok(stop)) = (cmd[2].parse::<i64>(), cmd[3].parse::<i64>()) else {
+        out_err(out, "ERR value is not an integer or out of range");
+        return Ok(());
+    };
+
+    with_global_data_read(|g_data| {
+        let Some(list) = g_data.db.expect_list(&cmd[1])? else {
+            out_arr(out, 0);
+            return Ok(());
+        };
+        // Negative indexes count from the end; clamp to the list
+        let len = list.len() as i64;
+        let start = if start < 0 { (len + start).max(0) } else { start };
+        let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+        if start > stop || start >= len {
+            out_arr(out, 0);
+            return Ok(());
+        }
+        out_arr(out, (stop - start + 1) as u32);
+        for element in list.range(start as usize..=stop as usize) {
+            out_str(out, element);
+        }
+        Ok(())
+    })
+}
+
+fn out_move_result(out: &mut Buffer, moved: Option<String>) {
+    match moved {
+        Some(element) => out_str(out, &element),
+        None => out_nil(out),
+    }
+}
+
+// LMOVE source destination LEFT|RIGHT LEFT|RIGHT
+pub fn do_lmove(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() != 5 {
+        out_err(out, "LMOVE requires: source destination LEFT|RIGHT LEFT|RIGHT");
+        return Ok(());
+    }
+
+    let (Some(from), Some(to)) = (End::parse(&cmd[3]), End::parse(&cmd[4])) else {
+        out_err(out, "ERR syntax error");
+        return Ok(());
+    };
+
+    let moved = with_global_data(|g_data| move_generic(g_data, &cmd[1], &cmd[2], from, to))?;
+    out_move_result(out, moved);
+    Ok(())
+}
+
+// RPOPLPUSH source destination
+pub fn do_rpoplpush(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() != 3 {
+        out_err(out, "RPOPLPUSH requires: source destination");
+        return Ok(());
+    }
+
+    let moved = with_global_data(|g_data| move_generic(g_data, &cmd[1], &cmd[2], End::Right, End::Left))?;
+    out_move_result(out, moved);
+    Ok(())
+}
+
+/// Parse a blocking timeout in seconds (fractions allowed) into ms
+pub fn parse_timeout(arg: &str) -> Result<u64, &'static str> {
+    match arg.parse::<f64>() {
+        Ok(secs) if secs < 0.0 => Err("ERR timeout is negative"),
+        Ok(secs) if secs.is_finite() => Ok((secs * 1000.0).ceil() as u64),
+        _ => Err("ERR timeout is not a float or out of range"),
+    }
+}
+
+// BLMOVE source destination LEFT|RIGHT LEFT|RIGHT timeout
+pub fn do_blmove(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() != 6 {
+        out_err(out, "BLMOVE requires: source destination LEFT|RIGHT LEFT|RIGHT timeout");
+        return Ok(());
+    }
+
+    let (Some(from), Some(to)) = (End::parse(&cmd[3]), End::parse(&cmd[4])) else {
+        out_err(out, "ERR syntax error");
+        return Ok(());
+    };
+    let timeout_ms = match parse_timeout(&cmd[5]) {
+        Ok(ms) => ms,
+        Err(msg) => {
+            out_err(out, msg);
+            return Ok(());
+        }
+    };
+
+    with_global_data(|g_data| {
+        match move_generic(g_data, &cmd[1], &cmd[2], from, to)? {
+            None => {
+                // Nothing to move: wait for the source to get data
+                let now_ms = get_monotonic_time_ms();
+                if !g_data.blocking.block(cmd, &cmd[1..2], timeout_ms, now_ms) {
+                    out_nil(out);
+                }
+            }
+            moved => out_move_result(out, moved),
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,216 @@
+/* Logging */
+//
+// Leveled logging in the Redis format, to stdout or the configured logfile:
+//
+//   4242:M 15 Oct 2026 07:12:39.123 * [server] Server listening on [::]:1234
+//
+// Every message has a target naming the subsystem it comes from (server,
+// net, cmd, db, expire, persist). `loglevel` sets the default threshold and
+// `log-targets` overrides it per target, e.g. "net=trace,expire=debug".
+// The macros check the most verbose enabled level first, so a disabled
+// debug/trace call costs one atomic load and never formats its arguments.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 0,
+    Warning = 1,
+    Notice = 2,
+    Debug = 3,
+    Trace = 4,
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Option<Level> {
+        match s.to_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "warning" => Some(Level::Warning),
+            "notice" => Some(Level::Notice),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Notice => "notice",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    // Marker character in the log line, as in Redis where there's one
+    fn mark(self) -> char {
+        match self {
+            Level::Error => '!',
+            Level::Warning => '#',
+            Level::Notice => '*',
+            Level::Debug => '-',
+            Level::Trace => '.',
+        }
+    }
+}
+
+/// Parse a `log-targets` value: comma separated "target=level" pairs
+pub fn parse_targets(s: &str) -> Result<Vec<(String, Level)>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            let (target, level) = item
+                .split_once('=')
+                .ok_or_else(|| format!("expected target=level, got '{}'", item))?;
+            let level = Level::parse(level.trim()).ok_or_else(|| format!("unknown log level '{}'", level))?;
+            Ok((target.trim().to_lowercase(), level))
+        })
+        .collect()
+}
+
+struct Logger {
+    default: Level,
+    targets: Vec<(String, Level)>,
+    // None logs to stdout
+    file: Option<File>,
+}
+
+static LOGGER: Mutex<Logger> = Mutex::new(Logger { default: Level::Notice, targets: Vec::new(), file: None });
+
+// Most verbose level any target has enabled
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Notice as u8);
+
+pub fn max_level() -> Level {
+    match MAX_LEVEL.load(Ordering::Relaxed) {
+        0 => Level::Error,
+        1 => Level::Warning,
+        2 => Level::Notice,
+        3 => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+pub fn open_logfile(path: &str) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Apply the logging parameters of `config`. Values were validated when they
+/// were set; a logfile that can no longer be opened falls back to stdout.
+pub fn configure(config: &Config) {
+    let targets = parse_targets(&config.log_targets).unwrap_or_default();
+    let file = match config.logfile.as_str() {
+        "" => None,
+        path => match open_logfile(path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                eprintln!("Can't open logfile {}: {}, logging to stdout", path, e);
+                None
+            }
+        },
+    };
+
+    let max = targets.iter().map(|&(_, level)| level).fold(config.loglevel, Level::max);
+    let mut logger = LOGGER.lock().unwrap();
+    logger.default = config.loglevel;
+    logger.targets = targets;
+    logger.file = file;
+    MAX_LEVEL.store(max as u8, Ordering::Relaxed);
+}
+
+// "15 Oct 2026 07:12:39.123" in UTC
+fn format_timestamp(unix_ms: u64) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let secs = unix_ms / 1000;
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:02} {} {} {:02}:{:02}:{:02}.{:03}",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        unix_ms % 1000
+    )
+}
+
+/// Write one message if `target` has `level` enabled. Use the macros instead.
+pub fn log(level: Level, target: &str, args: fmt::Arguments) {
+    let mut logger = LOGGER.lock().unwrap();
+    let threshold = logger
+        .targets
+        .iter()
+        .find(|(name, _)| name == target)
+        .map_or(logger.default, |&(_, level)| level);
+    if level > threshold {
+        return;
+    }
+
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+    let line = format!(
+        "{}:M {} {} [{}] {}\n",
+        std::process::id(),
+        format_timestamp(now_ms),
+        level.mark(),
+        target,
+        args
+    );
+    // Nowhere left to report a failed log write
+    let _ = match logger.file.as_mut() {
+        Some(file) => file.write_all(line.as_bytes()),
+        None => io::stdout().lock().write_all(line.as_bytes()),
+    };
+}
+
+macro_rules! log_at {
+    ($level:expr, $target:expr, $($arg:tt)+) => {
+        if $crate::logging::max_level() >= $level {
+            $crate::logging::log($level, $target, format_args!($($arg)+));
+        }
+    };
+}
+
+macro_rules! log_error {
+    ($target:expr, $($arg:tt)+) => { $crate::logging::log_at!($crate::logging::Level::Error, $target, $($arg)+) };
+}
+
+macro_rules! log_warning {
+    ($target:expr, $($arg:tt)+) => { $crate::logging::log_at!($crate::logging::Level::Warning, $target, $($arg)+) };
+}
+
+macro_rules! log_notice {
+    ($target:expr, $($arg:tt)+) => { $crate::logging::log_at!($crate::logging::Level::Notice, $target, $($arg)+) };
+}
+
+macro_rules! log_debug {
+    ($target:expr, $($arg:tt)+) => { $crate::logging::log_at!($crate::logging::Level::Debug, $target, $($arg)+) };
+}
+
+macro_rules! log_trace {
+    ($target:expr, $($arg:tt)+) => { $crate::logging::log_at!($crate::logging::Level::Trace, $target, $($arg)+) };
+}
+
+pub(crate) use {log_at, log_debug, log_error, log_notice, log_trace, log_warning};
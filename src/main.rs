@@ -1,41 +1,112 @@
 /* Imports */
-use std::io::{self, Read, Write};
+use std::io::{self, IoSlice, Read, Write};
 use std::ops::Deref;
-use std::net::SocketAddr;
-use socket2::{Socket, Domain, Type, Protocol, SockAddr};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use socket2::{Socket, Domain, Type, Protocol, SockAddr, TcpKeepalive};
 use std::env;
-use errno::{errno, set_errno, Errno};
+use expire::Expires;
 use nix::poll::{poll, PollFd, PollFlags};
-use std::collections::HashMap;
-use std::os::unix::io::{AsFd, AsRawFd, RawFd};
-use intrusive_collections::{LinkedList, LinkedListLink, intrusive_adapter, linked_list::CursorMut,};
-
-use std::sync::{Arc, Mutex, OnceLock, Condvar};
-use std::cell::RefCell;
-use std::cmp::{Ordering, max};
-use std::rc::Rc;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, RandomState};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use intrusive_collections::{LinkedList, intrusive_adapter, linked_list::{AtomicLink, CursorMut},};
+
+use std::sync::{Arc, Mutex, OnceLock, Condvar, RwLock};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering as AtomicOrdering};
+use std::cmp::Ordering;
 use ordered_float::OrderedFloat;
+use redis::protocol::{RedisValue, Tag};
+
+use events::ServerEvent;
+use store::KvStore;
+use command::{CmdError, CmdResult, WRONGTYPE};
+use logging::{log_debug, log_error, log_notice, log_trace, log_warning};
 
 use std::thread;
 use std::collections::VecDeque;
 
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
+
+mod aof;
+mod audit;
+mod bench;
+mod blocking;
+mod bloom;
+mod clients;
+mod cli;
+mod cluster;
+mod command;
+mod config;
+mod crc64;
+mod daemon;
+mod events;
+mod evict;
+mod expire;
+mod failover;
+mod fdlimit;
+mod geo;
+mod gossip;
+mod handle;
+mod histogram;
+mod hyperloglog;
+mod info;
+mod latency;
+mod list;
+mod logging;
+mod memory;
+mod migrate;
+mod pause;
+mod rdb;
+mod replication;
+mod resp;
+mod scripting;
+mod sentinel;
+mod setops;
+mod signals;
+mod sim;
+mod store;
+mod stream;
+mod timer;
+mod tracking;
+mod zset;
 
 
 /* Constants */
 const BACKLOG: i32 = 128;
-const K_MAX_MSG: usize = 4096;
+// Output is written in chunks of about this size, see Conn::sealed
+const OUT_CHUNK_SIZE: usize = 16 * 1024;
+// Values at least this long are written from the keyspace rather than copied
+const SHARED_MIN_LEN: usize = 16 * 1024;
+// Most chunks handed to one writev
+const MAX_IOVECS: usize = 64;
+// Most reads from one client per event loop iteration
+const MAX_READS_PER_EVENT: usize = 16;
 /// Maximum load factor for chaining hash tables.
 /// A value > 1 is valid because multiple items can occupy one bucket.
-#[allow(dead_code)]
 const K_MAX_LOAD_FACTOR: usize = 8;
-#[allow(dead_code)]
 const K_REHASHING_WORK: usize = 128;
+/// Time the event loop spends on a resized keyspace table, every
+/// K_ACTIVE_REHASH_INTERVAL_MS while it lasts
+const K_ACTIVE_REHASH_BUDGET_US: u64 = 1000;
+const K_ACTIVE_REHASH_INTERVAL_MS: u64 = 10;
+/// Shrink once there are fewer entries than 1/K_MIN_FILL_RATIO of the buckets.
+/// Far below the growth threshold, so a table doesn't bounce between sizes.
+const K_MIN_FILL_RATIO: usize = 8;
+/// Never shrink below the size a fresh keyspace starts with
+const K_MIN_BUCKETS: usize = 16;
 
-const K_IDLE_TIMEOUT_MS: u64 = 5000; // 5 seconds
 const K_MAX_WORKS: usize = 2000;
 const K_LARGE_CONTAINER_SIZE: usize = 1000;
-static GLOBAL_DATA: OnceLock<Mutex<GData>> = OnceLock::new();
+// Everything the server keeps, keyspace included. Commands flagged
+// CMD_READONLY take it shared (with_global_data_read), so readers on other
+// threads don't queue behind each other; everything else takes it exclusive.
+static GLOBAL_DATA: OnceLock<RwLock<GData>> = OnceLock::new();
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
 
 
@@ -46,14 +117,51 @@ struct Buffer {
     data: Vec<u8>,
     start: usize,  // Beginning of valid data
     end: usize,    // End of valid data (exclusive)
+    // Large values spliced into the output without copying them: each goes
+    // right after the first `offset` bytes of data(). Only used once
+    // `share_values` is set, on the outgoing buffer of a framed connection.
+    shared: Vec<(usize, Arc<str>)>,
+    share_values: bool,
+}
+
+/* Buffer pool */
+//
+// Every connection gets a buffer each way and output is cut into
+// OUT_CHUNK_SIZE chunks, so buffers are created and dropped all the time.
+// Dropped ones are kept here and handed to the next Buffer::new instead of
+// going back to the allocator. Only buffers of a modest size are kept, and
+// only so many, so a burst of large replies doesn't pin memory.
+static BUFFER_POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+const BUFFER_INITIAL_CAPACITY: usize = 4096;
+const POOLED_MAX_CAPACITY: usize = 64 * 1024;
+const POOL_MAX_BUFFERS: usize = 1024;
+
+// Keep a buffer's allocation for reuse if it's worth keeping
+fn recycle_buffer(mut data: Vec<u8>) {
+    if !(BUFFER_INITIAL_CAPACITY..=POOLED_MAX_CAPACITY).contains(&data.capacity()) {
+        return;
+    }
+    data.clear();
+    let mut pool = BUFFER_POOL.lock().unwrap();
+    if pool.len() < POOL_MAX_BUFFERS {
+        pool.push(data);
+    }
+}
+
+/// Bytes held by the buffer pool
+fn buffer_pool_size() -> usize {
+    BUFFER_POOL.lock().unwrap().iter().map(|data| memory::alloc_size(data.capacity())).sum()
 }
 
 impl Buffer {
     fn new() -> Self {
+        let pooled = BUFFER_POOL.lock().unwrap().pop();
         Self {
-            data: Vec::with_capacity(4096), // Start with reasonable capacity
+            data: pooled.unwrap_or_else(|| Vec::with_capacity(BUFFER_INITIAL_CAPACITY)),
             start: 0,
             end: 0,
+            shared: Vec::new(),
+            share_values: false,
         }
     }
     
@@ -62,6 +170,8 @@ impl Buffer {
             data: Vec::with_capacity(capacity),
             start: 0,
             end: 0,
+            shared: Vec::new(),
+            share_values: false,
         }
     }
     
@@ -111,16 +221,6 @@ impl Buffer {
         }
     }
     
-    // Get a slice of the first n bytes without consuming them
-    fn peek(&self, n: usize) -> Option<&[u8]> {
-        if n <= self.len() {
-            Some(&self.data[self.start..self.start + n])
-        } else {
-
-            None
-        }
-    }
-    
     // Make room for new data by either moving existing data to front
     // or reallocating if necessary
     fn make_room(&mut self, needed: usize) {
@@ -146,31 +246,40 @@ impl Buffer {
                 new_data.extend_from_slice(&self.data[self.start..self.end]);
             }
             
-            self.data = new_data;
+            recycle_buffer(std::mem::replace(&mut self.data, new_data));
             self.start = 0;
             self.end = current_len;
         }
     }
 
+    // Drop everything after the first `len` bytes
+    fn truncate(&mut self, len: usize) {
+        assert!(len <= self.len());
+        self.end = self.start + len;
+        self.shared.retain(|(offset, _)| *offset <= len);
+    }
+
     fn response_begin(&mut self) -> usize {
         let header_pos = self.len();
         self.append_u32(0); // Reserve 4 bytes with placeholder
         header_pos
     }
     
-    // Calculate current response size (excluding header)
+    // Calculate current response size (excluding header), spliced values included
     fn response_size(&self, header_pos: usize) -> usize {
-        self.len() - header_pos - 4
+        let shared: usize =
+            self.shared.iter().filter(|(offset, _)| *offset > header_pos).map(|(_, value)| value.len()).sum();
+        self.len() - header_pos - 4 + shared
     }
     
     // Finalize response - write actual length to reserved header
-    fn response_end(&mut self, header_pos: usize) {
+    fn response_end(&mut self, header_pos: usize, max_reply_size: usize) {
         let mut msg_size = self.response_size(header_pos);
         
         // Check if response is too big
-        if msg_size > K_MAX_MSG {
+        if msg_size > max_reply_size {
             // Truncate buffer and write error instead
-            self.end = header_pos + 4; // Reset to just after header
+            self.truncate(header_pos + 4); // Reset to just after header
             out_err(self, "response is too big");
             msg_size = self.response_size(header_pos);
         }
@@ -195,13 +304,9 @@ impl Buffer {
         self.append(&data.to_le_bytes());
     }
     
-    fn append_f64(&mut self, data: f64) {
-        self.append(&data.to_le_bytes());
-    }
-
     fn out_begin_arr(&mut self) -> usize {
+        let ctx = self.len(); // Position of the tag, the count follows it
         self.append_u8(Tag::Arr as u8);
-        let ctx = self.len() - 4; // Position where count will go
         self.append_u32(0); // Placeholder count
         ctx
     }
@@ -223,10 +328,11 @@ impl Buffer {
     fn extend_from_slice(&mut self, data: &[u8]) {
         self.append(data);
     }
-    
-    // Drain n bytes from the front (for compatibility)
-    fn drain_front(&mut self, n: usize) {
-        self.consume(n);
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        recycle_buffer(std::mem::take(&mut self.data));
     }
 }
 
@@ -321,1021 +427,652 @@ impl ThreadPool {
 }
 
 
-#[derive(Debug)]
-pub struct Node<T> {
-    key: T,
-    left: Option<Rc<RefCell<Node<T>>>>,
-    right: Option<Rc<RefCell<Node<T>>>>,
-}
-
-impl<T> Node<T> {
-    fn new(key: T) -> Rc<RefCell<Self>> {
-        Rc::new(RefCell::new(Node {
-            key,
-            left: None,
-            right: None,
-        }))
-    }
-}
-
-#[derive(Debug)]
-struct AvlNode<T> {
-    parent: Option<Rc<RefCell<AvlNode<T>>>>,
-    left: Option<Rc<RefCell<AvlNode<T>>>>,
-    right: Option<Rc<RefCell<AvlNode<T>>>>,
-    height: u32,
-    key: T,
-}
-
-impl<T> AvlNode<T> {
-    fn new(key: T) -> Rc<RefCell<Self>> {
-        Rc::new(RefCell::new(Self {
-            parent: None,
-            left: None,
-            right: None,
-            height: 1, 
-            key,
-        }))
-    }
-}
-
-fn tree_search<T: Ord>(
-    from: &Option<Rc<RefCell<Node<T>>>>,
-    key: &T,
-) -> Option<Rc<RefCell<Node<T>>>> {
-    match from {
-        None => None,
-        Some(node_rc) => {
-            let node_ref = node_rc.borrow();
-            match key.cmp(&node_ref.key) {
-                Ordering::Less => tree_search::<T>(&node_ref.left, key),
-                Ordering::Greater => tree_search::<T>(&node_ref.right, key),
-                Ordering::Equal => Some(node_rc.clone()),
+fn avl_offset(
+    mut node: Option<Arc<Mutex<ZNode>>>, 
+    offset: i64
+) -> Option<Arc<Mutex<ZNode>>> {
+    let mut position = 0i64;
+    
+    while offset != position {
+        let node_rc = match &node {
+            Some(n) => n.clone(),
+            None => return None,
+        };
+        
+        // Get counts for decision making
+        let (right_count, left_count) = {
+            let borrowed = node_rc.lock().unwrap() ;
+            (
+                avl_count(borrowed.tree_right.clone()),
+                avl_count(borrowed.tree_left.clone())
+            )
+        };
+        
+        if position < offset && position + right_count as i64 >= offset {
+            // Target is inside the right subtree
+            let right_child = {
+                let borrowed = node_rc.lock().unwrap();
+                borrowed.tree_right.clone()
+            };
+            node = right_child;
+            
+            if let Some(new_node) = &node {
+                let left_count_new = avl_count(new_node.lock().unwrap().tree_left.clone());
+                position += left_count_new as i64 + 1;
             }
-        }
-    }
-}
-
-fn tree_insert<T: Ord>(root: &mut Option<Rc<RefCell<Node<T>>>>, key: T) {
-    match root {
-        None => {
-            *root = Some(Node::new(key));
-        }
-        Some(node_rc) => {
-            let mut node_ref = node_rc.borrow_mut();
-            if key < node_ref.key {
-                tree_insert::<T>(&mut node_ref.left, key);
-            } else if key > node_ref.key {
-                tree_insert::<T>(&mut node_ref.right, key);
+            
+        } else if position > offset && position - left_count as i64 <= offset {
+            // Target is inside the left subtree  
+            let left_child = {
+                let borrowed = node_rc.lock().unwrap();
+                borrowed.tree_left.clone()
+            };
+            node = left_child;
+            
+            if let Some(new_node) = &node {
+                let right_count_new = avl_count(new_node.lock().unwrap().tree_right.clone());
+                position -= right_count_new as i64 + 1;
             }
-            // if equal, do nothing (no dups)
-        }
-    }
-}
-
-fn tree_delete<T: Ord + Clone>(
-    node: Option<Rc<RefCell<Node<T>>>>,
-    key: &T,
-) -> Option<Rc<RefCell<Node<T>>>> {
-    match node {
-        None => None, // not found
-        Some(node_rc) => {
-            let ordering = {
-                // borrow just for comparison
-                let node_ref = node_rc.borrow();
-                key.cmp(&node_ref.key)
+            
+        } else {
+            // Go to the parent
+            let parent = {
+                let borrowed = node_rc.lock().unwrap() ;
+                borrowed.tree_parent.clone()
             };
-
-            match ordering {
-                std::cmp::Ordering::Less => {
-                    // go left
-                    let left_child = node_rc.borrow().left.clone();
-                    let new_left = tree_delete(left_child, key);
-                    node_rc.borrow_mut().left = new_left;
-                    Some(node_rc.clone())
-                }
-                std::cmp::Ordering::Greater => {
-                    // go right
-                    let right_child = node_rc.borrow().right.clone();
-                    let new_right = tree_delete(right_child, key);
-                    node_rc.borrow_mut().right = new_right;
-                    Some(node_rc.clone())
-                }
-                std::cmp::Ordering::Equal => {
-                    // found node -> detach
-                    node_detach(node_rc)
+            
+            match parent {
+                Some(parent_rc) => {
+                    // Check if current node is right child of parent
+                    let is_right_child = {
+                        let parent_borrowed = parent_rc.lock().unwrap();
+                        parent_borrowed.tree_right.as_ref()
+                            .is_some_and(|right| Arc::ptr_eq(right, &node_rc))
+                    };
+                    
+                    if is_right_child {
+                        position -= left_count as i64 + 1;
+                    } else {
+                        position += right_count as i64 + 1;
+                    }
+                    
+                    node = Some(parent_rc);
                 }
+                None => return None,
             }
         }
     }
+    
+    node
 }
 
 
 
-// Remove a node and fix tree structure
-fn node_detach<T: Ord + Clone >(node_rc: Rc<RefCell<Node<T>>>) -> Option<Rc<RefCell<Node<T>>>> {
-    let mut node_ref = node_rc.borrow_mut();
-
-    // Case 1: no right child → return left
-    if node_ref.right.is_none() {
-        return node_ref.left.take();
-    }
-
-    // Case 2: no left child → return right
-    if node_ref.left.is_none() {
-        return node_ref.right.take();
+fn successor(node: Option<Arc<Mutex<ZNode>>>) -> Option<Arc<Mutex<ZNode>>> {
+    let node_rc = node?;
+    
+    // Case 1: find the leftmost node in the right subtree
+    if let Some(right_child) = node_rc.lock().unwrap() .tree_right.clone() {
+        let mut current = right_child;
+        loop {
+            let left_child = current.lock().unwrap().tree_left.clone(); // Separate borrow
+            match left_child {
+                Some(child) => current = child,  // Now we can assign
+                None => break,
+            }
+        }
+        return Some(current);
     }
-
-    // Case 3: both children → find successor
-    let mut succ_parent = node_rc.clone();
-    let mut succ_rc = node_ref.right.clone().unwrap();
-
-    // Go left as far as possible
+    
+    // Case 2: find the ancestor where I'm the rightmost node in the left subtree  
+    let mut current = node_rc;
     loop {
-        let left_opt = succ_rc.borrow().left.clone();
-        if let Some(left_rc) = left_opt {
-            succ_parent = succ_rc.clone();
-            succ_rc = left_rc;
-        } else {
-            break;
+        let parent = current.lock().unwrap().tree_parent.clone(); // Separate borrow
+        match parent {
+            Some(parent_rc) => {
+                let is_left_child = parent_rc.lock().unwrap().tree_left.as_ref()
+                    .is_some_and(|left| Arc::ptr_eq(left, &current));
+                
+                if is_left_child {
+                    return Some(parent_rc);
+                }
+                current = parent_rc;  // Now we can assign
+            }
+            None => break,
         }
     }
+    
+    None
+}
 
-    // Take successor's key
-    let succ_key = succ_rc.borrow().key.clone();
-
-    // Recursively delete the successor (easy case, since no left child)
-    if Rc::ptr_eq(&succ_parent, &node_rc) {
-        // Successor is right child
-        succ_parent.borrow_mut().right = node_detach(succ_rc.clone());
-    } else {
-        succ_parent.borrow_mut().left = succ_rc.borrow().right.clone();
-    }
-
-    // Replace current node's key with successor's key
-    node_ref.key = succ_key;
 
-    Some(node_rc.clone())
-}
 
-fn avl_height<T>(node: &Option<Rc<RefCell<AvlNode<T>>>>) -> u32 {
+fn avl_count(node: Option<Arc<Mutex<ZNode>>>) -> u32 {
     match node {
-        Some(node_rc) => node_rc.borrow().height,
+        Some(rc_node) => rc_node.lock().unwrap() .tree_count,
         None => 0,
     }
 }
 
-fn avl_update<T>(node: &Rc<RefCell<AvlNode<T>>>) {
-    let left_height = {
-        let borrowed = node.borrow();
-        avl_height(&borrowed.left)
-    };
-    let right_height = {
-        let borrowed = node.borrow();
-        avl_height(&borrowed.right)
-    };
-
-    node.borrow_mut().height = 1 + max(left_height, right_height);
+/* Timer and Timeout */
+fn get_monotonic_time_ms() -> u64 {
+    sim::clock().monotonic_ms()
 }
 
-fn rot_left<T>(node: Rc<RefCell<AvlNode<T>>>) -> Rc<RefCell<AvlNode<T>>> {
-    // Step 1: unwrap relationships
-    let parent_opt = node.borrow().parent.clone();
-    let new_node = node
-        .borrow()
-        .right
-        .clone()
-        .expect("Right child must exist for left rotation");
-    let inner = new_node.borrow().left.clone();
-
-    {
-        // Step 2: relink node -> inner
-        let mut node_mut = node.borrow_mut();
-        node_mut.right = inner.clone();
-    }
-    if let Some(inner_node) = &inner {
-        inner_node.borrow_mut().parent = Some(node.clone());
-    }
-
-    {
-        // Step 3: new_node -> parent
-        new_node.borrow_mut().parent = parent_opt.clone();
-    }
+// Coarse clock for access stamps, in seconds since start. It only moves once
+// per event loop tick, so touching a key never costs a clock read.
+static LRU_CLOCK: AtomicU32 = AtomicU32::new(0);
 
-    {
-        // Step 4: new_node <-> node
-        new_node.borrow_mut().left = Some(node.clone());
-        node.borrow_mut().parent = Some(new_node.clone());
-    }
+fn lru_clock() -> u32 {
+    LRU_CLOCK.load(AtomicOrdering::Relaxed)
+}
 
-    // Step 5: update heights bottom-up
-    avl_update(&node);
-    avl_update(&new_node);
+fn update_lru_clock() {
+    LRU_CLOCK.store((get_monotonic_time_ms() / 1000) as u32, AtomicOrdering::Relaxed);
+}
 
-    new_node
+/// Monotonic clock in nanoseconds (closer to timespec precision)
+fn get_monotonic_time_ns() -> u128 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    let start = START.get_or_init(Instant::now);
+    start.elapsed().as_nanos()
 }
 
-fn rot_right<T>(node: Rc<RefCell<AvlNode<T>>>) -> Rc<RefCell<AvlNode<T>>> {
-    // Grab the pivot (left child)
-    let pivot = {
-        let node_borrow = node.borrow();
-        node_borrow.left.clone().expect("Left child must exist for right rotation")
-    };
+/// Wall clock (like CLOCK_REALTIME), in milliseconds since Unix epoch
+fn get_current_time_ms() -> u64 {
+    sim::clock().unix_ms()
+}
 
-    let pivot_right = pivot.borrow().right.clone();
 
-    // Step 1: node.left <- pivot.right
-    {
-        let mut node_mut = node.borrow_mut();
-        node_mut.left = pivot_right.clone();
-    }
-    if let Some(pivot_right_node) = &pivot_right {
-        pivot_right_node.borrow_mut().parent = Some(node.clone());
-    }
+fn next_timer_ms() -> i32 {
+    let now_ms = get_monotonic_time_ms();
+    let mut next_ms = u64::MAX;
 
-    // Step 2: pivot.parent <- node.parent
-    let parent = node.borrow().parent.clone();
-    {
-        pivot.borrow_mut().parent = parent.clone();
-    }
+    with_global_data(|g_data| {
+        // Idle connections and TTLs, which wait while clients are paused,
+        // the end of the pause, a failover's timeout and accepting again
+        let ttls = g_data.expires.next_deadline().filter(|_| !g_data.pause.is_active());
+        let timers = [
+            g_data.idle_timers.next_deadline(),
+            ttls,
+            g_data.pause.deadline(),
+            g_data.failover.next_timer_ms(now_ms),
+            g_data.accept.deadline(),
+        ];
+        for timers in timers {
+            next_ms = next_ms.min(timers.unwrap_or(u64::MAX));
+        }
 
-    // Step 3: reconnect parent’s link to pivot
-    if let Some(p) = parent {
-        let mut p_mut = p.borrow_mut();
-        if p_mut.left.as_ref().map_or(false, |left| Rc::ptr_eq(left, &node)) {
-            p_mut.left = Some(pivot.clone());
-        } else if p_mut.right.as_ref().map_or(false, |right| Rc::ptr_eq(right, &node)) {
-            p_mut.right = Some(pivot.clone());
+        // Blocked client timeouts, and suspended commands to go on with
+        if let Some(deadline) = g_data.blocking.next_deadline() {
+            next_ms = next_ms.min(deadline);
+        }
+        if g_data.blocking.has_suspended() {
+            next_ms = now_ms;
         }
 
-    }
+        // AOF fsync and rewrite checks
+        if let Some(at) = aof::next_timer_ms(g_data, now_ms) {
+            next_ms = next_ms.min(at);
+        }
 
-    // Step 4: pivot.right <- node
-    {
-        let mut pivot_mut = pivot.borrow_mut();
-        pivot_mut.right = Some(node.clone());
-    }
-    node.borrow_mut().parent = Some(pivot.clone());
+        // The storage log's rewrite
+        if let Some(at) = g_data.db.next_timer_ms(now_ms) {
+            next_ms = next_ms.min(at);
+        }
 
-    // Step 5: update heights bottom-up
-    avl_update(&node);
-    avl_update(&pivot);
+        // A resized keyspace table to move entries over from
+        if g_data.config.activerehashing && g_data.db.table().is_migrating() {
+            next_ms = next_ms.min(g_data.active_rehash_ms);
+        }
+    });
 
-    pivot
+    // Return timeout value
+    if next_ms == u64::MAX {
+        -1 // No timers
+    } else if next_ms <= now_ms {
+        0 // Expired/missed
+    } else {
+        (next_ms - now_ms) as i32
+    }
 }
 
 
+fn process_timers() {
+    let now_ms = get_monotonic_time_ms();
+
+    with_global_data(|g_data| {
+        // CLIENT PAUSE timeout
+        pause::expire(g_data, now_ms);
+
+        // Idle timers. A connection's timer is only moved when it's touched,
+        // so it's checked against the current timeout before closing it; a
+        // new timeout reschedules them all.
+        let idle_timeout_ms = g_data.config.timeout * 1000;
+        if idle_timeout_ms != g_data.idle_timeout_ms {
+            g_data.idle_timeout_ms = idle_timeout_ms;
+            for (&fd, conn) in g_data.fd2conn.iter_mut() {
+                conn_schedule_idle(&mut g_data.idle_timers, idle_timeout_ms, fd, conn);
+            }
+        }
+        for fd in g_data.idle_timers.expire(now_ms, usize::MAX) {
+            let Some(conn) = g_data.fd2conn.get_mut(&fd) else {
+                continue; // Its connection is gone
+            };
+            if conn.idle_exempt() || conn.last_active_ms + idle_timeout_ms > now_ms {
+                conn_schedule_idle(&mut g_data.idle_timers, idle_timeout_ms, fd, conn);
+                continue;
+            }
+            log_debug!("net", "Idle connection expired: {}", fd);
+            forget_client(g_data, fd);
+        }
 
-fn avl_fix_left<T>(node: Rc<RefCell<AvlNode<T>>>) -> Rc<RefCell<AvlNode<T>>> {
-    // Step 1: extract left child (drop after use)
-    let (left_left_height, left_right_height, needs_double) = {
-        let node_borrow = node.borrow();
-        let left_child = node_borrow.left.clone().expect("Left child must exist");
+        // Filters are built or dropped as lookup-filter changes, LFU
+        // counting starts and stops with an LFU policy
+        g_data.db.table_mut().set_lookup_filter(g_data.config.lookup_filter);
+        evict::configure(&g_data.config);
 
-        let left_left_height = avl_height(&left_child.borrow().left);
-        let left_right_height = avl_height(&left_child.borrow().right);
+        // Blocked clients whose timeout passed
+        blocking::expire_timeouts(g_data, now_ms);
 
-        (left_left_height, left_right_height, left_left_height < left_right_height)
-    };
+        // MIGRATE connections nobody used for a while
+        g_data.migrate_pool.close_idle(now_ms);
 
-    // Step 2: fix left-right case if needed
-    if needs_double {
-        let left_child = node.borrow().left.clone().unwrap();
-        let new_left = rot_left(left_child);
+        aof::cron(g_data);
 
-        // reconnect new subtree root as node.left
-        {
-            let mut node_mut = node.borrow_mut();
-            node_mut.left = Some(new_left.clone());
+        // Rehashing moves on with writes; this finishes a table that's left
+        // half moved when they stop
+        if g_data.config.activerehashing && now_ms >= g_data.active_rehash_ms {
+            g_data.active_rehash_ms = now_ms + K_ACTIVE_REHASH_INTERVAL_MS;
+            g_data.db.table_mut().rehash_for_us(K_ACTIVE_REHASH_BUDGET_US);
         }
-        new_left.borrow_mut().parent = Some(node.clone());
-    }
 
-    // Step 3: always rotate right on node
-    rot_right(node)
+        // TTL timers, a batch at a time; none while clients are paused
+        let batch = if g_data.pause.is_active() { 0 } else { K_MAX_WORKS };
+        for (key, generation) in g_data.expires.expire(now_ms, batch) {
+            // Only the entry the TTL was set on
+            if g_data.db.get(&key).is_none_or(|entry| entry.generation != generation) {
+                log_debug!("expire", "Stale TTL for key {}, its entry is gone", key);
+                continue;
+            }
+            g_data.db.delete(&key);
+            events::emit(g_data, &ServerEvent::KeyExpired { key: &key });
+            log_trace!("expire", "TTL expired for key: {}", key);
+        }
+    });
 }
 
-fn avl_fix_right<T>(node: Rc<RefCell<AvlNode<T>>>) -> Rc<RefCell<AvlNode<T>>> {
-    // Clone right child (must exist in imbalance case)
-    let right_child = {
-        node.borrow().right.clone().expect("Right child must exist")
-    };
 
-    // Check if this is a right-left case (double rotation needed)
-    let (rl_height, rr_height) = {
-        let rc_ref = right_child.borrow();
-        (avl_height(&rc_ref.left), avl_height(&rc_ref.right))
-    };
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ValueType {
+    Init = 0,
+    Str = 1,
+    ZSet = 2,
+    Stream = 3,
+    List = 4,
+}
 
-    if rr_height < rl_height {
-        // Right-left case: rotate right first on right child
-        let new_right = rot_right(right_child);
-        node.borrow_mut().right = Some(new_right);
+impl ValueType {
+    /// The type a snapshot or DUMP type byte stands for
+    pub fn from_u8(byte: u8) -> Option<ValueType> {
+        let value_type = match byte {
+            0 => ValueType::Init,
+            1 => ValueType::Str,
+            2 => ValueType::ZSet,
+            3 => ValueType::Stream,
+            4 => ValueType::List,
+            _ => return None,
+        };
+        debug_assert_eq!(value_type as u8, byte);
+        Some(value_type)
     }
 
-    // Always finish with a left rotation
-    rot_left(node)
+    /// Name of the type as TYPE and SCAN ... TYPE spell it
+    pub fn type_name(self) -> &'static str {
+        match self {
+            ValueType::Init => "none",
+            ValueType::Str => "string",
+            ValueType::ZSet => "zset",
+            ValueType::Stream => "stream",
+            ValueType::List => "list",
+        }
+    }
 }
 
+// Longest string Redis keeps in one allocation with its object header
+const EMBSTR_SIZE_LIMIT: usize = 44;
+// Listpack total-bytes and element-count fields plus the end byte
+const LISTPACK_HEADER_SIZE: usize = 7;
 
-fn avl_fix<T>(mut node: Rc<RefCell<AvlNode<T>>>) -> Rc<RefCell<AvlNode<T>>> {
-    loop {
-        let parent_opt = node.borrow().parent.clone();
+// Every value is behind an Arc, so cloning one is cheap: a snapshot takes
+// clones of the values and serializes them off the event loop. Writes go
+// through Arc::make_mut, which copies a value only while a snapshot still
+// shares it.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Init,
+    Str(Arc<str>),
+    ZSet(Arc<ZSet>),
+    Stream(Arc<stream::Stream>),
+    List(Arc<VecDeque<String>>),
+}
 
-        // Update auxiliary data
-        avl_update(&node);
+impl Value {
+    /// Name of the type as TYPE and SCAN ... TYPE spell it
+    pub fn type_name(&self) -> &'static str {
+        self.value_type().type_name()
+    }
+
+    /// An empty value of a container type, to create a key with
+    fn empty(value_type: ValueType) -> Value {
+        match value_type {
+            ValueType::Init => Value::Init,
+            ValueType::Str => Value::Str("".into()),
+            ValueType::ZSet => Value::ZSet(Arc::default()),
+            ValueType::Stream => Value::Stream(Arc::default()),
+            ValueType::List => Value::List(Arc::default()),
+        }
+    }
 
-        // Check for imbalance
-        let (left_height, right_height) = {
-            let nb = node.borrow();
-            (avl_height(&nb.left), avl_height(&nb.right))
-        };
-
-        // Fix imbalance if needed
-        let mut fixed = node.clone();
-        if left_height == right_height + 2 {
-            fixed = avl_fix_left(node);
-        } else if left_height + 2 == right_height {
-            fixed = avl_fix_right(node);
+    /// A list or sorted set with nothing left in it, which isn't kept (an
+    /// empty stream is, as in Redis)
+    fn is_emptied(&self) -> bool {
+        match self {
+            Value::ZSet(zset) => zset.name_to_node.is_empty(),
+            Value::List(list) => list.is_empty(),
+            Value::Init | Value::Str(..) | Value::Stream(..) => false,
         }
+    }
 
-        // If parent exists, reattach and move up
-        if let Some(parent) = parent_opt {
-            let is_left_child = {
-                let parent_ref = parent.borrow();
-                parent_ref.left.as_ref().map(|l| Rc::ptr_eq(l, &fixed)).unwrap_or(false)
-            };
-
-            if is_left_child {
-                parent.borrow_mut().left = Some(fixed.clone());
-            } else {
-                parent.borrow_mut().right = Some(fixed.clone());
+    /// The encoding OBJECT ENCODING reports. Values here have one
+    /// representation per type, so this is the one Redis would use for a
+    /// value of this shape under the configured conversion thresholds.
+    pub fn encoding(&self, config: &config::Config) -> &'static str {
+        match self {
+            Value::Init => "none",
+            Value::Str(s) => {
+                let canonical = s.parse::<i64>().is_ok_and(|n| n.to_string() == **s);
+                if canonical {
+                    "int"
+                } else if s.len() <= EMBSTR_SIZE_LIMIT {
+                    "embstr"
+                } else {
+                    "raw"
+                }
             }
-
-            node = parent; // continue upward
-        } else {
-            // No parent => root found
-            return fixed;
+            Value::ZSet(zset) => {
+                let max_value = config.zset_max_listpack_value as usize;
+                let small = zset.name_to_node.len() as u64 <= config.zset_max_listpack_entries
+                    && zset.name_to_node.keys().all(|name| name.len() <= max_value);
+                if small { "listpack" } else { "skiplist" }
+            }
+            Value::List(list) => {
+                let fits = match config.list_max_listpack_size {
+                    size if size > 0 => list.len() as u64 <= size as u64,
+                    // -1 .. -5: 4 KB .. 64 KB
+                    size => {
+                        let bytes: usize = LISTPACK_HEADER_SIZE + list.iter().map(|e| e.len() + 2).sum::<usize>();
+                        bytes <= 4096 << (-size - 1)
+                    }
+                };
+                if fits { "listpack" } else { "quicklist" }
+            }
+            Value::Stream(..) => "stream",
         }
     }
-}
 
-
-fn avl_del_easy<T>(node: Rc<RefCell<AvlNode<T>>>) -> Option<Rc<RefCell<AvlNode<T>>>> {
-    // Precondition: at most 1 child
-    {
-        let node_ref = node.borrow();
-        assert!(
-            !(node_ref.left.is_some() && node_ref.right.is_some()),
-            "Node must have at most 1 child"
-        );
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::Init => ValueType::Init,
+            Value::Str(..) => ValueType::Str,
+            Value::ZSet(..) => ValueType::ZSet,
+            Value::Stream(..) => ValueType::Stream,
+            Value::List(..) => ValueType::List,
+        }
     }
+}
 
-    // Extract child and parent
-    let child = {
-        let node_ref = node.borrow();
-        node_ref.left.clone().or_else(|| node_ref.right.clone())
-    };
-    let parent = node.borrow().parent.clone();
+// Key-value for the hash table
+#[derive(Debug)]
+pub struct Entry {
+    link: AtomicLink,
+    hcode: u64,  // Keep the hash for performance
+    // Index of its bucket in the table it's linked into, set by insert_hash:
+    // hcode masked for that table, so a move or a removal doesn't work it out
+    // again
+    bucket: usize,
+    key: String,
+    value: Value,
 
-    // Update child's parent pointer
-    if let Some(ref child_ref) = child {
-        child_ref.borrow_mut().parent = parent.clone();
-    }
+    // lru_clock() at the last access; reads update it under the shared lock
+    lru: AtomicU32,
+    // Access counter and the minute it last decayed, under an LFU policy
+    // (see evict)
+    lfu: AtomicU32,
+    // Unique to this entry, so a TTL set on it isn't taken for one of a
+    // later entry of the same key
+    generation: u64,
+    // Monotonic ms its TTL is due, NO_DEADLINE without one: the deadline of
+    // its timer in Expires, kept here for reads to check (see peek_entry)
+    ttl_deadline: AtomicU64,
+}
 
-    // If node is root, return new root (child or None)
-    if parent.is_none() {
-        return child;
-    }
+const NO_DEADLINE: u64 = u64::MAX;
 
-    // Attach child to the correct side of parent
-    let parent_node = parent.unwrap();
-    let is_left_child = {
-        let parent_ref = parent_node.borrow();
-        parent_ref.left.as_ref().map(|left| Rc::ptr_eq(left, &node)).unwrap_or(false)
-    };
+// Generation of the next entry created
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
 
-    if is_left_child {
-        parent_node.borrow_mut().left = child;
-    } else {
-        parent_node.borrow_mut().right = child;
+impl Entry {
+    fn new(key: String, value: Value) -> Self {
+        Self {
+            link: AtomicLink::new(),
+            hcode: 0, // set by HMap::insert, which holds the hash seed
+            bucket: 0,
+            key,
+            value,
+            lru: AtomicU32::new(lru_clock()),
+            lfu: AtomicU32::new(evict::lfu_init()),
+            generation: NEXT_GENERATION.fetch_add(1, AtomicOrdering::Relaxed),
+            ttl_deadline: AtomicU64::new(NO_DEADLINE),
+        }
     }
 
-    // Rebalance from parent upward
-    Some(avl_fix(parent_node))
-}
-
-fn avl_del<T: Clone>(node: Rc<RefCell<AvlNode<T>>>) -> Option<Rc<RefCell<AvlNode<T>>>> {
-    // Easy case: 0 or 1 child
-    let (has_left, has_right) = {
-        let node_ref = node.borrow();
-        (node_ref.left.is_some(), node_ref.right.is_some())
-    };
-    
-    if !has_left || !has_right {
-        return avl_del_easy(node);
+    /// Whether its TTL has passed, even if process_timers hasn't deleted it yet
+    fn is_expired(&self) -> bool {
+        let deadline = self.ttl_deadline.load(AtomicOrdering::Relaxed);
+        deadline != NO_DEADLINE && deadline <= get_monotonic_time_ms()
     }
 
-    // Hard case: find successor (leftmost node in right subtree)
-    let mut victim = {
-        let node_ref = node.borrow();
-        node_ref.right.clone().expect("Right child must exist")
-    };
-
-    loop {
-        let next = {
-            let v_ref = victim.borrow();
-            v_ref.left.clone()
-        };
-        match next {
-            Some(left_child) => victim = left_child,
-            None => break,
+    fn touch(&self) {
+        self.lru.store(lru_clock(), AtomicOrdering::Relaxed);
+        if evict::lfu_enabled() {
+            let lfu = evict::lfu_access(self.lfu.load(AtomicOrdering::Relaxed));
+            self.lfu.store(lfu, AtomicOrdering::Relaxed);
         }
     }
 
-    // Swap keys instead of copying whole structs
-    let victim_key = victim.borrow().key.clone();
-    let node_key = node.borrow().key.clone();
-
-    victim.borrow_mut().key = node_key;
-    node.borrow_mut().key = victim_key;
+    /// LFU access counter, as of now
+    fn freq(&self) -> u8 {
+        evict::lfu_counter(self.lfu.load(AtomicOrdering::Relaxed))
+    }
 
-    // Now delete the victim (which has the original key)
-    avl_del_easy(victim)
-}
+    fn has_ttl(&self) -> bool {
+        self.ttl_deadline.load(AtomicOrdering::Relaxed) != NO_DEADLINE
+    }
 
-fn avl_insert<T: Ord>(
-    root: &mut Option<Rc<RefCell<AvlNode<T>>>>, 
-    new_node: Rc<RefCell<AvlNode<T>>>
-) {
-    let mut current = root.clone();
-    let mut parent: Option<Rc<RefCell<AvlNode<T>>>> = None;
-    let mut is_left_child = false;
-    
-    while let Some(node) = current {
-        parent = Some(node.clone());
-        let cmp = new_node.borrow().key.cmp(&node.borrow().key);
-        
-        if cmp == std::cmp::Ordering::Less {
-            current = node.borrow().left.clone();
-            is_left_child = true;
-        } else {
-            current = node.borrow().right.clone();
-            is_left_child = false;
-        }
+    /// Seconds since the entry was last read or written
+    fn idle_secs(&self) -> u32 {
+        lru_clock().wrapping_sub(self.lru.load(AtomicOrdering::Relaxed))
     }
-    
-    new_node.borrow_mut().parent = parent.clone();
-    
-    if let Some(parent_node) = parent {
-        if is_left_child {
-            parent_node.borrow_mut().left = Some(new_node.clone());
-        } else {
-            parent_node.borrow_mut().right = Some(new_node.clone());
-        }
-        // Fix from the newly inserted node
-        *root = Some(avl_fix(new_node.clone()));
-    } else {
-        *root = Some(new_node);
+
+    // Backdate the last access, for RESTORE ... IDLETIME
+    fn set_idle_secs(&self, secs: u32) {
+        self.lru.store(lru_clock().wrapping_sub(secs), AtomicOrdering::Relaxed);
     }
 }
 
-fn avl_search_and_delete<T: Ord + Clone>(
-    root: &mut Option<Rc<RefCell<AvlNode<T>>>>,
-    key: &T,
-) -> Option<Rc<RefCell<AvlNode<T>>>> {
-    let mut current = root.clone();
+fn entry_del(key: &str) {
+    with_global_data(|g_data| entry_delete(g_data, key));
+}
 
-    while let Some(node) = current {
-        // Borrow only long enough to compare
-        let ordering = {
-            let node_ref = node.borrow();
-            key.cmp(&node_ref.key)
-        };
+// Delete a key if it exists, under a lock the caller holds; true if it did
+fn entry_delete(g_data: &mut GData, key: &str) -> bool {
+    let Some(entry) = g_data.take_entry(key) else {
+        return false;
+    };
+    clear_ttl(&mut g_data.expires, &entry);
+    g_data.stats.deleted_keys += 1;
 
-        match ordering {
-            std::cmp::Ordering::Less => {
-                current = node.borrow().left.clone();
-            }
-            std::cmp::Ordering::Greater => {
-                current = node.borrow().right.clone();
-            }
-            std::cmp::Ordering::Equal => {
-                // Found the node -> delegate actual removal
-                *root = avl_del(node.clone());
-                return Some(node);
-            }
-        }
-    }
+    let set_size = match &entry.value {
+        Value::ZSet(zset) => zset.name_to_node.len(),
+        _ => 0,
+    };
 
-    None // Key not found
-}
+    if set_size > K_LARGE_CONTAINER_SIZE {
+        log_debug!("db", "Large ZSet detected ({} items), scheduling async cleanup", set_size);
 
-fn avl_offset(
-    mut node: Option<Arc<Mutex<ZNode>>>, 
-    offset: i64
-) -> Option<Arc<Mutex<ZNode>>> {
-    let mut position = 0i64;
-    
-    while offset != position {
-        let node_rc = match &node {
-            Some(n) => n.clone(),
-            None => return None,
-        };
-        
-        // Get counts for decision making
-        let (right_count, left_count) = {
-            let borrowed = node_rc.lock().unwrap() ;
-            (
-                avl_count(borrowed.tree_right.clone()),
-                avl_count(borrowed.tree_left.clone())
-            )
-        };
-        
-        if position < offset && position + right_count as i64 >= offset {
-            // Target is inside the right subtree
-            let right_child = {
-                let borrowed = node_rc.lock().unwrap();
-                borrowed.tree_right.clone()
-            };
-            node = right_child;
-            
-            if let Some(new_node) = &node {
-                let left_count_new = avl_count(new_node.lock().unwrap().tree_left.clone());
-                position += left_count_new as i64 + 1;
-            }
-            
-        } else if position > offset && position - left_count as i64 <= offset {
-            // Target is inside the left subtree  
-            let left_child = {
-                let borrowed = node_rc.lock().unwrap();
-                borrowed.tree_left.clone()
-            };
-            node = left_child;
-            
-            if let Some(new_node) = &node {
-                let right_count_new = avl_count(new_node.lock().unwrap().tree_right.clone());
-                position -= right_count_new as i64 + 1;
-            }
-            
-        } else {
-            // Go to the parent
-            let parent = {
-                let borrowed = node_rc.lock().unwrap() ;
-                borrowed.tree_parent.clone()
-            };
-            
-            match parent {
-                Some(parent_rc) => {
-                    // Check if current node is right child of parent
-                    let is_right_child = {
-                        let parent_borrowed = parent_rc.lock().unwrap();
-                        parent_borrowed.tree_right.as_ref()
-                            .map_or(false, |right| Arc::ptr_eq(right, &node_rc))
-                    };
-                    
-                    if is_right_child {
-                        position -= left_count as i64 + 1;
-                    } else {
-                        position += right_count as i64 + 1;
-                    }
-                    
-                    node = Some(parent_rc);
-                }
-                None => return None,
-            }
-        }
+        // Submit async work (entry drops here, doing the real cleanup)
+        g_data.thread_pool.submit(move || {
+            // Simulate expensive cleanup work
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            log_debug!("db", "Background: Completed simulated ZSet cleanup");
+        });
     }
-    
-    node
+    // entry drops here, automatically cleaning up the ZSet
+    true
 }
 
+// Run `f` on the value at `key`, which must be of `value_type`, through
+// GData::with_entry_mut. Without the key f isn't called and the result is
+// None, unless `create` asks for an empty value to start from.
+fn expect_mut<R>(
+    g_data: &mut GData,
+    key: &str,
+    value_type: ValueType,
+    create: bool,
+    f: impl FnOnce(&mut Value) -> R,
+) -> Result<Option<R>, CmdError> {
+    match g_data.db.peek_entry(key).map(|entry| entry.value.value_type()) {
+        Some(found) if found != value_type => return Err(CmdError::WrongType),
+        None if !create => return Ok(None),
+        _ => {}
+    }
+    Ok(Some(g_data.with_entry_mut(key, |slot| f(slot.get_or_insert_with(|| Value::empty(value_type))))))
+}
 
-
-fn successor(node: Option<Arc<Mutex<ZNode>>>) -> Option<Arc<Mutex<ZNode>>> {
-    let node_rc = node?;
-    
-    // Case 1: find the leftmost node in the right subtree
-    if let Some(right_child) = node_rc.lock().unwrap() .tree_right.clone() {
-        let mut current = right_child;
-        loop {
-            let left_child = current.lock().unwrap().tree_left.clone(); // Separate borrow
-            match left_child {
-                Some(child) => current = child,  // Now we can assign
-                None => break,
-            }
-        }
-        return Some(current);
-    }
-    
-    // Case 2: find the ancestor where I'm the rightmost node in the left subtree  
-    let mut current = node_rc;
-    loop {
-        let parent = current.lock().unwrap().tree_parent.clone(); // Separate borrow
-        match parent {
-            Some(parent_rc) => {
-                let is_left_child = parent_rc.lock().unwrap().tree_left.as_ref()
-                    .map_or(false, |left| Arc::ptr_eq(left, &current));
-                
-                if is_left_child {
-                    return Some(parent_rc);
-                }
-                current = parent_rc;  // Now we can assign
-            }
-            None => break,
-        }
-    }
-    
-    None
+/// expect_mut for the sorted set at `key`
+fn expect_zset_mut<R>(g_data: &mut GData, key: &str, create: bool, f: impl FnOnce(&mut ZSet) -> R) -> Result<Option<R>, CmdError> {
+    expect_mut(g_data, key, ValueType::ZSet, create, |value| match value {
+        Value::ZSet(zset) => f(Arc::make_mut(zset)),
+        _ => unreachable!("type checked by expect_mut"),
+    })
 }
 
+/// expect_mut for the list at `key`
+fn expect_list_mut<R>(
+    g_data: &mut GData,
+    key: &str,
+    create: bool,
+    f: impl FnOnce(&mut VecDeque<String>) -> R,
+) -> Result<Option<R>, CmdError> {
+    expect_mut(g_data, key, ValueType::List, create, |value| match value {
+        Value::List(list) => f(Arc::make_mut(list)),
+        _ => unreachable!("type checked by expect_mut"),
+    })
+}
 
+/// expect_mut for the stream at `key`
+fn expect_stream_mut<R>(
+    g_data: &mut GData,
+    key: &str,
+    create: bool,
+    f: impl FnOnce(&mut stream::Stream) -> R,
+) -> Result<Option<R>, CmdError> {
+    expect_mut(g_data, key, ValueType::Stream, create, |value| match value {
+        Value::Stream(stream) => f(Arc::make_mut(stream)),
+        _ => unreachable!("type checked by expect_mut"),
+    })
+}
 
-fn avl_count(node: Option<Arc<Mutex<ZNode>>>) -> u32 {
-    match node {
-        Some(rc_node) => rc_node.lock().unwrap() .tree_count,
-        None => 0,
-    }
+// Drop an entry already taken out of the db, along with its TTL
+fn entry_discard(g_data: &mut GData, entry: Box<Entry>) {
+    clear_ttl(&mut g_data.expires, &entry);
 }
 
-/* Timer and Timeout */
-fn get_monotonic_time_ms() -> u64 {
-	//use a static start time to measure elapsed time
-	static START: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
-	let start = START.get_or_init(|| Instant::now());
-		
-	start.elapsed().as_millis() as u64
+// Remove an entry's TTL; true if it had one
+fn clear_ttl(expires: &mut Expires, entry: &Entry) -> bool {
+    entry.ttl_deadline.store(NO_DEADLINE, AtomicOrdering::Relaxed);
+    expires.remove(&entry.key).is_some()
 }
 
-/// Monotonic clock in nanoseconds (closer to timespec precision)
-fn get_monotonic_time_ns() -> u128 {
-    static START: OnceLock<Instant> = OnceLock::new();
-    let start = START.get_or_init(|| Instant::now());
-    start.elapsed().as_nanos()
+// Have an entry expire at a unix time in ms
+fn entry_set_expire_at(expires: &mut Expires, entry: &Entry, expire_at: u64) {
+    let deadline = expires.set(entry.key.clone(), expire_at, entry.generation);
+    entry.ttl_deadline.store(deadline, AtomicOrdering::Relaxed);
 }
 
-/// Wall clock (like CLOCK_REALTIME), in milliseconds since Unix epoch
-fn get_current_time_ms() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64
+// Unix time in ms an entry expires at, if it has a TTL
+fn entry_expire_at(expires: &Expires, entry: &Entry) -> Option<u64> {
+    expires.get(&entry.key)
 }
 
+// Adapter for Entry to use in Intrusive Collections
+intrusive_adapter!(pub EntryAdapter = Box<Entry>: Entry { link: AtomicLink });
 
-type Link = Option<Arc<Mutex<DList>>>;
 
-#[derive(Debug)]
-struct DList {
-    prev: Link,
-    next: Link,
-}
 
-impl DList {
-     fn new() -> Arc<Mutex<Self>> {
-        Arc::new(Mutex::new(DList {
-            prev: None,
-            next: None,
-        }))
-    }
+//fixed-size hash table
+#[derive(Debug)]
+pub struct HashTable {
+    tab: Vec<LinkedList<EntryAdapter>>,
+    mask: usize,
+    size: usize,
+    // The hashes of the keys in here, with lookup-filter on
+    filter: Option<bloom::LookupFilter>,
 }
 
-fn dlist_detach(node: Arc<Mutex<DList>>) {
-    let (prev, next) = {
-        let node_ref = node.lock().unwrap();
-        (node_ref.prev.clone(), node_ref.next.clone())
-    };
-    
-    // Update prev node's next pointer
-    if let Some(prev_node) = &prev {
-        prev_node.lock().unwrap().next = next.clone();
-    }
-    
-    // Update next node's prev pointer  
-    if let Some(next_node) = &next {
-        next_node.lock().unwrap().prev = prev; // Use prev directly, not prev.clone()
-    }
-    
-    // Clear the detached node's pointers
-    let mut node_mut = node.lock().unwrap();
-    node_mut.prev = None;
-    node_mut.next = None;
-}
-
-fn dlist_init(node: Arc<Mutex<DList>>) {
-    let mut node_mut = node.lock().unwrap();
-    node_mut.prev = Some(node.clone());  // This should now work correctly
-    node_mut.next = Some(node.clone());
-}
-
-
-fn dlist_empty(node: Arc<Mutex<DList>>) -> bool {
-    let node_ref = node.lock().unwrap();
-    match &node_ref.next {
-        Some(next) => Arc::ptr_eq(&node, next),
-        None => true,
-    }
-}
-
-fn dlist_insert_before(target: &Arc<Mutex<DList>>, rookie: &Arc<Mutex<DList>>) {
-    // Get the previous node
-    let prev = {
-        let t = target.lock().unwrap();
-        t.prev.clone().expect("Target should have prev pointer")
-    };
-
-    // Update all connections carefully to avoid deadlocks
-    {
-        let mut p = prev.lock().unwrap();
-        p.next = Some(rookie.clone());
-    }
-
-    {
-        let mut r = rookie.lock().unwrap();
-        r.prev = Some(prev);
-        r.next = Some(target.clone());
-    }
-
-    {
-        let mut t = target.lock().unwrap();
-        t.prev = Some(rookie.clone());
-    }
-}
-
-fn next_timer_ms() -> i32 {
-    let now_ms = get_monotonic_time_ms();
-    let mut next_ms = u64::MAX;
-
-    with_global_data(|g_data| {
-        // Idle timers using linked list
-        if !dlist_empty(g_data.idle_list.clone()) {
-            let first_node = {
-                let idle_list_ref = g_data.idle_list.lock().unwrap(); // Changed from borrow()
-                idle_list_ref.next.clone()
-            };
-            
-            if let Some(first_node_rc) = first_node {
-                // Find which connection owns this idle node
-                for (_, conn) in &g_data.fd2conn {
-                    if Arc::ptr_eq(&conn.idle_node, &first_node_rc) { // Changed from Rc::ptr_eq
-                        next_ms = conn.last_active_ms + K_IDLE_TIMEOUT_MS;
-                        break;
-                    }
-                }
-            }
-        }
-
-        // TTL timers using heap
-        if !g_data.heap.is_empty() && g_data.heap[0].value < next_ms {
-            next_ms = g_data.heap[0].value;
-        }
-    });
-
-    // Return timeout value
-    if next_ms == u64::MAX {
-        -1 // No timers
-    } else if next_ms <= now_ms {
-        0 // Expired/missed
-    } else {
-        (next_ms - now_ms) as i32
-    }
-}
-
-
-fn process_timers() {
-    let now_ms = get_monotonic_time_ms();
-
-    with_global_data(|g_data| {
-        // Idle timers (linked list)
-        let mut expired_fds = Vec::new();
-        
-        loop {
-            let first_node = {
-                let idle_list_ref = g_data.idle_list.lock().unwrap(); // Changed from borrow()
-                idle_list_ref.next.clone()
-            };
-
-            let Some(first_node_rc) = first_node else {
-                break; // list empty
-            };
-
-            // Find which connection owns this idle node
-            let mut found_expired = false;
-            for (&fd, conn) in &g_data.fd2conn {
-                if Arc::ptr_eq(&conn.idle_node, &first_node_rc) { // Changed from Rc::ptr_eq
-                    let expire_at = conn.last_active_ms + K_IDLE_TIMEOUT_MS;
-                    if expire_at < now_ms {
-                        println!("Idle connection expired: {}", fd);
-                        expired_fds.push(fd);
-                        found_expired = true;
-                    }
-                    break;
-                }
-            }
-            
-            if !found_expired {
-                break; // First connection not expired, so none are
-            }
-        }
-        
-        // Remove expired connections
-        for fd in expired_fds {
-            if let Some(conn) = g_data.fd2conn.remove(&fd) {
-                dlist_detach(conn.idle_node.clone());
-            }
-        }
-
-        // TTL timers (heap)
-        let mut nworks = 0;
-        while !g_data.heap.is_empty()
-            && g_data.heap[0].value < now_ms
-            && nworks < K_MAX_WORKS
-        {
-            let heap_item = &g_data.heap[0];
-            let entry_ref = heap_item.entry_ref.clone();
-
-            // Delete from DB
-            {
-                let entry = entry_ref.lock().unwrap(); // Changed from borrow()
-                g_data.db.delete_entry(&entry.key);
-                println!("TTL expired for key: {}", entry.key);
-            }
-
-            // Delete from heap
-            heap_delete(&mut g_data.heap, 0);
-
-            nworks += 1;
-        }
-    });
-}
-
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-pub enum ValueType {
-    Init = 0,
-    Str = 1,
-    ZSet = 2,
-}
-
-#[derive(Debug)]
-pub enum Value {
-    Init,
-    Str(String),
-    ZSet(ZSet),
-}
-
-impl Value {
-    pub fn value_type(&self) -> ValueType {
-        match self {
-            Value::Init => ValueType::Init,
-            Value::Str(..) => ValueType::Str,
-            Value::ZSet(..) => ValueType::ZSet,
-        }
-    }
-}
-
-// Key-value for the hash table
-#[derive(Debug)]
-pub struct Entry {
-    link: LinkedListLink,
-    hcode: u64,  // Keep the hash for performance
-    key: String,
-    value: Value,
-
-    // for TTL: if None, entry is not in heap
-    heap_idx: Option<usize>,
+impl HashTable {
+    fn new(n_buckets: usize, filtered: bool) -> Self {
+        assert!(n_buckets.is_power_of_two());
 
-}
+        let tab = (0..n_buckets)
+            .map(|_| LinkedList::new(EntryAdapter::new()))
+            .collect::<Vec<_>>();
 
-impl Entry {
-    fn new(key: String, value: Value) -> Self {
-        let hcode = hash_std(key.as_bytes());
         Self {
-            link: LinkedListLink::new(),
-            hcode,
-            key,
-            value,
-            heap_idx: None,
+            tab,
+            mask: n_buckets - 1,
+            size: 0,
+            filter: filtered.then(|| bloom::LookupFilter::new(n_buckets * K_MAX_LOAD_FACTOR)),
         }
     }
 
-    fn new_string(key: String, str_value: String) -> Self {
-        Self::new(key, Value::Str(str_value))
-    }
-    
-    fn new_zset(key: String, zset: ZSet) -> Self {
-        Self::new(key, Value::ZSet(zset))
-    }
-
-}
-
-fn entry_del(key: &str) {
-    with_global_data(|g_data| {
-        if let Some(entry) = g_data.db.delete_entry_and_return(key) {
-            // Remove from TTL heap
-            if let Some(heap_idx) = entry.heap_idx {
-                if heap_idx < g_data.heap.len() {
-                    heap_delete(&mut g_data.heap, heap_idx);
-                }
-            }
-            
-            let set_size = match &entry.value {
-                Value::ZSet(zset) => zset.name_to_node.len(),
-                _ => 0,
-            };
-            
-            if set_size > K_LARGE_CONTAINER_SIZE {
-                println!("Large ZSet detected ({} items), scheduling async cleanup", set_size);
-                
-                // Submit async work (entry drops here, doing the real cleanup)
-                g_data.thread_pool.submit(move || {
-                    // Simulate expensive cleanup work
-                    std::thread::sleep(std::time::Duration::from_millis(10));
-                    println!("Background: Completed simulated ZSet cleanup");
-                });
-            }
-            // entry drops here, automatically cleaning up the ZSet
-        }
-    });
-}
-
-fn entry_set_ttl(entry_ref: Arc<Mutex<Entry>>, ttl_ms: i64, heap: &mut Vec<HeapItem>) {
-    let mut entry = entry_ref.lock().unwrap();
-    
-    if ttl_ms < 0 {
-        if let Some(idx) = entry.heap_idx {
-            heap_delete(heap, idx);
-            entry.heap_idx = None;
-        }
-    } else {
-        let expire_at = get_monotonic_time_ms() + ttl_ms as u64;
-        let item = HeapItem::new(expire_at, entry_ref.clone());
-        heap_upsert(heap, &mut entry.heap_idx, item);
+    // Start or stop keeping a filter, filled from the entries in here
+    fn set_filtered(&mut self, filtered: bool) {
+        self.filter = filtered.then(|| {
+            let mut filter = bloom::LookupFilter::new(self.tab.len() * K_MAX_LOAD_FACTOR);
+            self.iter().for_each(|entry| filter.add(entry.hcode));
+            filter
+        });
     }
-}
 
-fn heap_upsert(heap: &mut Vec<HeapItem>, heap_idx: &mut Option<usize>, item: HeapItem) {
-    match *heap_idx {
-        Some(pos) if pos < heap.len() => {
-            // Update existing item
-            heap[pos] = item;
-            heap_update(heap, pos);
-        }
-        _ => {
-            // Add new item
-            let pos = heap.len();
-            heap.push(item);
-            *heap_idx = Some(pos);
-            heap_update(heap, pos);
-        }
+    // Whether an entry with hash `hcode` may be in here; false means there's
+    // no need to walk its bucket
+    fn may_contain(&self, hcode: u64) -> bool {
+        !self.tab.is_empty() && self.filter.as_ref().is_none_or(|filter| filter.may_contain(hcode))
     }
-}
-
-// Adapter for Entry to use in Intrusive Collections
-intrusive_adapter!(pub EntryAdapter = Box<Entry>: Entry { link: LinkedListLink });
 
-
-#[derive(Debug)]
-pub struct HNode {
-    link: LinkedListLink,
-    hcode: u64,
-} 
-
-
-impl HNode {
-    fn new(hcode: u64) -> Self {
-        Self {
-            link: LinkedListLink::new(),
-            hcode,
-        }
+    // The bucket index of hash `hcode` in here
+    fn bucket_of(&self, hcode: u64) -> usize {
+        (hcode as usize) & self.mask
     }
-}
-    
-
-//Adapter for HNode to use in Intrusive Collections
-intrusive_adapter!(pub HNodeAdapter = Box<HNode>: HNode { link: LinkedListLink });
-
-//fixed-size hash table
-#[derive(Debug)]
-pub struct HashTable {
-    tab: Vec<LinkedList<EntryAdapter>>,
-    mask: usize,
-    size: usize,
-}
-
-impl HashTable {
-    fn new(n_buckets: usize) -> Self {
-        assert!(n_buckets.is_power_of_two());
-
-        let tab = (0..n_buckets)
-            .map(|_| LinkedList::new(EntryAdapter::new()))
-            .collect::<Vec<_>>();
 
-        Self {
-            tab,
-            mask: n_buckets - 1,
-            size: 0,
+    // An entry was unlinked from its bucket
+    fn unlinked(&mut self, entry: &Entry) {
+        self.size -= 1;
+        if let Some(filter) = &mut self.filter {
+            filter.remove(entry.hcode);
         }
     }
 
@@ -1351,7 +1088,21 @@ impl HashTable {
 
 fn trigger_rehashing(hmap: &mut HMap) {
     let new_capacity = (hmap.newer.mask + 1) * 2;
-    let old_table = std::mem::replace(&mut hmap.newer, HashTable::new(new_capacity));
+    start_resize(hmap, new_capacity);
+}
+
+// Shrink to the smallest table with at least one bucket per entry
+fn trigger_shrinking(hmap: &mut HMap) {
+    let new_capacity = hmap.newer.size.next_power_of_two().max(K_MIN_BUCKETS);
+    start_resize(hmap, new_capacity);
+}
+
+// Swap in an empty table of `new_capacity` buckets; maybe_migrate, and the
+// event loop through rehash_for_us, move the entries over from the old one a
+// few at a time
+fn start_resize(hmap: &mut HMap, new_capacity: usize) {
+    let filtered = hmap.lookup_filter;
+    let old_table = std::mem::replace(&mut hmap.newer, HashTable::new(new_capacity, filtered));
     hmap.older = Some(old_table);
     hmap.migrate_pos = 0;
 }
@@ -1363,50 +1114,101 @@ pub struct HMap {
     newer: HashTable,
     older: Option<HashTable>,
     migrate_pos: usize, 
+    // Keys for SipHash-1-3, drawn at random when the keyspace is created, so
+    // clients can't pick keys that all land in one bucket
+    seed: RandomState,
+    // Tables keep a bloom::LookupFilter, as lookup-filter asks
+    lookup_filter: bool,
+    // Lookups that count as an access (see lookup_entry) that found a key,
+    // and that didn't; reads count them under the shared lock
+    hits: AtomicU64,
+    misses: AtomicU64,
+    // Lookups the filters answered without walking a bucket
+    filtered: AtomicU64,
 }
 
 impl HMap {
     fn new(initial_capacity: usize) -> Self {
         Self {
-            newer: HashTable::new(initial_capacity),
+            newer: HashTable::new(initial_capacity, false),
             older: None,
             migrate_pos: 0,
+            seed: RandomState::new(),
+            lookup_filter: false,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            filtered: AtomicU64::new(0),
         }
     }
 
-    fn lookup_entry(&self, key: &str) -> Option<&Entry> {
+    /// Keep a negative lookup filter for each table, or drop them; building
+    /// them walks every key, so only a change of the setting does it
+    pub fn set_lookup_filter(&mut self, enabled: bool) {
+        if enabled == self.lookup_filter {
+            return;
+        }
+        self.lookup_filter = enabled;
+        self.newer.set_filtered(enabled);
+        if let Some(older) = &mut self.older {
+            older.set_filtered(enabled);
+        }
+    }
+
+    /// Lookups that found their key, that didn't, and that the filters
+    /// answered on their own, for INFO
+    pub fn lookup_stats(&self) -> (u64, u64, u64) {
+        (
+            self.hits.load(AtomicOrdering::Relaxed),
+            self.misses.load(AtomicOrdering::Relaxed),
+            self.filtered.load(AtomicOrdering::Relaxed),
+        )
+    }
+
+    /// Start the lookup counters over, for CONFIG RESETSTAT
+    pub fn reset_lookup_stats(&self) {
+        for counter in [&self.hits, &self.misses, &self.filtered] {
+            counter.store(0, AtomicOrdering::Relaxed);
+        }
+    }
+
+    // peek_entry, expired or not
+    fn find_entry(&self, key: &str) -> Option<&Entry> {
         let eq = |entry: &Entry, probe: &str| -> bool {
             entry.key == probe
         };
         self.lookup(key, eq)
     }
 
-    fn set(&mut self, key: String, value: String) {
-        let entry = Box::new(Entry::new_string(key, value));  // Use new_string helper
-        self.insert(entry);
-    }
-
     pub fn lookup<F>(&self, key: &str, eq: F) -> Option<&Entry>
     where
         F: Fn(&Entry, &str) -> bool + Copy,
     {
-        if let Some(hit) = hash_lookup(&self.newer, key, eq) {
-            return Some(hit);
+        // Hashed once for both tables; entries keep their hcode as they move
+        let hcode = hash_key(&self.seed, key);
+        let tables = std::iter::once(&self.newer).chain(self.older.as_ref());
+        let mut walked = false;
+        for table in tables.filter(|table| table.may_contain(hcode)) {
+            walked = true;
+            if let Some(hit) = hash_lookup(table, table.bucket_of(hcode), hcode, key, eq) {
+                return Some(hit);
+            }
         }
-        if let Some(ref older_table) = self.older {
-            return hash_lookup(older_table, key, eq);
+        if !walked && self.lookup_filter {
+            self.filtered.fetch_add(1, AtomicOrdering::Relaxed);
         }
         None
     }
 
 
-    pub fn insert(&mut self, entry: Box<Entry>) {
+    pub fn insert(&mut self, mut entry: Box<Entry>) {
         // check if hash map is initialised
         if self.newer.tab.is_empty() {
-            self.newer = HashTable::new(4);
+            self.newer = HashTable::new(4, self.lookup_filter);
         }
  
-        // insert into newer table
+        // insert into newer table (writes count as an access)
+        entry.touch();
+        entry.hcode = hash_key(&self.seed, &entry.key);
         insert_hash(&mut self.newer, entry);
  
         // rehashing check
@@ -1427,59 +1229,92 @@ impl HMap {
     where
         F: Fn(&Entry, &str) -> bool + Copy,
     {
-        if let Some(mut cursor) = hash_lookup_cursor(&mut self.newer, key, eq) {
+        let node = self.delete_from_tables(key, eq);
+        if node.is_some() {
+            // shrinking check, mirroring the growth check in insert
+            if self.older.is_none() {
+                let capacity = self.newer.mask + 1;
+                if capacity > K_MIN_BUCKETS && self.newer.size < capacity / K_MIN_FILL_RATIO {
+                    trigger_shrinking(self);
+                }
+            }
+            self.maybe_migrate();
+        }
+        node
+    }
+
+    fn delete_from_tables<F>(&mut self, key: &str, eq: F) -> Option<Box<Entry>>
+    where
+        F: Fn(&Entry, &str) -> bool + Copy,
+    {
+        let hcode = hash_key(&self.seed, key);
+        let bucket = self.newer.bucket_of(hcode);
+        if let Some(mut cursor) = hash_lookup_cursor(&mut self.newer, bucket, hcode, key, eq) {
             let node = cursor.remove();
-            if node.is_some() {
-                self.newer.size -= 1;
+            if let Some(entry) = &node {
+                self.newer.unlinked(entry);
             }
             return node;
         }
 
-        if let Some(ref mut older_table) = self.older {
-            if let Some(mut cursor) = hash_lookup_cursor(older_table, key, eq) {
-                let node = cursor.remove();
-                if node.is_some() {
-                    older_table.size -= 1;
-                }
-                return node;
+        if let Some(ref mut older_table) = self.older
+            && let bucket = older_table.bucket_of(hcode)
+            && let Some(mut cursor) = hash_lookup_cursor(older_table, bucket, hcode, key, eq)
+        {
+            let node = cursor.remove();
+            if let Some(entry) = &node {
+                older_table.unlinked(entry);
             }
+            return node;
         }
 
         None
     }
 
-    pub fn hashmap_rehashing(&mut self) {
+    /// Move entries from the older table to the newer one, up to `max_work`
+    /// steps, where moving an entry and passing an empty bucket both count
+    /// as one, so a sparse older table can't make one call walk it all.
+    /// Entries are unlinked and relinked as they are, in the same box and
+    /// with the hcode computed when they were inserted, so a move costs no
+    /// allocation and no hashing. Returns the steps taken.
+    pub fn hashmap_rehashing(&mut self, max_work: usize) -> usize {
+        let Some(older) = &mut self.older else {
+            return 0;
+        };
         let mut nwork = 0;
-
-        while nwork < K_REHASHING_WORK && self.older.as_ref().map_or(0, |o| o.size) > 0 {
-            if let Some(older) = &mut self.older {
-                if self.migrate_pos >= older.tab.len() {
-                    break;
-                }
-
-                if older.tab[self.migrate_pos].is_empty(){
-                    self.migrate_pos += 1;
-                    continue;
-                }
-
-               let mut cursor = older.tab[self.migrate_pos].front_mut();
-
-                if let Some(entry) = cursor.remove() {
+        while nwork < max_work && older.size > 0 && self.migrate_pos < older.tab.len() {
+            nwork += 1;
+            let bucket = &mut older.tab[self.migrate_pos];
+            match bucket.front_mut().remove() {
+                Some(entry) => {
+                    debug_assert_eq!(entry.bucket, self.migrate_pos);
+                    older.unlinked(&entry);
                     insert_hash(&mut self.newer, entry);
-                    nwork += 1;
                 }
+                None => self.migrate_pos += 1,
             }
         }
 
-        if let Some(older) = &self.older {
-            if older.size == 0 {
-                self.older = None;
+        if older.size == 0 {
+            self.older = None;
+        }
+        nwork
+    }
+
+    /// Rehash for about `budget_us` microseconds, K_REHASHING_WORK steps at
+    /// a time; for the event loop, so a table whose resize no write carries
+    /// on with still gets done
+    pub fn rehash_for_us(&mut self, budget_us: u64) {
+        let start_ns = get_monotonic_time_ns();
+        while self.is_migrating() {
+            self.hashmap_rehashing(K_REHASHING_WORK);
+            if get_monotonic_time_ns() - start_ns >= budget_us as u128 * 1000 {
+                break;
             }
         }
     }
-    
-    #[allow(dead_code)]
-    fn is_migrating(&self) -> bool {
+
+    pub fn is_migrating(&self) -> bool {
         self.older.is_some()
     }
 
@@ -1489,6 +1324,50 @@ impl HMap {
         newer_size + older_size
     }
 
+    /// One step of a SCAN: visit the entries of the buckets `cursor` points
+    /// at and return the cursor to continue from, 0 once the scan is done.
+    ///
+    /// The cursor counts with its bits reversed, so it walks bucket indexes
+    /// high bit first. A bucket's entries move to bucket `i` or `i + old
+    /// size` when the table doubles (and back when it halves), and those are
+    /// exactly the indexes a reversed counter visits next to each other. So a
+    /// resize between steps never makes the scan skip a key that was present
+    /// from start to end; at worst some keys are returned twice. While two
+    /// tables exist, the small one's bucket is visited together with every
+    /// bucket of the large one that its entries can move to.
+    pub fn scan<F: FnMut(&Entry)>(&self, cursor: u64, mut visit: F) -> u64 {
+        fn next_cursor(cursor: u64, mask: u64) -> u64 {
+            // Increment the bits under the mask, high bit first
+            (cursor | !mask).reverse_bits().wrapping_add(1).reverse_bits()
+        }
+
+        // Keys whose TTL passed are left out, as peek_entry does
+        let mut visit_bucket = |table: &HashTable, cursor: u64| {
+            table.tab[cursor as usize & table.mask].iter().filter(|entry| !entry.is_expired()).for_each(&mut visit);
+        };
+
+        let Some(older) = &self.older else {
+            if self.newer.tab.is_empty() {
+                return 0;
+            }
+            visit_bucket(&self.newer, cursor);
+            return next_cursor(cursor, self.newer.mask as u64);
+        };
+
+        let (small, large) = if older.mask < self.newer.mask { (older, &self.newer) } else { (&self.newer, older) };
+        let (small_mask, large_mask) = (small.mask as u64, large.mask as u64);
+        visit_bucket(small, cursor);
+        let mut cursor = cursor;
+        loop {
+            visit_bucket(large, cursor);
+            cursor = next_cursor(cursor, large_mask);
+            // Done once the bits only the large table has wrapped around
+            if cursor & (small_mask ^ large_mask) == 0 {
+                return cursor;
+            }
+        }
+    }
+
     fn iter(&self) -> impl Iterator<Item = &Entry> + '_ {
         let newer_iter = self.newer.iter();
         let older_iter = self.older.as_ref().map(|h| h.iter()).into_iter().flatten();
@@ -1496,13 +1375,6 @@ impl HMap {
     }
 
 
-    pub fn delete_entry(&mut self, key: &str) -> bool {
-        let eq = |entry: &Entry, probe: &str| -> bool {
-            entry.key == probe
-        };
-        self.delete(key, eq).is_some()
-    }
-
     pub fn delete_entry_and_return(&mut self, key: &str) -> Option<Box<Entry>> {
         let eq = |entry: &Entry, probe: &str| -> bool {
             entry.key == probe
@@ -1511,9 +1383,13 @@ impl HMap {
     }
 
     pub fn maybe_migrate(&mut self) {
-        if self.older.is_some() {
-            self.hashmap_rehashing();
-        }
+        self.hashmap_rehashing(K_REHASHING_WORK);
+    }
+
+    // Entries waiting in the older table to be moved
+    #[cfg(test)]
+    fn pending_migration(&self) -> usize {
+        self.older.as_ref().map_or(0, |older| older.size)
     }
 
 }
@@ -1524,14 +1400,94 @@ impl Default for HMap {
     }
 }
 
-
+// Reads of the keyspace, whatever stores it
+impl dyn KvStore {
+    // The entry at `key`, counting as an access to it and as a keyspace hit
+    // or miss
+    fn lookup_entry(&self, key: &str) -> Option<&Entry> {
+        let Some(entry) = self.peek_entry(key) else {
+            self.table().misses.fetch_add(1, AtomicOrdering::Relaxed);
+            return None;
+        };
+        self.table().hits.fetch_add(1, AtomicOrdering::Relaxed);
+        entry.touch();
+        Some(entry)
+    }
+
+    // lookup_entry without counting as an access (OBJECT, TTL). A key whose
+    // TTL passed is missing from here on: process_timers deletes it in the
+    // next round, as its timer is due by then too.
+    fn peek_entry(&self, key: &str) -> Option<&Entry> {
+        self.get(key).filter(|entry| !entry.is_expired())
+    }
+
+    // The value at `key` as `pick` sees it: None without a key, WrongType
+    // when `pick` doesn't take the type stored there. Counts as an access.
+    fn expect_value<'a, T: ?Sized>(
+        &'a self,
+        key: &str,
+        pick: impl FnOnce(&'a Value) -> Option<&'a T>,
+    ) -> Result<Option<&'a T>, CmdError> {
+        match self.lookup_entry(key) {
+            None => Ok(None),
+            Some(entry) if matches!(entry.value, Value::Init) => Ok(None),
+            Some(entry) => pick(&entry.value).map(Some).ok_or(CmdError::WrongType),
+        }
+    }
+
+    /// The string at `key`, if any
+    fn expect_str(&self, key: &str) -> Result<Option<&Arc<str>>, CmdError> {
+        self.expect_value(key, |value| match value {
+            Value::Str(s) => Some(s),
+            _ => None,
+        })
+    }
+
+    /// The sorted set at `key`, if any
+    fn expect_zset(&self, key: &str) -> Result<Option<&ZSet>, CmdError> {
+        self.expect_value(key, |value| match value {
+            Value::ZSet(zset) => Some(&**zset),
+            _ => None,
+        })
+    }
+
+    /// The list at `key`, if any
+    fn expect_list(&self, key: &str) -> Result<Option<&VecDeque<String>>, CmdError> {
+        self.expect_value(key, |value| match value {
+            Value::List(list) => Some(&**list),
+            _ => None,
+        })
+    }
+
+    /// The stream at `key`, if any
+    fn expect_stream(&self, key: &str) -> Result<Option<&stream::Stream>, CmdError> {
+        self.expect_value(key, |value| match value {
+            Value::Stream(stream) => Some(&**stream),
+            _ => None,
+        })
+    }
+}
+
+
 //Sorted Set //
 #[derive(Debug, Default)]
-struct ZSet {
+pub struct ZSet {
     root: Option<Arc<Mutex<ZNode>>>, // AVL root
     name_to_node: HashMap<String, Arc<Mutex<ZNode>>> // index by name
 }
 
+// A deep copy, for copy-on-write: the nodes are linked through their Arcs,
+// so the copy rebuilds the tree from the members
+impl Clone for ZSet {
+    fn clone(&self) -> Self {
+        let mut zset = ZSet::new();
+        for (name, node) in &self.name_to_node {
+            zset.insert(node.lock().unwrap().score, name.clone());
+        }
+        zset
+    }
+}
+
 impl ZSet {
     fn new() -> Self {
         Self {
@@ -1548,6 +1504,7 @@ impl ZSet {
         // Check if node already exists
         if let Some(existing_node) = self.lookup(&name) {
             self.zset_update(&existing_node, score);
+            zset::debug_check(self);
             return false; // Updated existing
         }
         
@@ -1559,6 +1516,7 @@ impl ZSet {
         
         // Insert into tree
         self.tree_insert(znode);
+        zset::debug_check(self);
         
         true // Inserted new
     }
@@ -1587,11 +1545,6 @@ impl ZSet {
         self.name_to_node.get(name).cloned()
     }
 
-    fn lookup_by_score(&self, score: f64, name: &str) -> Option<Arc<Mutex<ZNode>>> {
-        znode_search(&self.root, score, name)
-    }
-
-
     fn delete(&mut self, node: &Arc<Mutex<ZNode>>) {
         let name = node.lock().unwrap().name.clone();
     
@@ -1603,6 +1556,7 @@ impl ZSet {
     
         // Remove from tree
         self.root = znode_delete(self.root.clone(), node);
+        zset::debug_check(self);
     }
 
     fn zset_seekge(
@@ -1633,7 +1587,73 @@ impl ZSet {
         candidate
     }
 
+    fn len(&self) -> usize {
+        self.name_to_node.len()
+    }
+
+    /// The member with the lowest (score, name)
+    fn first(&self) -> Option<Arc<Mutex<ZNode>>> {
+        let mut node = self.root.clone()?;
+        loop {
+            let left = node.lock().unwrap().tree_left.clone();
+            match left {
+                Some(left) => node = left,
+                None => return Some(node),
+            }
+        }
+    }
+
+    /// The member with the highest (score, name)
+    fn last(&self) -> Option<Arc<Mutex<ZNode>>> {
+        let mut node = self.root.clone()?;
+        loop {
+            let right = node.lock().unwrap().tree_right.clone();
+            match right {
+                Some(right) => node = right,
+                None => return Some(node),
+            }
+        }
+    }
+
+    /// The member at `rank`, 0 being the lowest, in O(log n) through the
+    /// subtree counts
+    fn at_rank(&self, rank: usize) -> Option<Arc<Mutex<ZNode>>> {
+        znode_offset(self.first(), rank as i64)
+    }
 
+    /// The first member at `min` or past it, in (score, name) order
+    fn seek_score(&self, min: ScoreBound) -> Option<Arc<Mutex<ZNode>>> {
+        let mut node = self.zset_seekge(min.score, "");
+        while let Some(candidate) = node.clone() {
+            if min.below(candidate.lock().unwrap().score) {
+                break;
+            }
+            node = znode_offset(Some(candidate), 1);
+        }
+        node
+    }
+
+    /// Remove the members from `start` on, in order, for as long as
+    /// `in_range` holds, and return how many went.
+    ///
+    /// The range is collected before anything is removed: deleting rotates
+    /// the tree under the parent links znode_offset walks. Each removal
+    /// then rebalances and fixes tree_count on its own path, as ZREM does.
+    fn remove_range(&mut self, start: Option<Arc<Mutex<ZNode>>>, mut in_range: impl FnMut(&ZNode) -> bool) -> usize {
+        let mut doomed = Vec::new();
+        let mut node = start;
+        while let Some(current) = node {
+            if !in_range(&current.lock().unwrap()) {
+                break;
+            }
+            node = znode_offset(Some(current.clone()), 1);
+            doomed.push(current);
+        }
+        for node in &doomed {
+            self.delete(node);
+        }
+        doomed.len()
+    }
 
 }
 
@@ -1648,13 +1668,11 @@ struct ZNode {
 
     // Data
     score: f64,
-    len: usize,
     name: String,
 }
 
 impl ZNode {
     fn new(score: f64, name: String) -> Arc<Mutex<Self>> {
-        let len = name.len();
         Arc::new(Mutex::new(Self {
             tree_parent: None,
             tree_left: None,
@@ -1662,7 +1680,6 @@ impl ZNode {
             tree_height: 1,
             tree_count: 1,
             score,
-            len,
             name,
         }))
     }
@@ -1743,42 +1760,10 @@ fn znode_insert(root: &mut Option<Arc<Mutex<ZNode>>>, new_node: Arc<Mutex<ZNode>
     }
 }
 
-fn znode_search(
-    root: &Option<Arc<Mutex<ZNode>>>, 
-    score: f64, 
-    name: &str
-) -> Option<Arc<Mutex<ZNode>>> {
-    let mut current = root.clone();
-    
-    while let Some(node) = current {
-        let cmp = {
-            let node_ref = node.lock().unwrap();
-            (OrderedFloat(score), name)
-                .cmp(&(OrderedFloat(node_ref.score), node_ref.name.as_str()))
-        };
-        
-        match cmp {
-            std::cmp::Ordering::Less => {
-                current = node.lock().unwrap().tree_left.clone();
-            }
-            std::cmp::Ordering::Greater => {
-                current = node.lock().unwrap().tree_right.clone();
-            }
-            std::cmp::Ordering::Equal => {
-                return Some(node);
-            }
-        }
-    }
-    
-    None
-}
-
 fn znode_delete(
     root: Option<Arc<Mutex<ZNode>>>, 
     target: &Arc<Mutex<ZNode>>
 ) -> Option<Arc<Mutex<ZNode>>> {
-    // This is similar to your existing avl_del but for ZNode
-    
     // Easy case: 0 or 1 child
     let (has_left, has_right) = {
         let target_ref = target.lock().unwrap();
@@ -1806,36 +1791,48 @@ fn znode_delete(
         }
     }
 
-    // Swap the data (score, name) instead of moving nodes
-    let victim_score = victim.lock().unwrap().score;
-    let victim_name = victim.lock().unwrap().name.clone();
-    let victim_len = victim.lock().unwrap().len;
-    
-    let target_score = target.lock().unwrap().score;
-    let target_name = target.lock().unwrap().name.clone();
-    let target_len = target.lock().unwrap().len;
+    // Detach the successor (it has no left child), then move it into the
+    // target's place. Nodes keep their own data: name_to_node points at them.
+    let mut root = znode_del_easy(root, &victim);
 
-    // Swap the data
+    let (left, right, parent, height, count) = {
+        let target_ref = target.lock().unwrap();
+        (
+            target_ref.tree_left.clone(),
+            target_ref.tree_right.clone(),
+            target_ref.tree_parent.clone(),
+            target_ref.tree_height,
+            target_ref.tree_count,
+        )
+    };
     {
         let mut victim_mut = victim.lock().unwrap();
-        victim_mut.score = target_score;
-        victim_mut.name = target_name;
-        victim_mut.len = target_len;
+        victim_mut.tree_left = left.clone();
+        victim_mut.tree_right = right.clone();
+        victim_mut.tree_parent = parent.clone();
+        victim_mut.tree_height = height;
+        victim_mut.tree_count = count;
     }
-    
-    {
-        let mut target_mut = target.lock().unwrap();
-        target_mut.score = victim_score;
-        target_mut.name = victim_name;
-        target_mut.len = victim_len;
+    for child in left.iter().chain(right.iter()) {
+        child.lock().unwrap().tree_parent = Some(Arc::clone(&victim));
     }
 
-    // Now delete the victim (which has at most 1 child)
-    znode_del_easy(root, &victim)
+    match parent {
+        Some(parent_node) => {
+            let mut parent_mut = parent_node.lock().unwrap();
+            if parent_mut.tree_left.as_ref().is_some_and(|l| Arc::ptr_eq(l, target)) {
+                parent_mut.tree_left = Some(Arc::clone(&victim));
+            } else {
+                parent_mut.tree_right = Some(Arc::clone(&victim));
+            }
+        }
+        None => root = Some(Arc::clone(&victim)),
+    }
+    root
 }
 
 fn znode_del_easy(
-    root: Option<Arc<Mutex<ZNode>>>, 
+    _root: Option<Arc<Mutex<ZNode>>>, 
     target: &Arc<Mutex<ZNode>>
 ) -> Option<Arc<Mutex<ZNode>>> {
     // Precondition: target has at most 1 child
@@ -1869,7 +1866,7 @@ fn znode_del_easy(
     let is_left_child = {
         let parent_ref = parent_node.lock().unwrap();
         parent_ref.tree_left.as_ref()
-            .map_or(false, |left| Arc::ptr_eq(left, target))
+            .is_some_and(|left| Arc::ptr_eq(left, target))
     };
 
     if is_left_child {
@@ -1923,9 +1920,9 @@ fn znode_rot_right(node: Arc<Mutex<ZNode>>) -> Arc<Mutex<ZNode>> {
 
     if let Some(p) = parent {
         let mut p_mut = p.lock().unwrap();
-        if p_mut.tree_left.as_ref().map_or(false, |left| Arc::ptr_eq(left, &node)) {
+        if p_mut.tree_left.as_ref().is_some_and(|left| Arc::ptr_eq(left, &node)) {
             p_mut.tree_left = Some(pivot.clone());
-        } else if p_mut.tree_right.as_ref().map_or(false, |right| Arc::ptr_eq(right, &node)) {
+        } else if p_mut.tree_right.as_ref().is_some_and(|right| Arc::ptr_eq(right, &node)) {
             p_mut.tree_right = Some(pivot.clone());
         }
     }
@@ -1963,9 +1960,9 @@ fn znode_rot_left(node: Arc<Mutex<ZNode>>) -> Arc<Mutex<ZNode>> {
 
     if let Some(p) = parent {
         let mut p_mut = p.lock().unwrap();
-        if p_mut.tree_left.as_ref().map_or(false, |left| Arc::ptr_eq(left, &node)) {
+        if p_mut.tree_left.as_ref().is_some_and(|left| Arc::ptr_eq(left, &node)) {
             p_mut.tree_left = Some(pivot.clone());
-        } else if p_mut.tree_right.as_ref().map_or(false, |right| Arc::ptr_eq(right, &node)) {
+        } else if p_mut.tree_right.as_ref().is_some_and(|right| Arc::ptr_eq(right, &node)) {
             p_mut.tree_right = Some(pivot.clone());
         }
     }
@@ -2055,969 +2052,2235 @@ fn znode_fix(mut node: Arc<Mutex<ZNode>>) -> Arc<Mutex<ZNode>> {
             } else {
                 parent.lock().unwrap().tree_right = Some(Arc::clone(&fixed));
             }
-            
-            node = parent;
-        } else {
-            return fixed;
+            
+            node = parent;
+        } else {
+            return fixed;
+        }
+    }
+}
+
+fn znode_offset(
+    node: Option<Arc<Mutex<ZNode>>>, 
+    offset: i64
+) -> Option<Arc<Mutex<ZNode>>> {
+    match node {
+        Some(znode_rc) => {
+            // Just delegate to avl_offset
+            avl_offset(Some(znode_rc.clone()), offset)
+        }
+        None => None,
+    }
+}
+
+
+pub fn insert_hash(htab: &mut HashTable, mut entry: Box<Entry>) {
+    let pos = htab.bucket_of(entry.hcode);
+    entry.bucket = pos;
+    if let Some(filter) = &mut htab.filter {
+        filter.add(entry.hcode);
+    }
+    htab.tab[pos].push_front(entry);
+    htab.size += 1;
+}
+
+/// The entry for `key`, whose hash is `hcode`, in one table, where it
+/// falls in bucket `bucket`. The cached hcode of each entry is compared
+/// first so `eq` only runs on likely matches.
+pub fn hash_lookup<'a, F>(
+    table: &'a HashTable,
+    bucket: usize,
+    hcode: u64,
+    key: &str,
+    eq: F,
+) -> Option<&'a Entry>
+where
+    F: Fn(&Entry, &str) -> bool + Copy,
+{
+    if table.tab.is_empty() {
+        return None;
+    }
+
+    let mut cursor = table.tab[bucket].front();
+
+    while !cursor.is_null() {
+        let entry = cursor.get().unwrap();
+        if entry.hcode == hcode && eq(entry, key) {
+            return Some(entry);
+        }
+        cursor.move_next();
+    }
+
+    None
+}
+
+pub fn hash_lookup_cursor<'a, F>(
+    htab: &'a mut HashTable,
+    bucket: usize,
+    hcode: u64,
+    key: &str,
+    eq: F,
+) -> Option<CursorMut<'a, EntryAdapter>>
+where
+    F: Fn(&Entry, &str) -> bool,
+{
+    if htab.tab.is_empty() {
+        return None;
+    }
+
+    let mut cur = htab.tab[bucket].front_mut();
+
+    while let Some(entry) = cur.get() {
+        if entry.hcode == hcode && eq(entry, key) {
+            return Some(cur);
+        }
+        cur.move_next();
+    }
+    None
+}
+
+pub fn hash_delete(
+    htab: &mut HashTable,
+    mut cursor: CursorMut<EntryAdapter>,
+) -> Option<Box<Entry>> {
+    let node = cursor.remove();
+    if let Some(entry) = &node {
+        htab.unlinked(entry);
+    }
+    node
+}
+
+// Client fd -> monotonic ms at which it times out for being idle
+type IdleTimers = timer::TimerWheel<RawFd>;
+
+// global data structure
+#[derive(Debug)]
+struct GData {
+    db: Box<dyn KvStore>,
+    // Monotonic ms the event loop rehashes the keyspace table at next
+    active_rehash_ms: u64,
+    fd2conn: HashMap<RawFd, Conn>,
+    idle_timers: IdleTimers,
+    // config.timeout (in ms) the idle timers were set with
+    idle_timeout_ms: u64,
+    expires: Expires,
+    thread_pool: ThreadPool,
+    blocking: blocking::Blocking,
+    // KEYS run by a client, in progress, by its fd
+    keys_scans: HashMap<RawFd, KeysScan>,
+    stats: info::ServerStats,
+    // Startup load of the snapshot or AOF
+    loading: info::Loading,
+    tracking: tracking::Tracking,
+    cluster: cluster::Cluster,
+    // Idle connections to MIGRATE targets
+    migrate_pool: migrate::Pool,
+    replication: replication::Replication,
+    aof: aof::Aof,
+    audit: audit::Audit,
+    config: config::Config,
+    // Set by QUIT: close the running client once its reply is written
+    quit_requested: bool,
+    // Set by CLIENT REPLY: the running client's new reply mode
+    reply_mode_requested: Option<ReplyMode>,
+    // Set by SYNC, PSYNC and REPLCONF ACK: sent to the running client as is,
+    // instead of a reply
+    raw_reply_requested: Option<Vec<u8>>,
+    pause: pause::Pause,
+    eviction_pool: evict::Pool,
+    failover: failover::Failover,
+    command_stats: latency::CommandStats,
+    accept: fdlimit::AcceptState,
+    clients: clients::Clients,
+}
+
+impl GData {
+    /// Take the entry at `key` out of the db for a write. One whose TTL
+    /// passed is expired here instead, and the write finds no key.
+    fn take_entry(&mut self, key: &str) -> Option<Box<Entry>> {
+        let entry = self.db.delete(key)?;
+        if !entry.is_expired() {
+            return Some(entry);
+        }
+        entry_discard(self, entry);
+        events::emit(self, &ServerEvent::KeyExpired { key });
+        log_trace!("expire", "TTL expired for key: {}", key);
+        None
+    }
+
+    /// Read, modify and write back the value at `key` in one step. `f` gets
+    /// the value (None if the key doesn't exist) and may change it, replace
+    /// it, put one in to create the key or take it to delete the key. The
+    /// side effects are then the same for every caller: a key that stays
+    /// keeps its TTL and counts as accessed, a deleted one (or a list or
+    /// sorted set left empty) goes with its TTL, and clients tracking the
+    /// key or blocked on it are told. Replication and the AOF see the
+    /// command that did it, as for any write.
+    fn with_entry_mut<R>(&mut self, key: &str, f: impl FnOnce(&mut Option<Value>) -> R) -> R {
+        let mut entry = self.take_entry(key);
+        let mut value = entry.as_mut().map(|entry| mem::replace(&mut entry.value, Value::Init));
+
+        let result = f(&mut value);
+
+        match (entry, value.filter(|value| !value.is_emptied())) {
+            // Nothing there before or after
+            (None, None) => return result,
+            (Some(entry), None) => entry_discard(self, entry),
+            (Some(mut entry), Some(value)) => {
+                entry.value = value;
+                entry.touch();
+                let expire_at = entry_expire_at(&self.expires, &entry);
+                self.db.set(entry, expire_at);
+            }
+            (None, Some(value)) => self.db.set(Box::new(Entry::new(key.to_string(), value)), None),
+        }
+        events::emit(self, &ServerEvent::KeyModified { key });
+        self.blocking.signal_key_ready(key);
+        result
+    }
+
+    /// Take the entry at `key` out, along with the unix time in ms its TTL
+    /// is due at, if it has one. With place_entry this is how a key moves
+    /// to another name (RENAME): whatever the type, the TTL goes with the
+    /// value as the same point in time.
+    fn take_entry_with_ttl(&mut self, key: &str) -> Option<(Box<Entry>, Option<u64>)> {
+        let entry = self.take_entry(key)?;
+        let expire_at = entry_expire_at(&self.expires, &entry);
+        clear_ttl(&mut self.expires, &entry);
+        Some((entry, expire_at))
+    }
+
+    /// Put `value` in at `key`, replacing what was there, to expire at the
+    /// unix time in ms `expire_at`. Every key made from another one or from
+    /// a serialized value comes in this way, so RENAME, COPY, RESTORE (and
+    /// so MIGRATE's target) and loading a snapshot all keep TTLs the same.
+    /// A time already past leaves no key, as if it had expired right away;
+    /// false then.
+    fn place_entry(&mut self, key: &str, value: Value, expire_at: Option<u64>) -> bool {
+        if let Some(old) = self.take_entry(key) {
+            entry_discard(self, old);
+            self.stats.replaced_keys += 1;
+        }
+        events::emit(self, &ServerEvent::KeyModified { key });
+        if expire_at.is_some_and(|at| at <= get_current_time_ms()) {
+            return false;
+        }
+        let entry = Box::new(Entry::new(key.to_string(), value));
+        if let Some(at) = expire_at {
+            entry_set_expire_at(&mut self.expires, &entry, at);
+        }
+        self.db.set(entry, expire_at);
+        self.blocking.signal_key_ready(key);
+        true
+    }
+}
+
+impl std::fmt::Debug for ThreadPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ThreadPool")
+            .field("num_threads", &self.threads.len())
+            .field("queue_len", &{
+                let (lock, _) = &*self.queue;
+                lock.lock().unwrap().len()
+            })
+            .field("shutdown", &{
+                *self.shutdown.lock().unwrap()
+            })
+            .finish()
+    }
+}
+
+
+fn out_nil(buf: &mut Buffer) {
+    buf.append(&[Tag::Nil as u8]);
+}
+
+fn out_str(buf: &mut Buffer, s: &str) {
+    buf.append_u8(Tag::Str as u8);
+    buf.append_u32(s.len() as u32);
+    buf.append(s.as_bytes());
+}
+
+// out_str for a stored value: a large one is written to the client straight
+// from the keyspace's copy, where the buffer allows it
+fn out_shared_str(buf: &mut Buffer, s: &Arc<str>) {
+    if !buf.share_values || s.len() < SHARED_MIN_LEN {
+        return out_str(buf, s);
+    }
+    buf.append_u8(Tag::Str as u8);
+    buf.append_u32(s.len() as u32);
+    buf.shared.push((buf.len(), s.clone()));
+}
+
+fn out_int(buf: &mut Buffer, val: i64) {
+    buf.append_u8(Tag::Int as u8);
+    buf.append_i64(val);
+}
+
+fn out_dbl(buf: &mut Buffer, val: f64) {
+    buf.append(&[Tag::Dbl as u8]);
+    buf.append(&val.to_le_bytes());
+}
+
+fn out_arr(buf: &mut Buffer, n: u32) {
+    buf.append(&[Tag::Arr as u8]);
+    buf.append(&n.to_le_bytes());
+}
+
+// A push message of `n` items, which follow as for an array
+fn out_push(buf: &mut Buffer, n: u32) {
+    buf.append(&[Tag::Push as u8]);
+    buf.append(&n.to_le_bytes());
+}
+
+fn out_err(buf: &mut Buffer, msg: &str) {
+    buf.append(&[Tag::Err as u8]);
+    buf.append(&(msg.len() as u32).to_le_bytes());
+    buf.append(msg.as_bytes());
+}
+
+fn out_value(buf: &mut Buffer, value: &RedisValue) {
+    match value {
+        RedisValue::Nil => out_nil(buf),
+        RedisValue::Err(msg) => out_err(buf, msg),
+        RedisValue::Str(s) => out_str(buf, s),
+        RedisValue::Int(n) => out_int(buf, *n),
+        RedisValue::Dbl(d) => out_dbl(buf, *d),
+        RedisValue::Arr(items) | RedisValue::Push(items) => {
+            match value {
+                RedisValue::Push(_) => out_push(buf, items.len() as u32),
+                _ => out_arr(buf, items.len() as u32),
+            }
+            for item in items {
+                out_value(buf, item);
+            }
+        }
+    }
+}
+
+// Entries, or buckets, KEYS walks per event loop round for a client
+const KEYS_CHUNK_SIZE: usize = 10_000;
+
+/// A KEYS spread over event loop rounds: where its scan is and the keys
+/// matched so far, a set as a resize can make the scan see a key twice
+#[derive(Debug, Default)]
+struct KeysScan {
+    cursor: u64,
+    keys: HashSet<String>,
+}
+
+// KEYS [pattern]
+//
+// Run by a client, KEYS walks the keyspace with the SCAN cursor, at most
+// KEYS_CHUNK_SIZE entries a round, and the client waits suspended between
+// rounds so the others are served meanwhile. The reply has every key that
+// matched from start to end, as SCAN guarantees, each once. Scripts get the
+// whole list at once.
+fn do_keys(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let pattern = cmd.get(1).map_or("*", String::as_str);
+    let fd = blocking::current_client();
+    let mut scan = fd.and_then(|fd| with_global_data(|g_data| g_data.keys_scans.remove(&fd))).unwrap_or_default();
+    let chunk = if fd.is_some() { KEYS_CHUNK_SIZE } else { usize::MAX };
+
+    let done = with_global_data_read(|g_data| {
+        // Each step visits a bucket or more, so empty ones count too
+        let (mut seen, mut steps) = (0, 0);
+        loop {
+            scan.cursor = g_data.db.scan(scan.cursor, &mut |entry| {
+                seen += 1;
+                if glob_match(pattern, &entry.key, false) {
+                    scan.keys.insert(entry.key.clone());
+                }
+            });
+            steps += 1;
+            if scan.cursor == 0 {
+                return true;
+            }
+            if seen >= chunk || steps >= chunk {
+                return false;
+            }
+        }
+    });
+
+    if !done
+        && let Some(fd) = fd
+        && with_global_data(|g_data| g_data.blocking.suspend(cmd))
+    {
+        with_global_data(|g_data| g_data.keys_scans.insert(fd, scan));
+        return Ok(());
+    }
+    out_arr(out, scan.keys.len() as u32);
+    for key in &scan.keys {
+        out_str(out, key);
+    }
+    Ok(())
+}
+
+// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+fn do_scan(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let Ok(mut cursor) = cmd[1].parse::<u64>() else {
+        out_err(out, "ERR invalid cursor");
+        return Ok(());
+    };
+    let mut pattern = None;
+    let mut count = 10;
+    let mut type_name = None;
+    for option in cmd[2..].chunks(2) {
+        match (option[0].to_uppercase().as_str(), option.get(1)) {
+            ("MATCH", Some(value)) => pattern = Some(value.as_str()),
+            ("COUNT", Some(value)) => match value.parse::<usize>() {
+                Ok(n) if n > 0 => count = n,
+                _ => {
+                    out_err(out, "ERR value is out of range, must be positive");
+                    return Ok(());
+                }
+            },
+            ("TYPE", Some(value)) => type_name = Some(value.to_lowercase()),
+            _ => {
+                out_err(out, "ERR syntax error");
+                return Ok(());
+            }
+        }
+    }
+
+    with_global_data_read(|g_data| {
+        let mut keys = Vec::new();
+        // COUNT is a hint: stop after enough keys, or enough empty buckets
+        let mut buckets = count.saturating_mul(10);
+        loop {
+            cursor = g_data.db.scan(cursor, &mut |entry| {
+                let matched = pattern.is_none_or(|pattern| glob_match(pattern, &entry.key, false))
+                    && type_name.as_deref().is_none_or(|name| g_data.db.type_name(entry) == name);
+                if matched {
+                    keys.push(entry.key.clone());
+                }
+            });
+            buckets -= 1;
+            if cursor == 0 || keys.len() >= count || buckets == 0 {
+                break;
+            }
+        }
+
+        out_arr(out, 2);
+        out_str(out, &cursor.to_string());
+        out_arr(out, keys.len() as u32);
+        for key in &keys {
+            out_str(out, key);
+        }
+    });
+    Ok(())
+}
+
+// PING [message]
+fn do_ping(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    match cmd.len() {
+        1 => out_str(out, "PONG"),
+        2 => out_str(out, &cmd[1]),
+        _ => out_err(out, "ERR wrong number of arguments for 'ping' command"),
+    }
+    Ok(())
+}
+
+// ECHO message
+fn do_echo(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    out_str(out, &cmd[1]);
+    Ok(())
+}
+
+// QUIT: reply, then close the connection once the reply is flushed
+fn do_quit(_cmd: &[String], out: &mut Buffer) -> CmdResult {
+    with_global_data(|g_data| g_data.quit_requested = true);
+    out_str(out, "OK");
+    Ok(())
+}
+
+// RESET: return the connection to its initial state. Besides blocking (and a
+// blocked client can't send RESET) that's client tracking and the reply mode.
+fn do_reset(_cmd: &[String], out: &mut Buffer) -> CmdResult {
+    with_global_data(|g_data| {
+        if let Some(fd) = blocking::current_client() {
+            g_data.tracking.disable(fd);
+            g_data.reply_mode_requested = Some(ReplyMode::On);
+        }
+    });
+    out_str(out, "RESET");
+    Ok(())
+}
+
+// SHUTDOWN [NOSAVE|SAVE]: exit, after writing a snapshot with SAVE
+fn do_shutdown(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let save = match cmd.get(1).map(|arg| arg.to_uppercase()).as_deref() {
+        None | Some("NOSAVE") if cmd.len() <= 2 => false,
+        Some("SAVE") if cmd.len() == 2 => true,
+        _ => {
+            out_err(out, "ERR syntax error");
+            return Ok(());
+        }
+    };
+    if save && let Err(e) = rdb::save_file(rdb::RDB_FILENAME) {
+        log_warning!("server", "Error trying to save the DB, can't exit: {}", e);
+        out_err(out, "ERR Errors trying to SHUTDOWN. Check logs.");
+        return Ok(());
+    }
+    exit_server();
+}
+
+/// Exit after writing out what the append-only file hasn't got yet, unless
+/// a script is still running: it would be left with half of what the script
+/// did
+fn exit_server() -> ! {
+    if !scripting::is_running() {
+        with_global_data(|g_data| {
+            aof::flush(g_data);
+            g_data.db.flush(&g_data.expires, &g_data.thread_pool);
+        });
+    }
+    daemon::remove_pidfile();
+    log_notice!("server", "Redis is now ready to exit, bye bye...");
+    std::process::exit(0);
+}
+
+// CLIENT subcommand ...
+fn do_client(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    match cmd[1].to_uppercase().as_str() {
+        "TRACKING" => tracking::do_tracking(cmd, out),
+        "REPLY" => do_client_reply(cmd, out),
+        "PAUSE" => pause::do_pause(cmd, out),
+        "UNPAUSE" => pause::do_unpause(cmd, out),
+        "NO-EVICT" => do_client_no_evict(cmd, out),
+        "ID" => clients::do_id(cmd, out),
+        "LIST" => clients::do_list(cmd, out),
+        "KILL" => clients::do_kill(cmd, out),
+        _ => out_err(out, "ERR unknown CLIENT subcommand or wrong number of arguments"),
+    }
+    Ok(())
+}
+
+// CLIENT REPLY ON|OFF|SKIP
+//
+// OFF stops replies until ON, SKIP drops the next command's reply only; the
+// commands themselves still run and are propagated. The dispatcher switches
+// the mode once this reply is done, so only ON gets its OK.
+fn do_client_reply(cmd: &[String], out: &mut Buffer) {
+    if cmd.len() != 3 {
+        out_err(out, "ERR wrong number of arguments for 'client|reply' command");
+        return;
+    }
+    let mode = match cmd[2].to_uppercase().as_str() {
+        "ON" => ReplyMode::On,
+        "OFF" => ReplyMode::Off,
+        "SKIP" => ReplyMode::Skip,
+        _ => {
+            out_err(out, "ERR syntax error");
+            return;
+        }
+    };
+    with_global_data(|g_data| {
+        if blocking::current_client().is_none() {
+            out_err(out, "ERR CLIENT REPLY can't be used here");
+            return;
+        }
+        g_data.reply_mode_requested = Some(mode);
+        out_str(out, "OK");
+    });
+}
+
+// CLIENT NO-EVICT ON|OFF
+//
+// Clients are never evicted (there's no maxmemory-clients), so every one of
+// them is exempt already; this only validates and acknowledges.
+fn do_client_no_evict(cmd: &[String], out: &mut Buffer) {
+    match cmd.get(2).map(|arg| arg.to_uppercase()).as_deref() {
+        Some("ON" | "OFF") if cmd.len() == 3 => out_str(out, "OK"),
+        _ if cmd.len() != 3 => out_err(out, "ERR wrong number of arguments for 'client|no-evict' command"),
+        _ => out_err(out, "ERR syntax error"),
+    }
+}
+
+// GET key
+fn do_get(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 2 {
+        out_err(out, "GET requires a key");
+        return Ok(());
+    }
+
+    with_global_data_read(|g_data| {
+        match g_data.db.expect_str(&cmd[1])? {
+            Some(value) => out_shared_str(out, value),
+            None => out_nil(out),
+        }
+        Ok(())
+    })
+}
+
+// SET key value [GET] [KEEPTTL]
+fn do_set(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 3 {
+        out_err(out, "SET requires key and value");
+        return Err("SET requires key and value".into());
+    }
+
+    let (mut get, mut keepttl) = (false, false);
+    for option in &cmd[3..] {
+        match option.to_uppercase().as_str() {
+            "GET" => get = true,
+            "KEEPTTL" => keepttl = true,
+            _ => {
+                out_err(out, "ERR syntax error");
+                return Ok(());
+            }
+        }
+    }
+
+    set_generic(&cmd[1], &cmd[2], get, keepttl, out)
+}
+
+// GETSET key value
+fn do_getset(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() != 3 {
+        out_err(out, "GETSET requires key and value");
+        return Ok(());
+    }
+
+    set_generic(&cmd[1], &cmd[2], true, false, out)
+}
+
+// With `get`, reply with the old string value (nil if there was none) instead
+// of nil; the old value must be a string or nothing is written. The new value
+// replaces the old entry, whose TTL goes with it unless `keepttl`.
+fn set_generic(key: &str, value: &str, get: bool, keepttl: bool, out: &mut Buffer) -> CmdResult {
+    with_global_data(|g_data| {
+        if get {
+            match g_data.db.expect_str(key)? {
+                Some(old) => out_shared_str(out, old),
+                None => out_nil(out),
+            }
+        } else {
+            out_nil(out);  // SET returns nil on success
+        }
+
+        if g_data.db.peek_entry(key).is_some() {
+            g_data.stats.replaced_keys += 1;
+        }
+        if !keepttl && let Some(entry) = g_data.db.peek_entry(key) {
+            clear_ttl(&mut g_data.expires, entry);
+        }
+        g_data.with_entry_mut(key, |slot| *slot = Some(Value::Str(value.into())));
+        Ok(())
+    })
+}
+
+fn do_del(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 2 {
+        out_err(out, "DEL requires at least one key");
+        return Ok(());
+    }
+
+    // Each key is checked and removed in one step, all under one lock
+    let deleted = with_global_data(|g_data| cmd[1..].iter().filter(|key| entry_delete(g_data, key)).count());
+    out_int(out, deleted as i64);
+    Ok(())
+}
+
+// RENAME key newkey
+fn do_rename(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    rename_generic(cmd, out, false)
+}
+
+// RENAMENX key newkey
+fn do_renamenx(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    rename_generic(cmd, out, true)
+}
+
+// Move the value at cmd[1] to cmd[2], TTL and idle time included. RENAME
+// replaces what's at the new name and replies OK; RENAMENX leaves it and
+// replies 0, or 1 after renaming.
+fn rename_generic(cmd: &[String], out: &mut Buffer, nx: bool) -> CmdResult {
+    let (key, newkey) = (&cmd[1], &cmd[2]);
+    with_global_data(|g_data| {
+        if g_data.db.peek_entry(key).is_none() {
+            out_err(out, "ERR no such key");
+            return;
+        }
+        if nx && g_data.db.peek_entry(newkey).is_some() {
+            out_int(out, 0);
+            return;
+        }
+        if key != newkey
+            && let Some((entry, expire_at)) = g_data.take_entry_with_ttl(key)
+        {
+            let idle_secs = entry.idle_secs();
+            g_data.place_entry(newkey, entry.value, expire_at);
+            if let Some(entry) = g_data.db.peek_entry(newkey) {
+                entry.set_idle_secs(idle_secs);
+            }
+        }
+        if nx {
+            out_int(out, 1);
+        } else {
+            out_str(out, "OK");
+        }
+    });
+    Ok(())
+}
+
+// COPY source destination [DB index] [REPLACE]
+fn do_copy(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let (key, newkey) = (&cmd[1], &cmd[2]);
+    let mut replace = false;
+    let mut options = cmd[3..].iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            // There is only database 0
+            "DB" => match options.next().map(|index| index.parse::<i64>()) {
+                Some(Ok(0)) => {}
+                Some(Ok(_)) => {
+                    out_err(out, "ERR DB index is out of range");
+                    return Ok(());
+                }
+                _ => {
+                    out_err(out, "ERR value is not an integer or out of range");
+                    return Ok(());
+                }
+            },
+            _ => {
+                out_err(out, "ERR syntax error");
+                return Ok(());
+            }
+        }
+    }
+    if key == newkey {
+        out_err(out, "ERR source and destination objects are the same");
+        return Ok(());
+    }
+
+    with_global_data(|g_data| {
+        let Some(entry) = g_data.db.peek_entry(key) else {
+            out_int(out, 0);
+            return;
+        };
+        if !replace && g_data.db.peek_entry(newkey).is_some() {
+            out_int(out, 0);
+            return;
+        }
+        // Values are shared until one side changes (see Value)
+        let (value, expire_at) = (entry.value.clone(), entry_expire_at(&g_data.expires, entry));
+        g_data.place_entry(newkey, value, expire_at);
+        out_int(out, 1);
+    });
+    Ok(())
+}
+
+fn do_zquery(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 6 {
+        out_err(out, "ZQUERY requires: key score name offset limit");
+        return Ok(());
+    }
+
+    let key = &cmd[1];
+    let score: f64 = cmd[2].parse().map_err(|_| "Invalid score")?;
+    let name: &str = &cmd[3];
+    let offset: i64 = cmd[4].parse().map_err(|_| "Invalid offset")?;
+    let limit: usize = cmd[5].parse().map_err(|_| "Invalid limit")?;
+
+    with_global_data_read(|g_data| {
+        let Some(zset) = g_data.db.expect_zset(key)? else {
+            out_nil(out);
+            return Ok(());
+        };
+        let mut znode = zset.zset_seekge(score, name);
+
+        if let Some(node) = znode.clone() {
+            znode = znode_offset(Some(node), offset);
+        }
+
+        // However many members that is: the reply is only bounded by
+        // max-reply-size, like any other
+        let ctx = out.out_begin_arr();
+        let mut members = 0usize;
+
+        while let Some(node) = znode {
+            if members >= limit {
+                break;
+            }
+
+            // Borrow and extract data first, then drop the borrow
+            let (name, score) = {
+                let node_ref = node.lock().unwrap();
+                (node_ref.name.clone(), node_ref.score)
+            };
+
+            out_str(out, &name);
+            out_dbl(out, score);
+            members += 1;
+
+            // Now safe to move node since borrow ended
+            znode = znode_offset(Some(node), 1);
+        }
+
+        out.out_end_arr(ctx, members as u32 * 2);
+        Ok(())
+    })
+}
+
+// ZRANGEBYSCORE key min max [WITHSCORES] [LIMIT offset count]
+//
+// The paging clients know from Redis: skip `offset` members of the range,
+// none at all if it's negative, then return up to `count` of them, all with a
+// negative count.
+fn do_zrangebyscore(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let (Some(min), Some(max)) = (ScoreBound::parse(&cmd[2]), ScoreBound::parse(&cmd[3])) else {
+        out_err(out, "ERR min or max is not a float");
+        return Ok(());
+    };
+    let mut withscores = false;
+    let mut limit: Option<(i64, i64)> = None;
+    let mut args = cmd[4..].iter();
+    while let Some(arg) = args.next() {
+        match arg.to_uppercase().as_str() {
+            "WITHSCORES" => withscores = true,
+            "LIMIT" => {
+                let (Some(offset), Some(count)) = (args.next(), args.next()) else {
+                    out_err(out, "ERR syntax error");
+                    return Ok(());
+                };
+                let (Ok(offset), Ok(count)) = (offset.parse(), count.parse()) else {
+                    out_err(out, "ERR value is not an integer or out of range");
+                    return Ok(());
+                };
+                limit = Some((offset, count));
+            }
+            _ => {
+                out_err(out, "ERR syntax error");
+                return Ok(());
+            }
+        }
+    }
+    let (offset, count) = limit.unwrap_or((0, -1));
+    let count = usize::try_from(count).unwrap_or(usize::MAX);
+
+    with_global_data_read(|g_data| {
+        let ctx = out.out_begin_arr();
+        let mut members = 0usize;
+        if let Some(zset) = g_data.db.expect_zset(&cmd[1])?
+            && offset >= 0
+        {
+            // Past the offset in O(log n) through the subtree counts
+            let mut node = znode_offset(zset.seek_score(min), offset);
+            while let Some(current) = node {
+                if members == count {
+                    break;
+                }
+                let (name, score) = {
+                    let current = current.lock().unwrap();
+                    (current.name.clone(), current.score)
+                };
+                if !max.above(score) {
+                    break;
+                }
+                out_str(out, &name);
+                if withscores {
+                    out_dbl(out, score);
+                }
+                members += 1;
+                node = znode_offset(Some(current), 1);
+            }
+        }
+        out.out_end_arr(ctx, (members * if withscores { 2 } else { 1 }) as u32);
+        Ok(())
+    })
+}
+
+/// The arguments of EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT: when the key
+/// is to expire and on what condition
+struct TtlSpec {
+    /// Unix time in ms
+    at_ms: i64,
+    /// Only if the key has no TTL
+    nx: bool,
+    /// Only if it has one
+    xx: bool,
+    /// Only if the new TTL is later than the current one, no TTL being forever
+    gt: bool,
+    /// Only if it's earlier
+    lt: bool,
+}
+
+impl TtlSpec {
+    /// Parse `name key time [NX|XX|GT|LT ...]`, time being in ms with
+    /// `millis` or else seconds, and a unix time with `absolute` or else
+    /// relative to now
+    fn parse(cmd: &[String], millis: bool, absolute: bool) -> Result<TtlSpec, String> {
+        let Ok(time) = cmd[2].parse::<i64>() else {
+            return Err("ERR value is not an integer or out of range".to_string());
+        };
+        let mut spec = TtlSpec { at_ms: 0, nx: false, xx: false, gt: false, lt: false };
+        for arg in &cmd[3..] {
+            match arg.to_uppercase().as_str() {
+                "NX" => spec.nx = true,
+                "XX" => spec.xx = true,
+                "GT" => spec.gt = true,
+                "LT" => spec.lt = true,
+                _ => return Err(format!("ERR Unsupported option {}", arg)),
+            }
+        }
+        if spec.nx && (spec.xx || spec.gt || spec.lt) {
+            return Err("ERR NX and XX, GT or LT options at the same time are not compatible".to_string());
+        }
+        if spec.gt && spec.lt {
+            return Err("ERR GT and LT options at the same time are not compatible".to_string());
+        }
+
+        let invalid = || format!("ERR invalid expire time in '{}' command", cmd[0].to_lowercase());
+        let ms = if millis { Some(time) } else { time.checked_mul(1000) }.ok_or_else(invalid)?;
+        spec.at_ms = if absolute { ms } else { ms.checked_add(get_current_time_ms() as i64).ok_or_else(invalid)? };
+        Ok(spec)
+    }
+
+    /// Whether a key expiring at `current` (unix ms, None without a TTL)
+    /// may get this TTL
+    fn allows(&self, current: Option<i64>) -> bool {
+        match current {
+            None => !self.xx && !self.gt,
+            Some(_) if self.nx => false,
+            Some(current) if self.gt => self.at_ms > current,
+            Some(current) if self.lt => self.at_ms < current,
+            Some(_) => true,
+        }
+    }
+}
+
+// EXPIRE key seconds [NX|XX|GT|LT]
+fn do_expire(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    expire_generic(cmd, out, false, false)
+}
+
+// PEXPIRE key milliseconds [NX|XX|GT|LT]
+fn do_pexpire(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    expire_generic(cmd, out, true, false)
+}
+
+// EXPIREAT key unix-time-seconds [NX|XX|GT|LT]
+fn do_expireat(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    expire_generic(cmd, out, false, true)
+}
+
+// PEXPIREAT key unix-time-ms [NX|XX|GT|LT]
+fn do_pexpireat(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    expire_generic(cmd, out, true, true)
+}
+
+// 1 if the TTL was set, 0 if the key doesn't exist or the condition didn't
+// hold. A time already past deletes the key.
+fn expire_generic(cmd: &[String], out: &mut Buffer, millis: bool, absolute: bool) -> CmdResult {
+    let spec = match TtlSpec::parse(cmd, millis, absolute) {
+        Ok(spec) => spec,
+        Err(e) => {
+            out_err(out, &e);
+            return Ok(());
+        }
+    };
+    let key = &cmd[1];
+
+    with_global_data(|g_data| {
+        if g_data.db.peek_entry(key).is_none() {
+            out_int(out, 0);
+            return;
+        }
+
+        let current = g_data.expires.get(key).map(|at| at as i64);
+        if !spec.allows(current) {
+            out_int(out, 0);
+            return;
+        }
+
+        if spec.at_ms <= get_current_time_ms() as i64 {
+            g_data.with_entry_mut(key, Option::take);
+        } else if let Some(entry) = g_data.db.peek_entry(key) {
+            entry_set_expire_at(&mut g_data.expires, entry, spec.at_ms as u64);
+            g_data.db.ttl_changed(key, Some(spec.at_ms as u64));
+        }
+        out_int(out, 1);
+    });
+    Ok(())
+}
+
+// TTL command - returns remaining TTL in seconds
+fn do_ttl(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 2 {
+        out_err(out, "TTL requires a key");
+        return Ok(());
+    }
+
+    let key = &cmd[1];
+    
+    with_global_data_read(|g_data| {
+        match g_data.db.peek_entry(key) {
+            Some(entry) => {
+                if let Some(expire_at) = entry_expire_at(&g_data.expires, entry) {
+                    let now_ms = get_current_time_ms();
+
+                    if expire_at > now_ms {
+                        let remaining_ms = expire_at - now_ms;
+                        let remaining_seconds = remaining_ms.div_ceil(1000); // Round up
+                        out_int(out, remaining_seconds as i64);
+                    } else {
+                        out_int(out, -2); // Key expired
+                    }
+                } else {
+                    out_int(out, -1); // No TTL set
+                }
+            }
+            None => {
+                out_int(out, -2); // Key doesn't exist
+            }
         }
-    }
+    });
+
+    Ok(())
 }
 
-fn znode_offset(
-    node: Option<Arc<Mutex<ZNode>>>, 
-    offset: i64
-) -> Option<Arc<Mutex<ZNode>>> {
-    match node {
-        Some(znode_rc) => {
-            // Just delegate to avl_offset
-            avl_offset(Some(znode_rc.clone()), offset)
-        }
-        None => None,
+
+fn do_persist(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 2 {
+        out_err(out, "PERSIST requires a key");
+        return Ok(());
     }
-}
 
+    let key = &cmd[1];
+    
+    // 0 if the key doesn't exist or has no TTL
+    let cleared = with_global_data(|g_data| {
+        let cleared = g_data.db.peek_entry(key).is_some_and(|entry| clear_ttl(&mut g_data.expires, entry));
+        if cleared {
+            g_data.db.ttl_changed(key, None);
+        }
+        cleared
+    });
+    out_int(out, cleared as i64);
 
-#[derive(Debug, Clone)]
-struct HeapItem {
-    value: u64,
-    entry_ref: Arc<Mutex<Entry>>,
+    Ok(())
 }
 
-impl HeapItem {
-    fn new(value: u64, entry_ref: Arc<Mutex<Entry>>) -> Self {
-        Self { value, entry_ref }
+// OBJECT FREQ|IDLETIME key
+fn do_object(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 2 {
+        out_err(out, "OBJECT requires a subcommand");
+        return Ok(());
     }
-}
 
-fn heap_left(i: usize) -> usize {
-    i * 2 + 1
+    match cmd[1].to_uppercase().as_str() {
+        "ENCODING" if cmd.len() == 3 => with_global_data_read(|g_data| match g_data.db.peek_entry(&cmd[2]) {
+            Some(entry) => out_str(out, entry.value.encoding(&g_data.config)),
+            None => out_nil(out),
+        }),
+        "IDLETIME" if cmd.len() == 3 => with_global_data_read(|g_data| match g_data.db.peek_entry(&cmd[2]) {
+            Some(entry) => out_int(out, entry.idle_secs() as i64),
+            None => out_nil(out),
+        }),
+        "FREQ" if cmd.len() == 3 => with_global_data_read(|g_data| match g_data.db.peek_entry(&cmd[2]) {
+            None => out_nil(out),
+            Some(entry) if g_data.config.maxmemory_policy.is_lfu() => out_int(out, entry.freq() as i64),
+            // Access frequency only exists under an LFU eviction policy
+            Some(_) => out_err(out, "ERR An LFU maxmemory policy is not selected, access frequency not tracked."),
+        }),
+        _ => out_err(out, "ERR unknown OBJECT subcommand or wrong number of arguments"),
+    }
+    Ok(())
 }
 
-fn heap_right(i: usize) -> usize {
-    i * 2 + 2
+/// Check `n` members added by one `command` against max-zset-members-per-command,
+/// counting a refusal in INFO stats
+fn check_zset_members(command: &str, n: usize) -> Result<(), String> {
+    with_global_data(|g_data| {
+        let result = g_data.config.limits.check_zset_members(command, n);
+        if result.is_err() {
+            g_data.stats.rejected_zset_members += 1;
+        }
+        result
+    })
 }
 
-fn heap_parent(i: usize) -> usize {
-    (i - 1) / 2
-}
+fn do_zadd(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 4 || !cmd.len().is_multiple_of(2) {
+        out_err(out, "ZADD requires: key score member [score member ...]");
+        return Ok(());
+    }
 
-fn heap_up(items: &mut [HeapItem], mut pos: usize) {
-    let temp = items[pos].clone();
+    let key = &cmd[1];
 
-    while pos > 0 && items[heap_parent(pos)].value > temp.value {
-        items[pos] = items[heap_parent(pos)].clone();
-        // Update backlink for moved item
-        items[pos].entry_ref.lock().unwrap().heap_idx = Some(pos);
-        pos = heap_parent(pos);
+    // Parse and validate all score-member pairs first
+    let mut pairs = Vec::new();
+    let mut i = 2;
+    while i + 1 < cmd.len() {
+        match cmd[i].parse::<f64>() {
+            Ok(score) => pairs.push((score, cmd[i + 1].clone())),
+            Err(_) => {
+                out_err(out, &format!("Invalid score: {}", cmd[i]));
+                return Ok(());
+            }
+        }
+        i += 2;
+    }
+    if let Err(e) = check_zset_members("zadd", pairs.len()) {
+        out_err(out, &e);
+        return Ok(());
     }
 
-    items[pos] = temp;
-    items[pos].entry_ref.lock().unwrap().heap_idx = Some(pos);
+    let added = with_global_data(|g_data| {
+        expect_zset_mut(g_data, key, true, |zset| {
+            pairs.into_iter().filter(|(score, member)| zset.insert(*score, member.clone())).count()
+        })
+    })?;
+    out_int(out, added.unwrap_or(0) as i64);
+    Ok(())
 }
 
-fn heap_down(items: &mut [HeapItem], mut pos: usize) {
-    let temp = items[pos].clone();
-    let len = items.len();
+fn do_zrem(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 3 {
+        out_err(out, "ZREM requires: key member [member ...]");
+        return Ok(());
+    }
 
-    loop {
-        let left = heap_left(pos);
-        let right = heap_right(pos);
+    let key = &cmd[1];
+    let members = &cmd[2..];
 
-        let mut min_pos = pos;
-        let mut min_val = temp.value;
+    // An emptied set is deleted along with the key
+    let removed = with_global_data(|g_data| {
+        expect_zset_mut(g_data, key, false, |zset| {
+            members
+                .iter()
+                .filter(|member| match zset.lookup(member) {
+                    Some(node) => {
+                        zset.delete(&node);
+                        true
+                    }
+                    None => false,
+                })
+                .count()
+        })
+    })?;
+    out_int(out, removed.unwrap_or(0) as i64);
+    Ok(())
+}
 
-        if left < len && items[left].value < min_val {
-            min_pos = left;
-            min_val = items[left].value;
+// ZRANDMEMBER key [count [WITHSCORES]]
+//
+// Members are picked by rank, uniformly. A positive count gives that many
+// distinct members, or the whole set when it has fewer; a negative one gives
+// exactly -count members that may repeat.
+fn do_zrandmember(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let count = match cmd.get(2).map(|arg| arg.parse::<i64>()) {
+        None => None,
+        // Anything asking for more than half of all longs, as Redis refuses
+        Some(Ok(count)) if count < -(i64::MAX / 2) => {
+            out_err(out, "ERR value is out of range");
+            return Ok(());
         }
-        if right < len && items[right].value < min_val {
-            min_pos = right;
+        Some(Ok(count)) => Some(count),
+        Some(Err(_)) => {
+            out_err(out, "ERR value is not an integer or out of range");
+            return Ok(());
         }
-
-        if min_pos == pos {
-            break;
+    };
+    let withscores = match cmd.get(3) {
+        None => false,
+        Some(arg) if arg.eq_ignore_ascii_case("WITHSCORES") && cmd.len() == 4 => true,
+        Some(_) => {
+            out_err(out, "ERR syntax error");
+            return Ok(());
         }
+    };
 
-        items[pos] = items[min_pos].clone();
-        // Update backlink for moved item
-        items[pos].entry_ref.lock().unwrap().heap_idx = Some(pos);
-        pos = min_pos;
-    }
-
-    items[pos] = temp;
-    items[pos].entry_ref.lock().unwrap().heap_idx = Some(pos);
-}
+    with_global_data_read(|g_data| {
+        let zset = g_data.db.expect_zset(&cmd[1])?;
+        let len = zset.map_or(0, |zset| zset.len());
+        let state = RandomState::new();
+        let random_rank = |i: u64| (state.hash_one(i) % len as u64) as usize;
 
+        let Some(count) = count else {
+            match zset.and_then(|zset| zset.at_rank(random_rank(0))) {
+                Some(node) => out_str(out, &node.lock().unwrap().name),
+                None => out_nil(out),
+            }
+            return Ok(());
+        };
+        let Some(zset) = zset.filter(|_| count != 0) else {
+            out_arr(out, 0);
+            return Ok(());
+        };
+        // Every member in the reply takes more than a byte, so a count past
+        // max-reply-size could only end in "response is too big": say so
+        // before drawing (and allocating) that many ranks
+        let items = if count < 0 { count.unsigned_abs() } else { count.unsigned_abs().min(len as u64) };
+        if items.saturating_mul(1 + withscores as u64) > g_data.config.max_reply_size as u64 {
+            out_err(out, "response is too big");
+            return Ok(());
+        }
 
-fn heap_update(items: &mut [HeapItem], pos: usize) {
-    if pos > 0 && items[heap_parent(pos)].value > items[pos].value {
-        heap_up(items, pos);
-    } else {
-        heap_down(items, pos); // len handled inside
-    }
-}
+        let ranks: Vec<usize> = if count < 0 {
+            (0..count.unsigned_abs()).map(random_rank).collect()
+        } else if count as usize >= len {
+            (0..len).collect()
+        } else if count as usize * 3 > len {
+            // Most of the set: drop random ranks from all of them
+            let mut ranks: Vec<usize> = (0..len).collect();
+            let mut i = 0;
+            while ranks.len() > count as usize {
+                ranks.swap_remove((state.hash_one(i) % ranks.len() as u64) as usize);
+                i += 1;
+            }
+            ranks
+        } else {
+            // A small part: draw until enough distinct ranks came up
+            let mut ranks = Vec::with_capacity(count as usize);
+            let mut seen = HashSet::new();
+            let mut i = 0;
+            while ranks.len() < count as usize {
+                let rank = random_rank(i);
+                if seen.insert(rank) {
+                    ranks.push(rank);
+                }
+                i += 1;
+            }
+            ranks
+        };
 
-fn heap_delete(items: &mut Vec<HeapItem>, pos: usize) {
-    if pos >= items.len() {
-        return;
-    }
-    
-    // Clear the backlink in the deleted item
-    items[pos].entry_ref.lock().unwrap().heap_idx = None;
-    
-    if let Some(last_item) = items.pop() {
-        if pos < items.len() {
-            // Replace deleted item with the last element
-            items[pos] = last_item;
-            // Update backlink for moved item
-            items[pos].entry_ref.lock().unwrap().heap_idx = Some(pos);
-            // Restore heap property
-            heap_update(items, pos);
+        let ctx = out.out_begin_arr();
+        for &rank in &ranks {
+            let node = zset.at_rank(rank).expect("rank within the set");
+            let node = node.lock().unwrap();
+            out_str(out, &node.name);
+            if withscores {
+                out_dbl(out, node.score);
+            }
         }
-    }
-}
-
-pub fn insert_hash(htab: &mut HashTable, entry: Box<Entry>) {
-    let pos = (entry.hcode as usize) & htab.mask;
-    htab.tab[pos].push_front(entry);
-    htab.size += 1;
+        let items = if withscores { ranks.len() * 2 } else { ranks.len() };
+        out.out_end_arr(ctx, items as u32);
+        Ok(())
+    })
 }
 
-pub fn hash_lookup<'a, F>(
-    table: &'a HashTable,
-    key: &str,
-    eq: F,
-) -> Option<&'a Entry>
-where
-    F: Fn(&Entry, &str) -> bool + Copy,
-{
-    let bucket_index = (hash_std(key.as_bytes()) as usize) & table.mask;
-    let bucket = &table.tab[bucket_index];
-
-    let mut cursor = bucket.cursor();
+// ZMPOP numkeys key [key ...] MIN|MAX [COUNT count]
+//
+// Pops from the first key holding a sorted set, lowest or highest scores
+// first, and replies [key, [[member, score] ...]], or nil if no key does.
+fn do_zmpop(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let (keys, end, count) = match list::parse_mpop(&cmd[1..]) {
+        Ok(parsed) => parsed,
+        Err(msg) => {
+            out_err(out, msg);
+            return Ok(());
+        }
+    };
+    let max = match end.to_uppercase().as_str() {
+        "MIN" => false,
+        "MAX" => true,
+        _ => {
+            out_err(out, "ERR syntax error");
+            return Ok(());
+        }
+    };
 
-    while !cursor.is_null() {
-        let entry = cursor.get().unwrap();
-        if eq(entry, key) {
-            return Some(entry);
+    with_global_data(|g_data| {
+        for key in keys {
+            let popped = expect_zset_mut(g_data, key, false, |zset| {
+                let mut popped = Vec::with_capacity(count.min(zset.len()));
+                while popped.len() < count {
+                    let Some(node) = (if max { zset.last() } else { zset.first() }) else {
+                        break;
+                    };
+                    zset.delete(&node);
+                    let node = node.lock().unwrap();
+                    popped.push((node.name.clone(), node.score));
+                }
+                popped
+            })?;
+            if let Some(popped) = popped {
+                out_arr(out, 2);
+                out_str(out, key);
+                out_arr(out, popped.len() as u32);
+                for (name, score) in &popped {
+                    out_arr(out, 2);
+                    out_str(out, name);
+                    out_dbl(out, *score);
+                }
+                return Ok(());
+            }
         }
-        cursor.move_next();
-    }
+        out_nil(out);
+        Ok(())
+    })
+}
 
-    None
+/// One end of a ZRANGEBYSCORE or ZREMRANGEBYSCORE range: a score, `(` in
+/// front to leave it out
+#[derive(Clone, Copy)]
+struct ScoreBound {
+    score: f64,
+    exclusive: bool,
 }
 
-pub fn hash_lookup_cursor<'a, F>(
-    htab: &'a mut HashTable,
-    key: &str,
-    eq: F,
-) -> Option<CursorMut<'a, EntryAdapter>>
-where
-    F: Fn(&Entry, &str) -> bool,
-{
-    if htab.tab.is_empty() {
-        return None;
+impl ScoreBound {
+    fn parse(arg: &str) -> Option<Self> {
+        let (score, exclusive) = match arg.strip_prefix('(') {
+            Some(rest) => (rest, true),
+            None => (arg, false),
+        };
+        let score: f64 = score.parse().ok()?;
+        (!score.is_nan()).then_some(ScoreBound { score, exclusive })
     }
 
-    let hcode = hash_std(key.as_bytes());
-    let pos = (hcode as usize) & htab.mask;
-
-    let mut cur = htab.tab[pos].front_mut();
-
-    while let Some(entry) = cur.get() {
-        if entry.hcode == hcode && eq(entry, key) {
-            return Some(cur);
-        }
-        cur.move_next();
+    fn below(self, score: f64) -> bool {
+        if self.exclusive { self.score < score } else { self.score <= score }
     }
-    None
-}
 
-pub fn hash_delete(
-    htab: &mut HashTable,
-    mut cursor: CursorMut<EntryAdapter>,
-) -> Option<Box<Entry>> {
-    let node = cursor.remove();
-    if node.is_some() {
-        htab.size -= 1;
+    fn above(self, score: f64) -> bool {
+        if self.exclusive { self.score > score } else { self.score >= score }
     }
-    node
 }
 
-// global data structure
-#[derive(Debug)]
-struct GData {
-    db: HMap,
-    fd2conn: HashMap<RawFd, Conn>,
-    idle_list: Arc<Mutex<DList>>,
-    heap: Vec<HeapItem>,
-    thread_pool: ThreadPool,
-    ttl_map: HashMap<String, usize>,
+/// One end of a ZREMRANGEBYLEX range: `-` and `+` for no bound, else a name
+/// after `[` to include it or `(` to leave it out
+#[derive(Clone, Copy)]
+enum LexBound<'a> {
+    Min,
+    Max,
+    Inclusive(&'a str),
+    Exclusive(&'a str),
 }
 
-impl GData {
-    fn new() -> Self {
-        let idle_list = DList::new();
-        // Initialize the idle list as circular
-        {
-            let mut idle_ref = idle_list.lock().unwrap();
-            idle_ref.prev = Some(idle_list.clone());
-            idle_ref.next = Some(idle_list.clone());
-        }
-        
-        Self {
-            db: HMap::default(),
-            fd2conn: HashMap::new(),
-            idle_list,
-            heap: Vec::new(),
-            thread_pool: ThreadPool::new(4),
-            ttl_map: HashMap::new(),
+impl<'a> LexBound<'a> {
+    fn parse(arg: &'a str) -> Option<Self> {
+        match arg {
+            "-" => Some(LexBound::Min),
+            "+" => Some(LexBound::Max),
+            _ => match arg.split_at_checked(1)? {
+                ("[", name) => Some(LexBound::Inclusive(name)),
+                ("(", name) => Some(LexBound::Exclusive(name)),
+                _ => None,
+            },
         }
     }
-}
 
-impl std::fmt::Debug for ThreadPool {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("ThreadPool")
-            .field("num_threads", &self.threads.len())
-            .field("queue_len", &{
-                let (lock, _) = &*self.queue;
-                lock.lock().unwrap().len()
-            })
-            .field("shutdown", &{
-                *self.shutdown.lock().unwrap()
-            })
-            .finish()
+    fn below(self, name: &str) -> bool {
+        match self {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(bound) => bound <= name,
+            LexBound::Exclusive(bound) => bound < name,
+        }
     }
-}
 
-// Synchronous deletion (runs in current thread)
-fn entry_del_sync(mut entry: Box<Entry>) {
-    match &mut entry.value {
-        Value::ZSet(zset) => {
-            println!("Clearing ZSet with {} items", zset.name_to_node.len());
-            
-            // Clear the hash map (this is the expensive O(N) operation)
-            zset.name_to_node.clear();
-            
-            // Clear the AVL tree root
-            zset.root = None;
-            
-            // All Rc<RefCell<ZNode>> references should be dropped automatically
-        }
-        Value::Str(_) => {
-            // Strings don't need special handling - just drop
-        }
-        Value::Init => {
-            // Nothing to clean up
+    fn above(self, name: &str) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(bound) => bound >= name,
+            LexBound::Exclusive(bound) => bound > name,
         }
     }
-    // Entry drops here, freeing all memory
 }
 
-// Wrapper for thread pool (matches C pattern)
-fn entry_del_async_wrapper(entry: Box<Entry>) {
-    entry_del_sync(entry);
+/// Remove from the sorted set at `key` with `remove`, replying how many
+/// members went. An emptied set is deleted along with the key.
+fn zremrange(key: &str, out: &mut Buffer, remove: impl FnOnce(&mut ZSet) -> usize) -> CmdResult {
+    let removed = with_global_data(|g_data| expect_zset_mut(g_data, key, false, remove))?;
+    out_int(out, removed.unwrap_or(0) as i64);
+    Ok(())
 }
 
+// ZREMRANGEBYSCORE key min max
+fn do_zremrangebyscore(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let (Some(min), Some(max)) = (ScoreBound::parse(&cmd[2]), ScoreBound::parse(&cmd[3])) else {
+        out_err(out, "ERR min or max is not a float");
+        return Ok(());
+    };
 
-fn out_nil(buf: &mut Buffer) {
-    buf.append(&[Tag::Nil as u8]);
+    zremrange(&cmd[1], out, |zset| zset.remove_range(zset.seek_score(min), |node| max.above(node.score)))
 }
 
-fn out_str(buf: &mut Buffer, s: &str) {
-    buf.append_u8(Tag::Str as u8);
-    buf.append_u32(s.len() as u32);
-    buf.append(s.as_bytes());
+// ZREMRANGEBYRANK key start stop
+fn do_zremrangebyrank(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let (Ok(start), Ok(stop)) = (cmd[2].parse::<i64>(), cmd[3].parse::<i64>()) else {
+        out_err(out, "ERR value is not an integer or out of range");
+        return Ok(());
+    };
+
+    zremrange(&cmd[1], out, |zset| {
+        // Negative ranks count from the end, as in LRANGE
+        let len = zset.len() as i64;
+        let start = if start < 0 { (len + start).max(0) } else { start };
+        let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+        if start > stop || start >= len {
+            return 0;
+        }
+        let mut left = stop - start + 1;
+        let first = znode_offset(zset.first(), start);
+        zset.remove_range(first, |_| {
+            left -= 1;
+            left >= 0
+        })
+    })
 }
 
-fn out_int(buf: &mut Buffer, val: i64) {
-    buf.append_u8(Tag::Int as u8);
-    buf.append_i64(val);
+// ZREMRANGEBYLEX key min max
+//
+// Like Redis, meant for a set whose members all have the same score, where
+// (score, name) order is name order; on other sets which members go is
+// unspecified.
+fn do_zremrangebylex(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let (Some(min), Some(max)) = (LexBound::parse(&cmd[2]), LexBound::parse(&cmd[3])) else {
+        out_err(out, "ERR min or max not valid string range item");
+        return Ok(());
+    };
+
+    zremrange(&cmd[1], out, |zset| {
+        let Some(first) = zset.first() else {
+            return 0;
+        };
+        let mut start = match min {
+            LexBound::Inclusive(name) | LexBound::Exclusive(name) => {
+                let score = first.lock().unwrap().score;
+                zset.zset_seekge(score, name)
+            }
+            _ => Some(first),
+        };
+        while let Some(node) = start.clone() {
+            if min.below(&node.lock().unwrap().name) {
+                break;
+            }
+            start = znode_offset(Some(node), 1);
+        }
+        zset.remove_range(start, |node| max.above(&node.name))
+    })
 }
 
-fn out_dbl(buf: &mut Buffer, val: f64) {
-    buf.append(&[Tag::Dbl as u8]);
-    buf.append(&val.to_le_bytes());
+fn global_data() -> &'static RwLock<GData> {
+    GLOBAL_DATA.get_or_init(|| {
+        RwLock::new(GData {
+            db: Box::new(HMap::default()),
+            fd2conn: HashMap::new(),
+            idle_timers: IdleTimers::default(),
+            idle_timeout_ms: 0,
+            active_rehash_ms: 0,
+            expires: Expires::default(),
+            thread_pool: ThreadPool::new(4),
+            blocking: blocking::Blocking::default(),
+            keys_scans: HashMap::new(),
+            stats: info::ServerStats::default(),
+            loading: info::Loading::default(),
+            tracking: tracking::Tracking::default(),
+            cluster: cluster::Cluster::default(),
+            migrate_pool: migrate::Pool::default(),
+            replication: replication::Replication::default(),
+            aof: aof::Aof::default(),
+            audit: audit::Audit::default(),
+            config: config::Config::default(),
+            quit_requested: false,
+            reply_mode_requested: None,
+            raw_reply_requested: None,
+            pause: pause::Pause::default(),
+            eviction_pool: evict::Pool::default(),
+            failover: failover::Failover::default(),
+            command_stats: latency::CommandStats::default(),
+            accept: fdlimit::AcceptState::default(),
+            clients: clients::Clients::default(),
+        })
+    })
 }
 
-fn out_arr(buf: &mut Buffer, n: u32) {
-    buf.append(&[Tag::Arr as u8]);
-    buf.append(&n.to_le_bytes());
+/// Run `f` with exclusive access to the global data. Not reentrant: `f`
+/// must not call with_global_data or with_global_data_read itself.
+fn with_global_data<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut GData) -> R,
+{
+    let mut guard = global_data().write().unwrap();
+    f(&mut guard)
 }
 
-fn out_err(buf: &mut Buffer, msg: &str) {
-    buf.append(&[Tag::Err as u8]);
-    buf.append(&(msg.len() as u32).to_le_bytes());
-    buf.append(msg.as_bytes());
+/// Run `f` with shared access to the global data, alongside any other
+/// readers. For read-only commands (CMD_READONLY) and anything else that
+/// only looks; touching a key's access time is fine through the shared
+/// reference.
+fn with_global_data_read<F, R>(f: F) -> R
+where
+    F: FnOnce(&GData) -> R,
+{
+    let guard = global_data().read().unwrap();
+    f(&guard)
+}
+
+#[derive(Debug)]
+struct Conn{
+    socket: Socket,
+    // Unique for the life of the server, unlike the fd (see clients.rs)
+    id: u64,
+
+    // What the event loop does with the connection next; changed only
+    // through the transition methods below
+    state: ConnState,
+    // Length-prefixed frames or text, decided by the first bytes received
+    proto: resp::Proto,
+    // The text request at the front of `incoming`, as far as it's parsed
+    partial: Option<resp::Partial>,
+    // Refused at accept (protected mode, maxclients): the error it's sent once
+    // its protocol is known, before the connection is closed
+    refused: Option<&'static str>,
+    // client-rate-limit budget
+    rate_limit: RateLimit,
+    // Which command replies are sent (CLIENT REPLY)
+    reply_mode: ReplyMode,
+
+    //buffered input and output
+    incoming: Buffer,
+    // Replies being assembled; once it holds OUT_CHUNK_SIZE bytes it's moved
+    // to `sealed` so a large backlog is never copied around to grow it
+    outgoing: Buffer,
+    // Finished chunks of output ahead of `outgoing`, oldest first
+    sealed: VecDeque<Chunk>,
+
+    last_active_ms: u64,
 }
 
-fn do_keys(out: &mut Buffer) -> Result<(), &'static str> {
-    with_global_data(|g_data| {
-        let key_count = g_data.db.size();
-        out_arr(out, key_count as u32);
-        
-        // Iterate and output each key
-        for entry in g_data.db.iter() {
-            out_str(out, &entry.key);
+impl Conn {
+    fn new(socket: Socket) -> Self {
+        Self {
+            socket,
+            id: clients::next_id(),
+            state: ConnState::ReadingRequest,
+            proto: resp::Proto::Detecting,
+            partial: None,
+            refused: None,
+            rate_limit: RateLimit::default(),
+            reply_mode: ReplyMode::On,
+            incoming: Buffer::new(),
+            outgoing: Buffer::new(),
+            sealed: VecDeque::new(),
+            last_active_ms: get_monotonic_time_ms(),
         }
-    });
-    
-    Ok(())
-    
+    }
 }
 
-// GET key
-fn do_get(db: &HMap, cmd: &[String], out: &mut Buffer) -> Result<(), &'static str> {
-    if cmd.len() < 2 {
-        out_err(out, "GET requires a key");
-        return Ok(());
+impl Conn {
+    /// Close the reply started at `header_pos`. Text clients get it
+    /// re-encoded as RESP in place of the length-prefixed frame.
+    fn finish_reply(&mut self, header_pos: usize, max_reply_size: usize) {
+        self.outgoing.response_end(header_pos, max_reply_size);
+        if self.proto == resp::Proto::Text {
+            let mut text = Vec::new();
+            match RedisValue::decode(&self.outgoing[header_pos + 4..]) {
+                Some((value, _)) => resp::encode_reply(&value, &mut text),
+                None => resp::encode_reply(&RedisValue::Err("ERR malformed reply".to_string()), &mut text),
+            }
+            self.outgoing.truncate(header_pos);
+            self.outgoing.append(&text);
+        }
+        if !self.outgoing.shared.is_empty() {
+            self.seal_shared();
+        }
+        if self.outgoing.len() >= OUT_CHUNK_SIZE {
+            let chunk = self.take_outgoing();
+            self.sealed.push_back(Chunk::Bytes(chunk));
+        }
     }
 
-    let key = &cmd[1];
-
-    match db.lookup_entry(key.as_str()) {
-        None => {
-            out_nil(out);
-        }
-        Some(entry) => {
-            match &entry.value {
-                Value::Str(string_value) => {
-                    // Handle string values (your original logic)
-                    if string_value.len() > K_MAX_MSG {
-                        out_err(out, "value too large");
-                        return Ok(());
-                    }
-                    out_str(out, string_value);
-                }
-                Value::ZSet(_zset) => {
-                    // GET command doesn't work on sorted sets
-                    out_err(out, "WRONGTYPE Operation against a key holding the wrong kind of value");
-                }
-                Value::Init => {
-                    out_nil(out);
-                }
+    /// finish_reply for the reply to a command, which CLIENT REPLY may have
+    /// turned off: then it's dropped instead
+    fn finish_command_reply(&mut self, header_pos: usize, max_reply_size: usize) {
+        match self.reply_mode {
+            ReplyMode::On => self.finish_reply(header_pos, max_reply_size),
+            ReplyMode::Off => self.outgoing.truncate(header_pos),
+            ReplyMode::Skip => {
+                self.outgoing.truncate(header_pos);
+                self.reply_mode = ReplyMode::On;
             }
         }
     }
 
-    Ok(())
-}
-
-fn do_set(cmd: &[String], out: &mut Buffer) -> Result<(), &'static str> {
-    if cmd.len() < 3 {
-        out_err(out, "SET requires key and value");
-        return Err("SET requires key and value");
+    /// Queue bytes to be written as they are, rather than a reply: the
+    /// replication stream
+    fn append_raw(&mut self, bytes: &[u8]) {
+        self.outgoing.append(bytes);
+        if self.outgoing.len() >= OUT_CHUNK_SIZE {
+            let chunk = self.take_outgoing();
+            self.sealed.push_back(Chunk::Bytes(chunk));
+        }
     }
 
-    let key = cmd[1].clone();
-    let value = cmd[2].clone();
-
-    with_global_data(|g_data| {
-        g_data.db.set(key, value);
-        out_nil(out);  // SET returns nil on success
-    });
-
-    Ok(())
-}
+    // Move `outgoing` to the chunk queue cut at its spliced values, which go
+    // in between as chunks of their own. Only the bytes after the first
+    // splice, the rest of the reply, are copied.
+    fn seal_shared(&mut self) {
+        let shared = std::mem::take(&mut self.outgoing.shared);
+        let mut pieces = Vec::new(); // back to front
+        for (offset, value) in shared.into_iter().rev() {
+            let mut piece = Buffer::with_capacity(self.outgoing.len() - offset);
+            piece.append(&self.outgoing[offset..]);
+            self.outgoing.truncate(offset);
+            pieces.push(Chunk::Bytes(piece));
+            pieces.push(Chunk::Shared(value, 0));
+        }
+        let prefix = self.take_outgoing();
+        self.sealed.push_back(Chunk::Bytes(prefix));
+        self.sealed.extend(pieces.into_iter().rev().filter(|chunk| !chunk.remaining().is_empty()));
+    }
 
-fn do_del(cmd: &[String], out: &mut Buffer) -> Result<(), &'static str> {
-    if cmd.len() < 2 {
-        out_err(out, "DEL requires at least one key");
-        return Ok(());
+    // Swap in an empty outgoing buffer, returning the old one
+    fn take_outgoing(&mut self) -> Buffer {
+        let mut fresh = Buffer::new();
+        fresh.share_values = self.outgoing.share_values;
+        std::mem::replace(&mut self.outgoing, fresh)
     }
 
-    let mut deleted_count = 0i64;
+    // Any output not yet written to the socket
+    fn has_output(&self) -> bool {
+        !self.sealed.is_empty() || !self.outgoing.is_empty()
+    }
 
-    // DEL can delete multiple keys: DEL key1 key2 key3
-    for key in &cmd[1..] {
-        with_global_data(|g_data| {
-            if g_data.db.lookup_entry(key).is_some() {
-                deleted_count += 1;
-                // Don't increment here - do it outside the closure
+    // Drop `n` written bytes from the front of the output
+    fn consume_output(&mut self, mut n: usize) {
+        while let Some(chunk) = self.sealed.front_mut() {
+            let left = chunk.remaining().len();
+            if n < left {
+                match chunk {
+                    Chunk::Bytes(buffer) => buffer.consume(n),
+                    Chunk::Shared(_, written) => *written += n,
+                }
+                return;
             }
-        });
-        
-        // Use new async-capable entry_del
-        if deleted_count > 0 {
-            entry_del(key);
+            n -= left;
+            self.sealed.pop_front();
         }
+        self.outgoing.consume(n);
     }
 
-    out_int(out, deleted_count);
-    Ok(())
-}
+    // Waiting on the server rather than idle by choice, so the idle timeout
+    // doesn't apply
+    fn idle_exempt(&self) -> bool {
+        matches!(self.state, ConnState::Blocked | ConnState::Paused)
+    }
 
-fn do_zquery(cmd: &[String], out: &mut Buffer) -> Result<(), &'static str> {
-    if cmd.len() < 6 {
-        out_err(out, "ZQUERY requires: key score name offset limit");
-        return Ok(());
+    /* State transitions */
+
+    // Requests are read in these states
+    fn reads_requests(&self) -> bool {
+        matches!(self.state, ConnState::ReadingRequest | ConnState::Subscribed)
     }
 
-    let key = &cmd[1];
-    let score: f64 = cmd[2].parse().map_err(|_| "Invalid score")?;
-    let name: &str = &cmd[3];
-    let offset: i64 = cmd[4].parse().map_err(|_| "Invalid offset")?;
-    let limit: usize = cmd[5].parse().map_err(|_| "Invalid limit")?;
+    // Replies were queued: stop reading until they're written. A blocked
+    // client still has earlier pipelined replies written out.
+    fn replies_queued(&mut self) {
+        if self.state == ConnState::ReadingRequest {
+            self.state = ConnState::WritingResponse;
+        }
+    }
 
-    with_global_data(|g_data| {
-        match g_data.db.lookup_entry(key) {
-            Some(entry) => match &entry.value {
-                Value::ZSet(zset) => {
-                    let mut znode = zset.zset_seekge(score, name);
+    // All output was written
+    fn output_drained(&mut self) {
+        match self.state {
+            ConnState::Draining => self.close(),
+            ConnState::WritingResponse => self.state = ConnState::ReadingRequest,
+            _ => {}
+        }
+    }
 
-                    if let Some(node) = znode.clone() {
-                        znode = znode_offset(Some(node), offset);
-                    }
+    // Nothing more is read (QUIT, a protocol error, a refused connection):
+    // close once the replies queued so far are written
+    fn close_after_write(&mut self) {
+        self.incoming.truncate(0);
+        self.state = if self.has_output() { ConnState::Draining } else { ConnState::Closing };
+    }
 
-                    let ctx = out.out_begin_arr();
-                    let mut n = 0i64; // <-- this was missing
+    // The client shut down its side (read EOF), after the requests read
+    // before it ran. A command they left blocked or parked is still served:
+    // the next read sees EOF again once it's done.
+    fn input_ended(&mut self) {
+        match self.state {
+            ConnState::ReadingRequest | ConnState::Subscribed => self.close(),
+            ConnState::WritingResponse => self.state = ConnState::Draining,
+            _ => {}
+        }
+    }
 
-                    while let Some(node) = znode {
-                        if n >= limit as i64 * 2 {
-                            break;
-                        }
+    // The current command waits for a key or a timeout
+    fn block(&mut self) {
+        if self.state != ConnState::Closing {
+            self.state = ConnState::Blocked;
+        }
+    }
 
-                        // Borrow and extract data first, then drop the borrow
-                        let (name, score) = {
-                            let node_ref = node.lock().unwrap();
-                            (node_ref.name.clone(), node_ref.score)
-                        };
+    // The next command is held by CLIENT PAUSE
+    fn park(&mut self) {
+        if self.state != ConnState::Closing {
+            self.state = ConnState::Paused;
+        }
+    }
 
-                        out_str(out, &name);
-                        out_dbl(out, score);
-                        n += 2;
+    // The pause is over. Like an unblocked connection the parked one goes
+    // through a POLLOUT round, which runs the commands left in its input.
+    // Returns whether it was parked.
+    fn unpark(&mut self) -> bool {
+        let parked = self.state == ConnState::Paused;
+        if parked {
+            self.state = ConnState::WritingResponse;
+        }
+        parked
+    }
 
-                        // Now safe to move node since borrow ended
-                        znode = znode_offset(Some(node), 1);
-                    }
+    // The blocking command was answered
+    fn unblock(&mut self) {
+        if self.state == ConnState::Blocked {
+            self.state = ConnState::WritingResponse;
+        }
+    }
 
-                    out.out_end_arr(ctx, n as u32);
+    fn close(&mut self) {
+        self.state = ConnState::Closing;
+    }
 
-                }
-                _ => out_err(out, "WRONGTYPE Operation against a key holding the wrong kind of value"),
-            },
-            None => out_nil(out),
+    // What to poll the socket for
+    fn poll_events(&self) -> PollFlags {
+        let mut events = PollFlags::POLLERR;
+        if self.reads_requests() {
+            events |= PollFlags::POLLIN;
         }
-    });
+        // Nothing left to write while WritingResponse: a blocked command's
+        // reply was dropped (CLIENT REPLY), or the connection was unparked,
+        // and the POLLOUT round moves on
+        if self.state != ConnState::Closing
+            && (self.has_output() || matches!(self.state, ConnState::WritingResponse | ConnState::Draining))
+        {
+            events |= PollFlags::POLLOUT;
+        }
+        events
+    }
+}
 
-    Ok(())
+/// Which command replies a connection is sent, set with CLIENT REPLY.
+/// Pushed messages (invalidations) are sent regardless.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReplyMode {
+    On,
+    Off,
+    /// Drop the next command's reply, then back to On
+    Skip,
 }
 
-fn do_expire(cmd: &[String], out: &mut Buffer) -> Result<(), &'static str> {
-    if cmd.len() < 3 {
-        out_err(out, "EXPIRE requires key and seconds");
-        return Ok(());
-    }
+/// Where a connection is in its request/response cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnState {
+    /// Waiting for the next request
+    ReadingRequest,
+    /// Replies are being written; the next requests wait until they're out
+    WritingResponse,
+    /// In a blocking command (BLMOVE ...): nothing is read until it's served
+    /// or times out, though earlier replies are still written
+    Blocked,
+    /// Its next command waits for CLIENT PAUSE to end: it stays in the input,
+    /// and nothing more is read
+    Paused,
+    /// Receiving pushed messages: requests are read while output is written
+    #[allow(dead_code)] // Entered by SUBSCRIBE, which doesn't exist yet
+    Subscribed,
+    /// Nothing more is read, after QUIT or once the client shut down its
+    /// side; the replies already queued are written, then it's Closing
+    Draining,
+    /// Done; the event loop closes it
+    Closing,
+}
 
-    let ttl_seconds: i64 = match cmd[2].parse() {
-        Ok(val) => val,
-        Err(_) => {
-            out_err(out, "Expected int64");
-            return Ok(());
-        }
-    };
-    
-    let key = cmd[1].clone();
-    
-     with_global_data(|g_data| {
-        // Use the remove-modify-insert pattern to set TTL
-        if let Some(mut entry_box) = g_data.db.delete_entry_and_return(&key) {
-            if ttl_seconds <= 0 {
-                // Remove existing TTL
-                if let Some(heap_idx) = entry_box.heap_idx {
-                    if heap_idx < g_data.heap.len() {
-                        heap_delete(&mut g_data.heap, heap_idx);
-                    }
-                }
-                entry_box.heap_idx = None;
-                out_int(out, 1);
-            } else {
-                // Set TTL
-                let expire_at = get_monotonic_time_ms() + (ttl_seconds * 1000) as u64;
-                let entry_ref = Arc::new(Mutex::new(Entry {
-                    link: LinkedListLink::new(),
-                    hcode: entry_box.hcode,
-                    key: entry_box.key.clone(),
-                    value: Value::Str(key.clone()), // Placeholder for heap
-                    heap_idx: entry_box.heap_idx,
-                }));
-                let heap_item = HeapItem::new(expire_at, entry_ref);
-                
-                heap_upsert(&mut g_data.heap, &mut entry_box.heap_idx, heap_item);
-                out_int(out, 1);
-            }
-            
-            // Re-insert the entry
-            g_data.db.insert(entry_box);
-        } else {
-            out_int(out, 0); // Key not found
+// A piece of a connection's pending output
+#[derive(Debug)]
+enum Chunk {
+    Bytes(Buffer),
+    // A value shared with the keyspace, and how much of it is written
+    Shared(Arc<str>, usize),
+}
+
+impl Chunk {
+    // What's left to write
+    fn remaining(&self) -> &[u8] {
+        match self {
+            Chunk::Bytes(buffer) => buffer,
+            Chunk::Shared(value, written) => &value.as_bytes()[*written..],
         }
-    });
+    }
+}
 
-    Ok(())
+// Token bucket behind client-rate-limit: it refills at `rate` commands a
+// second and holds at most one second's worth, so short bursts are allowed
+#[derive(Debug)]
+struct RateLimit {
+    tokens: f64,
+    last_ms: u64,
 }
 
-// TTL command - returns remaining TTL in seconds
-fn do_ttl(cmd: &[String], out: &mut Buffer) -> Result<(), &'static str> {
-    if cmd.len() < 2 {
-        out_err(out, "TTL requires a key");
-        return Ok(());
+impl Default for RateLimit {
+    // Starts full; the first refill caps it at the rate
+    fn default() -> Self {
+        Self { tokens: f64::INFINITY, last_ms: 0 }
     }
+}
 
-    let key = &cmd[1];
-    
-    with_global_data(|g_data| {
-        match g_data.db.lookup_entry(key) {
-            Some(entry) => {
-                if let Some(heap_idx) = entry.heap_idx {
-                    if heap_idx < g_data.heap.len() {
-                        let expire_at = g_data.heap[heap_idx].value;
-                        let now_ms = get_monotonic_time_ms();
-                        
-                        if expire_at > now_ms {
-                            let remaining_ms = expire_at - now_ms;
-                            let remaining_seconds = (remaining_ms + 999) / 1000; // Round up
-                            out_int(out, remaining_seconds as i64);
-                        } else {
-                            out_int(out, -2); // Key expired
-                        }
-                    } else {
-                        out_int(out, -1); // No TTL set
-                    }
-                } else {
-                    out_int(out, -1); // No TTL set
-                }
-            }
-            None => {
-                out_int(out, -2); // Key doesn't exist
-            }
+impl RateLimit {
+    // Take a token for one command, if there is one
+    fn allow(&mut self, rate: u64, now_ms: u64) -> bool {
+        let refill = now_ms.saturating_sub(self.last_ms) as f64 * rate as f64 / 1000.0;
+        self.tokens = (self.tokens + refill).min(rate as f64);
+        self.last_ms = now_ms;
+        if self.tokens < 1.0 {
+            return false;
         }
-    });
-
-    Ok(())
+        self.tokens -= 1.0;
+        true
+    }
 }
 
+// Record client activity: stamp the time and push back the connection's idle
+// timer (or drop it while it's exempt from the idle timeout)
+fn conn_touch(g_data: &mut GData, fd: RawFd, conn: &mut Conn) {
+    conn.last_active_ms = get_monotonic_time_ms();
+    conn_schedule_idle(&mut g_data.idle_timers, g_data.config.timeout * 1000, fd, conn);
+}
 
-fn do_persist(cmd: &[String], out: &mut Buffer) -> Result<(), &'static str> {
-    if cmd.len() < 2 {
-        out_err(out, "PERSIST requires a key");
-        return Ok(());
+fn conn_schedule_idle(idle_timers: &mut IdleTimers, idle_timeout_ms: u64, fd: RawFd, conn: &Conn) {
+    if idle_timeout_ms == 0 || conn.idle_exempt() {
+        idle_timers.remove(&fd);
+    } else {
+        idle_timers.insert(fd, conn.last_active_ms + idle_timeout_ms);
     }
+}
 
-    let key = &cmd[1];
-    
-    with_global_data(|g_data| {
-        match g_data.db.lookup_entry(key) {
-            Some(entry) => {
-                if let Some(heap_idx) = entry.heap_idx {
-                    if heap_idx < g_data.heap.len() {
-                        heap_delete(&mut g_data.heap, heap_idx);
-                        out_int(out, 1); // TTL was removed
-                    } else {
-                        out_int(out, 0); // No TTL was set
-                    }
-                } else {
-                    out_int(out, 0); // No TTL was set
+fn run_server(mut config: config::Config) -> io::Result<()> {
+    logging::configure(&config);
+    fdlimit::adjust_open_files_limit(&mut config);
+    let appendonly = config.appendonly.then(|| config.appendfilename.clone());
+    let storage_log = (config.storage_backend == store::Backend::Log).then(|| config.storage_log.clone());
+    let storage_memory = config.storage_memory;
+    let (bind, port, protected_mode) = (config.bind, config.port, config.protected_mode);
+    if config.cluster_enabled {
+        // Clients are sent to this node at the address it listens on
+        let ip = bind.filter(|ip| !ip.is_unspecified()).unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let id = with_global_data(|g_data| {
+            g_data.cluster.enable(ip, port);
+            g_data.cluster.myself().id.clone()
+        });
+        log_notice!("cluster", "Cluster mode, node id {}", id);
+    }
+    with_global_data(|g_data| g_data.config = config);
+    if with_global_data(|g_data| g_data.cluster.enabled) {
+        gossip::start(bind, port)?;
+    }
+
+    // The log backend has the whole dataset; otherwise the AOF has the latest
+    // writes, and the snapshot is only used without one
+    if let Some(path) = &storage_log {
+        let (store, ttls) = store::LogStore::open(path, storage_memory).map_err(|e| {
+            log_error!("persist", "Failed to load the storage log {}", e);
+            io::Error::new(io::ErrorKind::InvalidData, e)
+        })?;
+        let keys = with_global_data(|g_data| {
+            g_data.db = Box::new(store);
+            for (key, at) in ttls {
+                if let Some(entry) = g_data.db.get(&key) {
+                    entry_set_expire_at(&mut g_data.expires, entry, at);
                 }
             }
-            None => {
-                out_int(out, 0); // Key doesn't exist
+            g_data.db.size()
+        });
+        log_notice!("persist", "Loaded {}: {} keys", path, keys);
+    } else if let Some(path) = appendonly {
+        match aof::load_file(&path) {
+            Ok(Some(stats)) => {
+                if let Some(preamble) = stats.preamble {
+                    log_notice!(
+                        "persist",
+                        "Loaded the snapshot preamble of {}: {} keys ({} expired), {} function libraries",
+                        path, preamble.keys, preamble.expired, preamble.libraries
+                    );
+                }
+                log_notice!("persist", "Loaded {}: {} commands", path, stats.commands);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                log_error!("persist", "Failed to load append-only file {}", e);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
             }
         }
-    });
-
-    Ok(())
-}
-
-fn do_zadd(cmd: &[String], out: &mut Buffer) -> Result<(), &'static str> {
-    if cmd.len() < 4 || (cmd.len() % 2) != 0 {
-        out_err(out, "ZADD requires: key score member [score member ...]");
-        return Ok(());
     }
+    let aof_loaded = with_global_data(|g_data| g_data.aof.is_open());
+    let snapshot = if aof_loaded || storage_log.is_some() { Ok(None) } else { rdb::load_file(rdb::RDB_FILENAME) };
 
-    let key = &cmd[1];
-    let mut added = 0;
-
-    // Parse and validate all score-member pairs first
-    let mut pairs = Vec::new();
-    let mut i = 2;
-    while i + 1 < cmd.len() {
-        match cmd[i].parse::<f64>() {
-            Ok(score) => pairs.push((score, cmd[i + 1].clone())),
-            Err(_) => {
-                out_err(out, &format!("Invalid score: {}", cmd[i]));
-                return Ok(());
-            }
+    match snapshot {
+        Ok(Some(stats)) => log_notice!(
+            "persist",
+            "Loaded {}: {} keys ({} expired), {} function libraries",
+            rdb::RDB_FILENAME, stats.keys, stats.expired, stats.libraries
+        ),
+        Ok(None) => {}
+        Err(e) => {
+            log_error!("persist", "Failed to load snapshot {}", e);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
         }
-        i += 2;
     }
 
-    with_global_data(|g_data| {
-        // Get or create ZSet
-        let mut zset_entry = match g_data.db.delete_entry_and_return(key) {
-            Some(entry) => match entry.value {
-                Value::ZSet(_) => entry,
-                _ => {
-                    out_err(out, "WRONGTYPE Operation against a key holding the wrong kind of value");
-                    return;
-                }
-            },
-            None => Box::new(Entry::new_zset(key.clone(), ZSet::new())),
-        };
+    // "*" listens on IPv6 and IPv4 alike
+    let addr = SocketAddr::new(bind.unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED)), port);
+    let server_socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        server_socket.set_only_v6(bind.is_some())?;
+    }
+    server_socket.set_reuse_address(true)?;
+    let sockaddr = SockAddr::from(addr);
+    server_socket.bind(&sockaddr)?;
+    server_socket.set_nonblocking(true)?;
+    server_socket.listen(BACKLOG)?;
+    with_global_data(|g_data| g_data.accept.reserve_spare());
+    log_notice!("server", "Server listening on {:?}", addr);
+    if protected_mode && !addr.ip().is_loopback() {
+        log_notice!("server", "Protected mode is on: only local clients are served (protected-mode no to allow others)");
+    }
 
-        // Add all pairs
-        if let Value::ZSet(ref mut zset) = zset_entry.value {
-            for (score, member) in pairs {
-                if zset.insert(score, member) {
-                    added += 1;
-                }
-            }
+    let signal_wake = signals::install()?;
+
+    loop {
+        if let Err(e) = serve_round(&mut sim::SystemPoller, Some(&server_socket), Some(&signal_wake)) {
+            log_error!("server", "Poll error: {}", e);
+            break;
         }
 
-        // Re-insert the entry
-        g_data.db.insert(zset_entry);
-    });
+        let requests = signals::take_requests(&signal_wake);
+        if requests.shutdown {
+            log_warning!("server", "Received a signal, shutting down");
+            exit_server();
+        }
+        if requests.reload {
+            config::reload();
+        }
+    }
 
-    out_int(out, added);
     Ok(())
 }
 
-fn do_zrem(cmd: &[String], out: &mut Buffer) -> Result<(), &'static str> {
-    if cmd.len() < 3 {
-        out_err(out, "ZREM requires: key member [member ...]");
-        return Ok(());
-    }
-
-    let key = &cmd[1];
-    let members = &cmd[2..];
-    let mut removed = 0;
 
+/// One round of the event loop: wait with `poller` for I/O or the next timer,
+/// accept on `listener`, serve the clients that are ready, then run the
+/// timers. `wake` is only polled to cut the wait short; a signal doing that
+/// returns Ok.
+fn serve_round(poller: &mut dyn sim::Poller, listener: Option<&Socket>, wake: Option<&UnixStream>) -> io::Result<()> {
+    // Log this round's writes before waiting for more
     with_global_data(|g_data| {
-        if let Some(mut zset_entry) = g_data.db.delete_entry_and_return(key) {
-            if let Value::ZSet(ref mut zset) = zset_entry.value {
-                for member in members {
-                    if let Some(node) = zset.lookup(member) {
-                        zset.delete(&node);
-                        removed += 1;
-                    }
-                }
-
-                // Re-insert if ZSet is not empty
-                if !zset.name_to_node.is_empty() {
-                    g_data.db.insert(zset_entry);
-                }
-                // If empty, let it drop (effectively deleting the key)
-            } else {
-                // Wrong type - re-insert and error
-                g_data.db.insert(zset_entry);
-                out_err(out, "WRONGTYPE Operation against a key holding the wrong kind of value");
-                return;
-            }
-        }
+        aof::flush(g_data);
+        audit::flush(g_data);
+        g_data.db.flush(&g_data.expires, &g_data.thread_pool);
     });
 
-    out_int(out, removed);
-    Ok(())
-}
+    // Not while accepting is paused for want of descriptors
+    let listener = listener.filter(|_| with_global_data(|g_data| g_data.accept.accepting(get_monotonic_time_ms())));
+    let mut poll_fds = Vec::new();
+    if let Some(listener) = listener {
+        poll_fds.push(PollFd::new(listener, PollFlags::POLLIN));
+    }
+    let first_client = poll_fds.len();
 
-fn with_global_data<F, R>(f: F) -> R
-where
-    F: FnOnce(&mut GData) -> R,
-{
-    let data = GLOBAL_DATA.get_or_init(|| {
-        let idle_list = DList::new();
-        dlist_init(idle_list.clone());
-        Mutex::new(GData {
-            db: HMap::default(),
-            fd2conn: HashMap::new(),
-            idle_list,
-            heap: Vec::new(),
-            thread_pool: ThreadPool::new(4),
-            ttl_map: HashMap::new(),
-        })
-    });
-    
-    let mut guard = data.lock().unwrap();
-    f(&mut *guard)
-}
+    let client_entries = client_poll_entries();
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-#[repr(u8)]
-enum Tag {
-    Nil = 0,    // nil
-    Err = 1,    // error code + msg
-    Str = 2,    // string
-    Int = 3,    // int64
-    Dbl = 4,    // double
-    Arr = 5,    // array
-}
-
-impl Tag {
-    /// Create an empty RedisValue of this type
-    /// Useful for protocol deserialization scaffolding
-    fn empty_value(&self) -> RedisValue {
-        match self {
-            Tag::Nil => RedisValue::Nil,
-            Tag::Err => RedisValue::Err(String::new()),
-            Tag::Str => RedisValue::Str(String::new()),
-            Tag::Int => RedisValue::Int(0),
-            Tag::Dbl => RedisValue::Dbl(0.0),
-            Tag::Arr => RedisValue::Arr(Vec::new()),
-        }
+    for (_, socket, events) in &client_entries {
+        poll_fds.push(PollFd::new(socket, *events));
     }
-    
-    /// Create a RedisValue with actual data
-    /// Will be useful when parsing protocol messages
-    fn with_data(&self, data: &[u8]) -> Result<RedisValue, String> {
-        match self {
-            Tag::Nil => Ok(RedisValue::Nil),
-            Tag::Err => Ok(RedisValue::Err(String::from_utf8_lossy(data).to_string())),
-            Tag::Str => Ok(RedisValue::Str(String::from_utf8_lossy(data).to_string())),
-            Tag::Int => {
-                let s = String::from_utf8_lossy(data);
-                s.parse::<i64>()
-                    .map(RedisValue::Int)
-                    .map_err(|_| "Invalid integer".to_string())
-            }
-            Tag::Dbl => {
-                let s = String::from_utf8_lossy(data);
-                s.parse::<f64>()
-                    .map(RedisValue::Dbl)
-                    .map_err(|_| "Invalid double".to_string())
-            }
-            Tag::Arr => {
-                // Arrays need special parsing - just create empty for now
-                Ok(RedisValue::Arr(Vec::new()))
-            }
-        }
+    // Last, so the slots above still line up with client_entries
+    if let Some(wake) = wake {
+        poll_fds.push(PollFd::new(wake, PollFlags::POLLIN));
     }
-}
 
-// Redis value that can hold any data type
-#[derive(Debug, Clone)]
-enum RedisValue {
-    Nil,
-    Err(String),                    // Error message
-    Str(String),                    // String value
-    Int(i64),                       // Integer value
-    Dbl(f64),                       // Double value
-    Arr(Vec<RedisValue>),          // Array of values (can be nested)
-}
+    let timeout_ms = next_timer_ms();
+    match poller.poll(&mut poll_fds, timeout_ms) {
+        Ok(_) => {}
+        // A signal came in; the caller checks what for
+        Err(nix::errno::Errno::EINTR) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+    update_lru_clock();
+    let mut to_remove = Vec::new(); // Store fds to remove after loop
 
-impl RedisValue {
-    fn tag(&self) -> Tag {
-        match self {
-            RedisValue::Nil => Tag::Nil,
-            RedisValue::Err(_) => Tag::Err,
-            RedisValue::Str(_) => Tag::Str,
-            RedisValue::Int(_) => Tag::Int,
-            RedisValue::Dbl(_) => Tag::Dbl,
-            RedisValue::Arr(_) => Tag::Arr,
-        }
+    let readable = |poll_fd: &PollFd| poll_fd.revents().unwrap_or(PollFlags::empty());
+    if let Some(listener) = listener
+        && readable(&poll_fds[0]).contains(PollFlags::POLLIN)
+    {
+        accept_clients(listener);
+    }
+    // Client sockets are polled through dup'd handles, so map the slot back
+    // to the fd the connection is registered under
+    for (i, poll_fd) in poll_fds[first_client..first_client + client_entries.len()].iter().enumerate() {
+        service_client(client_entries[i].0, readable(poll_fd), &mut to_remove);
     }
-}
 
-#[derive(Debug)]
-struct Conn{
-    socket: Socket,
+    for fd in to_remove {
+        drop_client(fd);
+    }
 
-    //application intention, for the event loop
-    want_read: bool,
-    want_write: bool,
-    want_close: bool,
+    // Process timers after handling all I/O events
+    process_timers();
 
-    //buffered input and output
-    incoming: Buffer,
-    outgoing: Buffer,
+    // The next part of commands that run over several rounds
+    blocking::resume_suspended();
 
-    last_active_ms: u64,
-    idle_node: Arc<Mutex<DList>>
+    // Keys changed by the commands and expiries above
+    with_global_data(tracking::send_invalidations);
 
+    // What the commands above wrote, to the replicas
+    with_global_data(replication::send_stream);
 
+    // Once they have it all, a failover can hand over
+    with_global_data(failover::cron);
+    Ok(())
 }
 
-impl Conn {
-    fn new(socket: Socket) -> Self {
-        Self {
-            socket,
-            want_read: true,
-            want_write: false,
-            want_close: false,
-            incoming: Buffer::new(),
-            outgoing: Buffer::new(),
-            last_active_ms: get_monotonic_time_ms(),
-            idle_node: DList::new(),
+// Take every connection waiting on the listening socket
+fn accept_clients(server_socket: &Socket) {
+    loop {
+        match server_socket.accept() {
+            Ok((client_socket, client_addr)) => {
+                let peer = client_addr.as_socket();
+                let client_addr = peer.map(|a| a.to_string()).unwrap_or_default();
+                log_debug!("net", "Client connected: {}", client_addr);
+                if let Err(e) = client_socket.set_nonblocking(true) {
+                    log_warning!("net", "Failed to set {} non-blocking: {}", client_addr, e);
+                    continue;
+                }
+                let config = with_global_data(|g_data| g_data.config.clone());
+                if let Err(e) = tune_client_socket(&client_socket, &config) {
+                    log_warning!("net", "Failed to set socket options for {}: {}", client_addr, e);
+                }
+                let client_fd = client_socket.as_raw_fd();
+
+                let mut conn = Conn::new(client_socket);
+                let clients = with_global_data(|g_data| {
+                    g_data.accept.accepted();
+                    g_data.fd2conn.len()
+                });
+                if peer.is_some_and(|peer| config.denies_peer(peer.ip())) {
+                    log_notice!("net", "Protected mode: refusing client {}", client_addr);
+                    conn.refused = Some(PROTECTED_MODE_ERR);
+                } else if clients as u64 >= config.maxclients {
+                    log_warning!("net", "maxclients reached: refusing client {}", client_addr);
+                    conn.refused = Some("ERR max number of clients reached");
+                }
+
+                add_client(client_fd, conn);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) if fdlimit::is_exhausted(&e) => {
+                let now_ms = get_monotonic_time_ms();
+                with_global_data(|g_data| g_data.accept.exhausted(server_socket, &e, now_ms));
+                break;
+            }
+            Err(e) => {
+                log_warning!("net", "Accept error: {}", e);
+                break;
+            }
         }
     }
 }
 
-fn events_from_conn(conn: &Conn) -> PollFlags {
-    let mut events = PollFlags::POLLERR;
-    if conn.want_read {
-        events |= PollFlags::POLLIN;
-    }
-    if conn.want_write {
-        events |= PollFlags::POLLOUT;
+// The peer address of a client, with IPv4 clients of an IPv6 listener
+// shown as IPv4
+fn peer_addr_string(socket: &Socket) -> String {
+    let Some(addr) = socket.peer_addr().ok().and_then(|addr| addr.as_socket()) else {
+        return String::new();
+    };
+    match addr.ip() {
+        IpAddr::V6(ip) if let Some(v4) = ip.to_ipv4_mapped() => SocketAddr::new(IpAddr::V4(v4), addr.port()).to_string(),
+        _ => addr.to_string(),
     }
-    events
 }
 
-fn run_server() -> io::Result<()> {
-    let server_socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
-    server_socket.set_only_v6(false)?;
-    server_socket.set_reuse_address(true)?;
-    let addr: SocketAddr = "[::]:1234".parse().unwrap();
-    let sockaddr = SockAddr::from(addr);
-    server_socket.bind(&sockaddr)?;
-    server_socket.set_nonblocking(true)?;
-    server_socket.listen(BACKLOG)?;
-    println!("Server listening on {:?}", addr);
-
-    let running = true;
+// Register a connection with the event loop
+fn add_client(fd: RawFd, mut conn: Conn) {
+    let addr = peer_addr_string(&conn.socket);
+    with_global_data(|g_data| {
+        events::emit(g_data, &ServerEvent::ClientConnected { fd, id: conn.id, addr: &addr });
+        conn_touch(g_data, fd, &mut conn);
+        g_data.fd2conn.insert(fd, conn);
+    });
+}
 
-    while running {
-        let mut poll_fds = Vec::new();
-        poll_fds.push(PollFd::new(&server_socket, PollFlags::POLLIN));
 
-        let client_entries: Vec<(RawFd, Socket, PollFlags)> = with_global_data(|g_data| {
-            g_data.fd2conn
-                .iter()
-                .map(|(&fd, conn)| {
-                    let sock_clone = conn.socket.try_clone().unwrap();
-                    (fd, sock_clone, events_from_conn(conn))
-                })
-                .collect()
-        });
+// Sockets to poll for the connections in the table, dup'd so the table
+// isn't borrowed while polling. Out of descriptors, a connection that can't
+// be dup'd sits this round out.
+fn client_poll_entries() -> Vec<(RawFd, Socket, PollFlags)> {
+    with_global_data(|g_data| {
+        g_data.fd2conn
+            .iter()
+            .filter_map(|(&fd, conn)| {
+                let sock_clone = conn.socket.try_clone().ok()?;
+                Some((fd, sock_clone, conn.poll_events()))
+            })
+            .collect()
+    })
+}
 
-        for (_, socket, events) in &client_entries {
-            poll_fds.push(PollFd::new(socket, *events));
-        }
-
-        let timeout_ms = next_timer_ms();
-        match poll(&mut poll_fds, timeout_ms) {
-            Ok(_) => {
-                let server_fd = server_socket.as_raw_fd();
-                let mut to_remove = Vec::new(); // Store fds to remove after loop
-
-                for poll_fd in &poll_fds {
-                    let fd = poll_fd.as_fd().as_raw_fd();
-                    let revents = poll_fd.revents().unwrap_or(PollFlags::empty());
-
-                    if fd == server_fd && revents.contains(PollFlags::POLLIN) {
-                        // Handle new connections
-                        loop {
-                            match server_socket.accept() {
-                                Ok((client_socket, client_addr)) => {
-                                    println!("Client connected: {:?}", client_addr);
-                                    client_socket.set_nonblocking(true)?;
-                                    let client_fd = client_socket.as_raw_fd();
-                                    
-                                    let conn = Conn::new(client_socket);
-                                    
-                                    with_global_data(|g_data| {
-                                        dlist_insert_before(&g_data.idle_list, &conn.idle_node);
-                                        g_data.fd2conn.insert(client_fd, conn);
-                                    });
-                                }
-                                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                                Err(e) => {
-                                    eprintln!("Accept error: {}", e);
-                                    break;
-                                }
-                            }
-                        }
-                    } else if revents.contains(PollFlags::POLLIN) {
-                        with_global_data(|g_data| {
-                            if let Some(conn) = g_data.fd2conn.get_mut(&fd) {
-                                if conn.want_read {
-                                    match handle_read(conn) {
-                                        Ok(()) => {}
-                                        Err(_) => {
-                                            println!("Client {} disconnected", fd);
-                                            to_remove.push(fd);
-                                        }
-                                    }
-                                }
-                            }
-                        });
-                    } else if revents.contains(PollFlags::POLLOUT) {
-                        with_global_data(|g_data| {
-                            if let Some(conn) = g_data.fd2conn.get_mut(&fd) {
-                                if conn.want_write && !conn.outgoing.is_empty() {
-                                    match handle_write(conn) {
-                                        Ok(()) => {}
-                                        Err(_) => {
-                                            println!("Client {} disconnected during write", fd);
-                                            to_remove.push(fd);
-                                        }
-                                    }
-                                }
-                            }
-                        });
+// Read and run the requests of a client, or write out its replies, as its
+// poll events say. Clients to drop are added to `to_remove`.
+fn service_client(fd: RawFd, revents: PollFlags, to_remove: &mut Vec<RawFd>) {
+    // The socket failed, or the client is gone both ways so nothing can be
+    // written to it any more; with POLLIN as well its last requests are
+    // still read, and the write of their replies fails
+    let hung_up = revents.contains(PollFlags::POLLHUP) && !revents.contains(PollFlags::POLLIN);
+    if hung_up || revents.intersects(PollFlags::POLLERR | PollFlags::POLLNVAL) {
+        log_debug!("net", "Client {} hung up ({:?})", fd, revents);
+        to_remove.push(fd);
+        return;
+    }
+    if revents.contains(PollFlags::POLLIN) {
+        // Take the connection out of the table while it is serviced:
+        // command handlers lock the global data themselves.
+        let taken = with_global_data(|g_data| g_data.fd2conn.remove(&fd));
+        if let Some(mut conn) = taken {
+            if conn.reads_requests() {
+                match handle_read(&mut conn) {
+                    Ok(()) => {}
+                    Err(_) => {
+                        log_debug!("net", "Client {} disconnected", fd);
+                        to_remove.push(fd);
                     }
-
-                    // Check for connections that should be closed
-                    with_global_data(|g_data| {
-                        if let Some(conn) = g_data.fd2conn.get(&fd) {
-                            if conn.want_close {
-                                to_remove.push(fd);
-                            }
-                        }
-                    });
                 }
-
-                // ADD THIS SECTION: Remove disconnected clients
-                for fd in to_remove {
-                    with_global_data(|g_data| {
-                        if let Some(conn) = g_data.fd2conn.remove(&fd) {
-                            // Remove from idle list
-                            dlist_detach(conn.idle_node.clone());
-                            println!("Cleaned up connection for fd: {}", fd);
-                        }
-                    });
+            }
+            with_global_data(|g_data| {
+                conn_touch(g_data, fd, &mut conn);
+                g_data.fd2conn.insert(fd, conn);
+            });
+        }
+    } else if revents.contains(PollFlags::POLLOUT) {
+        let pipelined = with_global_data(|g_data| {
+            let conn = g_data.fd2conn.get_mut(&fd)?;
+            if conn.state != ConnState::Closing && conn.has_output() {
+                match handle_write(conn) {
+                    Ok(()) => {}
+                    Err(_) => {
+                        log_debug!("net", "Client {} disconnected during write", fd);
+                        to_remove.push(fd);
+                        return None;
+                    }
                 }
-
-                // Process timers after handling all I/O events
-                process_timers();
+            } else if !conn.has_output() {
+                conn.output_drained();
             }
-            Err(e) => {
-                eprintln!("Poll error: {}", e);
-                break;
+            // Requests that came in behind a blocked or suspended command
+            // are run once its reply is out
+            let pipelined = conn.reads_requests() && !conn.has_output() && !conn.incoming.is_empty();
+            pipelined.then(|| g_data.fd2conn.remove(&fd)).flatten()
+        });
+        if let Some(mut conn) = pipelined {
+            if run_requests(&mut conn).is_err() {
+                log_debug!("net", "Client {} disconnected", fd);
+                to_remove.push(fd);
             }
+            with_global_data(|g_data| {
+                conn_touch(g_data, fd, &mut conn);
+                g_data.fd2conn.insert(fd, conn);
+            });
         }
     }
 
-    Ok(())
+    // Check for connections that should be closed
+    with_global_data(|g_data| {
+        if let Some(conn) = g_data.fd2conn.get(&fd)
+            && conn.state == ConnState::Closing
+        {
+            to_remove.push(fd);
+        }
+    });
 }
 
+// Forget a disconnected client
+fn drop_client(fd: RawFd) {
+    with_global_data(|g_data| forget_client(g_data, fd));
+}
 
-fn handle_read(conn: &mut Conn) -> io::Result<()> {
-    // 1. Non-blocking read
-    let mut buf = [0u8; 64 * 1024];
-    match conn.socket.read(&mut buf) {
-        Ok(0) => {
-            // EOF: client closed
-            conn.want_close = true;
-            return Ok(());
+// Close the connection at `fd` and forget everything about its client
+fn forget_client(g_data: &mut GData, fd: RawFd) {
+    g_data.blocking.unblock(fd);
+    g_data.keys_scans.remove(&fd);
+    // Once, the first time it's forgotten
+    if g_data.clients.id_of(fd).is_some() {
+        events::emit(g_data, &ServerEvent::ClientDisconnected { fd });
+    }
+    if g_data.fd2conn.remove(&fd).is_some() {
+        g_data.idle_timers.remove(&fd);
+        log_trace!("net", "Cleaned up connection for fd: {}", fd);
+    }
+}
+
+/// One round of the event loop from inside a script that's past
+/// lua-time-limit: requests of the other clients get BUSY (see
+/// try_parse_request) or stop the script. New connections wait in the
+/// listen backlog, timers until the script is done.
+fn serve_while_busy() {
+    let client_entries = client_poll_entries();
+    let mut poll_fds: Vec<PollFd> =
+        client_entries.iter().map(|(_, socket, events)| PollFd::new(socket, *events)).collect();
+    if poll(&mut poll_fds, 0).is_err() {
+        return;
+    }
+
+    // The script's own client is restored for the rest of its run
+    blocking::without_client(|| {
+        let mut to_remove = Vec::new();
+        for (poll_fd, (fd, ..)) in poll_fds.iter().zip(&client_entries) {
+            service_client(*fd, poll_fd.revents().unwrap_or(PollFlags::empty()), &mut to_remove);
         }
-        Ok(n) => {
-            // Append to incoming buffer
-            conn.incoming.extend_from_slice(&buf[..n]);
+        for fd in to_remove {
+            drop_client(fd);
         }
-        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-            // No data yet, try again later
-            return Ok(());
+    });
+}
+
+fn handle_read(conn: &mut Conn) -> io::Result<()> {
+    // 1. Non-blocking reads until the socket is drained, up to a limit so one
+    // busy client can't hold up the others
+    let mut buf = [0u8; 64 * 1024];
+    let mut eof = false;
+    for _ in 0..MAX_READS_PER_EVENT {
+        match conn.socket.read(&mut buf) {
+            Ok(0) => {
+                // EOF: the client shut down its side, maybe right after its
+                // last requests, which are still run and answered
+                eof = true;
+                break;
+            }
+            Ok(n) => {
+                // Append to incoming buffer
+                conn.incoming.extend_from_slice(&buf[..n]);
+                if n < buf.len() {
+                    break; // A short read means there's nothing more right now
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
         }
-        Err(e) => return Err(e),
     }
+    // 2. Run the requests and start writing the replies
+    if !conn.incoming.is_empty() {
+        run_requests(conn)?;
+    }
+    if eof {
+        conn.input_ended();
+    }
+    Ok(())
+}
 
-    // 2. Try to parse requests
+// Run the complete requests in the input of a connection and write out what
+// they replied
+fn run_requests(conn: &mut Conn) -> io::Result<()> {
     try_parse_request(conn)?;
 
-    if !conn.outgoing.is_empty() {
-        conn.want_read = false;   // Stop reading until we send response
-        conn.want_write = true;   // Start writing the response
+    if conn.has_output() {
+        conn.replies_queued();
 
         match handle_write(conn) {
             Ok(()) => {}
@@ -3030,111 +4293,243 @@ fn handle_read(conn: &mut Conn) -> io::Result<()> {
 
 }
 
-fn handle_write(conn: &mut Conn) -> io::Result<()> {
-    assert!(!conn.outgoing.is_empty());
+// Apply the tcp-* options to a freshly accepted client socket
+fn tune_client_socket(socket: &Socket, config: &config::Config) -> io::Result<()> {
+    socket.set_tcp_nodelay(config.tcp_nodelay)?;
+    if config.tcp_keepalive == 0 {
+        return socket.set_keepalive(false);
+    }
+    let keepalive = TcpKeepalive::new()
+        .with_time(Duration::from_secs(config.tcp_keepalive))
+        .with_interval(Duration::from_secs(config.tcp_keepalive_interval))
+        .with_retries(config.tcp_keepalive_probes);
+    socket.set_tcp_keepalive(&keepalive)
+}
 
-    match conn.socket.write(&conn.outgoing) {
-        Ok(0) => {
-            conn.want_close = true;
-            return Err(io::Error::new(io::ErrorKind::WriteZero, "Socket closed"));
+// Write as much output as the socket takes, all chunks in one writev each
+// round, until it's all sent or the socket is full
+fn handle_write(conn: &mut Conn) -> io::Result<()> {
+    assert!(conn.has_output());
+
+    while conn.has_output() {
+        let chunks = conn.sealed.iter().map(Chunk::remaining).chain(std::iter::once(conn.outgoing.data()));
+        let slices: Vec<IoSlice> =
+            chunks.filter(|chunk| !chunk.is_empty()).take(MAX_IOVECS).map(IoSlice::new).collect();
+        let count = slices.len();
+        match conn.socket.write_vectored(&slices) {
+            Ok(0) => {
+                conn.close();
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "Socket closed"));
+            }
+            Ok(n) => {
+                conn.consume_output(n); // Remove written bytes
+                log_trace!("net", "Wrote {} bytes from {} chunks", n, count);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Ok(());
+            }
+            Err(e) => {
+                conn.close();
+                return Err(e);
+            }
         }
-        Ok(n) => {
-            conn.outgoing.consume(n); // Remove written bytes
+    }
+
+    conn.output_drained();
+    Ok(())
+}
 
-            if conn.outgoing.is_empty() {
-                conn.want_write = false;
-                conn.want_read = true;
+
+/// Glob-style match as in Redis: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes
+fn glob_match(pattern: &str, s: &str, nocase: bool) -> bool {
+    let (p, s) = (pattern.as_bytes(), s.as_bytes());
+    let eq = |a: u8, b: u8| if nocase { a.eq_ignore_ascii_case(&b) } else { a == b };
+    let (mut pi, mut si) = (0, 0);
+    // Where to resume after the last '*' if the rest fails to match
+    let mut star: Option<(usize, usize)> = None;
+
+    while si < s.len() {
+        let matched = match p.get(pi) {
+            Some(b'*') => {
+                star = Some((pi, si));
+                pi += 1;
+                continue;
+            }
+            Some(b'?') => Some(pi + 1),
+            Some(b'[') => {
+                let mut i = pi + 1;
+                let negate = p.get(i) == Some(&b'^');
+                if negate {
+                    i += 1;
+                }
+                let mut found = false;
+                while i < p.len() && p[i] != b']' {
+                    if p[i] == b'\\' && i + 1 < p.len() {
+                        found |= eq(p[i + 1], s[si]);
+                        i += 2;
+                    } else if i + 2 < p.len() && p[i + 1] == b'-' && p[i + 2] != b']' {
+                        let (lo, hi) = (p[i].min(p[i + 2]), p[i].max(p[i + 2]));
+                        let c = s[si];
+                        found |= (lo..=hi).contains(&c)
+                            || (nocase && ((lo..=hi).contains(&c.to_ascii_lowercase()) || (lo..=hi).contains(&c.to_ascii_uppercase())));
+                        i += 3;
+                    } else {
+                        found |= eq(p[i], s[si]);
+                        i += 1;
+                    }
+                }
+                (found != negate).then_some(i + 1)
             }
+            Some(b'\\') if pi + 1 < p.len() => eq(p[pi + 1], s[si]).then_some(pi + 2),
+            Some(&c) => eq(c, s[si]).then_some(pi + 1),
+            None => None,
+        };
 
-            println!("Wrote {} bytes, {} bytes remaining", n, conn.outgoing.len());
-        }
-        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-            return Ok(());
-        }
-        Err(e) => {
-            conn.want_close = true;
-            return Err(e);
+        match matched {
+            Some(next) => {
+                pi = next;
+                si += 1;
+            }
+            None => match star {
+                // Let the last '*' swallow one more character and retry
+                Some((star_pi, star_si)) => {
+                    pi = star_pi + 1;
+                    si = star_si + 1;
+                    star = Some((star_pi, star_si + 1));
+                }
+                None => return false,
+            },
         }
     }
 
-    Ok(())
+    p[pi.min(p.len())..].iter().all(|&c| c == b'*')
 }
 
+const PROTECTED_MODE_ERR: &str = "DENIED Running in protected mode: no password is set and the server \
+    listens beyond loopback, so only local clients are served. Connect from this host, bind to 127.0.0.1, \
+    or set protected-mode no if every client that can reach the port is trusted.";
 
 fn try_parse_request(conn: &mut Conn) -> io::Result<()> {
     loop {
-        // 3. Need at least 4 bytes for header
-        if conn.incoming.len() < 4 {
-            break;
-        }
-
-        // Parse message length
-        let len_bytes: [u8; 4] = conn.incoming[..4].try_into().unwrap();
-        let msg_len = u32::from_le_bytes(len_bytes) as usize;
-
-        // Protocol sanity check
-        if msg_len > K_MAX_MSG {
-            conn.want_close = true;
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Message too long"));
+        if conn.proto == resp::Proto::Detecting {
+            if conn.incoming.len() < 4 {
+                break;
+            }
+            conn.proto = resp::detect(&conn.incoming[..4]);
+            // Text replies are re-encoded, so they can't point into the keyspace
+            conn.outgoing.share_values = conn.proto != resp::Proto::Text;
         }
 
-        let total_len = 4 + msg_len;
-        if conn.incoming.len() < total_len {
-            // Not enough data yet, wait for next read
+        if let Some(reason) = conn.refused {
+            let max_reply_size = with_global_data_read(|g_data| g_data.config.max_reply_size);
+            let header_pos = conn.outgoing.response_begin();
+            out_err(&mut conn.outgoing, reason);
+            conn.finish_reply(header_pos, max_reply_size);
+            conn.close_after_write();
             break;
         }
 
-        // Extract message body
-        let message_data = conn.incoming[4..total_len].to_vec();
-        println!("client says: {}", String::from_utf8_lossy(&message_data));
+        let (limits, max_reply_size, rate_limit) = with_global_data_read(|g_data| {
+            (g_data.config.limits.frame_limits(), g_data.config.max_reply_size, g_data.config.client_rate_limit)
+        });
 
-        // 4. Parse command and generate response
-        let message_str = String::from_utf8_lossy(&message_data);
-        let parts: Vec<String> = message_str.split_whitespace().map(|s| s.to_string()).collect();
+        let parsed = if conn.proto == resp::Proto::Text {
+            resp::parse_request_resuming(&conn.incoming, &limits, &mut conn.partial)
+        } else {
+            redis::protocol::parse_frame(&conn.incoming, &limits)
+        };
+        let (parts, total_len) = match parsed {
+            Ok(Some(request)) => request,
+            // Not enough data yet, wait for next read
+            Ok(None) => break,
+            Err(e) => {
+                // The stream can't be resynced: report and hang up
+                log_debug!("net", "Client {}: {}", conn.socket.as_raw_fd(), e);
+                with_global_data(|g_data| g_data.stats.count_protocol_error(&e));
+                let header_pos = conn.outgoing.response_begin();
+                out_err(&mut conn.outgoing, &format!("ERR {}", e));
+                conn.finish_reply(header_pos, max_reply_size);
+                conn.close_after_write();
+                break;
+            }
+        };
+        log_trace!("net", "client says: {:?}", parts);
 
         if !parts.is_empty() {
+            // Held until CLIENT PAUSE ends, with the rest of the input
+            if let Some(command) = command::lookup(&parts[0])
+                && with_global_data_read(|g_data| g_data.pause.holds(command))
+            {
+                conn.park();
+                break;
+            }
+
             // Begin response (reserve header space)
             let header_pos = conn.outgoing.response_begin();
+
+            if rate_limit > 0 && !conn.rate_limit.allow(rate_limit, get_monotonic_time_ms()) {
+                out_err(&mut conn.outgoing, "ERR client-rate-limit exceeded, slow down");
+                conn.finish_command_reply(header_pos, max_reply_size);
+                conn.incoming.consume(total_len);
+                continue;
+            }
+
+            // A script ran past lua-time-limit and this is served from its hook
+            if let Some(busy) = scripting::busy_error()
+                && !command::lookup(&parts[0]).is_some_and(|command| scripting::allowed_while_busy(command, &parts))
+            {
+                out_err(&mut conn.outgoing, busy);
+                conn.finish_command_reply(header_pos, max_reply_size);
+                conn.incoming.consume(total_len);
+                continue;
+            }
     
             // Process the command
-            match parts[0].to_uppercase().as_str() {
-                "GET" => {
-                    with_global_data(|g_data| {
-                        do_get(&g_data.db, &parts, &mut conn.outgoing).unwrap();
-                    });
-                }
-                "SET" => {
-                    do_set(&parts, &mut conn.outgoing).unwrap();
-                }
-                "DEL" => {
-                    do_del(&parts, &mut conn.outgoing).unwrap();
-                }
-                "KEYS" => {
-                    do_keys(&mut conn.outgoing).unwrap();
-                }
-                "ZADD" => {
-                    do_zadd(&parts, &mut conn.outgoing).unwrap();
-                }
-                "ZREM" => {
-                    do_zrem(&parts, &mut conn.outgoing).unwrap();
-                }
-                "ZQUERY" => {
-                    do_zquery(&parts, &mut conn.outgoing).unwrap();  // Add this line
-                }
-                "EXPIRE" => {
-                    do_expire(&parts, &mut conn.outgoing).unwrap();
+            let fd = conn.socket.as_raw_fd();
+            if blocking::execute_for_client(fd, &parts, &mut conn.outgoing) {
+                // No reply until the client is served or times out, and
+                // pipelined commands wait behind the blocked one
+                conn.outgoing.truncate(header_pos);
+                conn.incoming.consume(total_len);
+                conn.block();
+                break;
+            }
+            // End response (write actual size to header). CLIENT REPLY's own
+            // reply goes by the mode it switched to, as its OK isn't one.
+            let requested =
+                with_global_data_read(|g_data| g_data.reply_mode_requested.is_some() || g_data.raw_reply_requested.is_some());
+            let (mode, raw) = if requested {
+                with_global_data(|g_data| (g_data.reply_mode_requested.take(), g_data.raw_reply_requested.take()))
+            } else {
+                (None, None)
+            };
+            match (mode, raw) {
+                // Replication speaks its own bytes
+                (_, Some(raw)) => {
+                    conn.outgoing.truncate(header_pos);
+                    conn.append_raw(&raw);
                 }
-                "TTL" => {
-                    do_ttl(&parts, &mut conn.outgoing).unwrap();
+                (Some(ReplyMode::On), None) => {
+                    conn.reply_mode = ReplyMode::On;
+                    conn.finish_reply(header_pos, max_reply_size);
                 }
-                "PERSIST" => {
-                    do_persist(&parts, &mut conn.outgoing).unwrap();
+                (Some(mode), None) => {
+                    conn.reply_mode = mode;
+                    conn.outgoing.truncate(header_pos);
                 }
-                _ => out_err(&mut conn.outgoing, "Unknown command"),
+                (None, None) => conn.finish_command_reply(header_pos, max_reply_size),
             }
-            // End response (write actual size to header)
-            conn.outgoing.response_end(header_pos);
 
+            // This command may have fed keys other clients are blocked on
+            blocking::serve_ready_keys();
+
+            if with_global_data_read(|g_data| g_data.quit_requested)
+                && with_global_data(|g_data| std::mem::take(&mut g_data.quit_requested))
+            {
+                // Anything pipelined after QUIT is dropped
+                conn.close_after_write();
+                break;
+            }
         }
         // After the command processing block, add:
         conn.incoming.consume(total_len);
@@ -3145,169 +4540,605 @@ fn try_parse_request(conn: &mut Conn) -> io::Result<()> {
 
 
 
-fn one_request<T: Read + Write>(socket: &mut T) -> io::Result<()> {
-    let mut rbuf = [0u8; 4 + K_MAX_MSG];
-
-    set_errno(Errno(0));
-
-    // Read header
-    if let Err(e) = read_full(socket, &mut rbuf[..4]) {
-        if errno().0 == 0 {
-            println!("EOF");
-        } else {
-            println!("read() error: {}", e);
-        }
-        return Err(e);
+/// Run one command, writing its reply to `out`. Every command goes through
+/// here, whether it arrived from a client or from a script.
+fn execute_command(parts: &[String], out: &mut Buffer) {
+    let Some(command) = command::lookup(&parts[0]) else {
+        out_err(out, "Unknown command");
+        return;
+    };
+    if let Some(redirect) = cluster::check_redirect(command, parts) {
+        out_err(out, &redirect);
+        return;
     }
-
-    let len = u32::from_le_bytes(rbuf[..4].try_into().unwrap()) as usize;
-    if len > K_MAX_MSG {
-        eprintln!("too long");
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "too long"));
+    if command.has_flag(command::CMD_WRITE) && with_global_data_read(|g_data| g_data.replication.is_replica()) {
+        out_err(out, failover::READONLY_ERROR);
+        return;
     }
-
-    // Read body
-    if let Err(e) = read_full(socket, &mut rbuf[4..4 + len]) {
-        eprintln!("read() error: {}", e);
-        return Err(e);
+    if let Err(e) = with_global_data_read(|g_data| g_data.config.limits.check_keys(&command.keys(parts))) {
+        with_global_data(|g_data| g_data.stats.rejected_key_len += 1);
+        out_err(out, &e);
+        return;
     }
-
-    println!("client says: {}", String::from_utf8_lossy(&rbuf[4..4 + len]));
-
-    // Prepare reply
-    let reply = b"world";
-    let mut wbuf = vec![0u8; 4 + reply.len()];
-    wbuf[..4].copy_from_slice(&(reply.len() as u32).to_le_bytes());
-    wbuf[4..].copy_from_slice(reply);
-
-    // Send reply
-    write_all(socket, &wbuf)?;
-    Ok(())
-}
-
-// Read exactly `n` bytes into `buf`
-fn read_full<T: Read>(socket: &mut T, mut buf: &mut [u8]) -> io::Result<()> {
-    while !buf.is_empty() {
-        match socket.read(buf) {
-            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")),
-            Ok(rv) => {
-                assert!(rv <= buf.len());
-                buf = &mut buf[rv..]; // advance buffer
-            }
-            Err(e) => return Err(e),
-        }
+    if let Err(e) = evict::make_room(command) {
+        out_err(out, e);
+        return;
     }
-    Ok(())
+    run_command(command, parts, out);
 }
 
-// Write exactly all bytes from `buf`
-fn write_all<T: Write>(socket: &mut T, mut buf: &[u8]) -> io::Result<()> {
-    while !buf.is_empty() {
-        match socket.write(buf) {
-            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write to socket")),
-            Ok(rv) => {
-                assert!(rv <= buf.len());
-                buf = &buf[rv..]; // advance buffer
-            }
-            Err(e) => return Err(e),
+/// execute_command for an already resolved command
+fn run_command(command: &command::Command, parts: &[String], out: &mut Buffer) {
+    if !command.arity_ok(parts.len()) {
+        out_err(out, &format!("ERR wrong number of arguments for '{}' command", command.name));
+        return;
+    }
+    // Values the log backend left on disk are read back for the handler
+    let keys = command.keys(parts);
+    if with_global_data_read(|g_data| g_data.db.on_disk(&keys))
+        && let Err(e) = with_global_data(|g_data| g_data.db.load(&keys))
+    {
+        out_err(out, &e);
+        return;
+    }
+    let reply_at = out.len();
+    let started = Instant::now();
+    match (command.handler)(parts, out) {
+        Ok(()) => {}
+        Err(CmdError::WrongType) => {
+            out.truncate(reply_at);
+            out_err(out, WRONGTYPE);
         }
+        Err(CmdError::Failed(e)) => log_warning!("cmd", "{} failed: {}", command.name, e),
+    }
+    let usec = started.elapsed().as_micros() as u64;
+    let reply = &out.data()[reply_at..];
+    let failed = reply.first() == Some(&(Tag::Err as u8));
+    let event = ServerEvent::CommandExecuted { command, args: parts, reply, usec, failed };
+    if !with_global_data_read(|g_data| events::emit_shared(g_data, &event)) {
+        with_global_data(|g_data| events::emit(g_data, &event));
     }
-    Ok(())
 }
 
-/* Client Logic */
-fn run_client() -> std::io::Result<()> {
-    // Create socket
-    let mut socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
 
-    // Server address: 127.0.0.1:1234
-    let server_addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
-    let sockaddr = SockAddr::from(server_addr);
+// std's RandomState is SipHash-1-3 under per-instance random keys
+fn hash_key(seed: &RandomState, key: &str) -> u64 {
+    seed.hash_one(key.as_bytes())
+}
 
-    // Connect to server
-    socket.connect(&sockaddr)?;
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = env::args().collect();
 
-    // Prepare message with protocol header
-    query(&mut socket, "hello1")?;
-    query(&mut socket, "hello2")?;  
-    query(&mut socket, "hello3")?;
-    
+    match args.get(1).map(String::as_str) {
+        Some("client") => return cli::run(&args[2..]),
+        Some("bench") => return bench::run(&args[2..]),
+        Some("sentinel") => return sentinel::run(&args[2..]),
+        Some("sim") => return sim::run(&args[2..]),
+        _ => {}
+    }
 
-    Ok(())
+    let config = match config::Config::from_args(&args[1..]) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Bad configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = command::install_renames(&config.rename_commands) {
+        eprintln!("Bad configuration: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = env::set_current_dir(&config.dir) {
+        eprintln!("Can't chdir to '{}': {}", config.dir, e);
+        std::process::exit(1);
+    }
+    if let Err(e) = daemon::lock_dir() {
+        eprintln!("Can't start: {}", e);
+        std::process::exit(1);
+    }
+    if config.daemonize && let Err(e) = daemon::daemonize(&config).and_then(|()| daemon::record_lock_owner()) {
+        eprintln!("Can't daemonize: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = daemon::write_pidfile(&config) {
+        eprintln!("Can't write the pidfile: {}", e);
+        std::process::exit(1);
+    }
+    let result = run_server(config);
+    daemon::remove_pidfile();
+    result
 }
 
-fn query<T: Read + Write>(socket: &mut T, text: &str) -> io::Result<()> {
-    let len = text.len();
-    if len > K_MAX_MSG {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Message too long"));
+#[cfg(test)]
+mod tests {
+    use redis::protocol::RedisValue;
+
+    use crate::sim::testing::shared;
+
+    #[test]
+    fn del_counts_only_keys_that_exist() {
+        let mut sim = shared();
+        sim.call("del", "SET del:a 1");
+        assert_eq!(sim.call("del", "DEL del:missing"), RedisValue::Int(0));
+        assert_eq!(sim.call("del", "DEL del:missing del:a del:other"), RedisValue::Int(1));
+        assert_eq!(sim.call("del", "GET del:a"), RedisValue::Nil);
+        assert_eq!(sim.call("del", "DEL del:a"), RedisValue::Int(0));
+    }
+
+    #[test]
+    fn del_counts_a_repeated_key_once() {
+        let mut sim = shared();
+        sim.call("del", "SET del:dup 1");
+        assert_eq!(sim.call("del", "DEL del:dup del:dup del:dup"), RedisValue::Int(1));
+        sim.call("del", "SET del:dup 1");
+        sim.call("del", "SET del:dup2 1");
+        assert_eq!(sim.call("del", "DEL del:dup del:dup2 del:dup del:dup2"), RedisValue::Int(2));
+    }
+
+    #[test]
+    fn del_removes_every_type() {
+        let mut sim = shared();
+        sim.call("del", "SET del:type:str v");
+        sim.call("del", "RPUSH del:type:list a b");
+        sim.call("del", "ZADD del:type:zset 1 m");
+        sim.call("del", "XADD del:type:stream * f v");
+        let RedisValue::Arr(keys) = sim.call("del", "KEYS del:type:*") else { panic!("KEYS") };
+        assert_eq!(keys.len(), 4);
+        assert_eq!(
+            sim.call("del", "DEL del:type:str del:type:list del:type:zset del:type:stream del:type:none"),
+            RedisValue::Int(4)
+        );
+        assert_eq!(sim.call("del", "KEYS del:type:*"), RedisValue::Arr(vec![]));
+    }
+
+    #[test]
+    fn del_of_a_key_with_a_ttl() {
+        let mut sim = shared();
+        sim.call("del", "SET del:ttl v");
+        sim.call("del", "PEXPIRE del:ttl 100");
+        assert_eq!(sim.call("del", "DEL del:ttl"), RedisValue::Int(1));
+
+        // Gone with its TTL: the key made again doesn't expire
+        sim.call("del", "SET del:ttl v2");
+        sim.advance(200).unwrap();
+        assert_eq!(sim.call("del", "GET del:ttl"), RedisValue::Str("v2".into()));
+        assert_eq!(sim.call("del", "TTL del:ttl"), RedisValue::Int(-1));
+
+        // Past its TTL there's nothing to delete
+        sim.call("del", "PEXPIRE del:ttl 100");
+        sim.advance(200).unwrap();
+        assert_eq!(sim.call("del", "DEL del:ttl"), RedisValue::Int(0));
+    }
+
+    // PERSIST used to leave the entry pointing at its old heap slot, which
+    // the next TTL set moved into; an EXPIRE after it then rescheduled
+    // another key's TTL
+    #[test]
+    fn ttl_set_after_persist_is_the_keys_own() {
+        let mut sim = shared();
+        sim.call("ttl", "SET ttl:a 1");
+        sim.call("ttl", "SET ttl:b 1");
+        sim.call("ttl", "PEXPIRE ttl:a 1000");
+        sim.call("ttl", "PEXPIRE ttl:b 5000");
+        assert_eq!(sim.call("ttl", "PERSIST ttl:a"), RedisValue::Int(1));
+        assert_eq!(sim.call("ttl", "TTL ttl:a"), RedisValue::Int(-1));
+        sim.call("ttl", "PEXPIRE ttl:a 3000");
+
+        sim.advance(1500).unwrap();
+        assert_eq!(sim.call("ttl", "GET ttl:a"), RedisValue::Str("1".into()));
+        sim.advance(2000).unwrap();
+        assert_eq!(sim.call("ttl", "GET ttl:a"), RedisValue::Nil);
+        assert_eq!(sim.call("ttl", "GET ttl:b"), RedisValue::Str("1".into()));
+        sim.advance(2000).unwrap();
+        assert_eq!(sim.call("ttl", "GET ttl:b"), RedisValue::Nil);
+    }
+
+    #[test]
+    fn set_and_persist_clear_the_ttl_for_good() {
+        let mut sim = shared();
+        sim.call("ttl", "SET ttl:c 1");
+        sim.call("ttl", "PEXPIRE ttl:c 1000");
+        sim.call("ttl", "SET ttl:c 2");
+        assert_eq!(sim.call("ttl", "TTL ttl:c"), RedisValue::Int(-1));
+        sim.call("ttl", "SET ttl:d 1");
+        sim.call("ttl", "PEXPIRE ttl:d 1000");
+        assert_eq!(sim.call("ttl", "PERSIST ttl:d"), RedisValue::Int(1));
+        assert_eq!(sim.call("ttl", "PERSIST ttl:d"), RedisValue::Int(0));
+
+        // Their old TTLs pass without a trace, and TTLs set since still work
+        sim.call("ttl", "SET ttl:e 1");
+        sim.call("ttl", "PEXPIRE ttl:e 1500");
+        sim.advance(1200).unwrap();
+        assert_eq!(sim.call("ttl", "GET ttl:c"), RedisValue::Str("2".into()));
+        assert_eq!(sim.call("ttl", "GET ttl:d"), RedisValue::Str("1".into()));
+        assert_eq!(sim.call("ttl", "GET ttl:e"), RedisValue::Str("1".into()));
+        sim.advance(500).unwrap();
+        assert_eq!(sim.call("ttl", "GET ttl:e"), RedisValue::Nil);
+    }
+
+    fn calls_of(sim: &mut crate::sim::Simulation, command: &str) -> i64 {
+        match sim.call("stats", &format!("LATENCY PERCENTILES {}", command)) {
+            RedisValue::Arr(reply) => match reply.get(1) {
+                Some(RedisValue::Arr(stat)) => match stat[1] {
+                    RedisValue::Int(calls) => calls,
+                    _ => panic!("calls"),
+                },
+                _ => 0,
+            },
+            reply => panic!("{:?}", reply),
+        }
     }
 
-    // Prepare write buffer: 4-byte length header + body
-    let mut wbuf = [0u8; 4 + K_MAX_MSG];
-    wbuf[..4].copy_from_slice(&(len as u32).to_le_bytes());
-    wbuf[4..4 + len].copy_from_slice(text.as_bytes());
-
-    // Send request
-    socket.write_all(&wbuf[..4 + len])?;
-
-    // Prepare read buffer: header + max body + 1 byte
-    let mut rbuf = [0u8; 4 + K_MAX_MSG + 1];
-
-    // Read 4-byte header
-    socket.read_exact(&mut rbuf[..4])?;
-    let reply_len = u32::from_le_bytes(rbuf[..4].try_into().unwrap()) as usize;
+    // Read-only commands report under the shared lock, and still count
+    #[test]
+    fn reads_are_counted_and_tracked() {
+        let mut sim = shared();
+        sim.call("stats", "SET stats:k v");
+        let before = calls_of(&mut sim, "get");
+        sim.call("stats", "GET stats:k");
+        sim.call("stats", "GET stats:missing");
+        assert_eq!(calls_of(&mut sim, "get"), before + 2);
+
+        assert_eq!(sim.call("tracker", "CLIENT TRACKING ON"), RedisValue::Str("OK".into()));
+        sim.call("tracker", "GET stats:k");
+        sim.call("stats", "SET stats:k v2");
+        let replies = sim.replies();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].client, "tracker");
+        assert!(matches!(&replies[0].value, Some(RedisValue::Push(_))));
+        sim.call("tracker", "CLIENT TRACKING OFF");
+    }
+
+    fn filled_map(keys: usize) -> super::HMap {
+        let mut map = super::HMap::new(super::K_MIN_BUCKETS);
+        for i in 0..keys {
+            map.insert(Box::new(super::Entry::new(format!("rehash:{}", i), super::Value::Init)));
+        }
+        map
+    }
+
+    // However big the table, one write moves at most K_REHASHING_WORK
+    // entries, every key stays reachable mid-move, and the move finishes
+    #[test]
+    fn rehash_work_per_write_is_bounded() {
+        use super::{Entry, Value, K_REHASHING_WORK};
+
+        let mut map = filled_map(0);
+        let mut resizes = 0;
+        let mut i = 0;
+        while resizes < 8 {
+            let was_migrating = map.is_migrating();
+            let pending = map.pending_migration();
+            map.insert(Box::new(Entry::new(format!("rehash:{}", i), Value::Init)));
+            i += 1;
+            if was_migrating {
+                assert!(pending - map.pending_migration() <= K_REHASHING_WORK);
+            } else if map.is_migrating() {
+                resizes += 1;
+                assert!(map.pending_migration() + K_REHASHING_WORK >= map.size() - 1);
+            }
+            if map.is_migrating() && i % 97 == 0 {
+                for probe in (0..i).step_by(31) {
+                    assert!(map.find_entry(&format!("rehash:{}", probe)).is_some());
+                }
+            }
+        }
+        assert_eq!(map.size(), i);
 
-    if reply_len > K_MAX_MSG {
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "Reply too long"));
+        while map.is_migrating() {
+            assert!(map.hashmap_rehashing(K_REHASHING_WORK) <= K_REHASHING_WORK);
+        }
+        assert_eq!(map.size(), i);
+        for probe in 0..i {
+            let key = format!("rehash:{}", probe);
+            let entry = map.find_entry(&key).expect("key lost in the move");
+            assert_eq!(entry.bucket, map.newer.bucket_of(entry.hcode));
+        }
     }
 
-    // Read reply body
-    socket.read_exact(&mut rbuf[4..4 + reply_len])?;
+    // Shrinking moves entries as growing does, K_REHASHING_WORK at a time
+    #[test]
+    fn rehash_work_per_delete_is_bounded() {
+        use super::K_REHASHING_WORK;
 
-    // Print reply
-    println!(
-        "Server says: {}",
-        String::from_utf8_lossy(&rbuf[4..4 + reply_len])
-    );
+        let keys = 50_000;
+        let mut map = filled_map(keys);
+        while map.is_migrating() {
+            map.maybe_migrate();
+        }
+        let eq = |entry: &super::Entry, probe: &str| entry.key == probe;
+        for i in 0..keys {
+            let pending = map.pending_migration();
+            let was_migrating = map.is_migrating();
+            assert!(map.delete(&format!("rehash:{}", i), eq).is_some());
+            if was_migrating {
+                // The deleted key itself may have been waiting to move
+                assert!(pending - map.pending_migration() <= K_REHASHING_WORK + 1);
+            }
+        }
+        assert_eq!(map.size(), 0);
+    }
+
+    // cargo test --release rehash_bench -- --ignored --nocapture
+    //
+    // Time per insert into a million-key table, split by whether the insert
+    // carried a step of a resize. The slowest step has to stay within a small
+    // multiple of a plain insert: a resize that did its moves all at once
+    // would take milliseconds here.
+    #[test]
+    #[ignore]
+    fn rehash_bench() {
+        use std::time::Instant;
+        use super::{Entry, Value};
+
+        let mut map = filled_map(0);
+        let (mut plain, mut migrating) = (Vec::new(), Vec::new());
+        for i in 0..1_000_000 {
+            let entry = Box::new(Entry::new(format!("rehash:{}", i), Value::Init));
+            let was_migrating = map.is_migrating();
+            let start = Instant::now();
+            map.insert(entry);
+            let took = start.elapsed().as_nanos();
+            if was_migrating { migrating.push(took) } else { plain.push(took) }
+        }
 
-    Ok(())
-}
+        let summary = |times: &mut Vec<u128>| {
+            times.sort_unstable();
+            let at = |q: f64| times[((times.len() - 1) as f64 * q) as usize];
+            (at(0.5), at(0.99), at(0.999))
+        };
+        let (plain_p50, plain_p99, _) = summary(&mut plain);
+        let (mig_p50, mig_p99, mig_p999) = summary(&mut migrating);
+        println!("plain inserts:     {} p50 {}ns p99 {}ns", plain.len(), plain_p50, plain_p99);
+        println!("migrating inserts: {} p50 {}ns p99 {}ns p99.9 {}ns",
+            migrating.len(), mig_p50, mig_p99, mig_p999);
+        assert!(mig_p999 < 200_000, "a resize step took {}ns", mig_p999);
+    }
+
+
+    fn err(msg: &str) -> RedisValue {
+        RedisValue::Err(msg.to_string())
+    }
+
+    // A count below -LONG_MAX/2 is refused before anything is drawn
+    #[test]
+    fn zrandmember_refuses_counts_out_of_range() {
+        let mut sim = shared();
+        sim.call("zrand", "ZADD zrand:z 1 a 2 b 3 c");
+        let out_of_range = err("ERR value is out of range");
+        assert_eq!(sim.call("zrand", "ZRANDMEMBER zrand:z -9223372036854775808"), out_of_range);
+        assert_eq!(sim.call("zrand", "ZRANDMEMBER zrand:z -4611686018427387904"), out_of_range);
+        assert_eq!(
+            sim.call("zrand", "ZRANDMEMBER zrand:z -4611686018427387904 WITHSCORES"),
+            out_of_range
+        );
+        // Still the error on a missing key, as the count is checked first
+        assert_eq!(sim.call("zrand", "ZRANDMEMBER zrand:missing -9223372036854775808"), out_of_range);
+    }
+
+    // Within range, a count whose reply can't fit max-reply-size is refused
+    // without drawing it; one that can is answered in full
+    #[test]
+    fn zrandmember_caps_the_reply() {
+        let mut sim = shared();
+        sim.call("zrand", "ZADD zrand:cap 1 a 2 b 3 c");
+        let too_big = err("response is too big");
+        assert_eq!(sim.call("zrand", "ZRANDMEMBER zrand:cap -4611686018427387903"), too_big);
+        assert_eq!(sim.call("zrand", "ZRANDMEMBER zrand:cap -1000000000"), too_big);
+        match sim.call("zrand", "ZRANDMEMBER zrand:cap -1000") {
+            RedisValue::Arr(members) => assert_eq!(members.len(), 1000),
+            reply => panic!("{:?}", reply),
+        }
+        // A positive count is capped by the set's size, not refused
+        match sim.call("zrand", "ZRANDMEMBER zrand:cap 4611686018427387903 WITHSCORES") {
+            RedisValue::Arr(members) => assert_eq!(members.len(), 6),
+            reply => panic!("{:?}", reply),
+        }
+    }
 
-fn hash_std(data: &[u8]) -> u64 {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
-    
-    let mut hasher = DefaultHasher::new();
-    data.hash(&mut hasher);
-    hasher.finish()
-}
 
-fn main() -> std::io::Result<()> {
+    // A key deleted and set again is a new entry; the timer its old self
+    // left behind, if any, mustn't take the new one
+    #[test]
+    fn del_then_set_drops_the_old_ttl() {
+        let mut sim = shared();
+        sim.call("gen", "SET gen:a 1");
+        sim.call("gen", "PEXPIRE gen:a 1000");
+        sim.call("gen", "DEL gen:a");
+        sim.call("gen", "SET gen:a 2");
+        assert_eq!(sim.call("gen", "TTL gen:a"), RedisValue::Int(-1));
+
+        sim.advance(2000).unwrap();
+        assert_eq!(sim.call("gen", "GET gen:a"), RedisValue::Str("2".into()));
+    }
+
+    // The same, with DEL and SET in the round the old TTL comes due, and
+    // the new key given a later TTL of its own
+    #[test]
+    fn expire_del_set_survives_the_old_timer() {
+        let mut sim = shared();
+        sim.call("gen", "SET gen:b 1");
+        sim.call("gen", "PEXPIRE gen:b 1000");
+        sim.advance(999).unwrap();
+        sim.send("gen", "DEL gen:b").unwrap();
+        sim.send("gen", "SET gen:b 2").unwrap();
+        sim.send("gen", "PEXPIRE gen:b 3000").unwrap();
+        sim.advance(1).unwrap();
+        assert_eq!(sim.replies().len(), 3);
+
+        sim.advance(1000).unwrap();
+        assert_eq!(sim.call("gen", "GET gen:b"), RedisValue::Str("2".into()));
+        sim.advance(2000).unwrap();
+        assert_eq!(sim.call("gen", "GET gen:b"), RedisValue::Nil);
+    }
+
+
+    // A timer left over from an earlier entry of the key, as when one
+    // slips past clear_ttl, fires on the new entry and leaves it be
+    #[test]
+    fn a_stale_timer_leaves_the_new_entry_alone() {
+        let mut sim = shared();
+        sim.call("gen", "SET gen:c 1");
+        let old_generation = crate::with_global_data(|g_data| g_data.db.get("gen:c").unwrap().generation);
+        sim.call("gen", "DEL gen:c");
+        sim.call("gen", "SET gen:c 2");
+        crate::with_global_data(|g_data| {
+            let at = crate::get_current_time_ms() + 1000;
+            g_data.expires.set("gen:c".to_string(), at, old_generation);
+        });
 
-    // Optional: Demo tree before switching to client/server
-    let mut root: Option<Rc<RefCell<Node<i32>>>> = None;
+        sim.advance(2000).unwrap();
+        assert_eq!(sim.call("gen", "GET gen:c"), RedisValue::Str("2".into()));
+        assert_eq!(sim.call("gen", "TTL gen:c"), RedisValue::Int(-1));
+    }
 
-    tree_insert(&mut root, 5);
-    tree_insert(&mut root, 2);
-    tree_insert(&mut root, 8);
 
-    if let Some(node) = tree_search(&root, &2) {
-        println!("Found key: {}", node.borrow().key);
-    } else {
-        println!("Key not found");
+    // The members (strings) of an array reply, scores left out
+    fn names(reply: RedisValue) -> Vec<String> {
+        match reply {
+            RedisValue::Arr(items) => items
+                .into_iter()
+                .filter_map(|item| match item {
+                    RedisValue::Str(name) => Some(name),
+                    _ => None,
+                })
+                .collect(),
+            reply => panic!("{:?}", reply),
+        }
     }
 
-    let mut root: Option<Rc<RefCell<Node<i32>>>> = None;
-    // ... insert some values ...   
-    root = tree_delete(root, &5); // delete key = 5
-    println!("Tree operations complete, root is: {:?}", root.is_some());
-
+    #[test]
+    fn zrangebyscore_limit_pages_the_range() {
+        let mut sim = shared();
+        sim.call("page", "ZADD page:z 1 a 2 b 3 c 4 d 5 e");
+        let mut page = |line: &str| names(sim.call("page", &format!("ZRANGEBYSCORE page:z {}", line)));
+
+        assert_eq!(page("-inf +inf LIMIT 0 2"), ["a", "b"]);
+        assert_eq!(page("-inf +inf LIMIT 2 2"), ["c", "d"]);
+        assert_eq!(page("-inf +inf LIMIT 4 2"), ["e"]);
+        // Up to and past the end
+        assert_eq!(page("-inf +inf LIMIT 5 2"), Vec::<String>::new());
+        assert_eq!(page("-inf +inf LIMIT 1000 2"), Vec::<String>::new());
+        // A negative offset returns nothing, a negative count the rest
+        assert_eq!(page("-inf +inf LIMIT -1 2"), Vec::<String>::new());
+        assert_eq!(page("-inf +inf LIMIT 3 -1"), ["d", "e"]);
+        assert_eq!(page("-inf +inf LIMIT 0 0"), Vec::<String>::new());
+        // The offset counts from the start of the range, not of the set
+        assert_eq!(page("(2 4 LIMIT 1 5"), ["d"]);
+        assert_eq!(page("2 4 LIMIT 2 5"), ["d"]);
+        assert_eq!(page("2 4 LIMIT 3 5"), Vec::<String>::new());
+        assert_eq!(page("10 20 LIMIT 0 5"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn zrangebyscore_on_empty_and_missing_sets() {
+        let mut sim = shared();
+        sim.call("page", "ZADD page:gone 1 a");
+        sim.call("page", "ZREM page:gone a");
+        for key in ["page:gone", "page:missing"] {
+            for limit in ["", " LIMIT 0 10", " LIMIT -5 10", " LIMIT 3 -1"] {
+                let line = format!("ZRANGEBYSCORE {} -inf +inf WITHSCORES{}", key, limit);
+                assert_eq!(sim.call("page", &line), RedisValue::Arr(vec![]), "{}", line);
+            }
+        }
+        sim.call("page", "SET page:str v");
+        assert!(matches!(sim.call("page", "ZRANGEBYSCORE page:str 0 1"), RedisValue::Err(_)));
+        assert!(matches!(sim.call("page", "ZRANGEBYSCORE page:z 0 1 LIMIT 0"), RedisValue::Err(_)));
+        assert!(matches!(sim.call("page", "ZRANGEBYSCORE page:z 0 1 LIMIT x 1"), RedisValue::Err(_)));
+    }
+
+    #[test]
+    fn zrangebyscore_withscores_pairs_each_member() {
+        let mut sim = shared();
+        sim.call("page", "ZADD page:ws 1 a 2 b 3 c");
+        assert_eq!(
+            sim.call("page", "ZRANGEBYSCORE page:ws -inf +inf WITHSCORES LIMIT 1 1"),
+            RedisValue::Arr(vec![RedisValue::Str("b".into()), RedisValue::Dbl(2.0)])
+        );
+    }
 
-    let args: Vec<String> = env::args().collect();
-    
-    if args.len() > 1 && args[1] == "client" {
-        run_client()
-    } else {
-        run_server()
+    // ZQUERY key score name offset limit: from the first member at or after
+    // (score, name), moved by offset, which may go back
+    #[test]
+    fn zquery_offsets_from_the_seek() {
+        let mut sim = shared();
+        sim.call("page", "ZADD page:q 1 a 2 b 3 c 4 d 5 e");
+        let mut query = |line: &str| names(sim.call("page", &format!("ZQUERY page:q {}", line)));
+
+        assert_eq!(query("1 a 0 2"), ["a", "b"]);
+        assert_eq!(query("3 c 0 10"), ["c", "d", "e"]);
+        assert_eq!(query("3 c 2 10"), ["e"]);
+        assert_eq!(query("3 c 3 10"), Vec::<String>::new());
+        assert_eq!(query("3 c -1 2"), ["b", "c"]);
+        assert_eq!(query("3 c -2 1"), ["a"]);
+        assert_eq!(query("3 c -3 1"), Vec::<String>::new());
+        assert_eq!(query("3 c 0 0"), Vec::<String>::new());
+        // Past the last member, there's nothing to move from
+        assert_eq!(query("9 z -1 5"), Vec::<String>::new());
+
+        assert_eq!(sim.call("page", "ZQUERY page:missing 0 a 0 5"), RedisValue::Nil);
+    }
+
+
+    // What `client` was sent, None standing for the connection closing
+    fn sent_to(sim: &mut crate::sim::Simulation, client: &str) -> Vec<Option<RedisValue>> {
+        sim.replies().into_iter().filter(|reply| reply.client == client).map(|reply| reply.value).collect()
+    }
+
+    // A client that shuts down its side after its last requests still gets
+    // every reply, then the connection closes
+    #[test]
+    fn half_closed_client_is_answered_then_closed() {
+        let mut sim = shared();
+        sim.connect("half").unwrap();
+        sim.send("half", "SET half:a 1").unwrap();
+        sim.send("half", "GET half:a").unwrap();
+        sim.shutdown_write("half").unwrap();
+        sim.settle().unwrap();
+        assert_eq!(sent_to(&mut sim, "half"), [Some(RedisValue::Nil), Some(RedisValue::Str("1".into())), None]);
+    }
+
+    // Half-closed behind a blocked command: it's served, then closed
+    #[test]
+    fn half_closed_blocked_client_is_served_then_closed() {
+        let mut sim = shared();
+        sim.connect("half").unwrap();
+        sim.send("half", "BLMPOP 0 1 half:q LEFT").unwrap();
+        sim.shutdown_write("half").unwrap();
+        sim.settle().unwrap();
+        assert!(sim.is_connected("half"));
+        assert_eq!(sent_to(&mut sim, "half"), []);
+
+        sim.call("pusher", "RPUSH half:q x");
+        let sent = sent_to(&mut sim, "half");
+        assert!(matches!(sent.as_slice(), [Some(RedisValue::Arr(_)), None]), "{:?}", sent);
+    }
+
+    // QUIT is answered, and what was pipelined after it dropped
+    #[test]
+    fn quit_closes_after_its_reply() {
+        let mut sim = shared();
+        sim.connect("quitter").unwrap();
+        sim.send("quitter", "SET quit:a 1").unwrap();
+        sim.send("quitter", "QUIT").unwrap();
+        sim.send("quitter", "SET quit:b 1").unwrap();
+        sim.settle().unwrap();
+        assert_eq!(sent_to(&mut sim, "quitter"), [Some(RedisValue::Nil), Some(RedisValue::Str("OK".into())), None]);
+        assert_eq!(sim.call("other", "GET quit:b"), RedisValue::Nil);
+    }
+
+    // The bound on empty buckets is ten times COUNT, which used to overflow
+    #[test]
+    fn scan_with_a_huge_count() {
+        let mut sim = shared();
+        sim.call("scan", "SET scan:huge 1");
+        let line = format!("SCAN 0 COUNT {} MATCH scan:huge", usize::MAX / 10 + 1);
+        let RedisValue::Arr(reply) = sim.call("scan", &line) else { panic!("SCAN") };
+        assert_eq!(reply[0], RedisValue::Str("0".into()));
+        assert_eq!(names(reply[1].clone()), ["scan:huge"]);
+
+        let line = format!("SCAN 0 COUNT {}", usize::MAX);
+        assert!(matches!(sim.call("scan", &line), RedisValue::Arr(_)));
+        assert!(sim.is_connected("scan"));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,422 @@
+/* Memory accounting */
+//
+// MEMORY USAGE and MEMORY STATS report estimates, not allocator counters:
+// every value type adds up the heap blocks it owns through MemoryUsage, with
+// each block rounded up to the size class a jemalloc-style allocator would
+// hand out for it. Keys also carry their Entry box and their share of the
+// main hash table's bucket array.
+//
+// Built with `--features jemalloc`, the server runs on jemalloc and MEMORY
+// STATS/DOCTOR add what it reports (allocated, active, resident bytes and the
+// fragmentation ratios between them). used_memory() is the number anything
+// enforcing a memory limit should go by: the allocator's own count when
+// there is one, the estimate otherwise.
+
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+
+use intrusive_collections::LinkedList;
+
+use crate::command::CmdResult;
+use crate::{out_arr, out_dbl, out_err, out_int, out_nil, out_str, with_global_data_read, buffer_pool_size, Buffer, Chunk, EntryAdapter, Entry, GData, Value, ZNode, ZSet};
+
+pub trait MemoryUsage {
+    /// Estimated bytes of heap this value owns, allocator overhead included
+    fn memory_usage(&self) -> usize;
+}
+
+/// Bytes the allocator really hands out for a request of `n`: 8 byte classes
+/// up to 8, 16 byte steps up to 128, then four classes per power of two.
+pub fn alloc_size(n: usize) -> usize {
+    match n {
+        0 => 0,
+        1..=8 => 8,
+        9..=128 => (n + 15) & !15,
+        _ => {
+            let step = 1 << ((usize::BITS - 1 - (n - 1).leading_zeros()) - 2);
+            (n + step - 1) & !(step - 1)
+        }
+    }
+}
+
+// Ordered maps keep their elements in B-tree nodes of up to 11; with nodes
+// two thirds full on average that's 50% on top of the elements themselves
+pub fn btree_size(len: usize, elem_size: usize) -> usize {
+    len * elem_size * 3 / 2
+}
+
+impl MemoryUsage for String {
+    fn memory_usage(&self) -> usize {
+        alloc_size(self.capacity())
+    }
+}
+
+// Two reference counts ahead of the bytes
+impl MemoryUsage for Arc<str> {
+    fn memory_usage(&self) -> usize {
+        alloc_size(2 * size_of::<usize>() + self.len())
+    }
+}
+
+impl MemoryUsage for VecDeque<String> {
+    fn memory_usage(&self) -> usize {
+        alloc_size(self.capacity() * size_of::<String>()) + self.iter().map(String::memory_usage).sum::<usize>()
+    }
+}
+
+impl MemoryUsage for ZSet {
+    fn memory_usage(&self) -> usize {
+        // The name index: hashbrown keeps a control byte per slot
+        let index = alloc_size(self.name_to_node.capacity() * (size_of::<(String, Arc<Mutex<ZNode>>)>() + 1));
+        // Each member is an Arc'd tree node (two counters in front) plus its
+        // name, which the index holds a second copy of
+        let node = alloc_size(2 * size_of::<usize>() + size_of::<Mutex<ZNode>>());
+        let members: usize = self
+            .name_to_node
+            .iter()
+            .map(|(name, znode)| node + name.memory_usage() + znode.lock().unwrap().name.memory_usage())
+            .sum();
+        index + members
+    }
+}
+
+impl MemoryUsage for Value {
+    fn memory_usage(&self) -> usize {
+        match self {
+            Value::Init => 0,
+            Value::Str(s) => s.memory_usage(),
+            Value::ZSet(zset) => zset.memory_usage(),
+            Value::Stream(stream) => stream.memory_usage(),
+            Value::List(list) => list.memory_usage(),
+        }
+    }
+}
+
+impl MemoryUsage for Entry {
+    fn memory_usage(&self) -> usize {
+        alloc_size(size_of::<Entry>()) + self.key.memory_usage() + self.value.memory_usage()
+    }
+}
+
+/// What the allocator reports, when the server is built with jemalloc
+#[cfg_attr(not(feature = "jemalloc"), allow(dead_code))]
+#[derive(Debug, Clone, Copy)]
+pub struct AllocatorStats {
+    /// Bytes handed out to the program
+    pub allocated: usize,
+    /// Bytes in pages holding at least one allocation
+    pub active: usize,
+    /// Bytes of physical memory the allocator has mapped
+    pub resident: usize,
+}
+
+impl AllocatorStats {
+    /// Space wasted inside the allocator's own pages
+    pub fn allocator_frag_ratio(&self) -> f64 {
+        self.active as f64 / self.allocated.max(1) as f64
+    }
+
+    /// Physical memory held per byte actually in use
+    pub fn frag_ratio(&self) -> f64 {
+        self.resident as f64 / self.allocated.max(1) as f64
+    }
+}
+
+#[cfg(feature = "jemalloc")]
+pub fn allocator_stats() -> Option<AllocatorStats> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // jemalloc caches its counters until the epoch moves
+    epoch::advance().ok()?;
+    Some(AllocatorStats {
+        allocated: stats::allocated::read().ok()?,
+        active: stats::active::read().ok()?,
+        resident: stats::resident::read().ok()?,
+    })
+}
+
+#[cfg(not(feature = "jemalloc"))]
+pub fn allocator_stats() -> Option<AllocatorStats> {
+    None
+}
+
+// Where the estimated memory goes
+struct Estimate {
+    dataset: usize,
+    main: usize,
+    expires: usize,
+    clients: usize,
+    buffer_pool: usize,
+}
+
+impl Estimate {
+    fn overhead(&self) -> usize {
+        self.main + self.expires + self.clients + self.buffer_pool
+    }
+
+    fn total(&self) -> usize {
+        self.overhead() + self.dataset
+    }
+}
+
+// O(n): walks every key
+fn estimate(g_data: &GData) -> Estimate {
+    Estimate {
+        dataset: g_data.db.iter().map(Entry::memory_usage).sum(),
+        main: bucket_array_size(g_data),
+        expires: expires_size(g_data),
+        clients: clients_size(g_data),
+        buffer_pool: buffer_pool_size(),
+    }
+}
+
+/// Bytes in use: the allocator's count when linked with jemalloc (cheap),
+/// otherwise the MemoryUsage estimate of the whole server (walks every key)
+pub fn used_memory(g_data: &GData) -> usize {
+    match allocator_stats() {
+        Some(stats) => stats.allocated,
+        None => estimate(g_data).total(),
+    }
+}
+
+// Bytes of the main hash table's bucket arrays and lookup filters
+fn bucket_array_size(g_data: &GData) -> usize {
+    let db = g_data.db.table();
+    let tables = std::iter::once(&db.newer).chain(db.older.as_ref());
+    tables
+        .map(|table| {
+            let filter = table.filter.as_ref().map_or(0, |filter| alloc_size(filter.size_bytes()));
+            alloc_size(table.tab.capacity() * size_of::<LinkedList<EntryAdapter>>()) + filter
+        })
+        .sum()
+}
+
+// Bytes spent on TTLs: each is in the key -> unix time map, and its timer
+// in the wheel's key -> deadline map and in one of its slots, with a copy
+// of the key in each
+fn expires_size(g_data: &GData) -> usize {
+    // Entries plus the hash tables' control bytes
+    let timer = size_of::<(String, u64)>() + size_of::<(String, u64, usize)>() + size_of::<String>() + 3;
+    let keys: usize = g_data.expires.iter().map(|(key, _)| key.memory_usage() * 3).sum();
+    alloc_size(g_data.expires.len() * timer) + keys
+}
+
+// Buffers of the connections not being serviced right now
+fn clients_size(g_data: &GData) -> usize {
+    g_data
+        .fd2conn
+        .values()
+        .map(|conn| {
+            let sealed: usize = conn
+                .sealed
+                .iter()
+                .map(|chunk| match chunk {
+                    Chunk::Bytes(buffer) => alloc_size(buffer.data.capacity()),
+                    // Owned by the keyspace, or kept alive just for this write
+                    Chunk::Shared(..) => 0,
+                })
+                .sum();
+            alloc_size(conn.incoming.data.capacity()) + alloc_size(conn.outgoing.data.capacity()) + sealed
+        })
+        .sum()
+}
+
+// Plain-language findings for MEMORY DOCTOR
+fn diagnose(g_data: &GData) -> String {
+    const MB: usize = 1024 * 1024;
+    if used_memory(g_data) < 5 * MB {
+        return "The instance is using very little memory, there's nothing to diagnose yet.".to_string();
+    }
+
+    let mut issues = Vec::new();
+    match allocator_stats() {
+        Some(stats) => {
+            if stats.allocator_frag_ratio() > 1.1 && stats.active - stats.allocated > 10 * MB {
+                issues.push(format!(
+                    "High allocator fragmentation: {:.2} bytes in allocator pages per byte allocated. \
+                     Usually the result of deleting many keys after a peak; it shrinks as pages empty out.",
+                    stats.allocator_frag_ratio()
+                ));
+            }
+            if stats.frag_ratio() > 1.4 && stats.resident - stats.allocated > 10 * MB {
+                issues.push(format!(
+                    "High fragmentation: resident memory is {:.2} times the allocated memory. \
+                     The allocator keeps freed memory mapped for reuse; a restart gives it back.",
+                    stats.frag_ratio()
+                ));
+            }
+        }
+        None => issues.push(
+            "Allocator statistics are not available (build with --features jemalloc), \
+             fragmentation can't be checked."
+                .to_string(),
+        ),
+    }
+
+    let clients = g_data.fd2conn.len();
+    if clients > 0 && clients_size(g_data) / clients > 200 * 1024 {
+        issues.push(format!(
+            "Big client buffers: {} clients use {} bytes on average. \
+             Look for clients reading large replies slowly or pipelining heavily.",
+            clients,
+            clients_size(g_data) / clients
+        ));
+    }
+
+    if issues.is_empty() {
+        "No memory issues detected in this instance.".to_string()
+    } else {
+        issues.iter().map(|issue| format!("* {}\n", issue)).collect()
+    }
+}
+
+// Keys BIGKEYS looks at when not told
+const BIGKEYS_SAMPLES: usize = 10000;
+
+/// How big a value is in its type's own terms: bytes of a string, elements
+/// of a list, members of a sorted set, entries of a stream
+fn value_len(value: &Value) -> (usize, &'static str) {
+    match value {
+        Value::Init => (0, "bytes"),
+        Value::Str(s) => (s.len(), "bytes"),
+        Value::List(list) => (list.len(), "items"),
+        Value::ZSet(zset) => (zset.name_to_node.len(), "members"),
+        Value::Stream(stream) => (stream.len(), "entries"),
+    }
+}
+
+// Biggest key of a type among the sampled ones
+struct BigKey {
+    type_name: &'static str,
+    sampled: usize,
+    key: String,
+    len: usize,
+    unit: &'static str,
+}
+
+// Look at up to `samples` keys (0 = all of them) and keep the biggest of
+// each type. The scan starts at a random cursor, so repeated calls on a big
+// keyspace see different parts of it.
+fn bigkeys(g_data: &GData, samples: usize) -> Vec<BigKey> {
+    let db = g_data.db.table();
+    let buckets = std::iter::once(&db.newer).chain(db.older.as_ref()).map(|table| table.tab.len()).max().unwrap_or(0);
+    let samples = if samples == 0 { usize::MAX } else { samples };
+
+    let mut biggest: Vec<BigKey> = Vec::new();
+    let mut sampled = 0;
+    let mut cursor = RandomState::new().build_hasher().finish();
+    // A step visits at least one bucket of the larger table
+    for _ in 0..buckets {
+        cursor = db.scan(cursor, |entry| {
+            // Left on disk by the log backend (see store)
+            if matches!(entry.value, Value::Init) {
+                return;
+            }
+            let (len, unit) = value_len(&entry.value);
+            let type_name = entry.value.type_name();
+            sampled += 1;
+            match biggest.iter_mut().find(|big| big.type_name == type_name) {
+                Some(big) => {
+                    big.sampled += 1;
+                    if len > big.len {
+                        big.key = entry.key.clone();
+                        big.len = len;
+                    }
+                }
+                None => biggest.push(BigKey { type_name, sampled: 1, key: entry.key.clone(), len, unit }),
+            }
+        });
+        if sampled >= samples {
+            break;
+        }
+    }
+    biggest.sort_by_key(|big| big.type_name);
+    biggest
+}
+
+// MEMORY USAGE key | MEMORY STATS | MEMORY DOCTOR | MEMORY BIGKEYS [SAMPLES count]
+pub fn do_memory(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    match cmd[1].to_uppercase().as_str() {
+        "USAGE" if cmd.len() == 3 => with_global_data_read(|g_data| {
+            let keys = g_data.db.size();
+            match g_data.db.peek_entry(&cmd[2]) {
+                Some(entry) => {
+                    let bucket_share = bucket_array_size(g_data) / keys.max(1);
+                    out_int(out, (entry.memory_usage() + bucket_share) as i64);
+                }
+                None => out_nil(out),
+            }
+        }),
+        "STATS" if cmd.len() == 2 => with_global_data_read(|g_data| {
+            let keys = g_data.db.size();
+            let estimate = estimate(g_data);
+            let allocator = allocator_stats();
+            let total = allocator.map_or(estimate.total(), |stats| stats.allocated);
+
+            let mut ints: Vec<(&str, usize)> = vec![
+                ("total.allocated", total),
+                ("clients.normal", estimate.clients),
+                ("overhead.hashtable.main", estimate.main),
+                ("overhead.hashtable.expires", estimate.expires),
+                ("overhead.buffer-pool", estimate.buffer_pool),
+                ("overhead.total", estimate.overhead()),
+                ("keys.count", keys),
+                ("keys.bytes-per-key", total.checked_div(keys).unwrap_or(0)),
+                ("dataset.bytes", estimate.dataset),
+            ];
+            let mut ratios = vec![(
+                "dataset.percentage",
+                if total == 0 { 0.0 } else { estimate.dataset as f64 * 100.0 / total as f64 },
+            )];
+            if let Some(stats) = allocator {
+                ints.push(("allocator.allocated", stats.allocated));
+                ints.push(("allocator.active", stats.active));
+                ints.push(("allocator.resident", stats.resident));
+                ratios.push(("allocator-fragmentation.ratio", stats.allocator_frag_ratio()));
+                ratios.push(("fragmentation", stats.frag_ratio()));
+            }
+
+            out_arr(out, (ints.len() + ratios.len()) as u32 * 2);
+            for (name, value) in ints {
+                out_str(out, name);
+                out_int(out, value as i64);
+            }
+            for (name, value) in ratios {
+                out_str(out, name);
+                out_dbl(out, value);
+            }
+        }),
+        "DOCTOR" if cmd.len() == 2 => with_global_data_read(|g_data| out_str(out, &diagnose(g_data))),
+        "BIGKEYS" if cmd.len() == 2 || cmd.len() == 4 => {
+            let samples = match cmd.get(2..4) {
+                None => BIGKEYS_SAMPLES,
+                Some([option, count]) if option.eq_ignore_ascii_case("SAMPLES") => match count.parse() {
+                    Ok(count) => count,
+                    Err(_) => {
+                        out_err(out, "ERR value is not an integer or out of range");
+                        return Ok(());
+                    }
+                },
+                Some(_) => {
+                    out_err(out, "ERR syntax error");
+                    return Ok(());
+                }
+            };
+            // type, keys of the type sampled, biggest key, its length and the unit
+            let biggest = with_global_data_read(|g_data| bigkeys(g_data, samples));
+            out_arr(out, biggest.len() as u32);
+            for big in biggest {
+                out_arr(out, 5);
+                out_str(out, big.type_name);
+                out_int(out, big.sampled as i64);
+                out_str(out, &big.key);
+                out_int(out, big.len as i64);
+                out_str(out, big.unit);
+            }
+        }
+        _ => out_err(out, "ERR unknown MEMORY subcommand or wrong number of arguments"),
+    }
+    Ok(())
+}
@@ -0,0 +1,156 @@
+/* MIGRATE */
+//
+//   MIGRATE host port key timeout [COPY] [REPLACE]
+//
+// Moves a key to another instance: the value is serialized as for DUMP and
+// sent to the target as RESTORE key ttl payload [REPLACE], and once the target
+// accepts it the local key is deleted (kept with COPY). The TTL travels as the
+// time left, so the key expires at the same moment on both sides.
+//
+// The transfer blocks the server for at most `timeout` milliseconds per step,
+// as in Redis. Connections to targets are kept in a pool keyed by host:port
+// so moving many keys doesn't reconnect for each one; a pooled connection
+// that sat unused for MIGRATE_IDLE_MS is closed, and one that failed is
+// dropped so the next MIGRATE starts over with a fresh connection.
+
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+use redis::client::RedisClient;
+use redis::protocol::RedisValue;
+
+use crate::command::CmdResult;
+use crate::logging::log_warning;
+use crate::rdb::{bytes_to_chars, dump_value};
+use crate::{
+    entry_del, entry_expire_at, get_current_time_ms, get_monotonic_time_ms, out_err, out_nil, out_str, with_global_data, Buffer,
+};
+
+// Close pooled connections unused for this long
+const MIGRATE_IDLE_MS: u64 = 10_000;
+
+// What a timeout of 0 means
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+struct PooledConn {
+    client: RedisClient,
+    last_used_ms: u64,
+}
+
+#[derive(Default)]
+pub struct Pool {
+    // "host:port" -> connection not in use right now
+    conns: HashMap<String, PooledConn>,
+}
+
+impl Pool {
+    // Take the connection to `target` out of the pool, connecting if there's none
+    fn checkout(&mut self, target: &str, timeout: Duration) -> io::Result<RedisClient> {
+        match self.conns.remove(target) {
+            Some(conn) => {
+                conn.client.set_timeout(timeout)?;
+                Ok(conn.client)
+            }
+            None => RedisClient::connect_timeout(target, timeout),
+        }
+    }
+
+    fn checkin(&mut self, target: String, client: RedisClient, now_ms: u64) {
+        self.conns.insert(target, PooledConn { client, last_used_ms: now_ms });
+    }
+
+    /// Close connections that have been idle too long
+    pub fn close_idle(&mut self, now_ms: u64) {
+        self.conns.retain(|_, conn| now_ms.saturating_sub(conn.last_used_ms) < MIGRATE_IDLE_MS);
+    }
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_list().entries(self.conns.keys()).finish()
+    }
+}
+
+// MIGRATE host port key timeout [COPY] [REPLACE]
+pub fn do_migrate(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let (host, key) = (&cmd[1], &cmd[3]);
+    let Ok(port) = cmd[2].parse::<u16>() else {
+        out_err(out, "ERR Invalid port");
+        return Ok(());
+    };
+    let timeout_ms = match cmd[4].parse::<i64>() {
+        Ok(ms) if ms > 0 => ms as u64,
+        Ok(_) => DEFAULT_TIMEOUT_MS,
+        Err(_) => {
+            out_err(out, "ERR timeout is not an integer or out of range");
+            return Ok(());
+        }
+    };
+    let (mut copy, mut replace) = (false, false);
+    for option in &cmd[5..] {
+        match option.to_uppercase().as_str() {
+            "COPY" => copy = true,
+            "REPLACE" => replace = true,
+            _ => {
+                out_err(out, "ERR syntax error");
+                return Ok(());
+            }
+        }
+    }
+
+    // Serialize under the lock, but don't hold it across the network
+    let serialized = with_global_data(|g_data| {
+        let now_ms = get_current_time_ms();
+        g_data.db.peek_entry(key).map(|entry| {
+            let ttl_ms = entry_expire_at(&g_data.expires, entry).map_or(0, |at| at.saturating_sub(now_ms).max(1));
+            (bytes_to_chars(&dump_value(&entry.value)), ttl_ms)
+        })
+    });
+    let Some((payload, ttl_ms)) = serialized else {
+        out_str(out, "NOKEY");
+        return Ok(());
+    };
+
+    let target = format!("{}:{}", host, port);
+    let timeout = Duration::from_millis(timeout_ms);
+    let ttl = ttl_ms.to_string();
+    let mut args = vec!["RESTORE", key.as_str(), ttl.as_str(), payload.as_str()];
+    if replace {
+        args.push("REPLACE");
+    }
+
+    // In a cluster the target is importing the slot and only takes the key
+    // after ASKING
+    let (client, asking) =
+        with_global_data(|g_data| (g_data.migrate_pool.checkout(&target, timeout), g_data.cluster.enabled));
+    let reply = client.and_then(|mut client| {
+        if asking {
+            client.command(&["ASKING"])?;
+        }
+        client.command(&args).map(|reply| (client, reply))
+    });
+    let reply = match reply {
+        Ok((client, reply)) => {
+            with_global_data(|g_data| g_data.migrate_pool.checkin(target, client, get_monotonic_time_ms()));
+            reply
+        }
+        Err(e) => {
+            // The connection, if any, is in an unknown state: it's dropped
+            log_warning!("cmd", "MIGRATE to {} failed: {}", target, e);
+            out_err(out, &format!("IOERR error or timeout talking to target instance: {}", e));
+            return Ok(());
+        }
+    };
+
+    match reply {
+        RedisValue::Err(msg) => out_err(out, &format!("ERR Target instance replied with error: {}", msg)),
+        _ => {
+            if !copy {
+                entry_del(key);
+            }
+            out_nil(out);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,110 @@
+/* Client pause */
+//
+// CLIENT PAUSE timeout [WRITE|ALL] holds clients' commands back until the
+// timeout passes or CLIENT UNPAUSE, so a failover can let a replica catch up
+// with nothing new coming in. ALL (the default) holds every command but
+// CLIENT itself, which stays available to end the pause early; WRITE holds
+// the commands that may write: CMD_WRITE ones and scripts that aren't _RO.
+//
+// A held command parks its connection (ConnState::Paused): the request stays
+// in the input and nothing more is read from the connection until the pause
+// ends, when it runs as if it had just come in. Keys don't expire while
+// paused, so the dataset doesn't change under the failover either; reads
+// still see expired keys as gone. The end of the pause is one of the event
+// loop's timers. FAILOVER holds writes the same way, for as long as it runs.
+
+use crate::command::{Command, CMD_WRITE};
+use crate::{conn_schedule_idle, get_monotonic_time_ms, out_err, out_str, with_global_data, Buffer, GData};
+
+#[derive(Debug, Default)]
+pub struct Pause {
+    // Monotonic ms; None when not paused
+    until_ms: Option<u64>,
+    writes_only: bool,
+}
+
+impl Pause {
+    pub fn is_active(&self) -> bool {
+        self.until_ms.is_some()
+    }
+
+    /// When the pause ends, if there is one
+    pub fn deadline(&self) -> Option<u64> {
+        self.until_ms
+    }
+
+    /// Whether `command` has to wait for the pause to end
+    pub fn holds(&self, command: &Command) -> bool {
+        if !self.is_active() || command.name == "client" {
+            return false;
+        }
+        !self.writes_only || command.has_flag(CMD_WRITE) || matches!(command.name, "eval" | "evalsha" | "fcall")
+    }
+}
+
+/// End the pause if its time is up
+pub fn expire(g_data: &mut GData, now_ms: u64) {
+    if g_data.pause.until_ms.is_some_and(|until| until <= now_ms) {
+        resume(g_data);
+    }
+}
+
+/// Hold writes until resume(), for FAILOVER; a running pause that holds
+/// all commands goes on doing so
+pub fn hold_writes(g_data: &mut GData) {
+    let pause = &mut g_data.pause;
+    pause.writes_only = !pause.is_active() || pause.writes_only;
+    pause.until_ms = Some(u64::MAX);
+}
+
+/// End the pause now, letting the parked connections go on with their
+/// commands
+pub fn resume(g_data: &mut GData) {
+    g_data.pause = Pause::default();
+    let idle_timeout_ms = g_data.config.timeout * 1000;
+    for (&fd, conn) in g_data.fd2conn.iter_mut() {
+        if conn.unpark() {
+            conn_schedule_idle(&mut g_data.idle_timers, idle_timeout_ms, fd, conn);
+        }
+    }
+}
+
+// CLIENT PAUSE timeout [WRITE|ALL]
+//
+// A pause already running is only ever extended: it ends at the later of
+// the two deadlines, and holds all commands if either one does.
+pub fn do_pause(cmd: &[String], out: &mut Buffer) {
+    if !(3..=4).contains(&cmd.len()) {
+        out_err(out, "ERR wrong number of arguments for 'client|pause' command");
+        return;
+    }
+    let Ok(timeout_ms) = cmd[2].parse::<u64>() else {
+        out_err(out, "ERR timeout is not an integer or out of range");
+        return;
+    };
+    let writes_only = match cmd.get(3).map(|mode| mode.to_uppercase()).as_deref() {
+        None | Some("ALL") => false,
+        Some("WRITE") => true,
+        _ => {
+            out_err(out, "ERR syntax error");
+            return;
+        }
+    };
+    let until_ms = get_monotonic_time_ms().saturating_add(timeout_ms);
+    with_global_data(|g_data| {
+        let pause = &mut g_data.pause;
+        pause.writes_only = writes_only && (!pause.is_active() || pause.writes_only);
+        pause.until_ms = Some(pause.until_ms.map_or(until_ms, |until| until.max(until_ms)));
+    });
+    out_str(out, "OK");
+}
+
+// CLIENT UNPAUSE
+pub fn do_unpause(cmd: &[String], out: &mut Buffer) {
+    if cmd.len() != 2 {
+        out_err(out, "ERR wrong number of arguments for 'client|unpause' command");
+        return;
+    }
+    with_global_data(resume);
+    out_str(out, "OK");
+}
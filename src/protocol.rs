@@ -0,0 +1,668 @@
+/* Wire protocol */
+//
+// Every message is a u32 little-endian length followed by that many bytes.
+// A request is one command line (quoted like redis-cli); a reply is a single
+// Tag-encoded value:
+//
+//   Nil                      tag
+//   Err, Str                 tag u32-len bytes
+//   Int (i64), Dbl (f64)     tag 8 bytes
+//   Arr                      tag u32-count value...
+//
+// parse_frame is the server's request parser as a pure function: bytes in,
+// arguments or a ProtocolError out, so it can be fed arbitrary input.
+//
+// ReplyDecoder is the client side: it is fed the bytes of replies as reads
+// return them, however TCP split them up, and hands back each value once all
+// of it has arrived. It reads either the framed Tag format above or RESP, for
+// connections that talk text.
+
+use std::fmt;
+
+/// Most arguments a request may have
+pub const MAX_ARGS: usize = 1024 * 1024;
+
+/// Bounds a request must stay within
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameLimits {
+    /// Bytes in the whole request
+    pub max_request_size: usize,
+    /// Number of arguments
+    pub max_args: usize,
+    /// Bytes in any one argument
+    pub max_arg_len: usize,
+}
+
+/// Why a request was rejected. The stream can't be trusted past one of
+/// these, so the server replies with the error and closes the connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    RequestTooLarge { size: usize, limit: usize },
+    TooManyArgs { limit: usize },
+    ArgTooLong { len: usize, limit: usize },
+    UnbalancedQuotes,
+    InvalidEscape,
+    InvalidUtf8,
+    // Text protocols
+    InvalidMultibulkLength,
+    InvalidBulkLength,
+    ExpectedBulk(u8),
+    MissingCrlf,
+    // Replies
+    UnknownReplyType(u8),
+    MalformedReply,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::RequestTooLarge { size, limit } => {
+                write!(f, "Protocol error: request of {} bytes exceeds the {} byte limit", size, limit)
+            }
+            ProtocolError::TooManyArgs { limit } => write!(f, "Protocol error: more than {} arguments", limit),
+            ProtocolError::ArgTooLong { len, limit } => {
+                write!(f, "Protocol error: argument of {} bytes exceeds the {} byte limit", len, limit)
+            }
+            ProtocolError::UnbalancedQuotes => write!(f, "Protocol error: unbalanced quotes in request"),
+            ProtocolError::InvalidEscape => write!(f, "Protocol error: invalid \\x escape in request"),
+            ProtocolError::InvalidUtf8 => write!(f, "Protocol error: request is not valid UTF-8"),
+            ProtocolError::InvalidMultibulkLength => write!(f, "Protocol error: invalid multibulk length"),
+            ProtocolError::InvalidBulkLength => write!(f, "Protocol error: invalid bulk length"),
+            ProtocolError::ExpectedBulk(got) => write!(f, "Protocol error: expected '$', got '{}'", got.escape_ascii()),
+            ProtocolError::MissingCrlf => write!(f, "Protocol error: bulk string not terminated by CRLF"),
+            ProtocolError::UnknownReplyType(got) => {
+                write!(f, "Protocol error: unknown reply type '{}'", got.escape_ascii())
+            }
+            ProtocolError::MalformedReply => write!(f, "Protocol error: malformed reply"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Split a request into arguments the way redis-cli does: whitespace separates
+/// arguments, "double quotes" understand \n \r \t \" \\ and \xHH escapes, and
+/// 'single quotes' are literal except for \'.
+pub fn split_args(line: &str) -> Result<Vec<String>, ProtocolError> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    let unbalanced = || ProtocolError::UnbalancedQuotes;
+
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        let Some(&first) = chars.peek() else {
+            return Ok(args);
+        };
+
+        let mut arg = String::new();
+        match first {
+            '"' => {
+                chars.next();
+                loop {
+                    match chars.next().ok_or_else(unbalanced)? {
+                        '"' => break,
+                        '\\' => match chars.next().ok_or_else(unbalanced)? {
+                            'n' => arg.push('\n'),
+                            'r' => arg.push('\r'),
+                            't' => arg.push('\t'),
+                            'x' => {
+                                let hex: String = chars.by_ref().take(2).collect();
+                                if hex.len() != 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                                    return Err(ProtocolError::InvalidEscape);
+                                }
+                                arg.push(u8::from_str_radix(&hex, 16).unwrap() as char);
+                            }
+                            other => arg.push(other),
+                        },
+                        c => arg.push(c),
+                    }
+                }
+            }
+            '\'' => {
+                chars.next();
+                loop {
+                    match chars.next().ok_or_else(unbalanced)? {
+                        '\'' => break,
+                        '\\' if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                            arg.push('\'');
+                        }
+                        c => arg.push(c),
+                    }
+                }
+            }
+            _ => {
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    arg.push(c);
+                    chars.next();
+                }
+            }
+        }
+
+        // A closing quote must end the argument
+        if chars.peek().is_some_and(|c| !c.is_whitespace()) && (first == '"' || first == '\'') {
+            return Err(ProtocolError::UnbalancedQuotes);
+        }
+        args.push(arg);
+    }
+}
+
+/// Check a parsed request's arguments against `limits`
+pub fn check_args(args: &[String], limits: &FrameLimits) -> Result<(), ProtocolError> {
+    if args.len() > limits.max_args {
+        return Err(ProtocolError::TooManyArgs { limit: limits.max_args });
+    }
+    match args.iter().find(|arg| arg.len() > limits.max_arg_len) {
+        Some(arg) => Err(ProtocolError::ArgTooLong { len: arg.len(), limit: limits.max_arg_len }),
+        None => Ok(()),
+    }
+}
+
+/// Parse one length-prefixed request from the front of `buf`. Returns the
+/// arguments and how many bytes the frame took, or None until the whole frame
+/// has arrived. An oversized frame is rejected from its header alone.
+pub fn parse_frame(buf: &[u8], limits: &FrameLimits) -> Result<Option<(Vec<String>, usize)>, ProtocolError> {
+    let Some(header) = buf.get(..4) else {
+        return Ok(None);
+    };
+    let size = u32::from_le_bytes(header.try_into().unwrap()) as usize;
+    if size > limits.max_request_size {
+        return Err(ProtocolError::RequestTooLarge { size, limit: limits.max_request_size });
+    }
+    let Some(body) = buf.get(4..4 + size) else {
+        return Ok(None);
+    };
+
+    let line = std::str::from_utf8(body).map_err(|_| ProtocolError::InvalidUtf8)?;
+    let args = split_args(line)?;
+    check_args(&args, limits)?;
+    Ok(Some((args, 4 + size)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Tag {
+    Nil = 0,    // nil
+    Err = 1,    // error code + msg
+    Str = 2,    // string
+    Int = 3,    // int64
+    Dbl = 4,    // double
+    Arr = 5,    // array
+    Push = 6,   // out-of-band message, laid out like an array
+}
+
+impl Tag {
+    pub fn from_u8(byte: u8) -> Option<Tag> {
+        match byte {
+            0 => Some(Tag::Nil),
+            1 => Some(Tag::Err),
+            2 => Some(Tag::Str),
+            3 => Some(Tag::Int),
+            4 => Some(Tag::Dbl),
+            5 => Some(Tag::Arr),
+            6 => Some(Tag::Push),
+            _ => None,
+        }
+    }
+
+    /// Create an empty RedisValue of this type
+    /// Useful for protocol deserialization scaffolding
+    pub fn empty_value(&self) -> RedisValue {
+        match self {
+            Tag::Nil => RedisValue::Nil,
+            Tag::Err => RedisValue::Err(String::new()),
+            Tag::Str => RedisValue::Str(String::new()),
+            Tag::Int => RedisValue::Int(0),
+            Tag::Dbl => RedisValue::Dbl(0.0),
+            Tag::Arr => RedisValue::Arr(Vec::new()),
+            Tag::Push => RedisValue::Push(Vec::new()),
+        }
+    }
+
+    /// Create a RedisValue with actual data
+    /// Will be useful when parsing protocol messages
+    pub fn with_data(&self, data: &[u8]) -> Result<RedisValue, String> {
+        match self {
+            Tag::Nil => Ok(RedisValue::Nil),
+            Tag::Err => Ok(RedisValue::Err(String::from_utf8_lossy(data).to_string())),
+            Tag::Str => Ok(RedisValue::Str(String::from_utf8_lossy(data).to_string())),
+            Tag::Int => {
+                let s = String::from_utf8_lossy(data);
+                s.parse::<i64>()
+                    .map(RedisValue::Int)
+                    .map_err(|_| "Invalid integer".to_string())
+            }
+            Tag::Dbl => {
+                let s = String::from_utf8_lossy(data);
+                s.parse::<f64>()
+                    .map(RedisValue::Dbl)
+                    .map_err(|_| "Invalid double".to_string())
+            }
+            Tag::Arr | Tag::Push => {
+                // `data` is the wire payload: the count, then the encoded items
+                match RedisValue::decode_payload(*self, data) {
+                    Some((value, used)) if used == data.len() => Ok(value),
+                    _ => Err("Invalid array".to_string()),
+                }
+            }
+        }
+    }
+}
+
+// Redis value that can hold any data type
+#[derive(Debug, Clone, PartialEq)]
+pub enum RedisValue {
+    Nil,
+    Err(String),                    // Error message
+    Str(String),                    // String value
+    Int(i64),                       // Integer value
+    Dbl(f64),                       // Double value
+    Arr(Vec<RedisValue>),          // Array of values (can be nested)
+    Push(Vec<RedisValue>),         // Pushed by the server unasked, e.g. invalidations
+}
+
+impl RedisValue {
+    /// Decode one Tag-encoded value from the front of `data`. Returns the value
+    /// and how many bytes it took, or None if `data` is truncated or malformed.
+    pub fn decode(data: &[u8]) -> Option<(RedisValue, usize)> {
+        let tag = Tag::from_u8(*data.first()?)?;
+        let (value, used) = RedisValue::decode_payload(tag, &data[1..])?;
+        Some((value, 1 + used))
+    }
+
+    /// Decode the bytes that follow a tag
+    fn decode_payload(tag: Tag, data: &[u8]) -> Option<(RedisValue, usize)> {
+        let u32_at = |pos: usize| -> Option<u32> {
+            Some(u32::from_le_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+        };
+        let text_at = |pos: usize, len: usize| -> Option<String> {
+            Some(String::from_utf8_lossy(data.get(pos..pos + len)?).to_string())
+        };
+
+        match tag {
+            Tag::Nil => Some((RedisValue::Nil, 0)),
+            Tag::Err | Tag::Str => {
+                let len = u32_at(0)? as usize;
+                let text = text_at(4, len)?;
+                let value = if tag == Tag::Err {
+                    RedisValue::Err(text)
+                } else {
+                    RedisValue::Str(text)
+                };
+                Some((value, 4 + len))
+            }
+            Tag::Int => {
+                let bytes = data.get(0..8)?.try_into().ok()?;
+                Some((RedisValue::Int(i64::from_le_bytes(bytes)), 8))
+            }
+            Tag::Dbl => {
+                let bytes = data.get(0..8)?.try_into().ok()?;
+                Some((RedisValue::Dbl(f64::from_le_bytes(bytes)), 8))
+            }
+            Tag::Arr | Tag::Push => {
+                let count = u32_at(0)? as usize;
+                let mut pos = 4;
+                // Don't trust the count for the allocation, every item is >= 1 byte
+                let mut items = Vec::with_capacity(count.min(data.len()));
+                for _ in 0..count {
+                    let (item, used) = RedisValue::decode(&data[pos..])?;
+                    items.push(item);
+                    pos += used;
+                }
+                let value = if tag == Tag::Push {
+                    RedisValue::Push(items)
+                } else {
+                    RedisValue::Arr(items)
+                };
+                Some((value, pos))
+            }
+        }
+    }
+
+    /// Append the Tag encoding of this value to `out`, the inverse of decode
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.tag() as u8);
+        match self {
+            RedisValue::Nil => {}
+            RedisValue::Err(text) | RedisValue::Str(text) => {
+                out.extend_from_slice(&(text.len() as u32).to_le_bytes());
+                out.extend_from_slice(text.as_bytes());
+            }
+            RedisValue::Int(n) => out.extend_from_slice(&n.to_le_bytes()),
+            RedisValue::Dbl(d) => out.extend_from_slice(&d.to_le_bytes()),
+            RedisValue::Arr(items) | RedisValue::Push(items) => {
+                out.extend_from_slice(&(items.len() as u32).to_le_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+
+    pub fn tag(&self) -> Tag {
+        match self {
+            RedisValue::Nil => Tag::Nil,
+            RedisValue::Err(_) => Tag::Err,
+            RedisValue::Str(_) => Tag::Str,
+            RedisValue::Int(_) => Tag::Int,
+            RedisValue::Dbl(_) => Tag::Dbl,
+            RedisValue::Arr(_) => Tag::Arr,
+            RedisValue::Push(_) => Tag::Push,
+        }
+    }
+}
+
+/// How replies are laid out on the wire
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplyFormat {
+    /// u32 length, then one Tag-encoded value
+    Framed,
+    /// RESP2, plus the RESP3 push, null and double types
+    Resp,
+}
+
+// Bytes consumed before the buffer is compacted
+const DECODER_COMPACT_SIZE: usize = 64 * 1024;
+
+/// Incremental reply decoder: feed() it bytes as they are read, take complete
+/// values out with next_value()
+#[derive(Debug)]
+pub struct ReplyDecoder {
+    format: ReplyFormat,
+    buf: Vec<u8>,
+    // Start of the first value not handed out yet
+    pos: usize,
+}
+
+impl ReplyDecoder {
+    pub fn new(format: ReplyFormat) -> ReplyDecoder {
+        ReplyDecoder { format, buf: Vec::new(), pos: 0 }
+    }
+
+    pub fn format(&self) -> ReplyFormat {
+        self.format
+    }
+
+    /// Append bytes read from the connection
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if self.pos == self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+        } else if self.pos >= DECODER_COMPACT_SIZE {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Bytes fed but not yet decoded
+    pub fn pending(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    /// The next complete value, or None until more bytes are fed. After an
+    /// error the stream is out of step and the decoder shouldn't be used again.
+    pub fn next_value(&mut self) -> Result<Option<RedisValue>, ProtocolError> {
+        let data = &self.buf[self.pos..];
+        let decoded = match self.format {
+            ReplyFormat::Framed => decode_framed(data)?,
+            ReplyFormat::Resp => decode_resp(data)?,
+        };
+        Ok(decoded.map(|(value, used)| {
+            self.pos += used;
+            value
+        }))
+    }
+}
+
+/// One length-prefixed Tag value from the front of `data`
+fn decode_framed(data: &[u8]) -> Result<Option<(RedisValue, usize)>, ProtocolError> {
+    let Some(header) = data.get(..4) else {
+        return Ok(None);
+    };
+    let size = u32::from_le_bytes(header.try_into().unwrap()) as usize;
+    let Some(body) = data.get(4..4 + size) else {
+        return Ok(None);
+    };
+    match RedisValue::decode(body) {
+        Some((value, used)) if used == size => Ok(Some((value, 4 + size))),
+        _ => Err(ProtocolError::MalformedReply),
+    }
+}
+
+/// One RESP value from the front of `data`
+fn decode_resp(data: &[u8]) -> Result<Option<(RedisValue, usize)>, ProtocolError> {
+    let Some(&marker) = data.first() else {
+        return Ok(None);
+    };
+    let Some(newline) = data.iter().position(|&b| b == b'\n') else {
+        return Ok(None);
+    };
+    // A bare "\n" has no marker before it
+    let line = data.get(1..newline).and_then(|line| line.strip_suffix(b"\r")).ok_or(ProtocolError::MissingCrlf)?;
+    let text = || String::from_utf8_lossy(line).to_string();
+    let number = || -> Result<i64, ProtocolError> {
+        std::str::from_utf8(line).ok().and_then(|s| s.parse().ok()).ok_or(ProtocolError::MalformedReply)
+    };
+    let after_line = newline + 1;
+
+    match marker {
+        b'+' => Ok(Some((RedisValue::Str(text()), after_line))),
+        b'-' => Ok(Some((RedisValue::Err(text()), after_line))),
+        b':' => Ok(Some((RedisValue::Int(number()?), after_line))),
+        b'_' => Ok(Some((RedisValue::Nil, after_line))),
+        b',' => {
+            // "inf" and "-inf" parse too
+            let value: f64 =
+                std::str::from_utf8(line).ok().and_then(|s| s.parse().ok()).ok_or(ProtocolError::MalformedReply)?;
+            Ok(Some((RedisValue::Dbl(value), after_line)))
+        }
+        b'$' => {
+            let len = number().map_err(|_| ProtocolError::InvalidBulkLength)?;
+            if len < 0 {
+                return Ok(Some((RedisValue::Nil, after_line)));
+            }
+            let end = after_line + len as usize;
+            let Some(tail) = data.get(end..end + 2) else {
+                return Ok(None);
+            };
+            if tail != b"\r\n" {
+                return Err(ProtocolError::MissingCrlf);
+            }
+            let value = String::from_utf8_lossy(&data[after_line..end]).to_string();
+            Ok(Some((RedisValue::Str(value), end + 2)))
+        }
+        b'*' | b'>' => {
+            let count = number().map_err(|_| ProtocolError::InvalidMultibulkLength)?;
+            if count < 0 {
+                return Ok(Some((RedisValue::Nil, after_line)));
+            }
+            let mut pos = after_line;
+            // Don't trust the count for the allocation, every item is >= 3 bytes
+            let mut items = Vec::with_capacity((count as usize).min(data.len() / 3));
+            for _ in 0..count {
+                let Some((item, used)) = decode_resp(&data[pos..])? else {
+                    return Ok(None);
+                };
+                items.push(item);
+                pos += used;
+            }
+            let value = if marker == b'>' { RedisValue::Push(items) } else { RedisValue::Arr(items) };
+            Ok(Some((value, pos)))
+        }
+        other => Err(ProtocolError::UnknownReplyType(other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: FrameLimits = FrameLimits { max_request_size: 64, max_args: 3, max_arg_len: 8 };
+
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let mut buf = (body.len() as u32).to_le_bytes().to_vec();
+        buf.extend_from_slice(body);
+        buf
+    }
+
+    // Every value `decoder` gives back until it wants more bytes or fails
+    fn decode_all(format: ReplyFormat, bytes: &[u8]) -> Result<Vec<RedisValue>, ProtocolError> {
+        let mut decoder = ReplyDecoder::new(format);
+        decoder.feed(bytes);
+        let mut values = Vec::new();
+        while let Some(value) = decoder.next_value()? {
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    #[test]
+    fn malformed_requests_get_their_error() {
+        let cases: &[(&[u8], ProtocolError)] = &[
+            (&frame(&[b'a'; 65]), ProtocolError::RequestTooLarge { size: 65, limit: 64 }),
+            (&frame(b"a b c d"), ProtocolError::TooManyArgs { limit: 3 }),
+            (&frame(b"GET 123456789"), ProtocolError::ArgTooLong { len: 9, limit: 8 }),
+            (&frame(b"GET \"key"), ProtocolError::UnbalancedQuotes),
+            (&frame(b"GET 'key'x"), ProtocolError::UnbalancedQuotes),
+            (&frame(b"GET \"\\xZZ\""), ProtocolError::InvalidEscape),
+            (&frame(b"GET \xff"), ProtocolError::InvalidUtf8),
+        ];
+        for (input, error) in cases {
+            assert_eq!(parse_frame(input, &LIMITS).as_ref(), Err(error), "{:?}", input.escape_ascii().to_string());
+        }
+
+        // The size alone rejects a frame, before its body arrives
+        let header = 1000u32.to_le_bytes();
+        assert_eq!(parse_frame(&header, &LIMITS), Err(ProtocolError::RequestTooLarge { size: 1000, limit: 64 }));
+    }
+
+    #[test]
+    fn malformed_replies_get_their_error() {
+        let cases: &[(ReplyFormat, &[u8], ProtocolError)] = &[
+            (ReplyFormat::Resp, b"*x\r\n", ProtocolError::InvalidMultibulkLength),
+            (ReplyFormat::Resp, b">1.5\r\n", ProtocolError::InvalidMultibulkLength),
+            (ReplyFormat::Resp, b"$x\r\n", ProtocolError::InvalidBulkLength),
+            (ReplyFormat::Resp, b"+OK\n", ProtocolError::MissingCrlf),
+            (ReplyFormat::Resp, b"\n", ProtocolError::MissingCrlf),
+            (ReplyFormat::Resp, b"$2\r\nabcd", ProtocolError::MissingCrlf),
+            (ReplyFormat::Resp, b"?x\r\n", ProtocolError::UnknownReplyType(b'?')),
+            (ReplyFormat::Resp, b":x\r\n", ProtocolError::MalformedReply),
+            (ReplyFormat::Resp, b",x\r\n", ProtocolError::MalformedReply),
+            (ReplyFormat::Framed, &frame(&[9]), ProtocolError::MalformedReply),
+            (ReplyFormat::Framed, &frame(&[Tag::Int as u8, 1, 2]), ProtocolError::MalformedReply),
+            // Bytes past the value
+            (ReplyFormat::Framed, &frame(&[Tag::Nil as u8, 0]), ProtocolError::MalformedReply),
+        ];
+        for (format, input, error) in cases {
+            assert_eq!(decode_all(*format, input).as_ref(), Err(error), "{:?}", input.escape_ascii().to_string());
+        }
+    }
+
+    // xorshift64*, as in bench
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        // Mostly bytes that mean something to one of the formats
+        fn bytes(&mut self, len: u64) -> Vec<u8> {
+            const MEANINGFUL: &[u8] = b"*$+-:_,>\r\n0123456789\"'\\x \0\x01\x02\x03\x05\xff";
+            (0..len)
+                .map(|_| match self.below(4) {
+                    0 => self.next() as u8,
+                    _ => MEANINGFUL[self.below(MEANINGFUL.len() as u64) as usize],
+                })
+                .collect()
+        }
+
+        fn text(&mut self) -> String {
+            let len = self.below(12);
+            (0..len).map(|_| char::from_u32(self.below(0x500) as u32).unwrap_or('?')).collect()
+        }
+
+        fn value(&mut self, depth: u32) -> RedisValue {
+            match self.below(if depth < 3 { 7 } else { 5 }) {
+                0 => RedisValue::Nil,
+                1 => RedisValue::Err(self.text()),
+                2 => RedisValue::Str(self.text()),
+                3 => RedisValue::Int(self.next() as i64),
+                // Finite, so the decoded value compares equal
+                4 => RedisValue::Dbl((self.next() as i64) as f64 / 1e6),
+                5 => RedisValue::Arr((0..self.below(4)).map(|_| self.value(depth + 1)).collect()),
+                _ => RedisValue::Push((0..self.below(4)).map(|_| self.value(depth + 1)).collect()),
+            }
+        }
+    }
+
+    // Fixed seeds, so a failure comes back on every run; PROTOCOL_FUZZ_SEED
+    // runs just that one
+    fn seeds() -> Vec<u64> {
+        match std::env::var("PROTOCOL_FUZZ_SEED").ok().and_then(|v| v.parse().ok()) {
+            Some(seed) => vec![seed],
+            None => (1..=4).collect(),
+        }
+    }
+
+    #[test]
+    fn random_bytes_never_panic() {
+        for seed in seeds() {
+            let mut rng = Rng(seed | 1);
+            for _ in 0..20_000 {
+                let len = rng.below(48);
+                let mut input = rng.bytes(len);
+                // Sometimes a frame header that fits
+                if rng.below(2) == 0 {
+                    input.splice(..0, (len as u32).to_le_bytes());
+                }
+                let _ = parse_frame(&input, &LIMITS);
+                let _ = decode_all(ReplyFormat::Framed, &input);
+                let _ = decode_all(ReplyFormat::Resp, &input);
+                let _ = RedisValue::decode(&input);
+            }
+        }
+    }
+
+    #[test]
+    fn values_come_back_as_encoded() {
+        for seed in seeds() {
+            let mut rng = Rng(seed | 1);
+            for _ in 0..5_000 {
+                let value = rng.value(0);
+                let mut body = Vec::new();
+                value.encode(&mut body);
+                assert_eq!(RedisValue::decode(&body), Some((value.clone(), body.len())), "seed {}", seed);
+
+                // Framed, and handed to the decoder in pieces as TCP might
+                let bytes = frame(&body);
+                let mut decoder = ReplyDecoder::new(ReplyFormat::Framed);
+                let mut pos = 0;
+                let decoded = loop {
+                    let end = (pos + 1 + rng.below(8) as usize).min(bytes.len());
+                    decoder.feed(&bytes[pos..end]);
+                    pos = end;
+                    if let Some(decoded) = decoder.next_value().unwrap() {
+                        break decoded;
+                    }
+                    assert!(pos < bytes.len(), "seed {}: {:?} never came out", seed, value);
+                };
+                assert_eq!(decoded, value, "seed {}", seed);
+                assert_eq!(decoder.pending(), 0);
+            }
+        }
+    }
+}
@@ -0,0 +1,767 @@
+/* Snapshot persistence */
+//
+// SAVE writes the whole dataset to dump.rdb and the server loads it back at
+// startup. The file is "RRDB", a version byte, then a sequence of records each
+// starting with an opcode byte and the u32 length of what follows:
+//
+//   OP_FUNCTION  len code               a FUNCTION library, replayed on load
+//   OP_EXPIRE_MS len u64                unix-ms expiry of the key that follows
+//   <ValueType>  len key payload        one key; the type byte picks the decoder
+//   OP_EOF       CRC-64 (u64)           of every byte before the checksum
+//
+// Integers are little-endian and strings are a u32 length followed by bytes,
+// matching the wire protocol.
+//
+// Loading walks the record lengths and checks the CRC before applying
+// anything, so a truncated or damaged file is refused whole (unless
+// load-skip-corrupt says to load what it can), and each record must decode
+// to exactly its stated length. Errors name the offset and the
+// record they were found in. Version 1 files, which had neither lengths nor
+// a checksum, still load.
+//
+// Each value type's payload is written and read by its entry in the
+// serializer registry, looked up by ValueType; the match there has no
+// wildcard, so a new Value variant doesn't build until it has a serializer
+// (or is explicitly left out, as the Init placeholder is).
+//
+// DUMP serializes a single value with the same encoders, as
+//
+//   <ValueType> payload  version (u16)  CRC-64 of everything before it
+//
+// and RESTORE refuses a blob whose version or checksum doesn't match. The blob
+// travels as a string with one char per byte (U+0000-U+00FF), which is what
+// "\xHH" escapes in a request produce.
+
+use std::collections::{BTreeSet, VecDeque};
+use std::fs;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::command::CmdResult;
+use crate::crc64::crc64;
+use crate::logging::{log_error, log_notice, log_warning};
+use crate::stream::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamId};
+use crate::{
+    entry_expire_at, get_current_time_ms, info, out_err, out_nil,
+    out_str, scripting, with_global_data, with_global_data_read, Buffer, GData, Value, ValueType, ZSet,
+};
+
+pub const RDB_FILENAME: &str = "dump.rdb";
+
+pub const RDB_MAGIC: &[u8] = b"RRDB";
+const RDB_VERSION: u8 = 2;
+
+// DUMP payloads carry their own version, independent of the file's
+const DUMP_VERSION: u16 = 1;
+
+// Set while a BGSAVE is writing its file
+static BGSAVE_RUNNING: AtomicBool = AtomicBool::new(false);
+
+const OP_FUNCTION: u8 = 0xF5;
+const OP_EXPIRE_MS: u8 = 0xFC;
+const OP_EOF: u8 = 0xFF;
+
+/* Encoding */
+
+fn put_u8(buf: &mut Vec<u8>, v: u8) {
+    buf.push(v);
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_f64(buf: &mut Vec<u8>, v: f64) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_u32(buf, s.len() as u32);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn put_stream_id(buf: &mut Vec<u8>, id: &StreamId) {
+    put_u64(buf, id.ms);
+    put_u64(buf, id.seq);
+}
+
+fn put_zset(buf: &mut Vec<u8>, zset: &ZSet) {
+    put_u32(buf, zset.name_to_node.len() as u32);
+    for (name, node) in &zset.name_to_node {
+        put_f64(buf, node.lock().unwrap().score);
+        put_str(buf, name);
+    }
+}
+
+fn put_stream(buf: &mut Vec<u8>, stream: &Stream) {
+    put_u64(buf, stream.len() as u64);
+    for (id, fields) in stream.entries() {
+        put_stream_id(buf, id);
+        put_u32(buf, fields.len() as u32);
+        for (field, value) in fields {
+            put_str(buf, field);
+            put_str(buf, value);
+        }
+    }
+    put_stream_id(buf, &stream.last_id());
+
+    let groups: Vec<_> = stream.groups().collect();
+    put_u32(buf, groups.len() as u32);
+    for (name, group) in groups {
+        put_str(buf, name);
+        put_stream_id(buf, &group.last_delivered);
+        put_u32(buf, group.consumers.len() as u32);
+        for (consumer_name, consumer) in &group.consumers {
+            put_str(buf, consumer_name);
+            put_u64(buf, consumer.seen_time_ms);
+        }
+        // Consumer ownership is rebuilt from the PEL on load
+        put_u32(buf, group.pel.len() as u32);
+        for (id, pending) in &group.pel {
+            put_stream_id(buf, id);
+            put_str(buf, &pending.consumer);
+            put_u64(buf, pending.delivery_time_ms);
+            put_u64(buf, pending.delivery_count);
+        }
+    }
+}
+
+// An opcode and a length-prefixed body written by `body`
+fn put_record(buf: &mut Vec<u8>, opcode: u8, body: impl FnOnce(&mut Vec<u8>)) {
+    put_u8(buf, opcode);
+    let len_at = buf.len();
+    put_u32(buf, 0);
+    body(buf);
+    let len = (buf.len() - len_at - 4) as u32;
+    buf[len_at..len_at + 4].copy_from_slice(&len.to_le_bytes());
+}
+
+fn put_key(buf: &mut Vec<u8>, key: &str, value: &Value) {
+    if serializer(value.value_type()).is_none() {
+        return;
+    }
+    put_record(buf, value.value_type() as u8, |buf| {
+        put_str(buf, key);
+        put_value(buf, value);
+    });
+}
+
+fn put_list(buf: &mut Vec<u8>, list: &VecDeque<String>) {
+    put_u32(buf, list.len() as u32);
+    for element in list {
+        put_str(buf, element);
+    }
+}
+
+fn put_value(buf: &mut Vec<u8>, value: &Value) {
+    if let Some(serializer) = serializer(value.value_type()) {
+        (serializer.put)(buf, value);
+    }
+}
+
+/// The dataset as it was at one moment, with the function libraries. The
+/// values are clones sharing everything with the live ones until those are
+/// written to, so taking a snapshot under the lock costs a key copy and a
+/// reference count per key, and serializing it needs no lock at all. Values
+/// the log backend keeps on disk are read back for it (see store).
+pub struct Snapshot {
+    pub libraries: Vec<String>,
+    /// (key, value, unix ms it expires at)
+    pub keys: Vec<(String, Value, Option<u64>)>,
+}
+
+impl Snapshot {
+    pub fn capture(g_data: &GData, libraries: Vec<String>) -> Snapshot {
+        let keys = g_data
+            .db
+            .iter()
+            .filter_map(|entry| match g_data.db.value(entry) {
+                Ok(value) => Some((entry.key.clone(), value, entry_expire_at(&g_data.expires, entry))),
+                Err(e) => {
+                    log_error!("persist", "Can't read the value of {} back for the snapshot: {}", entry.key, e);
+                    None
+                }
+            })
+            .collect();
+        Snapshot { libraries, keys }
+    }
+}
+
+/// Serialize a snapshot into a file image
+pub fn serialize(snapshot: &Snapshot) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(RDB_MAGIC);
+    put_u8(&mut buf, RDB_VERSION);
+
+    for code in &snapshot.libraries {
+        put_record(&mut buf, OP_FUNCTION, |buf| put_str(buf, code));
+    }
+
+    for (key, value, expire_at) in &snapshot.keys {
+        if let Some(at) = expire_at {
+            put_record(&mut buf, OP_EXPIRE_MS, |buf| put_u64(buf, *at));
+        }
+        put_key(&mut buf, key, value);
+    }
+
+    put_u8(&mut buf, OP_EOF);
+    let crc = crc64(0, &buf);
+    put_u64(&mut buf, crc);
+    buf
+}
+
+/* Decoding */
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < n {
+            return Err(format!("unexpected end of file at offset {}", self.pos));
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        let offset = self.pos;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| format!("invalid UTF-8 string at offset {}", offset))
+    }
+
+    fn stream_id(&mut self) -> Result<StreamId, String> {
+        Ok(StreamId::new(self.u64()?, self.u64()?))
+    }
+}
+
+fn get_zset(r: &mut Reader) -> Result<ZSet, String> {
+    let mut zset = ZSet::new();
+    for _ in 0..r.u32()? {
+        let score = r.f64()?;
+        let name = r.str()?;
+        zset.insert(score, name);
+    }
+    Ok(zset)
+}
+
+fn get_stream(r: &mut Reader) -> Result<Stream, String> {
+    let mut stream = Stream::new();
+    for _ in 0..r.u64()? {
+        let id = r.stream_id()?;
+        let mut fields = Vec::new();
+        for _ in 0..r.u32()? {
+            fields.push((r.str()?, r.str()?));
+        }
+        if id <= stream.last_id() {
+            return Err(format!("stream IDs out of order before offset {}", r.pos));
+        }
+        stream.append(id, fields);
+    }
+    stream.set_last_id(r.stream_id()?);
+
+    for _ in 0..r.u32()? {
+        let name = r.str()?;
+        let mut group = ConsumerGroup::new(r.stream_id()?);
+        for _ in 0..r.u32()? {
+            let consumer_name = r.str()?;
+            let consumer = Consumer { seen_time_ms: r.u64()?, pending: BTreeSet::new() };
+            group.consumers.insert(consumer_name, consumer);
+        }
+        for _ in 0..r.u32()? {
+            let id = r.stream_id()?;
+            let pending = PendingEntry {
+                consumer: r.str()?,
+                delivery_time_ms: r.u64()?,
+                delivery_count: r.u64()?,
+            };
+            group.consumers.entry(pending.consumer.clone()).or_default().pending.insert(id);
+            group.pel.insert(id, pending);
+        }
+        stream.insert_group(name, group);
+    }
+    Ok(stream)
+}
+
+fn get_list(r: &mut Reader) -> Result<VecDeque<String>, String> {
+    let mut list = VecDeque::new();
+    for _ in 0..r.u32()? {
+        list.push_back(r.str()?);
+    }
+    Ok(list)
+}
+
+fn get_value(r: &mut Reader, type_byte: u8) -> Result<Value, String> {
+    match ValueType::from_u8(type_byte).and_then(serializer) {
+        Some(serializer) => (serializer.get)(r),
+        None => Err(format!("unknown record type {:#04x}", type_byte)),
+    }
+}
+
+/* Serializer registry */
+
+// How one value type is written after its type byte, and read back. `put`
+// is only handed values of its own type.
+struct Serializer {
+    put: fn(&mut Vec<u8>, &Value),
+    get: fn(&mut Reader) -> Result<Value, String>,
+}
+
+static STR: Serializer = Serializer {
+    put: |buf, value| {
+        if let Value::Str(s) = value {
+            put_str(buf, s);
+        }
+    },
+    get: |r| Ok(Value::Str(r.str()?.into())),
+};
+
+static ZSET: Serializer = Serializer {
+    put: |buf, value| {
+        if let Value::ZSet(zset) = value {
+            put_zset(buf, zset);
+        }
+    },
+    get: |r| Ok(Value::ZSet(get_zset(r)?.into())),
+};
+
+static STREAM: Serializer = Serializer {
+    put: |buf, value| {
+        if let Value::Stream(stream) = value {
+            put_stream(buf, stream);
+        }
+    },
+    get: |r| Ok(Value::Stream(get_stream(r)?.into())),
+};
+
+static LIST: Serializer = Serializer {
+    put: |buf, value| {
+        if let Value::List(list) = value {
+            put_list(buf, list);
+        }
+    },
+    get: |r| Ok(Value::List(get_list(r)?.into())),
+};
+
+// The serializer of each type; None for types that are never persisted
+fn serializer(value_type: ValueType) -> Option<&'static Serializer> {
+    match value_type {
+        ValueType::Init => None,
+        ValueType::Str => Some(&STR),
+        ValueType::ZSet => Some(&ZSET),
+        ValueType::Stream => Some(&STREAM),
+        ValueType::List => Some(&LIST),
+    }
+}
+
+// What a record is, for error messages
+fn section_name(opcode: u8, key: Option<&str>) -> String {
+    match (opcode, key) {
+        (OP_FUNCTION, _) => "function library".to_string(),
+        (OP_EXPIRE_MS, _) => "expire".to_string(),
+        (_, Some(key)) => format!("key '{}'", key),
+        (t, None) => format!("record of type {:#04x}", t),
+    }
+}
+
+fn corrupt(offset: usize, section: &str, detail: impl std::fmt::Display) -> String {
+    format!("corrupt snapshot at offset {} ({}): {}", offset, section, detail)
+}
+
+// Walk the records of a version 2 file from `pos` by their lengths alone,
+// returning the offset of the OP_EOF byte
+fn find_eof(data: &[u8], mut pos: usize) -> Result<usize, String> {
+    loop {
+        let Some(&opcode) = data.get(pos) else {
+            return Err(corrupt(pos, "end of file", "file ends before the EOF marker"));
+        };
+        if opcode == OP_EOF {
+            return Ok(pos);
+        }
+        let Some(len) = data.get(pos + 1..pos + 5) else {
+            return Err(corrupt(pos, &section_name(opcode, None), "file ends inside the record header"));
+        };
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        let left = data.len() - (pos + 5);
+        if len > left {
+            let detail = format!("record is {} bytes but only {} remain", len, left);
+            return Err(corrupt(pos, &section_name(opcode, None), detail));
+        }
+        pos += 5 + len;
+    }
+}
+
+/// Counts reported after a successful load
+pub struct LoadStats {
+    pub keys: usize,
+    pub expired: usize,
+    pub libraries: usize,
+}
+
+// Check the framing and checksum of a version 2 file from `pos`, returning
+// where the file ends
+fn check_file(data: &[u8], pos: usize) -> Result<usize, String> {
+    let eof = find_eof(data, pos)?;
+    let Some(stored) = data.get(eof + 1..eof + 9) else {
+        return Err(corrupt(eof + 1, "checksum", "file ends before the checksum"));
+    };
+    let expected = u64::from_le_bytes(stored.try_into().unwrap());
+    let actual = crc64(0, &data[..eof + 1]);
+    if expected != actual {
+        let detail = format!("expected CRC-64 {:#018x}, computed {:#018x}", expected, actual);
+        return Err(corrupt(eof + 1, "checksum", detail));
+    }
+    Ok(eof + 9)
+}
+
+// Where a load is between records
+struct LoadState {
+    stats: LoadStats,
+    // Set by an OP_EXPIRE_MS record for the key after it
+    expire_ms: Option<u64>,
+}
+
+// Decode and apply the record whose body starts at r.pos, its opcode and
+// length (in a version 2 file) already read
+fn load_record(r: &mut Reader, opcode: u8, record_at: usize, len: Option<usize>, state: &mut LoadState) -> Result<(), String> {
+    let body_at = r.pos;
+    // The body must decode to exactly its stated length
+    let check_len = |r: &Reader, section: &str| match len {
+        Some(len) if r.pos - body_at != len => {
+            let detail = format!("record length is {} bytes, contents took {}", len, r.pos - body_at);
+            Err(corrupt(record_at, section, detail))
+        }
+        _ => Ok(()),
+    };
+
+    match opcode {
+        OP_FUNCTION => {
+            let section = section_name(opcode, None);
+            let code = r.str().map_err(|e| corrupt(record_at, &section, e))?;
+            check_len(r, &section)?;
+            scripting::function_load(&code, true).map_err(|e| format!("failed to load function library: {}", e))?;
+            state.stats.libraries += 1;
+        }
+        OP_EXPIRE_MS => {
+            let section = section_name(opcode, None);
+            state.expire_ms = Some(r.u64().map_err(|e| corrupt(record_at, &section, e))?);
+            check_len(r, &section)?;
+        }
+        type_byte => {
+            let key = r.str().map_err(|e| corrupt(record_at, &section_name(type_byte, None), e))?;
+            let section = section_name(type_byte, Some(&key));
+            let value = get_value(r, type_byte).map_err(|e| corrupt(record_at, &section, e))?;
+            check_len(r, &section)?;
+            let expire_at = state.expire_ms.take();
+            // Not placed if past its TTL, which passed while the server was down
+            if with_global_data(|g_data| g_data.place_entry(&key, value, expire_at)) {
+                state.stats.keys += 1;
+            } else {
+                state.stats.expired += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Load the snapshot at the front of `data`, returning how many bytes it took.
+///
+/// With load-skip-corrupt, a file failing its checks is loaded anyway, as
+/// far as it can be: a record that doesn't decode is skipped when its
+/// length is sound, and loading stops at the first one whose isn't.
+pub fn load_from(data: &[u8]) -> Result<(LoadStats, usize), String> {
+    let mut r = Reader::new(data);
+    if r.take(RDB_MAGIC.len()).ok() != Some(RDB_MAGIC) {
+        return Err("not a snapshot file (bad magic)".to_string());
+    }
+    let version = r.u8().map_err(|e| corrupt(RDB_MAGIC.len(), "header", e))?;
+    if version != 1 && version != RDB_VERSION {
+        return Err(format!("unsupported snapshot version {}", version));
+    }
+    let framed = version >= 2;
+    let skip_corrupt = with_global_data_read(|g_data| g_data.config.load_skip_corrupt);
+
+    // Check the whole file before touching the keyspace
+    let mut end = None;
+    if framed {
+        match check_file(data, r.pos) {
+            Ok(file_end) => end = Some(file_end),
+            Err(e) if skip_corrupt => log_warning!("persist", "{}; loading what can be read", e),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut state = LoadState {
+        stats: LoadStats { keys: 0, expired: 0, libraries: 0 },
+        expire_ms: None,
+    };
+
+    loop {
+        let record_at = r.pos;
+        let header = r.u8().map_err(|e| corrupt(record_at, "end of file", e)).and_then(|opcode| match opcode {
+            OP_EOF => Ok((opcode, None)),
+            _ if framed => Ok((opcode, Some(r.u32()? as usize))),
+            _ => Ok((opcode, None)),
+        });
+        let (opcode, len) = match header {
+            Ok((OP_EOF, _)) => break,
+            Ok(header) => header,
+            Err(e) if skip_corrupt => {
+                log_warning!("persist", "{}; loading stops there", e);
+                info::loading_skipped();
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        info::loading_progress(record_at);
+
+        let body_at = r.pos;
+        match load_record(&mut r, opcode, record_at, len, &mut state) {
+            Ok(()) => {}
+            Err(e) if skip_corrupt => {
+                info::loading_skipped();
+                state.expire_ms = None;
+                // Stepped over if its length fits in the file
+                match len.map(|len| body_at + len).filter(|&next| next <= data.len()) {
+                    Some(next) => {
+                        log_warning!("persist", "Skipping a record: {}", e);
+                        r.pos = next;
+                    }
+                    None => {
+                        log_warning!("persist", "{}; loading stops there", e);
+                        break;
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let end = end.unwrap_or(r.pos);
+    info::loading_progress(end);
+    Ok((state.stats, end))
+}
+
+/// Load a snapshot into the (empty) keyspace. A missing file is not an error.
+pub fn load_file(path: &str) -> Result<Option<LoadStats>, String> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("can't read {}: {}", path, e)),
+    };
+    info::loading_start(path, data.len());
+    let loaded = load_from(&data);
+    info::loading_end();
+    loaded.map(|(stats, _)| Some(stats)).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Write a snapshot of the current dataset, replacing the old file atomically
+pub fn save_file(path: &str) -> io::Result<()> {
+    let libraries = scripting::function_library_codes();
+    let snapshot = with_global_data(|g_data| Snapshot::capture(g_data, libraries));
+    write_file(path, &snapshot)
+}
+
+/// A snapshot of the current dataset as a file image, for a replica's full
+/// resync
+pub fn serialize_current() -> Vec<u8> {
+    let libraries = scripting::function_library_codes();
+    let snapshot = with_global_data(|g_data| Snapshot::capture(g_data, libraries));
+    serialize(&snapshot)
+}
+
+fn write_file(path: &str, snapshot: &Snapshot) -> io::Result<()> {
+    let data = serialize(snapshot);
+    let tmp_path = format!("temp-{}.rdb", std::process::id());
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/* DUMP and RESTORE */
+
+pub fn dump_value(value: &Value) -> Vec<u8> {
+    let mut buf = Vec::new();
+    put_u8(&mut buf, value.value_type() as u8);
+    put_value(&mut buf, value);
+    buf.extend_from_slice(&DUMP_VERSION.to_le_bytes());
+    let crc = crc64(0, &buf);
+    put_u64(&mut buf, crc);
+    buf
+}
+
+const BAD_PAYLOAD: &str = "ERR DUMP payload version or checksum are wrong";
+
+pub fn restore_value(blob: &[u8]) -> Result<Value, String> {
+    let Some(body_len) = blob.len().checked_sub(10) else {
+        return Err(BAD_PAYLOAD.to_string());
+    };
+    let (body, footer) = blob.split_at(body_len);
+    let version = u16::from_le_bytes([footer[0], footer[1]]);
+    let crc = u64::from_le_bytes(footer[2..].try_into().unwrap());
+    if version != DUMP_VERSION || crc64(0, &blob[..body_len + 2]) != crc {
+        return Err(BAD_PAYLOAD.to_string());
+    }
+
+    let mut r = Reader::new(body);
+    let type_byte = r.u8().map_err(|_| BAD_PAYLOAD.to_string())?;
+    let value = get_value(&mut r, type_byte).map_err(|e| format!("ERR Bad data format: {}", e))?;
+    if r.pos != body.len() {
+        return Err("ERR Bad data format: trailing bytes".to_string());
+    }
+    Ok(value)
+}
+
+// One char per byte, as "\xHH" escapes decode
+pub fn bytes_to_chars(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+fn chars_to_bytes(s: &str) -> Option<Vec<u8>> {
+    s.chars().map(|c| u8::try_from(c).ok()).collect()
+}
+
+// DUMP key
+pub fn do_dump(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    with_global_data_read(|g_data| match g_data.db.lookup_entry(&cmd[1]) {
+        Some(entry) => out_str(out, &bytes_to_chars(&dump_value(&entry.value))),
+        None => out_nil(out),
+    });
+    Ok(())
+}
+
+// RESTORE key ttl serialized-value [REPLACE] [ABSTTL] [IDLETIME seconds]
+pub fn do_restore(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let key = &cmd[1];
+    let ttl = match cmd[2].parse::<i64>() {
+        Ok(ttl) if ttl >= 0 => ttl as u64,
+        _ => {
+            out_err(out, "ERR Invalid TTL value, must be >= 0");
+            return Ok(());
+        }
+    };
+
+    let (mut replace, mut absttl, mut idle_secs) = (false, false, None);
+    let mut options = cmd[4..].iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "ABSTTL" => absttl = true,
+            "IDLETIME" => match options.next().and_then(|v| v.parse::<u32>().ok()) {
+                Some(secs) => idle_secs = Some(secs),
+                None => {
+                    out_err(out, "ERR Invalid IDLETIME value, must be >= 0");
+                    return Ok(());
+                }
+            },
+            _ => {
+                out_err(out, "ERR syntax error");
+                return Ok(());
+            }
+        }
+    }
+
+    let value = chars_to_bytes(&cmd[3])
+        .ok_or_else(|| BAD_PAYLOAD.to_string())
+        .and_then(|blob| restore_value(&blob));
+    let value = match value {
+        Ok(value) => value,
+        Err(e) => {
+            out_err(out, &e);
+            return Ok(());
+        }
+    };
+
+    with_global_data(|g_data| {
+        if !replace && g_data.db.peek_entry(key).is_some() {
+            out_err(out, "BUSYKEY Target key name already exists.");
+            return;
+        }
+
+        // TTLs arrive relative in ms, or with ABSTTL as a unix time in ms. One
+        // already past leaves no key, which is what the TTL asked for.
+        let expire_at = match (ttl, absttl) {
+            (0, _) => None,
+            (at, true) => Some(at),
+            (ttl, false) => Some(get_current_time_ms().saturating_add(ttl)),
+        };
+        // After the insert, which counts as an access
+        if g_data.place_entry(key, value, expire_at)
+            && let (Some(secs), Some(entry)) = (idle_secs, g_data.db.peek_entry(key))
+        {
+            entry.set_idle_secs(secs);
+        }
+        out_nil(out);
+    });
+    Ok(())
+}
+
+// SAVE
+pub fn do_save(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() != 1 {
+        out_err(out, "SAVE takes no arguments");
+        return Ok(());
+    }
+    if BGSAVE_RUNNING.load(Ordering::Acquire) {
+        out_err(out, "ERR Background save already in progress");
+        return Ok(());
+    }
+
+    match save_file(RDB_FILENAME) {
+        Ok(()) => out_nil(out),
+        Err(e) => out_err(out, &format!("ERR failed to save snapshot: {}", e)),
+    }
+    Ok(())
+}
+
+// BGSAVE: take a snapshot and write it on the thread pool while commands
+// keep running
+pub fn do_bgsave(_cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if BGSAVE_RUNNING.swap(true, Ordering::AcqRel) {
+        out_err(out, "ERR Background save already in progress");
+        return Ok(());
+    }
+
+    let libraries = scripting::function_library_codes();
+    with_global_data(|g_data| {
+        let snapshot = Snapshot::capture(g_data, libraries);
+        g_data.thread_pool.submit(move || {
+            match write_file(RDB_FILENAME, &snapshot) {
+                Ok(()) => log_notice!("persist", "Background saving terminated with success"),
+                Err(e) => log_warning!("persist", "Background saving failed: {}", e),
+            }
+            BGSAVE_RUNNING.store(false, Ordering::Release);
+        });
+    });
+    log_notice!("persist", "Background saving started");
+    out_str(out, "Background saving started");
+    Ok(())
+}
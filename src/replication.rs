@@ -0,0 +1,579 @@
+/* Replication */
+//
+// The master side of replication. There is no replica side yet: an instance
+// is a master until FAILOVER hands over to one of its replicas (see
+// failover), after which it refuses writes until REPLICAOF NO ONE. A replica
+// connects as a client, tells its port with REPLCONF listening-port and asks
+// for the stream with
+//
+//   PSYNC replid offset   (or PSYNC ? -1, or SYNC, the first time)
+//
+// The stream is every write as the AOF logs it (aof::effect), RESP-encoded,
+// and a DEL for each key that expires or is evicted: replicas don't expire
+// keys of their own, they wait for the master's DEL. `master_repl_offset`
+// counts the bytes of the stream. The last repl-backlog-size bytes are kept
+// in a ring buffer, the backlog, from the first replica on. A replica that
+// comes back with our replid and an offset still in the backlog gets
+//
+//   +CONTINUE replid\r\n  and the stream from that offset on
+//
+// and anything else a full resync: the dataset as a snapshot, then the
+// stream from where the snapshot was taken:
+//
+//   +FULLRESYNC replid offset\r\n$<length>\r\n<snapshot>
+//
+// Offsets are 1-based as in Redis: a replica that has applied n bytes asks
+// for n + 1. Replicas are sent what's new once per event loop round, out of
+// the backlog itself; one that falls further behind than the backlog reaches
+// is disconnected, to come back for a full resync. REPLCONF ACK offset
+// reports what a replica applied, which ROLE shows, and INFO replication
+// with the seconds since (lag).
+//
+//   ROLE  ->  ["master", offset, [[ip, port, acked offset], ...]]
+//             ["slave", master ip, master port, "connect", -1] after FAILOVER
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, RandomState};
+use std::os::fd::BorrowedFd;
+use std::os::unix::io::RawFd;
+
+use redis::protocol::{RedisValue, Tag};
+use socket2::SockRef;
+
+use crate::{aof, blocking};
+use crate::command::{self, CmdResult, Command};
+use crate::events::ServerEvent;
+use crate::logging::{log_notice, log_warning};
+use crate::resp;
+use crate::{
+    forget_client, get_monotonic_time_ms, out_arr, out_err, out_int, out_str, rdb, with_global_data, with_global_data_read, Buffer,
+    GData,
+};
+
+#[derive(Debug)]
+pub struct Replication {
+    /// Bytes of replication stream produced so far
+    pub master_repl_offset: u64,
+    /// Names this master's stream; offsets are only comparable under it
+    replid: String,
+    // Created for the first replica
+    backlog: Option<Backlog>,
+    replicas: HashMap<RawFd, Replica>,
+    // REPLCONF listening-port of clients that haven't synced yet
+    listening_ports: HashMap<RawFd, u16>,
+    // The replica FAILOVER handed over to, now our master
+    master: Option<(String, u16)>,
+}
+
+#[derive(Debug)]
+struct Replica {
+    ip: String,
+    port: u16,
+    // Offset it acknowledged applying, and when (monotonic ms)
+    acked: u64,
+    acked_at_ms: u64,
+    // Offset sent up to
+    sent: u64,
+}
+
+// The tail of the stream: the bytes after offset `start`
+#[derive(Debug)]
+struct Backlog {
+    buf: VecDeque<u8>,
+    start: u64,
+}
+
+impl Backlog {
+    fn append(&mut self, bytes: &[u8], size: usize) {
+        self.buf.extend(bytes);
+        let excess = self.buf.len().saturating_sub(size);
+        self.buf.drain(..excess);
+        self.start += excess as u64;
+    }
+
+    // The bytes after `offset`, None if they aren't all here anymore
+    fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        let skip = usize::try_from(offset.checked_sub(self.start)?).ok()?;
+        (skip <= self.buf.len()).then(|| self.buf.range(skip..).copied().collect())
+    }
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self {
+            master_repl_offset: 0,
+            replid: new_replid(),
+            backlog: None,
+            replicas: HashMap::new(),
+            listening_ports: HashMap::new(),
+            master: None,
+        }
+    }
+}
+
+// 40 random hex digits
+fn new_replid() -> String {
+    let state = RandomState::new();
+    (0..3u8).map(|i| format!("{:016x}", state.hash_one(i))).collect::<String>()[..40].to_string()
+}
+
+impl Replication {
+    // Forget a client that disconnected
+    fn remove_client(&mut self, fd: RawFd) {
+        self.listening_ports.remove(&fd);
+        if let Some(replica) = self.replicas.remove(&fd) {
+            log_notice!("repl", "Connection with replica {}:{} lost", replica.ip, replica.port);
+        }
+    }
+
+    // A replica at `ip`:`port`, registered under `fd` as if it had synced
+    // and acknowledged the whole stream
+    #[cfg(test)]
+    pub fn add_caught_up_replica(&mut self, fd: RawFd, ip: &str, port: u16) {
+        let replica = Replica { ip: ip.to_string(), port, acked: u64::MAX, acked_at_ms: 0, sent: self.master_repl_offset };
+        self.replicas.insert(fd, replica);
+    }
+
+    #[cfg(test)]
+    pub fn forget_replica(&mut self, fd: RawFd) {
+        self.remove_client(fd);
+    }
+
+    /// The replication section of INFO
+    pub fn info(&self) -> Vec<(&'static str, String)> {
+        let (active, first_byte, histlen) = match &self.backlog {
+            Some(backlog) => (1, backlog.start + 1, backlog.buf.len()),
+            None => (0, 0, 0),
+        };
+        let mut fields = match &self.master {
+            None => vec![("role", "master".to_string())],
+            Some((host, port)) => vec![
+                ("role", "slave".to_string()),
+                ("master_host", host.clone()),
+                ("master_port", port.to_string()),
+                ("master_link_status", "down".to_string()),
+            ],
+        };
+        fields.extend([
+            ("connected_slaves", self.replicas.len().to_string()),
+            ("master_replid", self.replid.clone()),
+            ("master_repl_offset", self.master_repl_offset.to_string()),
+            ("repl_backlog_active", active.to_string()),
+            ("repl_backlog_first_byte_offset", first_byte.to_string()),
+            ("repl_backlog_histlen", histlen.to_string()),
+        ]);
+        fields
+    }
+
+    /// Whether FAILOVER made this server a replica
+    pub fn is_replica(&self) -> bool {
+        self.master.is_some()
+    }
+
+    /// Connected replicas
+    pub fn replica_count(&self) -> usize {
+        self.replicas.len()
+    }
+
+    /// Whether the replica listening at `ip`:`port` is connected
+    pub fn has_replica(&self, ip: &str, port: u16) -> bool {
+        self.replicas.values().any(|replica| replica.ip == ip && replica.port == port)
+    }
+
+    /// A replica that has acknowledged the whole stream: the one at `target`,
+    /// or any without one
+    pub fn caught_up_replica(&self, target: Option<&(String, u16)>) -> Option<(String, u16)> {
+        let mut replicas: Vec<(&RawFd, &Replica)> = self.replicas.iter().collect();
+        replicas.sort_by_key(|(fd, _)| **fd);
+        replicas
+            .into_iter()
+            .map(|(_, replica)| replica)
+            .filter(|replica| target.is_none_or(|(ip, port)| replica.ip == *ip && replica.port == *port))
+            .find(|replica| replica.acked >= self.master_repl_offset)
+            .map(|replica| (replica.ip.clone(), replica.port))
+    }
+
+    /// Our replication ID, and the offset a replica that has everything
+    /// would ask for next
+    pub fn next_psync(&self) -> (String, u64) {
+        (self.replid.clone(), self.master_repl_offset + 1)
+    }
+
+    /// Follow `master`, which took over our stream with FAILOVER
+    pub fn demote(&mut self, master: (String, u16)) {
+        self.master = Some(master);
+    }
+
+    /// Ask every replica to acknowledge its offset right away: REPLCONF
+    /// GETACK * goes down the stream, as any write would
+    pub fn request_acks(&mut self, backlog_size: usize) {
+        let Some(backlog) = self.backlog.as_mut() else {
+            return;
+        };
+        let mut request = Vec::new();
+        resp::encode_request(&["REPLCONF".to_string(), "GETACK".to_string(), "*".to_string()], &mut request);
+        backlog.append(&request, backlog_size);
+        self.master_repl_offset += request.len() as u64;
+    }
+
+    /// A line for each replica in INFO, "slave0:" and so on: where it is and
+    /// how long since it acknowledged that, in seconds. A proxy can send
+    /// reads to the replicas whose offset has caught up with a write's.
+    pub fn replica_info(&self) -> Vec<String> {
+        let now_ms = get_monotonic_time_ms();
+        let mut replicas: Vec<(&RawFd, &Replica)> = self.replicas.iter().collect();
+        replicas.sort_by_key(|(fd, _)| **fd);
+        replicas
+            .into_iter()
+            .map(|(_, replica)| {
+                format!(
+                    "ip={},port={},state=online,offset={},lag={}",
+                    replica.ip,
+                    replica.port,
+                    replica.acked,
+                    now_ms.saturating_sub(replica.acked_at_ms) / 1000
+                )
+            })
+            .collect()
+    }
+
+    fn start_backlog(&mut self) {
+        if self.backlog.is_none() {
+            self.backlog = Some(Backlog { buf: VecDeque::new(), start: self.master_repl_offset });
+        }
+    }
+}
+
+/// Propagate the commands the AOF logs and the keys that expire, and forget
+/// the clients that go
+pub fn on_event(g_data: &mut GData, event: &ServerEvent) {
+    match *event {
+        ServerEvent::CommandExecuted { command, args, reply, .. } if aof::should_log(command, args) => {
+            feed(g_data, command, args, reply)
+        }
+        ServerEvent::KeyExpired { key } => {
+            if let Some(del) = command::lookup_original("del") {
+                let mut reply = Vec::new();
+                RedisValue::Int(1).encode(&mut reply);
+                feed(g_data, del, &["DEL".to_string(), key.to_string()], &reply);
+            }
+        }
+        ServerEvent::ClientDisconnected { fd } => g_data.replication.remove_client(fd),
+        _ => {}
+    }
+}
+
+/// Add a write command that ran to the stream. Nothing to do before there's
+/// a backlog; afterwards this goes with aof::feed.
+pub fn feed(g_data: &mut GData, command: &Command, args: &[String], reply: &[u8]) {
+    if reply.first().is_none_or(|&tag| tag == Tag::Err as u8) {
+        return;
+    }
+    let Some(backlog) = g_data.replication.backlog.as_mut() else {
+        return;
+    };
+    let reply = RedisValue::decode(reply).map_or(RedisValue::Nil, |(value, _)| value);
+    let Some(args) = aof::effect(command, args, &reply) else {
+        return;
+    };
+    let mut request = Vec::new();
+    resp::encode_request(&args, &mut request);
+    backlog.append(&request, g_data.config.repl_backlog_size as usize);
+    g_data.replication.master_repl_offset += request.len() as u64;
+}
+
+/// Send each replica the stream it hasn't got yet
+pub fn send_stream(g_data: &mut GData) {
+    let repl = &mut g_data.replication;
+    let Some(backlog) = &repl.backlog else {
+        return;
+    };
+    let mut lagging = Vec::new();
+    for (&fd, replica) in repl.replicas.iter_mut() {
+        if replica.sent == repl.master_repl_offset {
+            continue;
+        }
+        let Some(conn) = g_data.fd2conn.get_mut(&fd) else {
+            continue; // Running a command of its own
+        };
+        match backlog.since(replica.sent) {
+            Some(bytes) => {
+                conn.append_raw(&bytes);
+                conn.replies_queued();
+                replica.sent = repl.master_repl_offset;
+            }
+            None => lagging.push(fd),
+        }
+    }
+    for fd in lagging {
+        if let Some(replica) = g_data.replication.replicas.get(&fd) {
+            log_warning!(
+                "repl",
+                "Replica {}:{} fell behind the backlog (repl-backlog-size), disconnecting",
+                replica.ip, replica.port
+            );
+        }
+        forget_client(g_data, fd);
+    }
+}
+
+// SYNC, or PSYNC replid offset
+pub fn do_psync(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let fd = blocking::current_client();
+    let Some(fd) = fd else {
+        out_err(out, "ERR PSYNC can't be used here");
+        return Ok(());
+    };
+    if cmd.len() > 3 {
+        // Only sent to replicas, to make them take over
+        let msg = match cmd[3..] {
+            [ref option] if option.eq_ignore_ascii_case("FAILOVER") => "ERR PSYNC FAILOVER can't be sent to a master.",
+            _ => "ERR syntax error",
+        };
+        out_err(out, msg);
+        return Ok(());
+    }
+    let wanted = match cmd {
+        [_, replid, offset] => match offset.parse::<i64>() {
+            Ok(offset) => Some((replid.as_str(), offset)),
+            Err(_) => {
+                out_err(out, "ERR value is not an integer or out of range");
+                return Ok(());
+            }
+        },
+        _ => None,
+    };
+    // SAFETY: the fd is the running client's, open until its command is done
+    let peer = SockRef::from(&unsafe { BorrowedFd::borrow_raw(fd) }).peer_addr().ok();
+    let ip = peer.and_then(|addr| addr.as_socket()).map_or_else(String::new, |addr| addr.ip().to_canonical().to_string());
+
+    // A partial resync, if the replica is following our stream and the
+    // backlog still has everything after where it is
+    let resumed = with_global_data(|g_data| {
+        let (replid, offset) = wanted?;
+        let repl = &mut g_data.replication;
+        if replid != repl.replid || offset < 1 {
+            return None;
+        }
+        let have = offset as u64 - 1;
+        let bytes = repl.backlog.as_ref()?.since(have)?;
+        let mut reply = format!("+CONTINUE {}\r\n", repl.replid).into_bytes();
+        reply.extend_from_slice(&bytes);
+        add_replica(g_data, fd, ip.clone(), g_data.replication.master_repl_offset);
+        g_data.raw_reply_requested = Some(reply);
+        Some(have)
+    });
+    if let Some(have) = resumed {
+        log_notice!("repl", "Partial resynchronization of replica {} from offset {}", ip, have + 1);
+        return Ok(());
+    }
+
+    // Full resync: the snapshot, then the stream from its offset on
+    let snapshot = rdb::serialize_current();
+    with_global_data(|g_data| {
+        g_data.replication.start_backlog();
+        let offset = g_data.replication.master_repl_offset;
+        let mut reply = format!("+FULLRESYNC {} {}\r\n${}\r\n", g_data.replication.replid, offset, snapshot.len()).into_bytes();
+        reply.extend_from_slice(&snapshot);
+        add_replica(g_data, fd, ip.clone(), offset);
+        g_data.raw_reply_requested = Some(reply);
+        log_notice!("repl", "Full resynchronization of replica {}: {} byte snapshot at offset {}", ip, snapshot.len(), offset);
+    });
+    Ok(())
+}
+
+fn add_replica(g_data: &mut GData, fd: RawFd, ip: String, sent: u64) {
+    let repl = &mut g_data.replication;
+    let port = repl.listening_ports.remove(&fd).unwrap_or(0);
+    repl.replicas.insert(fd, Replica { ip, port, acked: 0, acked_at_ms: get_monotonic_time_ms(), sent });
+}
+
+// REPLCONF option value [option value ...]
+//
+// listening-port: the port the replica serves clients on, for ROLE.
+// capa: capabilities, all accepted. ACK offset: what the replica applied;
+// it gets no reply. GETACK is for replicas, which this server isn't.
+pub fn do_replconf(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len().is_multiple_of(2) {
+        out_err(out, "ERR syntax error");
+        return Ok(());
+    }
+    let fd = blocking::current_client();
+    let Some(fd) = fd else {
+        out_err(out, "ERR REPLCONF can't be used here");
+        return Ok(());
+    };
+    for option in cmd[1..].chunks(2) {
+        let value = &option[1];
+        match option[0].to_lowercase().as_str() {
+            "listening-port" => {
+                let Ok(port) = value.parse::<u16>() else {
+                    out_err(out, "ERR value is not an integer or out of range");
+                    return Ok(());
+                };
+                with_global_data(|g_data| g_data.replication.listening_ports.insert(fd, port));
+            }
+            "capa" => {}
+            "ack" => {
+                with_global_data(|g_data| {
+                    if let (Some(replica), Ok(offset)) = (g_data.replication.replicas.get_mut(&fd), value.parse()) {
+                        replica.acked = offset;
+                        replica.acked_at_ms = get_monotonic_time_ms();
+                    }
+                    g_data.raw_reply_requested = Some(Vec::new());
+                });
+                return Ok(());
+            }
+            name => {
+                out_err(out, &format!("ERR Unrecognized REPLCONF option: {}", name));
+                return Ok(());
+            }
+        }
+    }
+    out_str(out, "OK");
+    Ok(())
+}
+
+// ROLE
+pub fn do_role(_cmd: &[String], out: &mut Buffer) -> CmdResult {
+    with_global_data_read(|g_data| {
+        let repl = &g_data.replication;
+        if let Some((host, port)) = &repl.master {
+            // Never connected: there's no replica side to do it
+            out_arr(out, 5);
+            out_str(out, "slave");
+            out_str(out, host);
+            out_int(out, *port as i64);
+            out_str(out, "connect");
+            out_int(out, -1);
+            return;
+        }
+        out_arr(out, 3);
+        out_str(out, "master");
+        out_int(out, repl.master_repl_offset as i64);
+        let mut replicas: Vec<(&RawFd, &Replica)> = repl.replicas.iter().collect();
+        replicas.sort_by_key(|(fd, _)| **fd);
+        out_arr(out, replicas.len() as u32);
+        for (_, replica) in replicas {
+            // Ports and offsets are strings here, as Redis sends them
+            out_arr(out, 3);
+            out_str(out, &replica.ip);
+            out_str(out, &replica.port.to_string());
+            out_str(out, &replica.acked.to_string());
+        }
+    });
+    Ok(())
+}
+
+// REPLICAOF NO ONE | REPLICAOF host port
+//
+// Only NO ONE, which makes a server FAILOVER demoted a master again, under a
+// new replication ID as its stream may have parted from its old replicas'.
+pub fn do_replicaof(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if !(cmd[1].eq_ignore_ascii_case("NO") && cmd[2].eq_ignore_ascii_case("ONE")) {
+        out_err(out, "ERR REPLICAOF host port is not supported: this server can't replicate from a master");
+        return Ok(());
+    }
+    with_global_data(|g_data| {
+        let repl = &mut g_data.replication;
+        if let Some((host, port)) = repl.master.take() {
+            repl.replid = new_replid();
+            log_notice!("repl", "No longer following {}:{}, master again with replid {}", host, port, repl.replid);
+        }
+    });
+    out_str(out, "OK");
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use redis::protocol::{FrameLimits, RedisValue, MAX_ARGS};
+
+    use crate::sim::testing::shared;
+    use crate::{get_current_time_ms, resp, with_global_data};
+
+    // The requests that went down the stream after `offset`
+    fn stream_since(offset: u64) -> Vec<Vec<String>> {
+        let bytes = with_global_data(|g_data| g_data.replication.backlog.as_ref().unwrap().since(offset).unwrap());
+        let limits = FrameLimits { max_request_size: usize::MAX, max_args: MAX_ARGS, max_arg_len: i64::MAX as usize };
+        let mut requests = Vec::new();
+        let mut rest = &bytes[..];
+        while let Some((args, used)) = resp::parse_request(rest, &limits).unwrap() {
+            requests.push(args);
+            rest = &rest[used..];
+        }
+        assert!(rest.is_empty());
+        requests
+    }
+
+    // Replicas and the AOF apply the stream later than the master ran it, so
+    // a TTL only means the same there as the unix time it comes to. RENAME
+    // and COPY carry the TTL the key has there, set by such a PEXPIREAT.
+    #[test]
+    fn ttls_go_down_the_stream_as_absolute_times() {
+        let mut sim = shared();
+        let offset = with_global_data(|g_data| {
+            g_data.replication.start_backlog();
+            g_data.replication.master_repl_offset
+        });
+        let now = get_current_time_ms();
+
+        sim.call("repl", "SET prop:a v");
+        sim.call("repl", "EXPIRE prop:a 100");
+        sim.call("repl", "PEXPIRE prop:a 5000");
+        sim.call("repl", &format!("EXPIREAT prop:a {}", now / 1000 + 200));
+        sim.call("repl", &format!("PEXPIREAT prop:a {}", now + 300_000));
+        // Not set, so not sent
+        sim.call("repl", "PEXPIRE prop:missing 1000");
+        sim.call("repl", "PEXPIRE prop:a 1000 NX");
+        sim.call("repl", "RENAME prop:a prop:b");
+        sim.call("repl", "COPY prop:b prop:c");
+
+        let pexpireat = |at: u64| vec!["PEXPIREAT".to_string(), "prop:a".to_string(), at.to_string()];
+        let stream = stream_since(offset);
+        assert_eq!(
+            stream,
+            [
+                vec!["SET".to_string(), "prop:a".to_string(), "v".to_string()],
+                pexpireat(now + 100_000),
+                pexpireat(now + 5000),
+                pexpireat((now / 1000 + 200) * 1000),
+                pexpireat(now + 300_000),
+                vec!["RENAME".to_string(), "prop:a".to_string(), "prop:b".to_string()],
+                vec!["COPY".to_string(), "prop:b".to_string(), "prop:c".to_string()],
+            ]
+        );
+        for key in ["prop:b", "prop:c"] {
+            assert_eq!(sim.call("repl", &format!("TTL {}", key)), RedisValue::Int(300));
+        }
+        with_global_data(|g_data| g_data.replication.backlog = None);
+    }
+
+    // Replicas keep a key until the master says it's gone
+    #[test]
+    fn expired_keys_go_down_the_stream_as_dels() {
+        let mut sim = shared();
+        let offset = with_global_data(|g_data| {
+            g_data.replication.start_backlog();
+            g_data.replication.master_repl_offset
+        });
+        let now = get_current_time_ms();
+
+        sim.call("repl", "SET prop:gone v");
+        sim.call("repl", "PEXPIRE prop:gone 100");
+        sim.advance(200).unwrap();
+        assert_eq!(sim.call("repl", "GET prop:gone"), RedisValue::Nil);
+
+        let args = |line: &str| line.split(' ').map(str::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            stream_since(offset),
+            [
+                args("SET prop:gone v"),
+                args(&format!("PEXPIREAT prop:gone {}", now + 100)),
+                args("DEL prop:gone"),
+            ]
+        );
+        with_global_data(|g_data| g_data.replication.backlog = None);
+    }
+}
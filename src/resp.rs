@@ -0,0 +1,377 @@
+/* Text protocols */
+//
+// Besides the length-prefixed frames, a connection may talk in text, as
+// telnet and Redis clients do. Which one is decided from the first 4 bytes a
+// client sends: a frame header for any allowed request size has a high byte
+// below '\t', which never shows up in a command line. Text connections then
+// send, per request, either
+//
+//   PING hello\r\n                               inline, split like redis-cli
+//   *2\r\n$4\r\nPING\r\n$5\r\nhello\r\n          RESP multibulk
+//
+// and get RESP2 replies back. Replies are produced in the Tag format as for
+// every other client and translated by encode_reply.
+
+use redis::protocol::{check_args, split_args, FrameLimits, ProtocolError, RedisValue};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Proto {
+    // Fewer than 4 bytes seen so far
+    Detecting,
+    Binary,
+    Text,
+}
+
+/// Classify a connection by the first 4 bytes it sent
+pub fn detect(head: &[u8]) -> Proto {
+    if head[3] < b'\t' {
+        Proto::Binary
+    } else {
+        Proto::Text
+    }
+}
+
+fn find_newline(buf: &[u8], from: usize) -> Option<usize> {
+    buf.get(from..)?.iter().position(|&b| b == b'\n').map(|pos| from + pos)
+}
+
+// The number after a '*' or '$' marker on the line buf[from..newline]
+fn parse_count(buf: &[u8], from: usize, newline: usize) -> Option<i64> {
+    let line = buf[from..newline].strip_suffix(b"\r")?;
+    std::str::from_utf8(line).ok()?.parse().ok()
+}
+
+/// A multibulk request received in part: the arguments parsed so far and
+/// where the next one starts. Kept with the connection between reads, so a
+/// large request isn't parsed again from its first byte every time more of it
+/// arrives.
+#[derive(Debug, Default)]
+pub struct Partial {
+    args: Vec<String>,
+    count: usize,
+    pos: usize,
+}
+
+/// Parse one request from the front of `buf`. Returns the arguments and the
+/// bytes used, None if the request isn't complete yet, or an error when the
+/// stream is malformed and the connection should be closed.
+pub fn parse_request(buf: &[u8], limits: &FrameLimits) -> Result<Option<(Vec<String>, usize)>, ProtocolError> {
+    parse_request_resuming(buf, limits, &mut None)
+}
+
+/// parse_request for a buffer that only grows at the back until the request
+/// is complete: an incomplete multibulk is left in `partial`, and the next
+/// call picks up from there.
+pub fn parse_request_resuming(
+    buf: &[u8],
+    limits: &FrameLimits,
+    partial: &mut Option<Partial>,
+) -> Result<Option<(Vec<String>, usize)>, ProtocolError> {
+    let parsed = if buf.first() == Some(&b'*') {
+        parse_multibulk(buf, limits, partial)?
+    } else {
+        parse_inline(buf)?
+    };
+    match parsed {
+        Some((_, used)) if used > limits.max_request_size => {
+            Err(ProtocolError::RequestTooLarge { size: used, limit: limits.max_request_size })
+        }
+        Some((args, used)) => {
+            check_args(&args, limits)?;
+            Ok(Some((args, used)))
+        }
+        None if buf.len() > limits.max_request_size => {
+            Err(ProtocolError::RequestTooLarge { size: buf.len(), limit: limits.max_request_size })
+        }
+        None => Ok(None),
+    }
+}
+
+fn parse_inline(buf: &[u8]) -> Result<Option<(Vec<String>, usize)>, ProtocolError> {
+    let Some(newline) = find_newline(buf, 0) else {
+        return Ok(None);
+    };
+
+    let line = buf[..newline].strip_suffix(b"\r").unwrap_or(&buf[..newline]);
+    let line = std::str::from_utf8(line).map_err(|_| ProtocolError::InvalidUtf8)?;
+    Ok(Some((split_args(line)?, newline + 1)))
+}
+
+fn parse_multibulk(
+    buf: &[u8],
+    limits: &FrameLimits,
+    partial: &mut Option<Partial>,
+) -> Result<Option<(Vec<String>, usize)>, ProtocolError> {
+    let mut request = match partial.take() {
+        Some(request) => request,
+        None => {
+            let Some(newline) = find_newline(buf, 0) else {
+                return Ok(None);
+            };
+            let count = match parse_count(buf, 1, newline) {
+                Some(n) if n > limits.max_args as i64 => {
+                    return Err(ProtocolError::TooManyArgs { limit: limits.max_args });
+                }
+                Some(n) if n >= 0 => n as usize,
+                _ => return Err(ProtocolError::InvalidMultibulkLength),
+            };
+            // Don't trust the count for the allocation, every argument is >= 1 byte
+            Partial { args: Vec::with_capacity(count.min(buf.len())), count, pos: newline + 1 }
+        }
+    };
+
+    while request.args.len() < request.count {
+        let pos = request.pos;
+        let Some(newline) = find_newline(buf, pos) else {
+            *partial = Some(request);
+            return Ok(None);
+        };
+        if buf[pos] != b'$' {
+            return Err(ProtocolError::ExpectedBulk(buf[pos]));
+        }
+        let len = match parse_count(buf, pos + 1, newline) {
+            Some(n) if n > limits.max_arg_len as i64 => {
+                return Err(ProtocolError::ArgTooLong { len: n as usize, limit: limits.max_arg_len });
+            }
+            Some(n) if n >= 0 => n as usize,
+            _ => return Err(ProtocolError::InvalidBulkLength),
+        };
+
+        let data = newline + 1;
+        if buf.len() < data + len + 2 {
+            *partial = Some(request);
+            return Ok(None);
+        }
+        if &buf[data + len..data + len + 2] != b"\r\n" {
+            return Err(ProtocolError::MissingCrlf);
+        }
+        let arg = std::str::from_utf8(&buf[data..data + len]).map_err(|_| ProtocolError::InvalidUtf8)?;
+        request.args.push(arg.to_string());
+        request.pos = data + len + 2;
+    }
+    Ok(Some((request.args, request.pos)))
+}
+
+/// Append `args` to `out` as a RESP multibulk request
+pub fn encode_request(args: &[String], out: &mut Vec<u8>) {
+    out.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        encode_bulk(arg, out);
+    }
+}
+
+fn encode_bulk(s: &str, out: &mut Vec<u8>) {
+    out.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+    out.extend_from_slice(s.as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+/// Append `value` to `out` as RESP2. Strings go out as bulk strings since the
+/// Tag format doesn't tell status replies apart; doubles as bulk strings too.
+/// Push messages, which only clients that asked for them get, use the RESP3
+/// push type.
+pub fn encode_reply(value: &RedisValue, out: &mut Vec<u8>) {
+    match value {
+        RedisValue::Nil => out.extend_from_slice(b"$-1\r\n"),
+        RedisValue::Err(msg) => {
+            // An error line can't contain line breaks
+            let msg = msg.replace(['\r', '\n'], " ");
+            out.extend_from_slice(format!("-{}\r\n", msg).as_bytes());
+        }
+        RedisValue::Str(s) => encode_bulk(s, out),
+        RedisValue::Int(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+        RedisValue::Dbl(d) => encode_bulk(&d.to_string(), out),
+        RedisValue::Arr(items) | RedisValue::Push(items) => {
+            let marker = if matches!(value, RedisValue::Push(_)) { '>' } else { '*' };
+            out.extend_from_slice(format!("{}{}\r\n", marker, items.len()).as_bytes());
+            for item in items {
+                encode_reply(item, out);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::protocol::{ReplyDecoder, ReplyFormat};
+
+    use super::*;
+
+    const LIMITS: FrameLimits = FrameLimits { max_request_size: 1024 * 1024, max_args: 1024, max_arg_len: 512 * 1024 };
+
+    #[test]
+    fn a_request_arriving_in_pieces_is_parsed_once() {
+        let args: Vec<String> = vec!["SET".into(), "key".into(), "x".repeat(1000)];
+        let mut request = Vec::new();
+        encode_request(&args, &mut request);
+        request.extend_from_slice(b"PING\r\n");
+
+        // Fed a byte at a time, each argument is taken once, when it's all there
+        let mut partial = None;
+        let mut parsed = None;
+        for end in 1..=request.len() {
+            let before = partial.as_ref().map_or(0, |partial: &Partial| partial.args.len());
+            match parse_request_resuming(&request[..end], &LIMITS, &mut partial).unwrap() {
+                Some(request) => {
+                    parsed = Some(request);
+                    break;
+                }
+                None => {
+                    let after = partial.as_ref().map_or(0, |partial| partial.args.len());
+                    assert!(after == before || after == before + 1, "{} arguments, then {}", before, after);
+                }
+            }
+        }
+        let (parsed, used) = parsed.unwrap();
+        assert_eq!(parsed, args);
+        assert_eq!(used, request.len() - b"PING\r\n".len());
+        assert!(partial.is_none());
+
+        // The connection's next request starts over
+        let rest = &request[used..];
+        assert_eq!(parse_request_resuming(rest, &LIMITS, &mut partial).unwrap(), Some((vec!["PING".to_string()], 6)));
+    }
+
+    #[test]
+    fn a_huge_count_is_not_preallocated() {
+        let limits = FrameLimits { max_args: 1 << 48, ..LIMITS };
+        let mut partial = None;
+        assert_eq!(parse_request_resuming(b"*1000000000000\r\n", &limits, &mut partial).unwrap(), None);
+        assert!(partial.unwrap().args.capacity() <= 16);
+    }
+
+    #[test]
+    fn malformed_requests_get_their_error() {
+        let cases: &[(&[u8], ProtocolError)] = &[
+            (b"*x\r\n", ProtocolError::InvalidMultibulkLength),
+            (b"*-1\r\n", ProtocolError::InvalidMultibulkLength),
+            (b"*1025\r\n", ProtocolError::TooManyArgs { limit: 1024 }),
+            (b"*1\r\n$x\r\n", ProtocolError::InvalidBulkLength),
+            (b"*1\r\n$-1\r\n", ProtocolError::InvalidBulkLength),
+            (b"*1\r\n$600000\r\n", ProtocolError::ArgTooLong { len: 600_000, limit: 512 * 1024 }),
+            (b"*1\r\n+PING\r\n", ProtocolError::ExpectedBulk(b'+')),
+            (b"*1\r\n$4\r\nPINGxx", ProtocolError::MissingCrlf),
+            (b"*1\r\n$1\r\n\xff\r\n", ProtocolError::InvalidUtf8),
+            (b"GET \xff\r\n", ProtocolError::InvalidUtf8),
+            (b"GET \"key\r\n", ProtocolError::UnbalancedQuotes),
+            (b"GET \"\\xZZ\"\r\n", ProtocolError::InvalidEscape),
+        ];
+        for (input, error) in cases {
+            assert_eq!(parse_request(input, &LIMITS).as_ref(), Err(error), "{:?}", input.escape_ascii().to_string());
+        }
+
+        // Too large whether or not it's complete
+        let limits = FrameLimits { max_request_size: 16, ..LIMITS };
+        let mut request = Vec::new();
+        encode_request(&["SET".into(), "key".into(), "value".into()], &mut request);
+        assert_eq!(parse_request(&request, &limits), Err(ProtocolError::RequestTooLarge { size: 33, limit: 16 }));
+        assert_eq!(
+            parse_request(&[b'x'; 17], &limits),
+            Err(ProtocolError::RequestTooLarge { size: 17, limit: 16 })
+        );
+    }
+
+    // xorshift64*, as in bench
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        // Mostly bytes that mean something to RESP or an inline request
+        fn bytes(&mut self, len: u64) -> Vec<u8> {
+            const MEANINGFUL: &[u8] = b"*$+-:\r\n0123456789\"'\\x \xff";
+            (0..len)
+                .map(|_| match self.below(4) {
+                    0 => self.next() as u8,
+                    _ => MEANINGFUL[self.below(MEANINGFUL.len() as u64) as usize],
+                })
+                .collect()
+        }
+
+        fn text(&mut self) -> String {
+            let len = self.below(12);
+            (0..len).map(|_| char::from_u32(self.below(0x500) as u32).unwrap_or('?')).collect()
+        }
+
+        // Values encode_reply writes unchanged: doubles go out as strings,
+        // and an error loses its line breaks
+        fn value(&mut self, depth: u32) -> RedisValue {
+            match self.below(if depth < 3 { 6 } else { 4 }) {
+                0 => RedisValue::Nil,
+                1 => RedisValue::Err(self.text().replace(['\r', '\n'], " ")),
+                2 => RedisValue::Str(self.text()),
+                3 => RedisValue::Int(self.next() as i64),
+                4 => RedisValue::Arr((0..self.below(4)).map(|_| self.value(depth + 1)).collect()),
+                _ => RedisValue::Push((0..self.below(4)).map(|_| self.value(depth + 1)).collect()),
+            }
+        }
+    }
+
+    // Fixed seeds, so a failure comes back on every run; RESP_FUZZ_SEED
+    // runs just that one
+    fn seeds() -> Vec<u64> {
+        match std::env::var("RESP_FUZZ_SEED").ok().and_then(|v| v.parse().ok()) {
+            Some(seed) => vec![seed],
+            None => (1..=4).collect(),
+        }
+    }
+
+    #[test]
+    fn random_bytes_never_panic() {
+        for seed in seeds() {
+            let mut rng = Rng(seed | 1);
+            for _ in 0..20_000 {
+                let len = rng.below(48);
+                let input = rng.bytes(len);
+                let _ = parse_request(&input, &LIMITS);
+
+                // And arriving in pieces, as a connection reads it
+                let mut partial = None;
+                for end in 0..=input.len() {
+                    if !matches!(parse_request_resuming(&input[..end], &LIMITS, &mut partial), Ok(None)) {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn requests_and_replies_come_back_as_encoded() {
+        for seed in seeds() {
+            let mut rng = Rng(seed | 1);
+            for _ in 0..5_000 {
+                let args: Vec<String> = (0..1 + rng.below(4)).map(|_| rng.text()).collect();
+                let mut request = Vec::new();
+                encode_request(&args, &mut request);
+                let mut partial = None;
+                let mut end = 0;
+                let parsed = loop {
+                    end = (end + 1 + rng.below(8) as usize).min(request.len());
+                    if let Some(parsed) = parse_request_resuming(&request[..end], &LIMITS, &mut partial).unwrap() {
+                        break parsed;
+                    }
+                    assert!(end < request.len(), "seed {}: {:?} never came out", seed, args);
+                };
+                assert_eq!(parsed, (args, request.len()), "seed {}", seed);
+
+                let value = rng.value(0);
+                let mut reply = Vec::new();
+                encode_reply(&value, &mut reply);
+                let mut decoder = ReplyDecoder::new(ReplyFormat::Resp);
+                decoder.feed(&reply);
+                assert_eq!(decoder.next_value(), Ok(Some(value)), "seed {}", seed);
+                assert_eq!(decoder.pending(), 0);
+            }
+        }
+    }
+}
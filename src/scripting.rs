@@ -0,0 +1,685 @@
+/* Lua scripting */
+//
+// EVAL/EVALSHA run Lua 5.4 with only the table, string and math libraries
+// loaded. redis.call feeds commands through execute_command (by way of
+// handle::execute_value), the same entry point client requests use, so
+// anything hooked there (propagation, stats) sees script writes too. Scripts
+// are atomic because the event loop runs them to completion before serving
+// any other client.
+//
+// FUNCTION LOAD registers named libraries in a separate interpreter. Library
+// source is kept alongside the compiled functions so the snapshot can save it
+// and replay it at startup.
+//
+// Atomic means a script that loops forever would hang the server, so a hook
+// looks at the clock every HOOK_INSTRUCTIONS Lua instructions. Once a script
+// has run for `lua-time-limit` ms the server is busy: from then on the hook
+// also serves the other clients, replying BUSY to everything but SCRIPT KILL
+// (FUNCTION KILL for a function) and SHUTDOWN NOSAVE. KILL stops the script
+// with an error at the next hook, unless it has written already: what it did
+// can't be undone, so only SHUTDOWN NOSAVE gets rid of it then.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+use mlua::{Function, HookTriggers, Lua, LuaOptions, MultiValue, StdLib, Table, Value as LuaValue, VmState};
+
+use crate::command::{CmdResult, Command};
+use crate::logging::log_warning;
+use crate::{
+    command, get_monotonic_time_ms, handle, out_arr, out_err, out_int, out_nil, out_str, out_value,
+    serve_while_busy, with_global_data, Buffer, RedisValue,
+};
+
+// Lua instructions between looks at the clock
+const HOOK_INSTRUCTIONS: u32 = 100_000;
+
+struct Scripting {
+    lua: Lua,
+    // sha1 hex -> compiled script body
+    scripts: HashMap<String, Function>,
+}
+
+struct Library {
+    code: String,
+    functions: Vec<String>,
+}
+
+struct Functions {
+    lua: Lua,
+    // library name -> source and the functions it registered
+    libraries: BTreeMap<String, Library>,
+    // function name -> callback
+    functions: HashMap<String, Function>,
+}
+
+// The script or function being run
+struct Running {
+    started_ms: u64,
+    // Run by FCALL rather than EVAL/EVALSHA
+    function: bool,
+    // Past lua-time-limit: other clients get BUSY
+    busy: bool,
+    // Ran a write command, so it can't be killed
+    wrote: bool,
+    // SCRIPT KILL or FUNCTION KILL asked to stop it
+    kill: bool,
+}
+
+thread_local! {
+    static SCRIPTING: RefCell<Option<Scripting>> = const { RefCell::new(None) };
+    static FUNCTIONS: RefCell<Option<Functions>> = const { RefCell::new(None) };
+    static RUNNING: RefCell<Option<Running>> = const { RefCell::new(None) };
+}
+
+fn with_running<R>(f: impl FnOnce(&mut Option<Running>) -> R) -> R {
+    RUNNING.with(|cell| f(&mut cell.borrow_mut()))
+}
+
+/// The BUSY error for other clients while a script runs past lua-time-limit,
+/// None when the server isn't busy
+pub fn busy_error() -> Option<&'static str> {
+    with_running(|running| match running {
+        Some(Running { busy: true, function: false, .. }) => {
+            Some("BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.")
+        }
+        Some(Running { busy: true, function: true, .. }) => {
+            Some("BUSY Redis is busy running a script. You can only call FUNCTION KILL or SHUTDOWN NOSAVE.")
+        }
+        _ => None,
+    })
+}
+
+/// Whether a command may run while the server is busy with a script
+pub fn allowed_while_busy(command: &Command, args: &[String]) -> bool {
+    let subcommand = args.get(1).map(|arg| arg.to_uppercase());
+    matches!((command.name, subcommand.as_deref()), ("script" | "function", Some("KILL")) | ("shutdown", Some("NOSAVE")))
+}
+
+/// Whether a script is running, whether or not it's busy yet
+pub fn is_running() -> bool {
+    with_running(|running| running.is_some())
+}
+
+// Run `f` as the running script, None if it was killed
+fn run_timed<R>(function: bool, f: impl FnOnce() -> R) -> Option<R> {
+    with_running(|running| {
+        *running = Some(Running { started_ms: get_monotonic_time_ms(), function, busy: false, wrote: false, kill: false })
+    });
+    let result = f();
+    let running = with_running(Option::take).expect("the running script is gone");
+    if running.busy {
+        log_warning!("cmd", "Slow script finished after {} ms", get_monotonic_time_ms() - running.started_ms);
+    }
+    (!running.kill).then_some(result)
+}
+
+// The hook: check the clock, and while busy serve the other clients
+fn check_running() -> mlua::Result<VmState> {
+    let limit_ms = with_global_data(|g_data| g_data.config.lua_time_limit);
+    let now_ms = get_monotonic_time_ms();
+    let became_busy = with_running(|running| match running {
+        Some(running) if !running.busy && limit_ms > 0 && now_ms - running.started_ms >= limit_ms => {
+            running.busy = true;
+            true
+        }
+        _ => false,
+    });
+    if became_busy {
+        log_warning!(
+            "cmd",
+            "Slow script detected: still in execution after {} ms. Other clients get BUSY until it ends or is killed.",
+            limit_ms
+        );
+    }
+
+    if busy_error().is_some() {
+        serve_while_busy();
+    }
+    // Raised again at every hook, should the script catch it with pcall
+    if with_running(|running| running.as_ref().is_some_and(|running| running.kill)) {
+        return Err(mlua::Error::RuntimeError("ERR Script killed by user".to_string()));
+    }
+    Ok(VmState::Continue)
+}
+
+// SCRIPT KILL (function = false) / FUNCTION KILL
+fn kill(function: bool) -> Result<(), &'static str> {
+    with_running(|running| match running {
+        Some(running) if running.function == function => {
+            if running.wrote {
+                return Err("UNKILLABLE Sorry the script already executed write commands against the dataset. \
+                    You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.");
+            }
+            running.kill = true;
+            Ok(())
+        }
+        _ => Err("NOTBUSY No scripts in execution right now."),
+    })
+}
+
+pub fn sha1_hex(body: &str) -> String {
+    sha1_smol::Sha1::from(body).digest().to_string()
+}
+
+fn create_lua() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH, LuaOptions::default())?;
+
+    let redis = lua.create_table()?;
+    redis.set("call", lua.create_function(|lua, args: MultiValue| redis_call(lua, args, true))?)?;
+    redis.set("pcall", lua.create_function(|lua, args: MultiValue| redis_call(lua, args, false))?)?;
+    redis.set(
+        "error_reply",
+        lua.create_function(|lua, msg: String| {
+            let t = lua.create_table()?;
+            t.set("err", msg)?;
+            Ok(t)
+        })?,
+    )?;
+    redis.set(
+        "status_reply",
+        lua.create_function(|lua, msg: String| {
+            let t = lua.create_table()?;
+            t.set("ok", msg)?;
+            Ok(t)
+        })?,
+    )?;
+    lua.globals().set("redis", redis)?;
+    lua.set_hook(HookTriggers::new().every_nth_instruction(HOOK_INSTRUCTIONS), |_, _| check_running());
+
+    Ok(lua)
+}
+
+// Run `f` with the interpreter, creating it on first use
+fn with_scripting<R>(f: impl FnOnce(&mut Scripting) -> R) -> R {
+    SCRIPTING.with(|cell| {
+        let mut slot = cell.borrow_mut();
+        let scripting = slot.get_or_insert_with(|| Scripting {
+            lua: create_lua().expect("failed to initialize Lua"),
+            scripts: HashMap::new(),
+        });
+        f(scripting)
+    })
+}
+
+/// Compile and cache a script body, returning its SHA1
+fn load_script(body: &str) -> Result<String, String> {
+    let sha = sha1_hex(body);
+    with_scripting(|scripting| {
+        if !scripting.scripts.contains_key(&sha) {
+            let function = scripting
+                .lua
+                .load(body)
+                .set_name(format!("@user_script_{}", sha))
+                .into_function()
+                .map_err(|e| format!("ERR Error compiling script (new function): {}", e))?;
+            scripting.scripts.insert(sha.clone(), function);
+        }
+        Ok(sha)
+    })
+}
+
+// redis.call / redis.pcall
+fn redis_call(lua: &Lua, args: MultiValue, raise: bool) -> mlua::Result<LuaValue> {
+    let mut cmd = Vec::with_capacity(args.len());
+    for arg in args {
+        match arg {
+            LuaValue::String(s) => cmd.push(s.to_str()?.to_string()),
+            LuaValue::Integer(n) => cmd.push(n.to_string()),
+            LuaValue::Number(n) => cmd.push(n.to_string()),
+            _ => {
+                return Err(mlua::Error::RuntimeError(
+                    "Lua redis lib command arguments must be strings or integers".to_string(),
+                ));
+            }
+        }
+    }
+
+    let reply = match cmd.first() {
+        None => RedisValue::Err("ERR Please specify at least one argument for this redis lib call".to_string()),
+        Some(name) if command::lookup(name).is_some_and(|c| c.has_flag(command::CMD_NOSCRIPT)) => {
+            RedisValue::Err("ERR This Redis command is not allowed from script".to_string())
+        }
+        Some(name) => {
+            if command::lookup(name).is_some_and(|c| c.has_flag(command::CMD_WRITE)) {
+                with_running(|running| running.iter_mut().for_each(|running| running.wrote = true));
+            }
+            // Blocking commands behave as if they timed out inside a script
+            handle::execute_value(&cmd)
+        }
+    };
+
+    match reply {
+        RedisValue::Err(msg) if raise => Err(mlua::Error::RuntimeError(msg)),
+        other => to_lua(lua, &other),
+    }
+}
+
+// Command reply -> Lua, following the Redis conversion rules
+fn to_lua(lua: &Lua, value: &RedisValue) -> mlua::Result<LuaValue> {
+    Ok(match value {
+        RedisValue::Nil => LuaValue::Boolean(false),
+        RedisValue::Int(n) => LuaValue::Integer(*n),
+        RedisValue::Dbl(d) => LuaValue::Number(*d),
+        RedisValue::Str(s) => LuaValue::String(lua.create_string(s)?),
+        RedisValue::Err(msg) => {
+            let t = lua.create_table()?;
+            t.set("err", msg.as_str())?;
+            LuaValue::Table(t)
+        }
+        RedisValue::Arr(items) | RedisValue::Push(items) => {
+            let t = lua.create_table_with_capacity(items.len(), 0)?;
+            for (i, item) in items.iter().enumerate() {
+                t.raw_set(i + 1, to_lua(lua, item)?)?;
+            }
+            LuaValue::Table(t)
+        }
+    })
+}
+
+// Lua return value -> reply
+fn from_lua(value: &LuaValue) -> RedisValue {
+    match value {
+        LuaValue::Nil => RedisValue::Nil,
+        LuaValue::Boolean(true) => RedisValue::Int(1),
+        LuaValue::Boolean(false) => RedisValue::Nil,
+        LuaValue::Integer(n) => RedisValue::Int(*n),
+        // Lua numbers are truncated to integers, as in Redis
+        LuaValue::Number(n) => RedisValue::Int(*n as i64),
+        LuaValue::String(s) => RedisValue::Str(s.to_string_lossy()),
+        LuaValue::Table(t) => table_to_reply(t),
+        _ => RedisValue::Nil,
+    }
+}
+
+fn table_to_reply(t: &Table) -> RedisValue {
+    if let Ok(LuaValue::String(msg)) = t.raw_get::<LuaValue>("err") {
+        return RedisValue::Err(msg.to_string_lossy());
+    }
+    if let Ok(LuaValue::String(msg)) = t.raw_get::<LuaValue>("ok") {
+        return RedisValue::Str(msg.to_string_lossy());
+    }
+    // Array part up to the first nil
+    let mut items = Vec::new();
+    for i in 1.. {
+        match t.raw_get::<LuaValue>(i) {
+            Ok(LuaValue::Nil) | Err(_) => break,
+            Ok(value) => items.push(from_lua(&value)),
+        }
+    }
+    RedisValue::Arr(items)
+}
+
+fn run_script(sha: &str, keys: &[String], argv: &[String]) -> RedisValue {
+    // Clone the handles out so the interpreter isn't borrowed while the
+    // script calls back into the command dispatcher
+    let Some((lua, function)) =
+        with_scripting(|scripting| scripting.scripts.get(sha).map(|f| (scripting.lua.clone(), f.clone())))
+    else {
+        return RedisValue::Err("NOSCRIPT No matching script. Please use EVAL.".to_string());
+    };
+
+    let setup = || -> mlua::Result<()> {
+        let globals = lua.globals();
+        globals.set("KEYS", lua.create_sequence_from(keys.iter().map(|k| k.as_str()))?)?;
+        globals.set("ARGV", lua.create_sequence_from(argv.iter().map(|a| a.as_str()))?)?;
+        // Same random sequence on every run keeps scripts deterministic
+        lua.load("math.randomseed(0)").exec()
+    };
+    if let Err(e) = setup() {
+        return RedisValue::Err(format!("ERR Error preparing script: {}", e));
+    }
+
+    match run_timed(false, || function.call::<LuaValue>(())) {
+        Some(Ok(value)) => from_lua(&value),
+        Some(Err(e)) => {
+            RedisValue::Err(format!("ERR Error running script (call to f_{}): {}", sha, script_error_message(&e)))
+        }
+        None => RedisValue::Err("ERR Script killed by user with SCRIPT KILL...".to_string()),
+    }
+}
+
+// Unwrap callback errors so a failing redis.call reports the command's own message
+fn script_error_message(e: &mlua::Error) -> String {
+    match e {
+        mlua::Error::CallbackError { cause, .. } => script_error_message(cause),
+        mlua::Error::RuntimeError(msg) => msg.clone(),
+        other => other.to_string(),
+    }
+}
+
+// Split "numkeys key [key ...] arg [arg ...]" into (keys, argv)
+fn split_keys_args(args: &[String]) -> Result<(&[String], &[String]), &'static str> {
+    let numkeys: i64 = args
+        .first()
+        .and_then(|n| n.parse().ok())
+        .ok_or("ERR value is not an integer or out of range")?;
+    if numkeys < 0 {
+        return Err("ERR Number of keys can't be negative");
+    }
+    let rest = &args[1..];
+    if numkeys as usize > rest.len() {
+        return Err("ERR Number of keys can't be greater than number of args");
+    }
+    Ok(rest.split_at(numkeys as usize))
+}
+
+// EVAL script numkeys [key ...] [arg ...]
+pub fn do_eval(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 3 {
+        out_err(out, "EVAL requires: script numkeys [key ...] [arg ...]");
+        return Ok(());
+    }
+
+    let (keys, argv) = match split_keys_args(&cmd[2..]) {
+        Ok(split) => split,
+        Err(msg) => {
+            out_err(out, msg);
+            return Ok(());
+        }
+    };
+
+    match load_script(&cmd[1]) {
+        Ok(sha) => out_value(out, &run_script(&sha, keys, argv)),
+        Err(msg) => out_err(out, &msg),
+    }
+    Ok(())
+}
+
+// EVALSHA sha1 numkeys [key ...] [arg ...]
+pub fn do_evalsha(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 3 {
+        out_err(out, "EVALSHA requires: sha1 numkeys [key ...] [arg ...]");
+        return Ok(());
+    }
+
+    let (keys, argv) = match split_keys_args(&cmd[2..]) {
+        Ok(split) => split,
+        Err(msg) => {
+            out_err(out, msg);
+            return Ok(());
+        }
+    };
+
+    out_value(out, &run_script(&cmd[1].to_lowercase(), keys, argv));
+    Ok(())
+}
+
+// SCRIPT LOAD script | SCRIPT EXISTS sha1 [sha1 ...] | SCRIPT FLUSH | SCRIPT KILL
+pub fn do_script(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 2 {
+        out_err(out, "SCRIPT requires: LOAD|EXISTS|FLUSH|KILL ...");
+        return Ok(());
+    }
+
+    match cmd[1].to_uppercase().as_str() {
+        "LOAD" if cmd.len() == 3 => match load_script(&cmd[2]) {
+            Ok(sha) => out_str(out, &sha),
+            Err(msg) => out_err(out, &msg),
+        },
+        "EXISTS" if cmd.len() > 2 => {
+            let shas = &cmd[2..];
+            out_arr(out, shas.len() as u32);
+            with_scripting(|scripting| {
+                for sha in shas {
+                    out_int(out, scripting.scripts.contains_key(&sha.to_lowercase()) as i64);
+                }
+            });
+        }
+        "FLUSH" => {
+            // A fresh interpreter also drops any globals scripts left behind
+            SCRIPTING.with(|cell| cell.borrow_mut().take());
+            out_nil(out);
+        }
+        "KILL" if cmd.len() == 2 => match kill(false) {
+            Ok(()) => out_nil(out),
+            Err(msg) => out_err(out, msg),
+        },
+        _ => out_err(out, "ERR unknown SCRIPT subcommand or wrong number of arguments"),
+    }
+    Ok(())
+}
+
+/* Functions */
+
+// Run `f` with the function engine, creating it on first use
+fn with_functions<R>(f: impl FnOnce(&mut Functions) -> R) -> R {
+    FUNCTIONS.with(|cell| {
+        let mut slot = cell.borrow_mut();
+        let functions = slot.get_or_insert_with(|| Functions {
+            lua: create_lua().expect("failed to initialize Lua"),
+            libraries: BTreeMap::new(),
+            functions: HashMap::new(),
+        });
+        f(functions)
+    })
+}
+
+// Parse the "#!lua name=<library>" shebang, returning the library name
+fn parse_shebang(code: &str) -> Result<String, String> {
+    let first_line = code.lines().next().unwrap_or("");
+    let Some(shebang) = first_line.strip_prefix("#!") else {
+        return Err("ERR Missing library metadata".to_string());
+    };
+
+    let mut parts = shebang.split_whitespace();
+    let engine = parts.next().unwrap_or("");
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(format!("ERR Engine '{}' not found", engine));
+    }
+
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(value) => name = Some(value.to_string()),
+            None => return Err(format!("ERR Invalid metadata value given: {}", part)),
+        }
+    }
+    let name = name.ok_or_else(|| "ERR Library name was not given".to_string())?;
+    if !valid_function_name(&name) {
+        return Err("ERR Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string());
+    }
+    Ok(name)
+}
+
+fn valid_function_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// redis.register_function('name', callback) or
+// redis.register_function{function_name='name', callback=callback}
+fn register_function_args(args: MultiValue) -> mlua::Result<(String, Function)> {
+    let mut args = args.into_iter();
+    match (args.next(), args.next()) {
+        (Some(LuaValue::String(name)), Some(LuaValue::Function(callback))) => Ok((name.to_str()?.to_string(), callback)),
+        (Some(LuaValue::Table(t)), None) => {
+            let name: String = t.get("function_name")?;
+            let callback: Function = t.get("callback")?;
+            Ok((name, callback))
+        }
+        _ => Err(mlua::Error::RuntimeError(
+            "wrong arguments given to redis.register_function".to_string(),
+        )),
+    }
+}
+
+/// Load a library from its source, returning the library name.
+/// With `replace`, an existing library of the same name is swapped out.
+pub fn function_load(code: &str, replace: bool) -> Result<String, String> {
+    let name = parse_shebang(code)?;
+
+    with_functions(|engine| {
+        if engine.libraries.contains_key(&name) && !replace {
+            return Err(format!("ERR Library '{}' already exists", name));
+        }
+
+        // Lua doesn't skip a leading "#" line when loading from a string, so
+        // blank the shebang out (keeping line numbers in error messages right)
+        let body = match code.find('\n') {
+            Some(pos) => &code[pos..],
+            None => "",
+        };
+
+        // register_function only exists while the library body runs
+        let registered = Rc::new(RefCell::new(Vec::<(String, Function)>::new()));
+        let run = || -> mlua::Result<()> {
+            let redis: Table = engine.lua.globals().get("redis")?;
+            let sink = registered.clone();
+            redis.set(
+                "register_function",
+                engine.lua.create_function(move |_, args: MultiValue| {
+                    sink.borrow_mut().push(register_function_args(args)?);
+                    Ok(())
+                })?,
+            )?;
+            let result = engine.lua.load(body).set_name(format!("@user_function_{}", name)).exec();
+            redis.set("register_function", LuaValue::Nil)?;
+            result
+        };
+        run().map_err(|e| format!("ERR Error registering functions: {}", script_error_message(&e)))?;
+
+        let registered = registered.take();
+        if registered.is_empty() {
+            return Err("ERR No functions registered".to_string());
+        }
+        for (i, (function_name, _)) in registered.iter().enumerate() {
+            if !valid_function_name(function_name) {
+                return Err("ERR Function names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string());
+            }
+            let owned_by_other = engine
+                .libraries
+                .iter()
+                .any(|(lib, library)| *lib != name && library.functions.contains(function_name));
+            if owned_by_other || registered[..i].iter().any(|(other, _)| other == function_name) {
+                return Err(format!("ERR Function {} already exists", function_name));
+            }
+        }
+
+        if let Some(old) = engine.libraries.remove(&name) {
+            for function_name in old.functions {
+                engine.functions.remove(&function_name);
+            }
+        }
+        let mut functions = Vec::with_capacity(registered.len());
+        for (function_name, callback) in registered {
+            functions.push(function_name.clone());
+            engine.functions.insert(function_name, callback);
+        }
+        engine.libraries.insert(name.clone(), Library { code: code.to_string(), functions });
+        Ok(name)
+    })
+}
+
+/// Source of every loaded library, in name order, for the snapshot
+pub fn function_library_codes() -> Vec<String> {
+    FUNCTIONS.with(|cell| match cell.borrow().as_ref() {
+        Some(engine) => engine.libraries.values().map(|library| library.code.clone()).collect(),
+        None => Vec::new(),
+    })
+}
+
+fn run_function(name: &str, keys: &[String], argv: &[String]) -> RedisValue {
+    let Some((lua, callback)) =
+        with_functions(|engine| engine.functions.get(name).map(|f| (engine.lua.clone(), f.clone())))
+    else {
+        return RedisValue::Err("ERR Function not found".to_string());
+    };
+
+    let call = || -> mlua::Result<LuaValue> {
+        let keys = lua.create_sequence_from(keys.iter().map(|k| k.as_str()))?;
+        let argv = lua.create_sequence_from(argv.iter().map(|a| a.as_str()))?;
+        callback.call::<LuaValue>((keys, argv))
+    };
+    match run_timed(true, call) {
+        Some(Ok(value)) => from_lua(&value),
+        Some(Err(e)) => RedisValue::Err(format!("ERR Error running function {}: {}", name, script_error_message(&e))),
+        None => RedisValue::Err("ERR Script killed by user with FUNCTION KILL...".to_string()),
+    }
+}
+
+// FCALL function numkeys [key ...] [arg ...]
+pub fn do_fcall(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 3 {
+        out_err(out, "FCALL requires: function numkeys [key ...] [arg ...]");
+        return Ok(());
+    }
+
+    let (keys, argv) = match split_keys_args(&cmd[2..]) {
+        Ok(split) => split,
+        Err(msg) => {
+            out_err(out, msg);
+            return Ok(());
+        }
+    };
+
+    out_value(out, &run_function(&cmd[1], keys, argv));
+    Ok(())
+}
+
+// FUNCTION LOAD [REPLACE] code | DELETE library | FLUSH | LIST [WITHCODE] | KILL
+pub fn do_function(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 2 {
+        out_err(out, "FUNCTION requires: LOAD|DELETE|FLUSH|LIST|KILL ...");
+        return Ok(());
+    }
+
+    match cmd[1].to_uppercase().as_str() {
+        "LOAD" if cmd.len() == 3 || (cmd.len() == 4 && cmd[2].eq_ignore_ascii_case("REPLACE")) => {
+            match function_load(&cmd[cmd.len() - 1], cmd.len() == 4) {
+                Ok(name) => out_str(out, &name),
+                Err(msg) => out_err(out, &msg),
+            }
+        }
+        "DELETE" if cmd.len() == 3 => {
+            let removed = with_functions(|engine| match engine.libraries.remove(&cmd[2]) {
+                Some(library) => {
+                    for function_name in library.functions {
+                        engine.functions.remove(&function_name);
+                    }
+                    true
+                }
+                None => false,
+            });
+            if removed {
+                out_nil(out);
+            } else {
+                out_err(out, "ERR Library not found");
+            }
+        }
+        "FLUSH" if cmd.len() == 2 => {
+            FUNCTIONS.with(|cell| cell.borrow_mut().take());
+            out_nil(out);
+        }
+        "LIST" if cmd.len() == 2 || (cmd.len() == 3 && cmd[2].eq_ignore_ascii_case("WITHCODE")) => {
+            let with_code = cmd.len() == 3;
+            with_functions(|engine| {
+                out_arr(out, engine.libraries.len() as u32);
+                for (name, library) in &engine.libraries {
+                    out_arr(out, if with_code { 8 } else { 6 });
+                    out_str(out, "library_name");
+                    out_str(out, name);
+                    out_str(out, "engine");
+                    out_str(out, "LUA");
+                    out_str(out, "functions");
+                    out_arr(out, library.functions.len() as u32);
+                    for function_name in &library.functions {
+                        out_arr(out, 2);
+                        out_str(out, "name");
+                        out_str(out, function_name);
+                    }
+                    if with_code {
+                        out_str(out, "library_code");
+                        out_str(out, &library.code);
+                    }
+                }
+            });
+        }
+        "KILL" if cmd.len() == 2 => match kill(true) {
+            Ok(()) => out_nil(out),
+            Err(msg) => out_err(out, msg),
+        },
+        _ => out_err(out, "ERR unknown FUNCTION subcommand or wrong number of arguments"),
+    }
+    Ok(())
+}
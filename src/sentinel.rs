@@ -0,0 +1,391 @@
+/* Sentinel */
+//
+//   redis sentinel [-p port] [--down-after ms] [--peer host:port]...
+//                  --monitor name host port quorum [--monitor ...]
+//
+// Watches masters and fails them over. Every PING_PERIOD each master gets a
+// PING, and a ROLE to learn its replicas while it's up. One that hasn't
+// answered for --down-after ms is subjectively down (+sdown); the sentinel
+// then asks each --peer whether it sees the same with SENTINEL
+// IS-MASTER-DOWN-BY-ADDR. When `quorum` sentinels, this one included, agree,
+// the master is objectively down (+odown): the first replica that still
+// answers is promoted with REPLICAOF NO ONE and monitored from then on as
+// the master (+switch-master).
+//
+// There's no leader election as in Redis: every sentinel that reaches +odown
+// promotes the first live replica in the order the master listed them, so
+// sentinels failing over at once pick the same one.
+//
+// Sentinels listen on -p for their peers and for clients:
+//
+//   PING
+//   SENTINEL MASTERS                             [name, ip, port, flags] per master
+//   SENTINEL GET-MASTER-ADDR-BY-NAME name        [ip, port] of the current master
+//   SENTINEL IS-MASTER-DOWN-BY-ADDR ip port      1 if this sentinel sees it down
+//
+// Events are logged in the Redis format ("+switch-master mymaster 127.0.0.1
+// 1234 127.0.0.1 1235"). With no pub/sub to publish them on yet, clients
+// learn about a failover by asking GET-MASTER-ADDR-BY-NAME again when their
+// master stops answering.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use redis::client::RedisClient;
+use redis::protocol::{parse_frame, FrameLimits, RedisValue};
+
+use crate::logging::{log_notice, log_warning};
+
+const DEFAULT_PORT: u16 = 26379;
+const PING_PERIOD: Duration = Duration::from_secs(1);
+// Connect and reply timeout when talking to masters, replicas and peers
+const IO_TIMEOUT: Duration = Duration::from_millis(500);
+// Wait this long before trying a failed failover again
+const FAILOVER_RETRY: Duration = Duration::from_secs(30);
+
+const LIMITS: FrameLimits = FrameLimits { max_request_size: 64 * 1024, max_args: 16, max_arg_len: 1024 };
+
+#[derive(Debug)]
+struct Master {
+    name: String,
+    host: String,
+    port: u16,
+    quorum: usize,
+    // From the last ROLE reply
+    replicas: Vec<(String, u16)>,
+    last_reply: Instant,
+    sdown: bool,
+    odown: bool,
+    last_failover: Option<Instant>,
+}
+
+impl Master {
+    fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    fn flags(&self) -> &'static str {
+        match (self.sdown, self.odown) {
+            (_, true) => "master,s_down,o_down",
+            (true, false) => "master,s_down",
+            (false, false) => "master",
+        }
+    }
+}
+
+type Masters = Arc<Mutex<Vec<Master>>>;
+
+struct Options {
+    port: u16,
+    down_after: Duration,
+    peers: Vec<String>,
+    masters: Vec<Master>,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: redis sentinel [-p port] [--down-after ms] [--peer host:port]... \
+         --monitor name host port quorum [--monitor ...]"
+    );
+    std::process::exit(1);
+}
+
+// The value after an option
+fn value(args: &mut std::slice::Iter<String>) -> String {
+    args.next().cloned().unwrap_or_else(|| usage())
+}
+
+fn parse_options(args: &[String]) -> Options {
+    let mut opts = Options {
+        port: DEFAULT_PORT,
+        down_after: Duration::from_secs(30),
+        peers: Vec::new(),
+        masters: Vec::new(),
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" => opts.port = value(&mut args).parse().unwrap_or_else(|_| usage()),
+            "--down-after" => {
+                let ms: u64 = value(&mut args).parse().ok().filter(|&ms| ms > 0).unwrap_or_else(|| usage());
+                opts.down_after = Duration::from_millis(ms);
+            }
+            "--peer" => opts.peers.push(value(&mut args)),
+            "--monitor" => {
+                let name = value(&mut args);
+                let host = value(&mut args);
+                let port = value(&mut args).parse().unwrap_or_else(|_| usage());
+                let quorum = value(&mut args).parse().ok().filter(|&q| q > 0).unwrap_or_else(|| usage());
+                opts.masters.push(Master {
+                    name,
+                    host,
+                    port,
+                    quorum,
+                    replicas: Vec::new(),
+                    last_reply: Instant::now(),
+                    sdown: false,
+                    odown: false,
+                    last_failover: None,
+                });
+            }
+            _ => usage(),
+        }
+    }
+    if opts.masters.is_empty() {
+        usage();
+    }
+    opts
+}
+
+/* Talking to the instances */
+
+// Run one command on `addr`, reusing the connection from last time. A
+// connection that failed is dropped, so the next call reconnects.
+fn query(conns: &mut HashMap<String, RedisClient>, addr: &str, args: &[&str]) -> io::Result<RedisValue> {
+    let mut client = match conns.remove(addr) {
+        Some(client) => client,
+        None => RedisClient::connect_timeout(addr, IO_TIMEOUT)?,
+    };
+    let reply = client.command(args)?;
+    conns.insert(addr.to_string(), client);
+    Ok(reply)
+}
+
+fn ping(conns: &mut HashMap<String, RedisClient>, addr: &str) -> bool {
+    matches!(query(conns, addr, &["PING"]), Ok(reply) if !matches!(reply, RedisValue::Err(_)))
+}
+
+// The replicas listed in a master's ROLE reply
+fn parse_role_replicas(reply: &RedisValue) -> Option<Vec<(String, u16)>> {
+    let RedisValue::Arr(role) = reply else {
+        return None;
+    };
+    let (Some(RedisValue::Str(kind)), Some(RedisValue::Arr(replicas))) = (role.first(), role.get(2)) else {
+        return None;
+    };
+    if kind != "master" {
+        return None;
+    }
+    replicas
+        .iter()
+        .map(|replica| match replica {
+            RedisValue::Arr(fields) => match (fields.first(), fields.get(1)) {
+                (Some(RedisValue::Str(ip)), Some(RedisValue::Str(port))) => Some((ip.clone(), port.parse().ok()?)),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/* Failure detection and failover */
+
+// One round of checks on masters[idx]
+fn check_master(opts: &Options, masters: &Masters, idx: usize, conns: &mut HashMap<String, RedisClient>) {
+    // Never hold the lock across the network
+    let addr = masters.lock().unwrap()[idx].addr();
+    let alive = ping(conns, &addr);
+    let replicas = if alive {
+        query(conns, &addr, &["ROLE"]).ok().as_ref().and_then(parse_role_replicas)
+    } else {
+        None
+    };
+
+    let (host, port, quorum) = {
+        let mut list = masters.lock().unwrap();
+        let master = &mut list[idx];
+        if alive {
+            master.last_reply = Instant::now();
+            if let Some(replicas) = replicas {
+                master.replicas = replicas;
+            }
+            if master.odown {
+                log_notice!("sentinel", "-odown master {} {} {}", master.name, master.host, master.port);
+            }
+            if master.sdown {
+                log_notice!("sentinel", "-sdown master {} {} {}", master.name, master.host, master.port);
+            }
+            master.sdown = false;
+            master.odown = false;
+            return;
+        }
+        if !master.sdown && master.last_reply.elapsed() >= opts.down_after {
+            master.sdown = true;
+            log_warning!("sentinel", "+sdown master {} {} {}", master.name, master.host, master.port);
+        }
+        if !master.sdown {
+            return;
+        }
+        (master.host.clone(), master.port, master.quorum)
+    };
+
+    // Count the sentinels that agree, this one first
+    let port_arg = port.to_string();
+    let mut votes = 1;
+    for peer in &opts.peers {
+        let reply = query(conns, peer, &["SENTINEL", "IS-MASTER-DOWN-BY-ADDR", &host, &port_arg]);
+        if matches!(reply, Ok(RedisValue::Int(1))) {
+            votes += 1;
+        }
+    }
+
+    let should_failover = {
+        let mut list = masters.lock().unwrap();
+        let master = &mut list[idx];
+        if votes >= quorum && !master.odown {
+            master.odown = true;
+            log_warning!(
+                "sentinel",
+                "+odown master {} {} {} #quorum {}/{}",
+                master.name, master.host, master.port, votes, quorum
+            );
+        }
+        master.odown && master.last_failover.is_none_or(|at| at.elapsed() >= FAILOVER_RETRY)
+    };
+    if should_failover {
+        failover(masters, idx, conns);
+    }
+}
+
+// Promote the first replica of masters[idx] that answers
+fn failover(masters: &Masters, idx: usize, conns: &mut HashMap<String, RedisClient>) {
+    let (name, replicas) = {
+        let mut list = masters.lock().unwrap();
+        let master = &mut list[idx];
+        master.last_failover = Some(Instant::now());
+        log_notice!("sentinel", "+try-failover master {} {} {}", master.name, master.host, master.port);
+        (master.name.clone(), master.replicas.clone())
+    };
+
+    for (host, port) in replicas {
+        let addr = format!("{}:{}", host, port);
+        if !ping(conns, &addr) {
+            continue;
+        }
+        match query(conns, &addr, &["REPLICAOF", "NO", "ONE"]) {
+            Ok(RedisValue::Err(msg)) => {
+                log_warning!("sentinel", "-failover-abort-promotion-refused {} {}: {}", name, addr, msg);
+            }
+            Ok(_) => {
+                let mut list = masters.lock().unwrap();
+                let master = &mut list[idx];
+                log_notice!(
+                    "sentinel",
+                    "+switch-master {} {} {} {} {}",
+                    master.name, master.host, master.port, host, port
+                );
+                master.host = host;
+                master.port = port;
+                master.replicas.clear();
+                master.last_reply = Instant::now();
+                master.sdown = false;
+                master.odown = false;
+                master.last_failover = None;
+                return;
+            }
+            Err(e) => log_warning!("sentinel", "-failover-abort {} {}: {}", name, addr, e),
+        }
+    }
+    log_warning!("sentinel", "-failover-abort-no-good-slave master {}", name);
+}
+
+fn monitor(opts: &Options, masters: &Masters) -> ! {
+    let mut conns = HashMap::new();
+    let count = masters.lock().unwrap().len();
+    loop {
+        for idx in 0..count {
+            check_master(opts, masters, idx, &mut conns);
+        }
+        thread::sleep(PING_PERIOD);
+    }
+}
+
+/* Serving peers and clients */
+
+fn execute(masters: &Masters, args: &[String]) -> RedisValue {
+    let list = masters.lock().unwrap();
+    let str_value = |s: &str| RedisValue::Str(s.to_string());
+    let Some(name) = args.first() else {
+        return RedisValue::Err("ERR empty command".to_string());
+    };
+    let sub = args.get(1).map(|s| s.to_uppercase());
+
+    match (name.to_uppercase().as_str(), sub.as_deref(), args.len()) {
+        ("PING", _, 1) => str_value("PONG"),
+        ("SENTINEL", Some("MASTERS"), 2) => RedisValue::Arr(
+            list.iter()
+                .map(|m| {
+                    RedisValue::Arr(vec![
+                        str_value(&m.name),
+                        str_value(&m.host),
+                        str_value(&m.port.to_string()),
+                        str_value(m.flags()),
+                    ])
+                })
+                .collect(),
+        ),
+        ("SENTINEL", Some("GET-MASTER-ADDR-BY-NAME"), 3) => match list.iter().find(|m| m.name == args[2]) {
+            Some(m) => RedisValue::Arr(vec![str_value(&m.host), str_value(&m.port.to_string())]),
+            None => RedisValue::Nil,
+        },
+        ("SENTINEL", Some("IS-MASTER-DOWN-BY-ADDR"), 4) => {
+            let down = list.iter().any(|m| m.host == args[2] && m.port.to_string() == args[3] && m.sdown);
+            RedisValue::Int(down as i64)
+        }
+        _ => RedisValue::Err(format!("ERR unknown sentinel command '{}'", args.join(" "))),
+    }
+}
+
+fn serve_client(masters: &Masters, mut stream: TcpStream) -> io::Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut chunk)?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        while let Some((args, used)) =
+            parse_frame(&buf, &LIMITS).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        {
+            buf.drain(..used);
+            let mut body = Vec::new();
+            execute(masters, &args).encode(&mut body);
+            let mut frame = (body.len() as u32).to_le_bytes().to_vec();
+            frame.extend_from_slice(&body);
+            stream.write_all(&frame)?;
+        }
+    }
+}
+
+/// Entry point for `redis sentinel ...`
+pub fn run(args: &[String]) -> io::Result<()> {
+    let mut opts = parse_options(args);
+    let masters: Masters = Arc::new(Mutex::new(std::mem::take(&mut opts.masters)));
+
+    let listener = TcpListener::bind(("::", opts.port))?;
+    log_notice!("sentinel", "Sentinel listening on port {}", opts.port);
+    for master in masters.lock().unwrap().iter() {
+        log_notice!("sentinel", "+monitor master {} {} {} quorum {}", master.name, master.host, master.port, master.quorum);
+    }
+
+    let served = masters.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let masters = served.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve_client(&masters, stream) {
+                    log_warning!("sentinel", "Client error: {}", e);
+                }
+            });
+        }
+    });
+
+    monitor(&opts, &masters)
+}
@@ -0,0 +1,99 @@
+/* Set algebra */
+//
+// Set operations are built from lazy iterators over members, so a command
+// that only needs a few results stops as soon as it has them instead of
+// computing the whole result first. Anything that can list its members and
+// answer membership is a MemberSet; intersection() walks the smallest of the
+// sets and yields each member the others all hold, asking the smaller ones
+// first, as a member is most likely to be missing from those.
+//
+// ZINTERCARD is the first user: with LIMIT n it counts no further than n,
+// so asking whether two big sets overlap at all (LIMIT 1) costs one probe
+// per member walked until the first common one, not the whole intersection.
+
+use crate::command::CmdResult;
+use crate::{out_err, out_int, with_global_data_read, Buffer, ZSet};
+
+/// A set of members, for the combinators below
+pub trait MemberSet {
+    fn len(&self) -> usize;
+    fn contains(&self, member: &str) -> bool;
+    fn members(&self) -> Box<dyn Iterator<Item = &str> + '_>;
+}
+
+impl MemberSet for ZSet {
+    fn len(&self) -> usize {
+        self.name_to_node.len()
+    }
+
+    fn contains(&self, member: &str) -> bool {
+        self.name_to_node.contains_key(member)
+    }
+
+    fn members(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(self.name_to_node.keys().map(String::as_str))
+    }
+}
+
+/// The members common to all of `sets`, yielded as they are found; empty
+/// without sets
+pub fn intersection(mut sets: Vec<&dyn MemberSet>) -> impl Iterator<Item = &str> {
+    sets.sort_by_key(|set| set.len());
+    let (smallest, others) = match sets.split_first() {
+        Some((smallest, others)) => (Some(*smallest), others.to_vec()),
+        None => (None, Vec::new()),
+    };
+    smallest
+        .into_iter()
+        .flat_map(|set| set.members())
+        .filter(move |member| others.iter().all(|set| set.contains(member)))
+}
+
+// ZINTERCARD numkeys key [key ...] [LIMIT limit]
+pub fn do_zintercard(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let numkeys = match cmd[1].parse::<usize>() {
+        Ok(numkeys) if numkeys > 0 => numkeys,
+        _ => {
+            out_err(out, "ERR numkeys should be greater than 0");
+            return Ok(());
+        }
+    };
+    let Some(keys) = numkeys.checked_add(2).and_then(|end| cmd.get(2..end)) else {
+        out_err(out, "ERR Number of keys can't be greater than number of args");
+        return Ok(());
+    };
+    let limit = match &cmd[2 + numkeys..] {
+        [] => usize::MAX,
+        [keyword, limit] if keyword.eq_ignore_ascii_case("LIMIT") => match limit.parse::<i64>() {
+            Ok(0) => usize::MAX,
+            Ok(limit) if limit > 0 => limit as usize,
+            Ok(_) => {
+                out_err(out, "ERR LIMIT can't be negative");
+                return Ok(());
+            }
+            Err(_) => {
+                out_err(out, "ERR value is not an integer or out of range");
+                return Ok(());
+            }
+        },
+        _ => {
+            out_err(out, "ERR syntax error");
+            return Ok(());
+        }
+    };
+
+    with_global_data_read(|g_data| {
+        let mut sets: Vec<&dyn MemberSet> = Vec::with_capacity(keys.len());
+        let mut missing = false;
+        for key in keys {
+            // Every key is type checked, even after one that doesn't exist
+            match g_data.db.expect_zset(key)? {
+                Some(zset) => sets.push(zset),
+                None => missing = true,
+            }
+        }
+        let count = if missing { 0 } else { intersection(sets).take(limit).count() };
+        out_int(out, count as i64);
+        Ok(())
+    })
+}
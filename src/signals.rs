@@ -0,0 +1,68 @@
+/* Signals */
+//
+// SIGHUP asks for the config file to be read again (see config::reload),
+// SIGTERM and SIGINT for the server to shut down as SHUTDOWN does. The
+// handler can't do either itself: it only raises a flag and writes a byte
+// to a pipe whose other end the event loop polls, so the loop wakes up
+// whichever thread the signal was delivered to, and the reload runs on the
+// main thread between rounds.
+
+use std::ffi::c_int;
+use std::io::{self, Read};
+use std::os::fd::{IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Write end of the wakeup pipe, -1 before install
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(signal: c_int) {
+    if signal == Signal::SIGHUP as c_int {
+        RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+    } else {
+        SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+    }
+    let fd: RawFd = WAKE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        // Async-signal-safe; a full pipe already has a wakeup pending
+        let _ = nix::unistd::write(fd, &[1]);
+    }
+}
+
+/// What the signals since the last check asked for
+#[derive(Debug, Default)]
+pub struct Requests {
+    pub reload: bool,
+    pub shutdown: bool,
+}
+
+/// Install the signal handlers. Returns the end of the wakeup pipe for the
+/// event loop to poll, which take_requests() drains.
+pub fn install() -> io::Result<UnixStream> {
+    let (reader, writer) = UnixStream::pair()?;
+    reader.set_nonblocking(true)?;
+    writer.set_nonblocking(true)?;
+    WAKE_FD.store(writer.into_raw_fd(), Ordering::SeqCst);
+
+    let action = SigAction::new(SigHandler::Handler(on_signal), SaFlags::SA_RESTART, SigSet::empty());
+    for signal in [Signal::SIGHUP, Signal::SIGTERM, Signal::SIGINT] {
+        // SAFETY: the handler only touches atomics and calls write(2)
+        unsafe { sigaction(signal, &action) }.map_err(io::Error::from)?;
+    }
+    Ok(reader)
+}
+
+/// The signals that came in since the last call
+pub fn take_requests(mut wake: &UnixStream) -> Requests {
+    let mut buf = [0u8; 64];
+    while matches!(wake.read(&mut buf), Ok(n) if n > 0) {}
+    Requests {
+        reload: RELOAD_REQUESTED.swap(false, Ordering::SeqCst),
+        shutdown: SHUTDOWN_REQUESTED.swap(false, Ordering::SeqCst),
+    }
+}
@@ -0,0 +1,455 @@
+/* Simulation */
+//
+// The event loop reads the time through a Clock and waits through a Poller,
+// so a test can swap both for ones it drives. A Simulation runs the server's
+// own event loop rounds (serve_round) over socketpair clients on a
+// ManualClock, and never sleeps: where the loop would wait for its next timer,
+// SimPoller moves the clock there instead. Idle timeouts, TTLs and blocking
+// timeouts so fire instantly, in order, and the same way on every run.
+//
+// `redis sim [script]` plays a scenario, one step per line, and prints each
+// reply with the virtual time it arrived at:
+//
+//   a SET k v            client a sends a command; clients connect on first use
+//   a PEXPIRE k 1500
+//   b BLMPOP 2 1 q LEFT
+//   advance 2000         let 2000 ms of virtual time pass
+//   a GET k
+//   shutdown a           client a shuts down its sending side, still reading
+//   close b
+//
+// Blank lines and lines starting with # are skipped.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use nix::poll::PollFd;
+use redis::protocol::{RedisValue, ReplyDecoder, ReplyFormat};
+use socket2::Socket;
+
+use crate::config::Config;
+use crate::{add_client, cli, logging, serve_round, with_global_data, Conn};
+
+/// Where the server gets the time from
+pub trait Clock: Send + Sync {
+    /// Milliseconds since some fixed start; never goes back
+    fn monotonic_ms(&self) -> u64;
+
+    /// Milliseconds since the Unix epoch
+    fn unix_ms(&self) -> u64;
+}
+
+/// The operating system's clocks
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn monotonic_ms(&self) -> u64 {
+        static START: OnceLock<Instant> = OnceLock::new();
+        START.get_or_init(Instant::now).elapsed().as_millis() as u64
+    }
+
+    fn unix_ms(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+    }
+}
+
+/// A clock that starts at 0 and only moves when advanced. The wall clock
+/// moves along with it from `unix_start_ms`.
+pub struct ManualClock {
+    elapsed_ms: AtomicU64,
+    unix_start_ms: u64,
+}
+
+impl ManualClock {
+    pub fn new(unix_start_ms: u64) -> ManualClock {
+        ManualClock { elapsed_ms: AtomicU64::new(0), unix_start_ms }
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.elapsed_ms.fetch_add(ms, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn monotonic_ms(&self) -> u64 {
+        self.elapsed_ms.load(Ordering::SeqCst)
+    }
+
+    fn unix_ms(&self) -> u64 {
+        self.unix_start_ms + self.monotonic_ms()
+    }
+}
+
+static CLOCK: OnceLock<&'static dyn Clock> = OnceLock::new();
+
+/// The clock the server runs on: the system's, unless another was installed
+/// before the time was first read
+pub fn clock() -> &'static dyn Clock {
+    *CLOCK.get_or_init(|| &SystemClock)
+}
+
+/// Run the server on `clock` from now on
+pub fn install_clock(clock: &'static dyn Clock) -> Result<(), String> {
+    CLOCK.set(clock).map_err(|_| "the clock was already read".to_string())
+}
+
+/// How the event loop waits for its sockets
+pub trait Poller {
+    /// Wait up to `timeout_ms`, -1 for no limit, for one of `fds` to be ready;
+    /// returns how many are
+    fn poll(&mut self, fds: &mut [PollFd], timeout_ms: i32) -> nix::Result<i32>;
+}
+
+/// poll(2)
+pub struct SystemPoller;
+
+impl Poller for SystemPoller {
+    fn poll(&mut self, fds: &mut [PollFd], timeout_ms: i32) -> nix::Result<i32> {
+        nix::poll::poll(fds, timeout_ms)
+    }
+}
+
+// Never blocks. With nothing ready it moves the clock to the end of the wait
+// instead, but not past `until`; once there, the loop is idle.
+struct SimPoller {
+    clock: &'static ManualClock,
+    until: u64,
+    idle: bool,
+}
+
+impl Poller for SimPoller {
+    fn poll(&mut self, fds: &mut [PollFd], timeout_ms: i32) -> nix::Result<i32> {
+        let ready = nix::poll::poll(fds, 0)?;
+        if ready > 0 || timeout_ms == 0 {
+            return Ok(ready);
+        }
+        let now = self.clock.monotonic_ms();
+        let wake_at = match u64::try_from(timeout_ms) {
+            Ok(timeout) => (now + timeout).min(self.until),
+            Err(_) => self.until,
+        };
+        self.clock.advance(wake_at.saturating_sub(now));
+        self.idle = wake_at <= now;
+        Ok(0)
+    }
+}
+
+struct SimClient {
+    name: String,
+    stream: UnixStream,
+    decoder: ReplyDecoder,
+}
+
+/// A reply as a client got it
+pub struct Reply {
+    /// Virtual time it arrived at
+    pub at_ms: u64,
+    pub client: String,
+    /// None stands for the server closing the connection
+    pub value: Option<RedisValue>,
+}
+
+/// The server in this process, run on virtual time by the caller
+pub struct Simulation {
+    clock: &'static ManualClock,
+    clients: Vec<SimClient>,
+    arrived: Vec<Reply>,
+}
+
+impl Simulation {
+    /// Serve in-process with `config` on a clock that starts at 0. This has to
+    /// come before anything reads the time.
+    pub fn new(config: Config) -> Result<Simulation, String> {
+        let clock: &'static ManualClock = Box::leak(Box::new(ManualClock::new(SystemClock.unix_ms())));
+        install_clock(clock)?;
+        with_global_data(|g_data| {
+            g_data.config = config;
+            logging::configure(&g_data.config);
+        });
+        Ok(Simulation { clock, clients: Vec::new(), arrived: Vec::new() })
+    }
+
+    /// Virtual milliseconds since the simulation started
+    pub fn now_ms(&self) -> u64 {
+        self.clock.monotonic_ms()
+    }
+
+    pub fn is_connected(&self, name: &str) -> bool {
+        self.clients.iter().any(|client| client.name == name)
+    }
+
+    /// Connect a client called `name` to the server
+    pub fn connect(&mut self, name: &str) -> io::Result<()> {
+        let (server_end, client_end) = UnixStream::pair()?;
+        server_end.set_nonblocking(true)?;
+        client_end.set_nonblocking(true)?;
+        let socket = Socket::from(OwnedFd::from(server_end));
+        add_client(socket.as_raw_fd(), Conn::new(socket));
+        self.clients.push(SimClient {
+            name: name.to_string(),
+            stream: client_end,
+            decoder: ReplyDecoder::new(ReplyFormat::Framed),
+        });
+        Ok(())
+    }
+
+    /// Send a request on `name`'s connection, written as the framed protocol
+    /// takes it: arguments separated by spaces, quoted where needed
+    pub fn send(&mut self, name: &str, line: &str) -> io::Result<()> {
+        let len = u32::try_from(line.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "request too long"))?;
+        let client = self.client(name)?;
+        client.stream.write_all(&len.to_le_bytes())?;
+        client.stream.write_all(line.as_bytes())
+    }
+
+    /// Shut down the sending side of `name`'s connection, as a client does
+    /// that has nothing more to ask but still reads the replies
+    pub fn shutdown_write(&mut self, name: &str) -> io::Result<()> {
+        self.client(name)?.stream.shutdown(Shutdown::Write)
+    }
+
+    /// Hang up `name`'s connection
+    pub fn close(&mut self, name: &str) -> io::Result<()> {
+        self.client(name)?;
+        self.clients.retain(|client| client.name != name);
+        Ok(())
+    }
+
+    /// Run the event loop until it would have to wait for time to pass
+    pub fn settle(&mut self) -> io::Result<()> {
+        self.run_until(self.now_ms())
+    }
+
+    /// Let `ms` of virtual time pass, serving whatever comes due on the way
+    pub fn advance(&mut self, ms: u64) -> io::Result<()> {
+        self.run_until(self.now_ms() + ms)
+    }
+
+    fn run_until(&mut self, until: u64) -> io::Result<()> {
+        let mut poller = SimPoller { clock: self.clock, until, idle: false };
+        loop {
+            poller.idle = false;
+            serve_round(&mut poller, None, None)?;
+            self.receive()?;
+            if poller.idle {
+                return Ok(());
+            }
+        }
+    }
+
+    /// The replies that arrived since the last call, in order. A client the
+    /// server hung up on is gone.
+    pub fn replies(&mut self) -> Vec<Reply> {
+        std::mem::take(&mut self.arrived)
+    }
+
+    // Read what the clients were sent this round, by client in the order
+    // they connected
+    fn receive(&mut self) -> io::Result<()> {
+        let at_ms = self.now_ms();
+        let mut closed = Vec::new();
+        for client in &mut self.clients {
+            let mut buf = [0u8; 4096];
+            loop {
+                match client.stream.read(&mut buf) {
+                    Ok(0) => {
+                        closed.push(client.name.clone());
+                        break;
+                    }
+                    Ok(n) => client.decoder.feed(&buf[..n]),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            while let Some(value) = client.decoder.next_value().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))? {
+                self.arrived.push(Reply { at_ms, client: client.name.clone(), value: Some(value) });
+            }
+        }
+        for name in closed {
+            self.clients.retain(|client| client.name != name);
+            self.arrived.push(Reply { at_ms, client: name, value: None });
+        }
+        Ok(())
+    }
+
+    fn client(&mut self, name: &str) -> io::Result<&mut SimClient> {
+        self.clients
+            .iter_mut()
+            .find(|client| client.name == name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no client named {}", name)))
+    }
+}
+
+/// redis sim [script]: play a scenario from the file, or stdin
+pub fn run(args: &[String]) -> io::Result<()> {
+    let input: Box<dyn BufRead> = match args {
+        [] => Box::new(io::stdin().lock()),
+        [path] => Box::new(BufReader::new(File::open(path)?)),
+        _ => {
+            eprintln!("Usage: redis sim [script]");
+            std::process::exit(1);
+        }
+    };
+    let mut sim = Simulation::new(Config::default()).map_err(io::Error::other)?;
+
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (step, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        let at_line = |e: io::Error| io::Error::new(e.kind(), format!("line {}: {}", i + 1, e));
+        match step {
+            "advance" => {
+                let ms = rest.parse().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("line {}: advance takes milliseconds", i + 1))
+                })?;
+                sim.advance(ms)?;
+            }
+            "shutdown" => {
+                sim.shutdown_write(rest).map_err(at_line)?;
+                sim.settle()?;
+            }
+            "close" => {
+                sim.close(rest).map_err(at_line)?;
+                sim.settle()?;
+            }
+            name => {
+                if !sim.is_connected(name) {
+                    sim.connect(name)?;
+                }
+                sim.send(name, rest).map_err(at_line)?;
+                sim.settle()?;
+            }
+        }
+
+        for reply in sim.replies() {
+            let label = format!("[{}ms] {}: ", reply.at_ms, reply.client);
+            match reply.value {
+                Some(value) => print!("{}{}", label, cli::format_reply(&value, &" ".repeat(label.len()))),
+                None => println!("{}(connection closed)", label),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What the tests share: there's one server per process and one clock, so
+/// they take turns with a single Simulation, and tell their keys apart by
+/// name
+#[cfg(test)]
+pub mod testing {
+    use std::sync::{Mutex, MutexGuard, OnceLock};
+
+    use redis::protocol::RedisValue;
+
+    use super::Simulation;
+    use crate::config::Config;
+
+    /// The simulation, for the calling test alone until it's dropped. Tests
+    /// that touch the global data take it first, so the clock is installed
+    /// before anything reads the time.
+    pub fn shared() -> MutexGuard<'static, Simulation> {
+        static SIM: OnceLock<Mutex<Simulation>> = OnceLock::new();
+        SIM.get_or_init(|| Mutex::new(Simulation::new(Config::default()).expect("simulation")))
+            .lock()
+            // A failed test doesn't stop the others
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    impl Simulation {
+        /// Send `line` as `name`, connecting it first, and return its reply.
+        /// Replies to other clients that come meanwhile are kept for replies().
+        pub fn call(&mut self, name: &str, line: &str) -> RedisValue {
+            if !self.is_connected(name) {
+                self.connect(name).unwrap();
+            }
+            self.send(name, line).unwrap();
+            self.settle().unwrap();
+            let (mine, others) = self.replies().into_iter().partition::<Vec<_>, _>(|reply| reply.client == name);
+            self.arrived = others;
+            match <[_; 1]>::try_from(mine) {
+                Ok([reply]) => reply.value.expect("connection closed"),
+                Err(replies) => panic!("{} got {} replies to {}", name, replies.len(), line),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::protocol::RedisValue;
+
+    use super::testing::shared;
+
+    #[test]
+    fn ttl_and_blocking_timeout_fire_on_virtual_time() {
+        let mut sim = shared();
+        let start = sim.now_ms();
+        assert_eq!(sim.call("sim-a", "SET sim:k v"), RedisValue::Nil);
+        assert_eq!(sim.call("sim-a", "PEXPIRE sim:k 1500"), RedisValue::Int(1));
+
+        // Nothing to pop: b waits, and nothing arrives until its timeout
+        sim.connect("sim-b").unwrap();
+        sim.send("sim-b", "BLMPOP 2 1 sim:q LEFT").unwrap();
+        sim.settle().unwrap();
+        assert!(sim.replies().is_empty());
+        sim.advance(1000).unwrap();
+        assert!(sim.replies().is_empty());
+        assert_eq!(sim.call("sim-a", "GET sim:k"), RedisValue::Str("v".into()));
+
+        sim.advance(1000).unwrap();
+        let replies = sim.replies();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].client, "sim-b");
+        assert_eq!(replies[0].value, Some(RedisValue::Nil));
+        assert_eq!(replies[0].at_ms - start, 2000);
+        assert_eq!(sim.call("sim-a", "GET sim:k"), RedisValue::Nil);
+    }
+
+    #[test]
+    fn blocked_client_is_served_by_a_push() {
+        let mut sim = shared();
+        sim.connect("sim-c").unwrap();
+        sim.send("sim-c", "BLMPOP 0 1 sim:list RIGHT").unwrap();
+        sim.settle().unwrap();
+        assert!(sim.replies().is_empty());
+
+        assert_eq!(sim.call("sim-d", "RPUSH sim:list x y"), RedisValue::Int(2));
+        let replies = sim.replies();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(
+            replies[0].value,
+            Some(RedisValue::Arr(vec![RedisValue::Str("sim:list".into()), RedisValue::Arr(vec![RedisValue::Str("y".into())])]))
+        );
+        assert_eq!(sim.call("sim-d", "LLEN sim:list"), RedisValue::Int(1));
+    }
+
+    #[test]
+    fn served_client_forgets_its_timeout() {
+        let mut sim = shared();
+        sim.connect("sim-e").unwrap();
+        sim.send("sim-e", "BLMPOP 1 1 sim:served LEFT").unwrap();
+        sim.settle().unwrap();
+        sim.call("sim-f", "RPUSH sim:served x");
+        assert_eq!(sim.replies().len(), 1);
+
+        // Blocked again with a longer timeout, only that one counts
+        sim.send("sim-e", "BLMPOP 3 1 sim:served LEFT").unwrap();
+        sim.advance(2000).unwrap();
+        assert!(sim.replies().is_empty());
+        sim.advance(1000).unwrap();
+        let replies = sim.replies();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].value, Some(RedisValue::Nil));
+    }
+}
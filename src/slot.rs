@@ -0,0 +1,21 @@
+/* Hash slots */
+//
+// A cluster splits the keyspace into CLUSTER_SLOTS hash slots: a key goes to
+// CRC16(key) % 16384, or of only the part between the first '{' and the next
+// '}' when that isn't empty (its hash tag), so related keys can be kept
+// together ("{user1}.name", "{user1}.mail"). The server and the cluster
+// client hash keys the same way through here.
+
+use crate::crc16::crc16;
+
+pub const CLUSTER_SLOTS: usize = 16384;
+
+/// The slot `key` belongs to
+pub fn key_hash_slot(key: &str) -> u16 {
+    let bytes = key.as_bytes();
+    let tag = bytes.iter().position(|&b| b == b'{').and_then(|open| {
+        let close = bytes[open + 1..].iter().position(|&b| b == b'}')?;
+        (close > 0).then(|| &bytes[open + 1..open + 1 + close])
+    });
+    crc16(tag.unwrap_or(bytes)) % CLUSTER_SLOTS as u16
+}
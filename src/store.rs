@@ -0,0 +1,1039 @@
+/* Storage backends */
+//
+// The keyspace is kept by a KvStore: entries are looked up, put in, taken
+// out and walked through it, and it's told when a key's TTL changes. The
+// one used is picked at startup with `storage-backend`:
+//
+//   memory  the hash table (HMap) alone, the default; what SAVE or the AOF
+//           didn't write out is gone on restart
+//   log     the same table, with every change also appended to the file
+//           `storage-log`; at startup that file is replayed, instead of
+//           loading the snapshot or the AOF
+//
+// The log is a sequence of records, each an opcode byte, the u32 length of
+// what follows, and a key (u32 length and bytes, little-endian as in the
+// snapshot):
+//
+//   LOG_SET key at value  the key's new value, serialized as DUMP does,
+//                         and the unix time in ms it expires at (0: never)
+//   LOG_DEL key           the key is gone
+//   LOG_TTL key at        its TTL changed, the value staying
+//
+// Expiry times are the ones Expires keeps, so a TTL comes back from the log
+// as the same point in time, however long the server was down.
+//
+// The store notes which keys changed and how; at the end of the event loop
+// round each of them gets one record for the state it's left in, so a key
+// written many times in a round is serialized once, and one taken out and
+// put straight back, as every in-place write does, gets no LOG_DEL. The
+// records are written once per round, as AOF commands are, and the file is
+// fsynced once a second. A write that fails part way leaves the rest of the
+// buffer to the next round, which goes on from where it stopped. A value is
+// still written whole in every round it changes, so with big collections
+// the log grows fast: once it's past LOG_MIN_REWRITE bytes and twice the
+// size it had after the last rewrite, it's rewritten with one LOG_SET per
+// key. As for the AOF, that's done on the thread pool
+// from a copy of the keyspace (values are shared, not copied), into a temp
+// file next to the log so the rename stays on one filesystem; records made
+// meanwhile go to both files, and the event loop only appends those and
+// swaps the file in. A record cut off at the end, as a crash mid-write
+// leaves it, is dropped from the file at startup.
+//
+// With `storage-memory` set the dataset can be bigger than RAM: keys and
+// their metadata stay in memory, but the values past that many bytes (each
+// counted as the size of its LOG_SET record) are dropped from memory, the
+// ones idle the longest first, sampling SPILL_SAMPLES keys per value as
+// eviction does. A value dropped is still in the log, where its latest
+// LOG_SET is; the entry is left holding Value::Init and the store where
+// that record is and the type of the value. Handlers borrow values from the
+// store while they run, so a command doesn't read them back itself: before
+// it runs, the values of the keys it names (Command::keys) are loaded, and
+// from then on they count as used. A snapshot (SAVE, BGSAVE, a replica's
+// full resync, an AOF rewrite) reads back those it needs as it's taken, and
+// SCAN ... TYPE asks the store for the type. At startup the log is replayed
+// without reading any value back; each comes in when a command uses it.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::expire::Expires;
+use crate::logging::{log_error, log_notice, log_warning};
+use crate::rdb::{dump_value, restore_value};
+use crate::{get_monotonic_time_ms, Entry, HMap, ThreadPool, Value, ValueType};
+
+const LOG_SET: u8 = 1;
+const LOG_DEL: u8 = 2;
+const LOG_TTL: u8 = 3;
+
+/// Smallest log that gets rewritten
+const LOG_MIN_REWRITE: u64 = 4 * 1024 * 1024;
+
+/// Keys sampled for each value dropped from memory
+const SPILL_SAMPLES: usize = 16;
+
+/// Where the keyspace is kept
+pub trait KvStore: std::fmt::Debug + Send + Sync {
+    /// The entry at `key`, expired or not
+    fn get(&self, key: &str) -> Option<&Entry>;
+    /// Put `entry` in, to expire at the unix time in ms `expire_at`; there's
+    /// no entry at its key
+    fn set(&mut self, entry: Box<Entry>, expire_at: Option<u64>);
+    /// Take the entry at `key` out
+    fn delete(&mut self, key: &str) -> Option<Box<Entry>>;
+    /// Every entry, in no particular order
+    fn iter(&self) -> Box<dyn Iterator<Item = &Entry> + '_>;
+    /// One step of a SCAN from `cursor` (see HMap::scan)
+    fn scan(&self, cursor: u64, visit: &mut dyn FnMut(&Entry)) -> u64;
+    fn size(&self) -> usize;
+    /// The TTL of the entry at `key`, staying in, is now the unix time in ms
+    /// `expire_at`, or it has none
+    fn ttl_changed(&mut self, _key: &str, _expire_at: Option<u64>) {}
+    /// Write out what changed, once per event loop round, with the TTLs of
+    /// `expires` and `pool` for work that's too long for the round
+    fn flush(&mut self, _expires: &Expires, _pool: &ThreadPool) {}
+    /// When flush() has something to do without a change to write
+    fn next_timer_ms(&self, _now_ms: u64) -> Option<u64> {
+        None
+    }
+    /// Whether the value of one of `keys` is only on disk
+    fn on_disk(&self, _keys: &[&str]) -> bool {
+        false
+    }
+    /// Have the values of `keys` in memory, for a command that uses them;
+    /// Err is the error reply when one can't be read back
+    fn load(&mut self, _keys: &[&str]) -> Result<(), String> {
+        Ok(())
+    }
+    /// The value of `entry`, read back if it's only on disk
+    fn value(&self, entry: &Entry) -> io::Result<Value> {
+        Ok(entry.value.clone())
+    }
+    /// The name of the type of `entry`'s value, on disk or not
+    fn type_name(&self, entry: &Entry) -> &'static str {
+        entry.value.type_name()
+    }
+    /// The hash table the entries are in, for what's particular to it:
+    /// lookup statistics and filters, bucket arrays
+    fn table(&self) -> &HMap;
+    fn table_mut(&mut self) -> &mut HMap;
+}
+
+impl KvStore for HMap {
+    fn get(&self, key: &str) -> Option<&Entry> {
+        self.find_entry(key)
+    }
+
+    fn set(&mut self, entry: Box<Entry>, _expire_at: Option<u64>) {
+        self.insert(entry);
+    }
+
+    fn delete(&mut self, key: &str) -> Option<Box<Entry>> {
+        self.delete_entry_and_return(key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &Entry> + '_> {
+        Box::new(HMap::iter(self))
+    }
+
+    fn scan(&self, cursor: u64, visit: &mut dyn FnMut(&Entry)) -> u64 {
+        HMap::scan(self, cursor, visit)
+    }
+
+    fn size(&self) -> usize {
+        HMap::size(self)
+    }
+
+    fn table(&self) -> &HMap {
+        self
+    }
+
+    fn table_mut(&mut self) -> &mut HMap {
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Memory,
+    Log,
+}
+
+impl Backend {
+    pub fn parse(s: &str) -> Option<Backend> {
+        match s.to_ascii_lowercase().as_str() {
+            "memory" => Some(Backend::Memory),
+            "log" => Some(Backend::Log),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Memory => "memory",
+            Backend::Log => "log",
+        }
+    }
+}
+
+/// The keyspace in memory, each change appended to a log file
+#[derive(Debug)]
+pub struct LogStore {
+    table: HMap,
+    path: String,
+    file: File,
+    // Records not written yet, the first `written` bytes of them excepted
+    buf: Vec<u8>,
+    written: usize,
+    // Bytes in the file, and after the last rewrite
+    size: u64,
+    base_size: u64,
+    last_fsync_ms: u64,
+    rewrite: Option<Rewrite>,
+    // Keys changed since the last flush, and how
+    changed: HashMap<String, Change>,
+    // Bytes of values kept in memory before the coldest are dropped, 0 for
+    // no limit
+    max_memory: u64,
+    // Where the value of each key is in the file; after a flush every key
+    // has one
+    places: HashMap<String, Place>,
+    // Bytes of the values in memory, counted as their LOG_SET records
+    resident: u64,
+}
+
+// Where a key's latest LOG_SET record is
+#[derive(Debug, Clone, Copy)]
+struct Place {
+    offset: u64,
+    len: u32,
+    // The type of the value, while it's only in the file
+    on_disk: Option<ValueType>,
+}
+
+// What's left of the changes to a key in a round
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    // A new value, expiring at the unix time in ms or not
+    Set(Option<u64>),
+    // The same value, with this TTL now
+    Ttl(Option<u64>),
+    Del,
+}
+
+// Each key and the offset of its record in the new file
+type RewriteResult = Arc<Mutex<Option<io::Result<Vec<(String, u64)>>>>>;
+
+// A rewrite running on the thread pool
+#[derive(Debug)]
+struct Rewrite {
+    temp_path: PathBuf,
+    // Records made since it started, for the new file too, and where the
+    // LOG_SETs among them are in it
+    buf: Vec<u8>,
+    since: HashMap<String, usize>,
+    // Set by the thread when it's done
+    result: RewriteResult,
+}
+
+// The payload of the LOG_SET record for `value`, expiring at the unix time
+// in ms `expire_at`
+fn set_payload(value: &Value, expire_at: Option<u64>) -> Vec<u8> {
+    let mut payload = expire_at.unwrap_or(0).to_le_bytes().to_vec();
+    payload.extend_from_slice(&dump_value(value));
+    payload
+}
+
+// opcode, length, key, then `payload`
+fn put_record(buf: &mut Vec<u8>, opcode: u8, key: &str, payload: &[u8]) {
+    buf.push(opcode);
+    buf.extend_from_slice(&((4 + key.len() + payload.len()) as u32).to_le_bytes());
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key.as_bytes());
+    buf.extend_from_slice(payload);
+}
+
+struct Record<'a> {
+    opcode: u8,
+    key: String,
+    payload: &'a [u8],
+    // Bytes it takes in the file
+    len: usize,
+}
+
+// The next record of `reader` into `buf`, false at the end of the file or
+// at a record cut off there, `left` bytes on
+fn read_record(reader: &mut impl Read, buf: &mut Vec<u8>, left: u64) -> io::Result<bool> {
+    if left < 5 {
+        return Ok(false);
+    }
+    buf.resize(5, 0);
+    reader.read_exact(buf)?;
+    let len = u32::from_le_bytes(buf[1..5].try_into().unwrap()) as usize;
+    if 5 + len as u64 > left {
+        return Ok(false);
+    }
+    buf.resize(5 + len, 0);
+    reader.read_exact(&mut buf[5..])?;
+    Ok(true)
+}
+
+// The record at the start of `data`, None if it's cut off
+fn get_record(data: &[u8]) -> Result<Option<Record<'_>>, String> {
+    if data.len() < 5 {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(data[1..5].try_into().unwrap()) as usize;
+    let Some(body) = data.get(5..5 + len) else {
+        return Ok(None);
+    };
+    let key_len = body.get(..4).map(|n| u32::from_le_bytes(n.try_into().unwrap()) as usize);
+    let Some(key) = key_len.and_then(|key_len| body.get(4..4 + key_len)) else {
+        return Err("key longer than its record".to_string());
+    };
+    let key = String::from_utf8(key.to_vec()).map_err(|_| "key isn't UTF-8".to_string())?;
+    let payload = &body[4 + key.len()..];
+    Ok(Some(Record { opcode: data[0], key, payload, len: 5 + len }))
+}
+
+impl LogStore {
+    /// Open the log at `path`, creating it if need be, and replay it, to
+    /// keep up to `max_memory` bytes of values in memory (0: all of them).
+    /// Returns the store and the TTLs of its keys, for the caller to set.
+    pub fn open(path: &str, max_memory: u64) -> Result<(LogStore, Vec<(String, u64)>), String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("can't open {}: {}", path, e))?;
+        let file_len = file.metadata().map_err(|e| format!("can't read {}: {}", path, e))?.len();
+        let mut reader = BufReader::new(&file);
+        let mut table = HMap::default();
+        let mut ttls = HashMap::new();
+        let mut places = HashMap::new();
+        let mut record = Vec::new();
+        let mut pos = 0;
+        while read_record(&mut reader, &mut record, file_len - pos).map_err(|e| format!("can't read {}: {}", path, e))? {
+            let corrupt = |e: String| format!("{}: bad record at offset {}: {}", path, pos, e);
+            let Some(Record { opcode, key, payload, len }) = get_record(&record).map_err(corrupt)? else {
+                break;
+            };
+            match opcode {
+                LOG_SET if payload.len() > 8 => {
+                    table.delete_entry_and_return(&key);
+                    match u64::from_le_bytes(payload[..8].try_into().unwrap()) {
+                        0 => ttls.remove(&key),
+                        at => ttls.insert(key.clone(), at),
+                    };
+                    let (value, on_disk) = if max_memory > 0 {
+                        let value_type = ValueType::from_u8(payload[8]).ok_or_else(|| corrupt("unknown type".to_string()))?;
+                        (Value::Init, Some(value_type))
+                    } else {
+                        (restore_value(&payload[8..]).map_err(corrupt)?, None)
+                    };
+                    places.insert(key.clone(), Place { offset: pos, len: len as u32, on_disk });
+                    table.insert(Box::new(Entry::new(key, value)));
+                }
+                LOG_SET => return Err(corrupt("SET record without its expiry and value".to_string())),
+                LOG_DEL => {
+                    table.delete_entry_and_return(&key);
+                    ttls.remove(&key);
+                    places.remove(&key);
+                }
+                LOG_TTL => {
+                    let at: [u8; 8] = payload.try_into().map_err(|_| corrupt("TTL isn't 8 bytes".to_string()))?;
+                    match u64::from_le_bytes(at) {
+                        0 => ttls.remove(&key),
+                        at => ttls.insert(key, at),
+                    };
+                }
+                opcode => return Err(corrupt(format!("unknown opcode {}", opcode))),
+            }
+            pos += len as u64;
+        }
+
+        if pos < file_len {
+            log_warning!("persist", "{} ends with an incomplete record, truncating {} bytes", path, file_len - pos);
+            file.set_len(pos).map_err(|e| format!("can't truncate {}: {}", path, e))?;
+        }
+        let resident = places.values().filter(|place| place.on_disk.is_none()).map(|place| place.len as u64).sum();
+        let store = LogStore {
+            table,
+            path: path.to_string(),
+            file,
+            buf: Vec::new(),
+            written: 0,
+            size: pos,
+            base_size: pos,
+            last_fsync_ms: get_monotonic_time_ms(),
+            rewrite: None,
+            changed: HashMap::new(),
+            max_memory,
+            places,
+            resident,
+        };
+        Ok((store, ttls.into_iter().collect()))
+    }
+
+    // One record per key changed since the last call
+    fn log_changes(&mut self) {
+        for (key, change) in std::mem::take(&mut self.changed) {
+            match change {
+                Change::Set(expire_at) => {
+                    let Some(entry) = self.table.find_entry(&key) else {
+                        continue;
+                    };
+                    let payload = set_payload(&entry.value, expire_at);
+                    let offset = self.record(LOG_SET, &key, &payload);
+                    let place = Place { offset, len: (9 + key.len() + payload.len()) as u32, on_disk: None };
+                    self.resident += place.len as u64;
+                    if let Some(old) = self.places.insert(key, place) {
+                        self.resident -= old.len as u64;
+                    }
+                }
+                Change::Ttl(expire_at) => {
+                    self.record(LOG_TTL, &key, &expire_at.unwrap_or(0).to_le_bytes());
+                }
+                Change::Del => {
+                    self.record(LOG_DEL, &key, &[]);
+                    if let Some(old) = self.places.remove(&key) {
+                        self.resident -= old.len as u64;
+                    }
+                }
+            }
+        }
+    }
+
+    // Add a record to the buffer, and to the rewrite's if one is running.
+    // Returns the offset it's going to have in the file.
+    fn record(&mut self, opcode: u8, key: &str, payload: &[u8]) -> u64 {
+        let start = self.buf.len();
+        put_record(&mut self.buf, opcode, key, payload);
+        if let Some(rewrite) = &mut self.rewrite {
+            if opcode == LOG_SET {
+                rewrite.since.insert(key.to_string(), rewrite.buf.len());
+            }
+            rewrite.buf.extend_from_slice(&self.buf[start..]);
+        }
+        self.size + (start - self.written) as u64
+    }
+
+    // The value of `key` has changed, or is gone: it's in memory as far as
+    // the count goes, until the next flush logs it
+    fn in_memory(&mut self, key: &str) {
+        if let Some(place) = self.places.get_mut(key)
+            && place.on_disk.take().is_some()
+        {
+            self.resident += place.len as u64;
+        }
+    }
+
+    // Read back the value of `key`, from its record at `place`
+    fn read_value(&self, key: &str, place: &Place) -> io::Result<Value> {
+        let mut data = vec![0; place.len as usize];
+        self.file.read_exact_at(&mut data, place.offset)?;
+        match get_record(&data) {
+            Ok(Some(record)) if record.opcode == LOG_SET && record.key == key && record.payload.len() > 8 => {
+                restore_value(&record.payload[8..]).map_err(io::Error::other)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("no SET record at offset {}", place.offset))),
+        }
+    }
+
+    // Drop values from memory, the coldest first, until what's left is
+    // under max_memory. Only values already in the file go.
+    fn spill(&mut self) {
+        while self.max_memory > 0 && self.resident > self.max_memory {
+            let mut coldest: Option<(u32, String)> = None;
+            let mut sampled = 0;
+            let mut cursor = RandomState::new().build_hasher().finish();
+            // The cursor wraps around, so a small table is walked whole
+            for _ in 0..SPILL_SAMPLES * 16 {
+                cursor = self.table.scan(cursor, |entry| {
+                    let Some(place) = self.places.get(&entry.key) else {
+                        return;
+                    };
+                    if place.on_disk.is_some() || place.offset + place.len as u64 > self.size {
+                        return;
+                    }
+                    sampled += 1;
+                    let idle = entry.idle_secs();
+                    if coldest.as_ref().is_none_or(|(most, _)| idle > *most) {
+                        coldest = Some((idle, entry.key.clone()));
+                    }
+                });
+                if sampled >= SPILL_SAMPLES {
+                    break;
+                }
+            }
+            let Some((_, key)) = coldest else {
+                break;
+            };
+            let Some(mut entry) = self.table.delete_entry_and_return(&key) else {
+                break;
+            };
+            let place = self.places.get_mut(&key).unwrap();
+            place.on_disk = Some(entry.value.value_type());
+            self.resident -= place.len as u64;
+            entry.value = Value::Init;
+            self.table.insert(entry);
+        }
+    }
+
+    // Write the log again with just the current keys, on `pool`. Every key's
+    // LOG_SET is in the file by now, so the thread copies those with the
+    // TTLs of now.
+    fn start_rewrite(&mut self, expires: &Expires, pool: &ThreadPool) {
+        let old = match self.file.try_clone() {
+            Ok(old) => old,
+            Err(e) => {
+                log_error!("persist", "Can't rewrite {}: {}", self.path, e);
+                self.base_size = self.size;
+                return;
+            }
+        };
+        let keys: Vec<_> = self
+            .places
+            .iter()
+            .map(|(key, place)| (key.clone(), *place, expires.get(key)))
+            .collect();
+        let temp_path = Path::new(&self.path).with_file_name(format!("temp-{}.log", std::process::id()));
+        let result: RewriteResult = Arc::default();
+
+        let (path, slot) = (temp_path.clone(), result.clone());
+        pool.submit(move || {
+            let outcome = write_rewrite(&old, &path, keys);
+            *slot.lock().unwrap() = Some(outcome);
+        });
+        self.rewrite = Some(Rewrite { temp_path, buf: Vec::new(), since: HashMap::new(), result });
+    }
+
+    // Back on the event loop: catch the new file up and swap it in, with
+    // each key's record at `offsets` unless it's been written since
+    fn install_rewrite(&mut self, rewrite: &Rewrite, offsets: Vec<(String, u64)>) -> io::Result<()> {
+        let mut file = OpenOptions::new().read(true).append(true).open(&rewrite.temp_path)?;
+        let base = file.metadata()?.len();
+        file.write_all(&rewrite.buf)?;
+        file.sync_data()?;
+        fs::rename(&rewrite.temp_path, &self.path)?;
+
+        // Whatever the old file still has buffered is in rewrite.buf too
+        self.buf.clear();
+        self.written = 0;
+        self.file = file;
+        self.size = self.file.metadata()?.len();
+        self.base_size = self.size;
+        for (key, offset) in offsets {
+            if let Some(place) = self.places.get_mut(&key)
+                && !rewrite.since.contains_key(&key)
+            {
+                place.offset = offset;
+            }
+        }
+        for (key, &pos) in &rewrite.since {
+            if let Some(place) = self.places.get_mut(key) {
+                place.offset = base + pos as u64;
+            }
+        }
+        Ok(())
+    }
+
+    // Swap in the rewrite if its thread is done
+    fn check_rewrite(&mut self) {
+        let done = self.rewrite.as_ref().and_then(|rewrite| rewrite.result.lock().unwrap().take());
+        let Some(outcome) = done else {
+            return;
+        };
+        let rewrite = self.rewrite.take().unwrap();
+        let before = self.size;
+        match outcome.and_then(|offsets| self.install_rewrite(&rewrite, offsets)) {
+            Ok(()) => log_notice!("persist", "Rewrote {}: {} bytes down to {}", self.path, before, self.size),
+            Err(e) => {
+                log_error!("persist", "Can't rewrite {}: {}", self.path, e);
+                let _ = fs::remove_file(&rewrite.temp_path);
+                // Not again before it doubles once more
+                self.base_size = self.size;
+            }
+        }
+    }
+}
+
+/// Append `buf` to `out`, going on from the `written` bytes of it an earlier
+/// call got out, and add what's written to `size`; `buf` is emptied once
+/// it's all out. A write that fails part way leaves the rest to the next
+/// call, so no byte is written twice.
+pub fn write_buf(out: &mut impl Write, buf: &mut Vec<u8>, written: &mut usize, size: &mut u64) -> io::Result<()> {
+    while *written < buf.len() {
+        match out.write(&buf[*written..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                *written += n;
+                *size += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    buf.clear();
+    *written = 0;
+    Ok(())
+}
+
+// One LOG_SET per key, to the file at `path`, copied from its record at
+// `place` in `old` with the TTL it has now. Returns where each one went.
+fn write_rewrite(old: &File, path: &Path, keys: Vec<(String, Place, Option<u64>)>) -> io::Result<Vec<(String, u64)>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let mut data = Vec::new();
+    let mut payload = Vec::new();
+    let mut record = Vec::new();
+    let mut offsets = Vec::with_capacity(keys.len());
+    let mut offset = 0;
+    for (key, place, expire_at) in keys {
+        data.resize(place.len as usize, 0);
+        old.read_exact_at(&mut data, place.offset)?;
+        let value = match get_record(&data) {
+            Ok(Some(record)) if record.opcode == LOG_SET && record.key == key && record.payload.len() > 8 => {
+                &record.payload[8..]
+            }
+            _ => {
+                let e = format!("no SET record for {} at offset {}", key, place.offset);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        };
+        payload.clear();
+        payload.extend_from_slice(&expire_at.unwrap_or(0).to_le_bytes());
+        payload.extend_from_slice(value);
+        record.clear();
+        put_record(&mut record, LOG_SET, &key, &payload);
+        file.write_all(&record)?;
+        offsets.push((key, offset));
+        offset += record.len() as u64;
+    }
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(offsets)
+}
+
+impl KvStore for LogStore {
+    fn get(&self, key: &str) -> Option<&Entry> {
+        self.table.find_entry(key)
+    }
+
+    fn set(&mut self, entry: Box<Entry>, expire_at: Option<u64>) {
+        self.in_memory(&entry.key);
+        self.changed.insert(entry.key.clone(), Change::Set(expire_at));
+        self.table.insert(entry);
+    }
+
+    fn delete(&mut self, key: &str) -> Option<Box<Entry>> {
+        let entry = self.table.delete_entry_and_return(key)?;
+        self.in_memory(key);
+        self.changed.insert(key.to_string(), Change::Del);
+        Some(entry)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &Entry> + '_> {
+        Box::new(HMap::iter(&self.table))
+    }
+
+    fn scan(&self, cursor: u64, visit: &mut dyn FnMut(&Entry)) -> u64 {
+        self.table.scan(cursor, visit)
+    }
+
+    fn size(&self) -> usize {
+        self.table.size()
+    }
+
+    fn ttl_changed(&mut self, key: &str, expire_at: Option<u64>) {
+        match self.changed.get_mut(key) {
+            // The value isn't logged yet: it goes with this TTL
+            Some(Change::Set(at)) => *at = expire_at,
+            _ => {
+                self.changed.insert(key.to_string(), Change::Ttl(expire_at));
+            }
+        }
+    }
+
+    fn flush(&mut self, expires: &Expires, pool: &ThreadPool) {
+        self.check_rewrite();
+        self.log_changes();
+
+        if !self.buf.is_empty()
+            && let Err(e) = write_buf(&mut self.file, &mut self.buf, &mut self.written, &mut self.size)
+        {
+            // The rest stays in the buffer, the next flush goes on with it
+            log_error!("persist", "Error writing to {}: {}", self.path, e);
+            return;
+        }
+
+        let now_ms = get_monotonic_time_ms();
+        if now_ms >= self.last_fsync_ms + 1000 {
+            self.last_fsync_ms = now_ms;
+            if let Err(e) = self.file.sync_data() {
+                log_error!("persist", "Can't fsync {}: {}", self.path, e);
+            }
+        }
+
+        self.spill();
+        if self.rewrite.is_none() && self.size >= LOG_MIN_REWRITE && self.size >= self.base_size * 2 {
+            self.start_rewrite(expires, pool);
+        }
+    }
+
+    fn next_timer_ms(&self, now_ms: u64) -> Option<u64> {
+        // Nothing wakes the event loop when the thread is done
+        self.rewrite.as_ref().map(|_| now_ms + 100)
+    }
+
+    fn on_disk(&self, keys: &[&str]) -> bool {
+        keys.iter().any(|key| self.places.get(*key).is_some_and(|place| place.on_disk.is_some()))
+    }
+
+    fn load(&mut self, keys: &[&str]) -> Result<(), String> {
+        for &key in keys {
+            let Some(place) = self.places.get(key).filter(|place| place.on_disk.is_some()) else {
+                continue;
+            };
+            let value = self
+                .read_value(key, place)
+                .map_err(|e| format!("ERR can't read the value of '{}' back from {}: {}", key, self.path, e))?;
+            let Some(mut entry) = self.table.delete_entry_and_return(key) else {
+                continue;
+            };
+            entry.value = value;
+            self.table.insert(entry);
+            self.in_memory(key);
+        }
+        Ok(())
+    }
+
+    fn value(&self, entry: &Entry) -> io::Result<Value> {
+        match self.places.get(&entry.key) {
+            Some(place) if place.on_disk.is_some() => self.read_value(&entry.key, place),
+            _ => Ok(entry.value.clone()),
+        }
+    }
+
+    fn type_name(&self, entry: &Entry) -> &'static str {
+        match self.places.get(&entry.key).and_then(|place| place.on_disk) {
+            Some(value_type) => value_type.type_name(),
+            None => entry.value.type_name(),
+        }
+    }
+
+    fn table(&self) -> &HMap {
+        &self.table
+    }
+
+    fn table_mut(&mut self) -> &mut HMap {
+        &mut self.table
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::{self, Write};
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use std::{fs, thread};
+
+    use super::{get_record, write_buf, KvStore, LogStore, LOG_DEL, LOG_MIN_REWRITE, LOG_SET, LOG_TTL};
+    use redis::protocol::RedisValue;
+
+    use crate::expire::Expires;
+    use crate::sim::testing::shared;
+    use crate::{with_global_data, with_global_data_read, Entry, ThreadPool, Value};
+
+    // 2100-01-01, far from anything the clocks say
+    const AT: u64 = 4_102_444_800_000;
+
+    // An empty directory of its own for `name`
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entry(key: &str, value: &str) -> Box<Entry> {
+        Box::new(Entry::new(key.to_string(), Value::Str(value.into())))
+    }
+
+    // The string at `key`, if it's in memory
+    fn string(store: &LogStore, key: &str) -> Option<String> {
+        match &store.get(key)?.value {
+            Value::Str(s) => Some(s.to_string()),
+            _ => None,
+        }
+    }
+
+    fn sorted(mut ttls: Vec<(String, u64)>) -> Vec<(String, u64)> {
+        ttls.sort();
+        ttls
+    }
+
+    #[test]
+    fn expiries_come_back_as_the_same_unix_times() {
+        // The store reads the clocks, which the simulation owns
+        let _sim = shared();
+        let dir = test_dir("ttls");
+        let path = dir.join("keyspace.log");
+        let path = path.to_str().unwrap();
+        let pool = ThreadPool::new(1);
+
+        let (mut store, _) = LogStore::open(path, 0).unwrap();
+        store.set(entry("ttl:a", "1"), Some(AT));
+        store.set(entry("ttl:b", "2"), None);
+        store.ttl_changed("ttl:b", Some(AT + 1));
+        store.set(entry("ttl:c", "3"), Some(AT + 2));
+        store.ttl_changed("ttl:c", None);
+        store.flush(&Expires::default(), &pool);
+        drop(store);
+
+        let (store, ttls) = LogStore::open(path, 0).unwrap();
+        assert_eq!(store.size(), 3);
+        assert_eq!(sorted(ttls), [("ttl:a".to_string(), AT), ("ttl:b".to_string(), AT + 1)]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // The opcodes of the records in the log at `path`
+    fn opcodes(path: &str) -> Vec<u8> {
+        let data = fs::read(path).unwrap();
+        let mut opcodes = Vec::new();
+        let mut pos = 0;
+        while let Some(record) = get_record(&data[pos..]).unwrap() {
+            opcodes.push(record.opcode);
+            pos += record.len;
+        }
+        assert_eq!(pos, data.len());
+        opcodes
+    }
+
+    // An in-place write takes the entry out and puts it back; what's logged
+    // is the key's state at the end of the round
+    #[test]
+    fn a_round_logs_each_key_once() {
+        let _sim = shared();
+        let dir = test_dir("rounds");
+        let path = dir.join("keyspace.log");
+        let path = path.to_str().unwrap();
+        let pool = ThreadPool::new(1);
+        let expires = Expires::default();
+
+        let (mut store, _) = LogStore::open(path, 0).unwrap();
+        store.set(entry("round:a", "0"), None);
+        store.set(entry("round:b", "0"), None);
+        store.flush(&expires, &pool);
+        assert_eq!(opcodes(path), [LOG_SET, LOG_SET]);
+
+        for n in 1..=3 {
+            store.delete("round:a");
+            store.set(entry("round:a", &n.to_string()), None);
+        }
+        store.ttl_changed("round:a", Some(AT));
+        store.ttl_changed("round:b", Some(AT + 1));
+        store.flush(&expires, &pool);
+        let mut round = opcodes(path).split_off(2);
+        round.sort();
+        assert_eq!(round, [LOG_SET, LOG_TTL]);
+
+        store.delete("round:b");
+        store.flush(&expires, &pool);
+        assert_eq!(opcodes(path).last(), Some(&LOG_DEL));
+        drop(store);
+
+        let (store, ttls) = LogStore::open(path, 0).unwrap();
+        assert_eq!(store.size(), 1);
+        let value = &store.get("round:a").unwrap().value;
+        assert!(matches!(value, Value::Str(s) if &**s == "3"));
+        assert_eq!(ttls, [("round:a".to_string(), AT)]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Values past storage-memory are left in the file and read back for the
+    // commands that use them
+    #[test]
+    fn values_past_the_memory_limit_are_read_back() {
+        let _sim = shared();
+        let dir = test_dir("spill");
+        let path = dir.join("keyspace.log");
+        let path = path.to_str().unwrap();
+        let pool = ThreadPool::new(1);
+        let expires = Expires::default();
+        let value = |n: usize| format!("{}{}", n, "v".repeat(1000));
+        let keys: Vec<_> = (0..10).map(|n| format!("spill:{}", n)).collect();
+
+        let (mut store, _) = LogStore::open(path, 3000).unwrap();
+        for (n, key) in keys.iter().enumerate() {
+            store.set(entry(key, &value(n)), None);
+        }
+        store.flush(&expires, &pool);
+        assert!(store.resident <= 3000, "{} bytes in memory", store.resident);
+        let on_disk: Vec<_> = keys.iter().filter(|key| store.on_disk(&[key.as_str()])).collect();
+        assert!(on_disk.len() >= 8, "{} values on disk", on_disk.len());
+
+        // Left out of memory, but not out of reach
+        let key = on_disk[0].as_str();
+        let n: usize = key["spill:".len()..].parse().unwrap();
+        let spilled = store.get(key).unwrap();
+        assert!(matches!(spilled.value, Value::Init));
+        assert_eq!(store.type_name(spilled), "string");
+        assert!(matches!(store.value(spilled).unwrap(), Value::Str(s) if *s == value(n)));
+        store.load(&[key]).unwrap();
+        assert!(!store.on_disk(&[key]));
+        assert_eq!(string(&store, key), Some(value(n)));
+
+        // A new value replaces the one on disk right away
+        let other = on_disk[1].as_str();
+        store.delete(other);
+        store.set(entry(other, "new"), None);
+        assert!(!store.on_disk(&[other]));
+        assert_eq!(string(&store, other).as_deref(), Some("new"));
+        store.flush(&expires, &pool);
+        drop(store);
+
+        // At startup every value is left on disk until it's used
+        let (mut store, _) = LogStore::open(path, 3000).unwrap();
+        assert_eq!(store.resident, 0);
+        let names: Vec<_> = keys.iter().map(String::as_str).collect();
+        assert!(names.iter().all(|key| store.on_disk(&[key])));
+        store.load(&names).unwrap();
+        for (n, key) in keys.iter().enumerate() {
+            let expected = if key == other { "new".to_string() } else { value(n) };
+            assert_eq!(string(&store, key), Some(expected));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Commands find the values they name in memory, whatever the store had
+    #[test]
+    fn commands_read_values_back_from_disk() {
+        let mut sim = shared();
+        let dir = test_dir("spill-sim");
+        let path = dir.join("keyspace.log");
+        let (store, _) = LogStore::open(path.to_str().unwrap(), 1).unwrap();
+        let table = with_global_data(|g_data| std::mem::replace(&mut g_data.db, Box::new(store)));
+
+        sim.call("spill", "RPUSH spill:list a b c");
+        sim.call("spill", "SET spill:str v");
+        sim.settle().unwrap();
+        assert!(with_global_data_read(|g_data| g_data.db.on_disk(&["spill:list"]) && g_data.db.on_disk(&["spill:str"])));
+
+        let list = sim.call("spill", "LRANGE spill:list 0 -1");
+        let strs = |items: &[&str]| RedisValue::Arr(items.iter().map(|item| RedisValue::Str(item.to_string())).collect());
+        assert_eq!(list, strs(&["a", "b", "c"]));
+        assert_eq!(sim.call("spill", "RPUSH spill:list d"), RedisValue::Int(4));
+        assert_eq!(sim.call("spill", "GET spill:str"), RedisValue::Str("v".into()));
+        sim.settle().unwrap();
+        assert_eq!(sim.call("spill", "SCAN 0 MATCH spill:* TYPE list COUNT 100"), RedisValue::Arr(vec![
+            RedisValue::Str("0".into()),
+            strs(&["spill:list"]),
+        ]));
+        assert_eq!(sim.call("spill", "LRANGE spill:list 0 -1"), strs(&["a", "b", "c", "d"]));
+
+        with_global_data(|g_data| g_data.db = table);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Takes up to 3 bytes a write, and fails the write after `fail_at`
+    pub struct Flaky {
+        pub out: Vec<u8>,
+        pub fail_at: Option<usize>,
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.fail_at.is_some_and(|at| self.out.len() >= at) {
+                self.fail_at = None;
+                return Err(io::Error::other("disk full"));
+            }
+            let n = buf.len().min(3);
+            self.out.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_failed_write_goes_on_where_it_stopped() {
+        let data = b"0123456789abcdef";
+        let mut buf = data.to_vec();
+        let mut out = Flaky { out: Vec::new(), fail_at: Some(7) };
+        let (mut written, mut size) = (0, 0);
+
+        assert!(write_buf(&mut out, &mut buf, &mut written, &mut size).is_err());
+        assert_eq!((written, size), (9, 9));
+        assert_eq!(out.out, &data[..9]);
+
+        write_buf(&mut out, &mut buf, &mut written, &mut size).unwrap();
+        assert_eq!((written, size), (0, data.len() as u64));
+        assert!(buf.is_empty());
+        assert_eq!(out.out, data);
+    }
+
+    #[test]
+    fn the_rewrite_runs_on_the_pool_next_to_the_log() {
+        let _sim = shared();
+        let dir = test_dir("rewrite");
+        let path = dir.join("keyspace.log");
+        let path = path.to_str().unwrap();
+        let pool = ThreadPool::new(1);
+        let mut expires = Expires::default();
+
+        // Each record of it a megabyte, the same key written in every round;
+        // values go to disk as soon as they're in the file
+        let (mut store, _) = LogStore::open(path, 1).unwrap();
+        let big = "x".repeat(1024 * 1024);
+        expires.set("rw:big".to_string(), AT, 0);
+        for round in 0..4 {
+            if round > 0 {
+                store.delete("rw:big");
+            }
+            store.set(entry("rw:big", &big), Some(AT));
+            store.flush(&expires, &pool);
+        }
+        assert!(store.size >= LOG_MIN_REWRITE);
+
+        // Left to the thread, next to the log
+        let rewrite = store.rewrite.as_ref().expect("a rewrite running");
+        assert_eq!(rewrite.temp_path.parent(), Some(dir.as_path()));
+        assert!(store.next_timer_ms(0).is_some());
+
+        // Changes made meanwhile end up in the new file too
+        store.set(entry("rw:late", "1"), None);
+        store.ttl_changed("rw:late", Some(AT + 1));
+        for _ in 0..500 {
+            if store.rewrite.is_none() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+            store.flush(&expires, &pool);
+        }
+        assert!(store.rewrite.is_none());
+        store.flush(&expires, &pool);
+        assert!(store.size < 2 * 1024 * 1024, "{} bytes after the rewrite", store.size);
+        assert_eq!(store.size, fs::metadata(path).unwrap().len());
+
+        // Values on disk are found in the new file, those copied over by the
+        // thread and those written since alike
+        assert!(store.on_disk(&["rw:big"]) && store.on_disk(&["rw:late"]));
+        store.load(&["rw:big", "rw:late"]).unwrap();
+        assert_eq!(string(&store, "rw:big").as_ref(), Some(&big));
+        assert_eq!(string(&store, "rw:late").as_deref(), Some("1"));
+        drop(store);
+
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().map(|file| file.unwrap().file_name()).collect();
+        assert_eq!(files, ["keyspace.log"]);
+        let (store, ttls) = LogStore::open(path, 0).unwrap();
+        assert_eq!(store.size(), 2);
+        assert_eq!(sorted(ttls), [("rw:big".to_string(), AT), ("rw:late".to_string(), AT + 1)]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1162 @@
+/* Streams */
+//
+// Append-only log of field/value entries keyed by "<ms>-<seq>" IDs. Entries
+// live in an ordered map so range scans in either direction are a single
+// BTreeMap::range call.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::mem::size_of;
+use std::ops::Bound;
+
+use crate::command::CmdResult;
+use crate::memory::{alloc_size, btree_size, MemoryUsage};
+use crate::{
+    expect_stream_mut, get_current_time_ms, out_arr, out_err, out_int, out_nil, out_str, with_global_data, with_global_data_read,
+    Buffer,
+};
+
+const ERR_INVALID_ID: &str = "ERR Invalid stream ID specified as stream command argument";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        Self { ms, seq }
+    }
+
+    /// Parse "<ms>-<seq>" or a bare "<ms>", in which case `default_seq` is used.
+    pub fn parse(s: &str, default_seq: u64) -> Option<StreamId> {
+        match s.split_once('-') {
+            Some((ms, seq)) => Some(StreamId::new(ms.parse().ok()?, seq.parse().ok()?)),
+            None => Some(StreamId::new(s.parse().ok()?, default_seq)),
+        }
+    }
+
+    /// Smallest ID strictly greater than this one
+    pub fn next(&self) -> Option<StreamId> {
+        if self.seq < u64::MAX {
+            Some(StreamId::new(self.ms, self.seq + 1))
+        } else if self.ms < u64::MAX {
+            Some(StreamId::new(self.ms + 1, 0))
+        } else {
+            None
+        }
+    }
+
+    /// Largest ID strictly smaller than this one
+    pub fn prev(&self) -> Option<StreamId> {
+        if self.seq > 0 {
+            Some(StreamId::new(self.ms, self.seq - 1))
+        } else if self.ms > 0 {
+            Some(StreamId::new(self.ms - 1, u64::MAX))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// How XADD was asked to pick the new entry's ID
+#[derive(Debug, Clone, Copy)]
+pub enum IdSpec {
+    Auto,           // "*"
+    AutoSeq(u64),   // "<ms>-*"
+    Explicit(StreamId),
+}
+
+impl IdSpec {
+    pub fn parse(s: &str) -> Option<IdSpec> {
+        if s == "*" {
+            return Some(IdSpec::Auto);
+        }
+        if let Some(ms) = s.strip_suffix("-*") {
+            return ms.parse().ok().map(IdSpec::AutoSeq);
+        }
+        StreamId::parse(s, 0).map(IdSpec::Explicit)
+    }
+}
+
+pub type StreamFields = Vec<(String, String)>;
+
+#[derive(Debug, Default, Clone)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, StreamFields>,
+    last_id: StreamId,
+    // Consumer groups are part of the stream value, so they travel with it
+    groups: BTreeMap<String, ConsumerGroup>,
+}
+
+/// A delivered-but-unacknowledged entry in a group's pending entries list (PEL)
+#[derive(Debug, Clone)]
+pub struct PendingEntry {
+    pub consumer: String,
+    pub delivery_time_ms: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Consumer {
+    pub seen_time_ms: u64,
+    // IDs this consumer owns in the group PEL
+    pub pending: BTreeSet<StreamId>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConsumerGroup {
+    pub last_delivered: StreamId,
+    pub pel: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<String, Consumer>,
+}
+
+impl ConsumerGroup {
+    pub fn new(last_delivered: StreamId) -> Self {
+        Self {
+            last_delivered,
+            pel: BTreeMap::new(),
+            consumers: BTreeMap::new(),
+        }
+    }
+
+    /// Look up a consumer, creating it on first use like XREADGROUP does.
+    /// Returns true if it was created.
+    pub fn touch_consumer(&mut self, name: &str, now_ms: u64) -> bool {
+        match self.consumers.get_mut(name) {
+            Some(consumer) => {
+                consumer.seen_time_ms = now_ms;
+                false
+            }
+            None => {
+                let consumer = Consumer { seen_time_ms: now_ms, pending: BTreeSet::new() };
+                self.consumers.insert(name.to_string(), consumer);
+                true
+            }
+        }
+    }
+
+    /// Record a (re)delivery of `id` to `consumer`, moving ownership if needed
+    fn assign(&mut self, id: StreamId, consumer: &str, now_ms: u64, bump_count: bool) {
+        let pending = self.pel.entry(id).or_insert_with(|| PendingEntry {
+            consumer: consumer.to_string(),
+            delivery_time_ms: now_ms,
+            delivery_count: 0,
+        });
+        if pending.consumer != consumer {
+            if let Some(previous) = self.consumers.get_mut(&pending.consumer) {
+                previous.pending.remove(&id);
+            }
+            pending.consumer = consumer.to_string();
+        }
+        pending.delivery_time_ms = now_ms;
+        if bump_count {
+            pending.delivery_count += 1;
+        }
+        if let Some(owner) = self.consumers.get_mut(consumer) {
+            owner.pending.insert(id);
+        }
+    }
+
+    /// Acknowledge an entry, returns true if it was pending
+    pub fn ack(&mut self, id: &StreamId) -> bool {
+        match self.pel.remove(id) {
+            Some(pending) => {
+                if let Some(consumer) = self.consumers.get_mut(&pending.consumer) {
+                    consumer.pending.remove(id);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove a consumer along with its pending entries, returns how many were pending
+    pub fn delete_consumer(&mut self, name: &str) -> usize {
+        match self.consumers.remove(name) {
+            Some(consumer) => {
+                for id in &consumer.pending {
+                    self.pel.remove(id);
+                }
+                consumer.pending.len()
+            }
+            None => 0,
+        }
+    }
+}
+
+/// Where XREADGROUP starts reading for one stream
+#[derive(Debug, Clone, Copy)]
+pub enum GroupReadFrom {
+    /// ">": entries never delivered to any consumer of the group
+    New,
+    /// An explicit ID: this consumer's own pending entries after it
+    History(StreamId),
+}
+
+impl MemoryUsage for Stream {
+    fn memory_usage(&self) -> usize {
+        let fields: usize = self
+            .entries
+            .values()
+            .map(|fields| {
+                let strings: usize = fields.iter().map(|(field, value)| field.memory_usage() + value.memory_usage()).sum();
+                alloc_size(fields.capacity() * size_of::<(String, String)>()) + strings
+            })
+            .sum();
+        let groups: usize = self
+            .groups
+            .iter()
+            .map(|(name, group)| {
+                let consumers: usize = group
+                    .consumers
+                    .iter()
+                    .map(|(name, consumer)| {
+                        name.memory_usage()
+                            + btree_size(consumer.pending.len(), size_of::<StreamId>())
+                    })
+                    .sum();
+                let pel: usize = group.pel.values().map(|pending| pending.consumer.memory_usage()).sum();
+                name.memory_usage()
+                    + btree_size(group.consumers.len(), size_of::<(String, Consumer)>())
+                    + consumers
+                    + btree_size(group.pel.len(), size_of::<(StreamId, PendingEntry)>())
+                    + pel
+            })
+            .sum();
+        btree_size(self.entries.len(), size_of::<(StreamId, StreamFields)>())
+            + fields
+            + btree_size(self.groups.len(), size_of::<(String, ConsumerGroup)>())
+            + groups
+    }
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            last_id: StreamId::MIN,
+            groups: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// Resolve an ID spec against the current top of the stream.
+    /// IDs must be strictly increasing, even across deleted or trimmed entries.
+    pub fn next_id(&self, spec: IdSpec, now_ms: u64) -> Result<StreamId, &'static str> {
+        let last = self.last_id;
+        let id = match spec {
+            IdSpec::Auto => {
+                if now_ms > last.ms {
+                    StreamId::new(now_ms, 0)
+                } else {
+                    // Clock went backwards or same millisecond: bump the sequence
+                    last.next().ok_or("ERR The stream has exhausted the last possible ID, unable to add more items")?
+                }
+            }
+            IdSpec::AutoSeq(ms) => {
+                if ms > last.ms {
+                    StreamId::new(ms, 0)
+                } else if ms == last.ms && last.seq < u64::MAX {
+                    StreamId::new(ms, last.seq + 1)
+                } else {
+                    return Err("ERR The ID specified in XADD is equal or smaller than the target stream top item");
+                }
+            }
+            IdSpec::Explicit(id) => {
+                if id == StreamId::MIN {
+                    return Err("ERR The ID specified in XADD must be greater than 0-0");
+                }
+                id
+            }
+        };
+
+        if id <= last {
+            return Err("ERR The ID specified in XADD is equal or smaller than the target stream top item");
+        }
+        Ok(id)
+    }
+
+    pub fn append(&mut self, id: StreamId, fields: StreamFields) {
+        debug_assert!(id > self.last_id);
+        self.entries.insert(id, fields);
+        self.last_id = id;
+    }
+
+    /// Entries with start <= id <= end, in ascending order
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl DoubleEndedIterator<Item = (&StreamId, &StreamFields)> {
+        // An empty iterator for inverted bounds instead of BTreeMap's panic
+        let bounds = if start <= end {
+            (Bound::Included(start), Bound::Included(end))
+        } else {
+            (Bound::Included(start), Bound::Excluded(start))
+        };
+        self.entries.range(bounds)
+    }
+
+    /// Drop the oldest entries until at most `maxlen` remain, returns how many were removed
+    pub fn trim_maxlen(&mut self, maxlen: usize) -> usize {
+        let mut removed = 0;
+        while self.entries.len() > maxlen {
+            self.entries.pop_first();
+            removed += 1;
+        }
+        removed
+    }
+
+    pub fn group(&self, name: &str) -> Option<&ConsumerGroup> {
+        self.groups.get(name)
+    }
+
+    pub fn group_mut(&mut self, name: &str) -> Option<&mut ConsumerGroup> {
+        self.groups.get_mut(name)
+    }
+
+    /// Returns false if a group with that name already exists
+    pub fn create_group(&mut self, name: &str, last_delivered: StreamId) -> bool {
+        if self.groups.contains_key(name) {
+            return false;
+        }
+        self.groups.insert(name.to_string(), ConsumerGroup::new(last_delivered));
+        true
+    }
+
+    pub fn destroy_group(&mut self, name: &str) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&StreamId, &StreamFields)> {
+        self.entries.iter()
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = (&String, &ConsumerGroup)> {
+        self.groups.iter()
+    }
+
+    /// Restore the last generated ID, which can be past the newest entry
+    /// after deletions or trimming
+    pub fn set_last_id(&mut self, id: StreamId) {
+        self.last_id = self.last_id.max(id);
+    }
+
+    pub fn insert_group(&mut self, name: String, group: ConsumerGroup) {
+        self.groups.insert(name, group);
+    }
+
+    /// Serve XREADGROUP for this stream. Entries that were deleted from the
+    /// stream while still pending come back with no fields.
+    pub fn read_group(
+        &mut self,
+        group_name: &str,
+        consumer: &str,
+        from: GroupReadFrom,
+        count: usize,
+        noack: bool,
+        now_ms: u64,
+    ) -> Option<Vec<(StreamId, Option<StreamFields>)>> {
+        let group = self.groups.get_mut(group_name)?;
+        group.touch_consumer(consumer, now_ms);
+
+        let mut result = Vec::new();
+        match from {
+            GroupReadFrom::New => {
+                let Some(start) = group.last_delivered.next() else {
+                    return Some(result);
+                };
+                let range = (Bound::Included(start), Bound::Unbounded);
+                for (id, fields) in self.entries.range(range).take(count) {
+                    group.last_delivered = *id;
+                    if !noack {
+                        group.assign(*id, consumer, now_ms, true);
+                    }
+                    result.push((*id, Some(fields.clone())));
+                }
+            }
+            GroupReadFrom::History(after) => {
+                let ids: Vec<StreamId> = group.consumers[consumer]
+                    .pending
+                    .range((Bound::Excluded(after), Bound::Unbounded))
+                    .take(count)
+                    .copied()
+                    .collect();
+                for id in ids {
+                    group.assign(id, consumer, now_ms, true);
+                    result.push((id, self.entries.get(&id).cloned()));
+                }
+            }
+        }
+        Some(result)
+    }
+
+    /// Serve XCLAIM: move pending entries idle for at least `min_idle_ms` to
+    /// `consumer`. Entries deleted from the stream are dropped from the PEL.
+    pub fn claim(
+        &mut self,
+        group_name: &str,
+        consumer: &str,
+        min_idle_ms: u64,
+        ids: &[StreamId],
+        opts: &ClaimOptions,
+        now_ms: u64,
+    ) -> Option<Vec<(StreamId, StreamFields)>> {
+        let group = self.groups.get_mut(group_name)?;
+        group.touch_consumer(consumer, now_ms);
+
+        let mut claimed = Vec::new();
+        for id in ids {
+            let Some(fields) = self.entries.get(id) else {
+                group.ack(id);
+                continue;
+            };
+            match group.pel.get(id) {
+                Some(pending) => {
+                    if now_ms.saturating_sub(pending.delivery_time_ms) < min_idle_ms {
+                        continue;
+                    }
+                }
+                None if opts.force => {}
+                None => continue,
+            }
+
+            group.assign(*id, consumer, now_ms, !opts.justid);
+            let pending = group.pel.get_mut(id).unwrap();
+            if let Some(idle) = opts.idle_ms {
+                pending.delivery_time_ms = now_ms.saturating_sub(idle);
+            } else if let Some(time) = opts.time_ms {
+                pending.delivery_time_ms = time;
+            }
+            if let Some(retry_count) = opts.retry_count {
+                pending.delivery_count = retry_count;
+            }
+            claimed.push((*id, fields.clone()));
+        }
+        Some(claimed)
+    }
+}
+
+/// Optional XCLAIM arguments
+#[derive(Debug, Default)]
+pub struct ClaimOptions {
+    pub idle_ms: Option<u64>,
+    pub time_ms: Option<u64>,
+    pub retry_count: Option<u64>,
+    pub force: bool,
+    pub justid: bool,
+}
+
+// Range bounds as accepted by XRANGE/XREVRANGE: "-", "+", "(<id>" or "<id>"
+fn parse_range_start(s: &str) -> Option<Option<StreamId>> {
+    match s {
+        "-" => Some(Some(StreamId::MIN)),
+        "+" => Some(Some(StreamId::MAX)),
+        _ => match s.strip_prefix('(') {
+            // An exclusive start past the last possible ID matches nothing
+            Some(rest) => StreamId::parse(rest, 0).map(|id| id.next()),
+            None => StreamId::parse(s, 0).map(Some),
+        },
+    }
+}
+
+fn parse_range_end(s: &str) -> Option<Option<StreamId>> {
+    match s {
+        "-" => Some(Some(StreamId::MIN)),
+        "+" => Some(Some(StreamId::MAX)),
+        _ => match s.strip_prefix('(') {
+            Some(rest) => StreamId::parse(rest, u64::MAX).map(|id| id.prev()),
+            None => StreamId::parse(s, u64::MAX).map(Some),
+        },
+    }
+}
+
+/// Write one entry as [id, [field, value, ...]]
+pub fn out_stream_entry(out: &mut Buffer, id: &StreamId, fields: &StreamFields) {
+    out_arr(out, 2);
+    out_str(out, &id.to_string());
+    out_arr(out, (fields.len() * 2) as u32);
+    for (field, value) in fields {
+        out_str(out, field);
+        out_str(out, value);
+    }
+}
+
+// XADD key [NOMKSTREAM] [MAXLEN [=|~] count] <* | id> field value [field value ...]
+pub fn do_xadd(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 5 {
+        out_err(out, "XADD requires: key [NOMKSTREAM] [MAXLEN [=|~] count] <* | id> field value [field value ...]");
+        return Ok(());
+    }
+
+    let key = &cmd[1];
+    let mut nomkstream = false;
+    let mut maxlen: Option<usize> = None;
+
+    let mut i = 2;
+    while i < cmd.len() {
+        match cmd[i].to_uppercase().as_str() {
+            "NOMKSTREAM" => {
+                nomkstream = true;
+                i += 1;
+            }
+            "MAXLEN" => {
+                i += 1;
+                // Approximate trimming is done exactly; the flag is accepted for compatibility
+                if i < cmd.len() && (cmd[i] == "=" || cmd[i] == "~") {
+                    i += 1;
+                }
+                match cmd.get(i).and_then(|s| s.parse::<usize>().ok()) {
+                    Some(n) => maxlen = Some(n),
+                    None => {
+                        out_err(out, "ERR value is not an integer or out of range");
+                        return Ok(());
+                    }
+                }
+                i += 1;
+            }
+            _ => break,
+        }
+    }
+
+    let Some(spec) = cmd.get(i).and_then(|s| IdSpec::parse(s)) else {
+        out_err(out, ERR_INVALID_ID);
+        return Ok(());
+    };
+    let pairs = &cmd[i + 1..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        out_err(out, "ERR wrong number of arguments for 'xadd' command");
+        return Ok(());
+    }
+    let fields: StreamFields = pairs
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+
+    with_global_data(|g_data| {
+        let created = g_data.db.expect_stream(key)?.is_none();
+        if created && nomkstream {
+            out_nil(out);
+            return Ok(());
+        }
+
+        let added = expect_stream_mut(g_data, key, true, |stream| {
+            let id = stream.next_id(spec, get_current_time_ms())?;
+            stream.append(id, fields);
+            if let Some(n) = maxlen {
+                stream.trim_maxlen(n);
+            }
+            Ok(id)
+        })?;
+
+        match added.expect("created if missing") {
+            Ok(id) => out_str(out, &id.to_string()),
+            Err(msg) => {
+                out_err(out, msg);
+                // A failed XADD must not leave an empty stream behind
+                if created {
+                    g_data.with_entry_mut(key, Option::take);
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+// XLEN key
+pub fn do_xlen(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 2 {
+        out_err(out, "XLEN requires a key");
+        return Ok(());
+    }
+
+    with_global_data_read(|g_data| {
+        out_int(out, g_data.db.expect_stream(&cmd[1])?.map_or(0, |stream| stream.len()) as i64);
+        Ok(())
+    })
+}
+
+// XRANGE key start end [COUNT count]
+pub fn do_xrange(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    xrange_generic(cmd, out, false)
+}
+
+// XREVRANGE key end start [COUNT count]
+pub fn do_xrevrange(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    xrange_generic(cmd, out, true)
+}
+
+fn xrange_generic(cmd: &[String], out: &mut Buffer, rev: bool) -> CmdResult {
+    if cmd.len() != 4 && cmd.len() != 6 {
+        if rev {
+            out_err(out, "XREVRANGE requires: key end start [COUNT count]");
+        } else {
+            out_err(out, "XRANGE requires: key start end [COUNT count]");
+        }
+        return Ok(());
+    }
+
+    let key = &cmd[1];
+    let (start_arg, end_arg) = if rev { (&cmd[3], &cmd[2]) } else { (&cmd[2], &cmd[3]) };
+    let (Some(start), Some(end)) = (parse_range_start(start_arg), parse_range_end(end_arg)) else {
+        out_err(out, ERR_INVALID_ID);
+        return Ok(());
+    };
+
+    let mut count = usize::MAX;
+    if cmd.len() == 6 {
+        if !cmd[4].eq_ignore_ascii_case("COUNT") {
+            out_err(out, "ERR syntax error");
+            return Ok(());
+        }
+        match cmd[5].parse::<i64>() {
+            Ok(n) => count = n.max(0) as usize,
+            Err(_) => {
+                out_err(out, "ERR value is not an integer or out of range");
+                return Ok(());
+            }
+        }
+    }
+
+    with_global_data_read(|g_data| {
+        let stream = g_data.db.expect_stream(key)?;
+
+        let ctx = out.out_begin_arr();
+        let mut n = 0u32;
+        // Exclusive bounds at either end of the ID space leave nothing to return
+        if let (Some(stream), Some(start), Some(end)) = (stream, start, end) {
+            let range = stream.range(start, end);
+            let entries: Box<dyn Iterator<Item = _>> = if rev {
+                Box::new(range.rev())
+            } else {
+                Box::new(range)
+            };
+            for (id, fields) in entries.take(count) {
+                out_stream_entry(out, id, fields);
+                n += 1;
+            }
+        }
+        out.out_end_arr(ctx, n);
+        Ok(())
+    })
+}
+
+// XREAD [COUNT count] STREAMS key [key ...] id [id ...]
+pub fn do_xread(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    let mut count = usize::MAX;
+    let mut i = 1;
+    while i < cmd.len() {
+        match cmd[i].to_uppercase().as_str() {
+            "COUNT" => {
+                match cmd.get(i + 1).and_then(|s| s.parse::<i64>().ok()) {
+                    Some(n) => count = n.max(0) as usize,
+                    None => {
+                        out_err(out, "ERR value is not an integer or out of range");
+                        return Ok(());
+                    }
+                }
+                i += 2;
+            }
+            "BLOCK" => {
+                out_err(out, "ERR XREAD BLOCK is not supported yet");
+                return Ok(());
+            }
+            "STREAMS" => {
+                i += 1;
+                break;
+            }
+            _ => {
+                out_err(out, "ERR syntax error");
+                return Ok(());
+            }
+        }
+    }
+
+    let rest = &cmd[i.min(cmd.len())..];
+    if rest.is_empty() || !rest.len().is_multiple_of(2) {
+        out_err(out, "ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.");
+        return Ok(());
+    }
+    let (keys, ids) = rest.split_at(rest.len() / 2);
+
+    with_global_data(|g_data| {
+        // Resolve every ID first so a bad argument fails the whole command
+        let mut streams = Vec::with_capacity(keys.len());
+        for (key, id_arg) in keys.iter().zip(ids) {
+            let stream = g_data.db.expect_stream(key)?;
+            let after = if id_arg == "$" {
+                stream.map_or(StreamId::MIN, |s| s.last_id())
+            } else {
+                match StreamId::parse(id_arg, 0) {
+                    Some(id) => id,
+                    None => {
+                        out_err(out, ERR_INVALID_ID);
+                        return Ok(());
+                    }
+                }
+            };
+            streams.push((key, stream, after));
+        }
+
+        // Only streams with new entries appear in the reply
+        let ready: Vec<_> = streams
+            .into_iter()
+            .filter_map(|(key, stream, after)| {
+                let stream = stream?;
+                let start = after.next()?;
+                let entries: Vec<_> = stream.range(start, StreamId::MAX).take(count).collect();
+                (!entries.is_empty()).then_some((key, entries))
+            })
+            .collect();
+
+        if ready.is_empty() {
+            out_nil(out);
+            return Ok(());
+        }
+
+        out_arr(out, ready.len() as u32);
+        for (key, entries) in ready {
+            out_arr(out, 2);
+            out_str(out, key);
+            out_arr(out, entries.len() as u32);
+            for (id, fields) in entries {
+                out_stream_entry(out, id, fields);
+            }
+        }
+        Ok(())
+    })
+}
+
+fn nogroup(key: &str, group: &str) -> String {
+    format!("NOGROUP No such key '{}' or consumer group '{}'", key, group)
+}
+
+// XGROUP CREATE key group <id | $> [MKSTREAM]
+// XGROUP SETID key group <id | $>
+// XGROUP DESTROY key group
+// XGROUP CREATECONSUMER key group consumer
+// XGROUP DELCONSUMER key group consumer
+pub fn do_xgroup(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 4 {
+        out_err(out, "XGROUP requires: CREATE|SETID|DESTROY|CREATECONSUMER|DELCONSUMER key group ...");
+        return Ok(());
+    }
+
+    let subcommand = cmd[1].to_uppercase();
+    let key = &cmd[2];
+    let group = &cmd[3];
+    let now_ms = get_current_time_ms();
+
+    with_global_data(|g_data| {
+        let result = match subcommand.as_str() {
+            "CREATE" | "SETID" => {
+                let Some(id_arg) = cmd.get(4) else {
+                    out_err(out, "ERR wrong number of arguments for 'xgroup' command");
+                    return Ok(());
+                };
+                let mkstream = subcommand == "CREATE"
+                    && cmd.get(5).is_some_and(|s| s.eq_ignore_ascii_case("MKSTREAM"));
+                let id = if id_arg == "$" {
+                    None
+                } else {
+                    match StreamId::parse(id_arg, 0) {
+                        Some(id) => Some(id),
+                        None => {
+                            out_err(out, ERR_INVALID_ID);
+                            return Ok(());
+                        }
+                    }
+                };
+
+                expect_stream_mut(g_data, key, mkstream, |stream| {
+                    let id = id.unwrap_or(stream.last_id());
+                    if subcommand == "CREATE" {
+                        if stream.create_group(group, id) {
+                            out_nil(out);
+                        } else {
+                            out_err(out, "BUSYGROUP Consumer Group name already exists");
+                        }
+                    } else {
+                        match stream.group_mut(group) {
+                            Some(g) => {
+                                g.last_delivered = id;
+                                out_nil(out);
+                            }
+                            None => out_err(out, &nogroup(key, group)),
+                        }
+                    }
+                })
+            }
+            "DESTROY" => expect_stream_mut(g_data, key, false, |stream| {
+                out_int(out, stream.destroy_group(group) as i64);
+            }),
+            "CREATECONSUMER" | "DELCONSUMER" => {
+                let Some(consumer) = cmd.get(4) else {
+                    out_err(out, "ERR wrong number of arguments for 'xgroup' command");
+                    return Ok(());
+                };
+                expect_stream_mut(g_data, key, false, |stream| match stream.group_mut(group) {
+                    Some(g) if subcommand == "CREATECONSUMER" => {
+                        out_int(out, g.touch_consumer(consumer, now_ms) as i64);
+                    }
+                    Some(g) => out_int(out, g.delete_consumer(consumer) as i64),
+                    None => out_err(out, &nogroup(key, group)),
+                })
+            }
+            _ => {
+                out_err(out, "ERR unknown XGROUP subcommand");
+                return Ok(());
+            }
+        };
+
+        if result?.is_none() {
+            out_err(out, "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.");
+        }
+        Ok(())
+    })
+}
+
+// XREADGROUP GROUP group consumer [COUNT count] [NOACK] STREAMS key [key ...] id [id ...]
+pub fn do_xreadgroup(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 7 || !cmd[1].eq_ignore_ascii_case("GROUP") {
+        out_err(out, "XREADGROUP requires: GROUP group consumer [COUNT count] [NOACK] STREAMS key [key ...] id [id ...]");
+        return Ok(());
+    }
+
+    let group = &cmd[2];
+    let consumer = &cmd[3];
+    let mut count = usize::MAX;
+    let mut noack = false;
+
+    let mut i = 4;
+    while i < cmd.len() {
+        match cmd[i].to_uppercase().as_str() {
+            "COUNT" => {
+                match cmd.get(i + 1).and_then(|s| s.parse::<i64>().ok()) {
+                    // COUNT 0 means no limit, as in Redis
+                    Some(n) if n > 0 => count = n as usize,
+                    Some(_) => count = usize::MAX,
+                    None => {
+                        out_err(out, "ERR value is not an integer or out of range");
+                        return Ok(());
+                    }
+                }
+                i += 2;
+            }
+            "NOACK" => {
+                noack = true;
+                i += 1;
+            }
+            "BLOCK" => {
+                out_err(out, "ERR XREADGROUP BLOCK is not supported yet");
+                return Ok(());
+            }
+            "STREAMS" => {
+                i += 1;
+                break;
+            }
+            _ => {
+                out_err(out, "ERR syntax error");
+                return Ok(());
+            }
+        }
+    }
+
+    let rest = &cmd[i.min(cmd.len())..];
+    if rest.is_empty() || !rest.len().is_multiple_of(2) {
+        out_err(out, "ERR Unbalanced 'xreadgroup' list of streams: for each stream key an ID or '>' must be specified.");
+        return Ok(());
+    }
+    let (keys, ids) = rest.split_at(rest.len() / 2);
+
+    let mut reads = Vec::with_capacity(keys.len());
+    for id_arg in ids {
+        if id_arg == ">" {
+            reads.push(GroupReadFrom::New);
+        } else {
+            match StreamId::parse(id_arg, 0) {
+                Some(id) => reads.push(GroupReadFrom::History(id)),
+                None => {
+                    out_err(out, ERR_INVALID_ID);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    let now_ms = get_current_time_ms();
+
+    with_global_data(|g_data| {
+        // Check every key before touching any group state
+        for key in keys {
+            if g_data.db.expect_stream(key)?.is_none_or(|stream| stream.group(group).is_none()) {
+                out_err(out, &format!(
+                    "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                    key, group
+                ));
+                return Ok(());
+            }
+        }
+
+        let mut replies = Vec::new();
+        for (key, from) in keys.iter().zip(reads) {
+            let read = expect_stream_mut(g_data, key, false, |stream| {
+                stream.read_group(group, consumer, from, count, noack, now_ms)
+            });
+            let entries = read?.flatten().unwrap_or_default();
+            // History reads always report the key, even when nothing is pending
+            if !entries.is_empty() || matches!(from, GroupReadFrom::History(_)) {
+                replies.push((key, entries));
+            }
+        }
+
+        if replies.is_empty() {
+            out_nil(out);
+            return Ok(());
+        }
+
+        out_arr(out, replies.len() as u32);
+        for (key, entries) in replies {
+            out_arr(out, 2);
+            out_str(out, key);
+            out_arr(out, entries.len() as u32);
+            for (id, fields) in entries {
+                match fields {
+                    Some(fields) => out_stream_entry(out, &id, &fields),
+                    None => {
+                        out_arr(out, 2);
+                        out_str(out, &id.to_string());
+                        out_nil(out);
+                    }
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+// XACK key group id [id ...]
+pub fn do_xack(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 4 {
+        out_err(out, "XACK requires: key group id [id ...]");
+        return Ok(());
+    }
+
+    let key = &cmd[1];
+    let group = &cmd[2];
+    let mut ids = Vec::with_capacity(cmd.len() - 3);
+    for arg in &cmd[3..] {
+        match StreamId::parse(arg, 0) {
+            Some(id) => ids.push(id),
+            None => {
+                out_err(out, ERR_INVALID_ID);
+                return Ok(());
+            }
+        }
+    }
+
+    with_global_data(|g_data| {
+        let acked = expect_stream_mut(g_data, key, false, |stream| match stream.group_mut(group) {
+            Some(g) => ids.iter().filter(|id| g.ack(id)).count(),
+            None => 0,
+        });
+        out_int(out, acked?.unwrap_or(0) as i64);
+        Ok(())
+    })
+}
+
+// XPENDING key group [[IDLE min-idle-time] start end count [consumer]]
+pub fn do_xpending(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 3 {
+        out_err(out, "XPENDING requires: key group [[IDLE min-idle-time] start end count [consumer]]");
+        return Ok(());
+    }
+
+    let key = &cmd[1];
+    let group_name = &cmd[2];
+
+    // Extended form arguments
+    let mut min_idle = 0u64;
+    let mut i = 3;
+    if cmd.get(i).is_some_and(|s| s.eq_ignore_ascii_case("IDLE")) {
+        match cmd.get(i + 1).and_then(|s| s.parse::<u64>().ok()) {
+            Some(n) => min_idle = n,
+            None => {
+                out_err(out, "ERR value is not an integer or out of range");
+                return Ok(());
+            }
+        }
+        i += 2;
+    }
+    let extended = cmd.len() > i;
+    let mut range = (StreamId::MIN, StreamId::MAX);
+    let mut count = 0usize;
+    let mut only_consumer: Option<&String> = None;
+    if extended {
+        if cmd.len() < i + 3 || cmd.len() > i + 4 {
+            out_err(out, "ERR syntax error");
+            return Ok(());
+        }
+        let (Some(Some(start)), Some(Some(end))) = (parse_range_start(&cmd[i]), parse_range_end(&cmd[i + 1])) else {
+            out_err(out, ERR_INVALID_ID);
+            return Ok(());
+        };
+        range = (start, end);
+        match cmd[i + 2].parse::<i64>() {
+            Ok(n) => count = n.max(0) as usize,
+            Err(_) => {
+                out_err(out, "ERR value is not an integer or out of range");
+                return Ok(());
+            }
+        }
+        only_consumer = cmd.get(i + 3);
+    }
+
+    let now_ms = get_current_time_ms();
+
+    with_global_data_read(|g_data| {
+        let group = g_data.db.expect_stream(key)?.and_then(|stream| stream.group(group_name));
+        let Some(group) = group else {
+            out_err(out, &nogroup(key, group_name));
+            return Ok(());
+        };
+
+        if !extended {
+            // Summary: [count, smallest id, greatest id, [[consumer, count] ...]]
+            out_arr(out, 4);
+            out_int(out, group.pel.len() as i64);
+            match (group.pel.keys().next(), group.pel.keys().next_back()) {
+                (Some(first), Some(last)) => {
+                    out_str(out, &first.to_string());
+                    out_str(out, &last.to_string());
+                    let owners: Vec<_> = group.consumers.iter().filter(|(_, c)| !c.pending.is_empty()).collect();
+                    out_arr(out, owners.len() as u32);
+                    for (name, consumer) in owners {
+                        out_arr(out, 2);
+                        out_str(out, name);
+                        out_int(out, consumer.pending.len() as i64);
+                    }
+                }
+                _ => {
+                    out_nil(out);
+                    out_nil(out);
+                    out_nil(out);
+                }
+            }
+            return Ok(());
+        }
+
+        let ctx = out.out_begin_arr();
+        let mut n = 0u32;
+        if range.0 <= range.1 {
+            let matching = group
+                .pel
+                .range(range.0..=range.1)
+                .filter(|(_, p)| only_consumer.is_none_or(|c| &p.consumer == c))
+                .filter(|(_, p)| now_ms.saturating_sub(p.delivery_time_ms) >= min_idle)
+                .take(count);
+            for (id, pending) in matching {
+                out_arr(out, 4);
+                out_str(out, &id.to_string());
+                out_str(out, &pending.consumer);
+                out_int(out, now_ms.saturating_sub(pending.delivery_time_ms) as i64);
+                out_int(out, pending.delivery_count as i64);
+                n += 1;
+            }
+        }
+        out.out_end_arr(ctx, n);
+        Ok(())
+    })
+}
+
+// XCLAIM key group consumer min-idle-time id [id ...] [IDLE ms] [TIME unix-time-ms]
+//        [RETRYCOUNT count] [FORCE] [JUSTID]
+pub fn do_xclaim(cmd: &[String], out: &mut Buffer) -> CmdResult {
+    if cmd.len() < 6 {
+        out_err(out, "XCLAIM requires: key group consumer min-idle-time id [id ...] [IDLE ms] [TIME ms] [RETRYCOUNT count] [FORCE] [JUSTID]");
+        return Ok(());
+    }
+
+    let key = &cmd[1];
+    let group = &cmd[2];
+    let consumer = &cmd[3];
+    let Ok(min_idle) = cmd[4].parse::<u64>() else {
+        out_err(out, "ERR Invalid min-idle-time argument for XCLAIM");
+        return Ok(());
+    };
+
+    // IDs come first, options after the first argument that isn't an ID
+    let mut ids = Vec::new();
+    let mut i = 5;
+    while let Some(id) = cmd.get(i).and_then(|s| StreamId::parse(s, 0)) {
+        ids.push(id);
+        i += 1;
+    }
+
+    let mut opts = ClaimOptions::default();
+    while i < cmd.len() {
+        let option = cmd[i].to_uppercase();
+        let numeric = |v: Option<&String>| v.and_then(|s| s.parse::<u64>().ok());
+        match option.as_str() {
+            "FORCE" => opts.force = true,
+            "JUSTID" => opts.justid = true,
+            "IDLE" | "TIME" | "RETRYCOUNT" => {
+                let Some(n) = numeric(cmd.get(i + 1)) else {
+                    out_err(out, "ERR value is not an integer or out of range");
+                    return Ok(());
+                };
+                match option.as_str() {
+                    "IDLE" => opts.idle_ms = Some(n),
+                    "TIME" => opts.time_ms = Some(n),
+                    _ => opts.retry_count = Some(n),
+                }
+                i += 1;
+            }
+            _ => {
+                out_err(out, &format!("ERR Unrecognized XCLAIM option '{}'", cmd[i]));
+                return Ok(());
+            }
+        }
+        i += 1;
+    }
+
+    let now_ms = get_current_time_ms();
+
+    with_global_data(|g_data| {
+        let claimed = expect_stream_mut(g_data, key, false, |stream| {
+            stream.claim(group, consumer, min_idle, &ids, &opts, now_ms)
+        });
+        let Some(Some(claimed)) = claimed? else {
+            out_err(out, &nogroup(key, group));
+            return Ok(());
+        };
+
+        out_arr(out, claimed.len() as u32);
+        for (id, fields) in &claimed {
+            if opts.justid {
+                out_str(out, &id.to_string());
+            } else {
+                out_stream_entry(out, id, fields);
+            }
+        }
+        Ok(())
+    })
+}
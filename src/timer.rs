@@ -0,0 +1,356 @@
+/* Timer wheel */
+//
+// A hierarchical timer wheel holding one deadline (monotonic ms) per key.
+// Level 0 has a slot per millisecond for the next 64 ms, level 1 a slot per
+// 64 ms for the next 64 * 64 ms, and so on; LEVELS levels of 64 slots cover
+// any u64 deadline. A timer goes in the lowest level whose window it falls
+// in, and when time enters a slot of a higher level the timers in it are
+// cascaded down, so each one moves at most LEVELS times before it fires.
+//
+// Adding, moving and removing a timer are O(1). The next deadline is found
+// from one occupancy bitmask per level, so it costs O(LEVELS) however many
+// timers there are; for a slot above level 0 it's the time the slot must be
+// cascaded, which is never later than the timers in it. Timers that are due
+// are collected in one batch and handed out up to a limit at a time.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+const LEVEL_BITS: u32 = 6;
+const SLOTS: usize = 1 << LEVEL_BITS;
+// 11 * 6 bits > 64: the top level spans every possible deadline
+const LEVELS: usize = 11;
+
+#[derive(Debug, Clone, Copy)]
+enum Place {
+    Due,
+    // Index into `slots`: level * SLOTS + slot
+    Slot(usize),
+}
+
+#[derive(Debug)]
+pub struct TimerWheel<K> {
+    // Time the wheel has been advanced to
+    now_ms: u64,
+    slots: Vec<HashSet<K>>,
+    // Bit s of occupied[l] is set while slot s of level l holds timers
+    occupied: [u64; LEVELS],
+    // Deadline passed, not handed out yet
+    due: HashSet<K>,
+    // key -> (deadline, where the timer is)
+    timers: HashMap<K, (u64, Place)>,
+}
+
+impl<K> Default for TimerWheel<K> {
+    fn default() -> Self {
+        Self {
+            now_ms: 0,
+            slots: (0..LEVELS * SLOTS).map(|_| HashSet::new()).collect(),
+            occupied: [0; LEVELS],
+            due: HashSet::new(),
+            timers: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> TimerWheel<K> {
+    /// Set the deadline of `key`, replacing any it had; returns the old one
+    pub fn insert(&mut self, key: K, deadline_ms: u64) -> Option<u64> {
+        let old = self.remove(&key);
+        self.place(key, deadline_ms);
+        old
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<u64> {
+        let (deadline, place) = self.timers.remove(key)?;
+        match place {
+            Place::Due => {
+                self.due.remove(key);
+            }
+            Place::Slot(i) => {
+                self.slots[i].remove(key);
+                if self.slots[i].is_empty() {
+                    self.occupied[i / SLOTS] &= !(1 << (i % SLOTS));
+                }
+            }
+        }
+        Some(deadline)
+    }
+
+    /// When the wheel next needs attention: the earliest deadline at level 0,
+    /// or the earliest cascade above it. None without timers.
+    pub fn next_deadline(&self) -> Option<u64> {
+        if !self.due.is_empty() {
+            return Some(self.now_ms);
+        }
+        self.next_visit()
+    }
+
+    /// Advance to `now_ms` and take out up to `max` timers that are due
+    pub fn expire(&mut self, now_ms: u64, max: usize) -> Vec<K> {
+        while let Some(at) = self.next_visit().filter(|&at| at <= now_ms) {
+            self.advance_to(at);
+        }
+        // No slot is entered in between, so there's nothing to cascade
+        self.now_ms = self.now_ms.max(now_ms);
+
+        let keys: Vec<K> = self.due.iter().take(max).cloned().collect();
+        for key in &keys {
+            self.due.remove(key);
+            self.timers.remove(key);
+        }
+        keys
+    }
+
+    fn place(&mut self, key: K, deadline_ms: u64) {
+        if deadline_ms <= self.now_ms {
+            self.due.insert(key.clone());
+            self.timers.insert(key, (deadline_ms, Place::Due));
+            return;
+        }
+        // The lowest level where the deadline is less than a full turn away.
+        // Above level 0 it's then at least one slot away, so the slot is
+        // entered (and cascaded) before the deadline.
+        let level = (0..LEVELS)
+            .find(|&l| (deadline_ms >> (l as u32 * LEVEL_BITS)) - (self.now_ms >> (l as u32 * LEVEL_BITS)) < SLOTS as u64)
+            .unwrap();
+        let slot = ((deadline_ms >> (level as u32 * LEVEL_BITS)) as usize) % SLOTS;
+        let i = level * SLOTS + slot;
+        self.slots[i].insert(key.clone());
+        self.occupied[level] |= 1 << slot;
+        self.timers.insert(key, (deadline_ms, Place::Slot(i)));
+    }
+
+    // Start of the nearest occupied slot on any level. A slot's window is
+    // always one a u64 deadline falls in, so the start fits; the arithmetic
+    // is checked all the same, near u64::MAX and on the top level, where the
+    // windows ahead run out before the slots do.
+    fn next_visit(&self) -> Option<u64> {
+        (0..LEVELS)
+            .filter(|&l| self.occupied[l] != 0)
+            .filter_map(|l| {
+                let shift = l as u32 * LEVEL_BITS;
+                let window = self.now_ms >> shift;
+                // Slots ahead of the current one, nearest first
+                let ahead = self.occupied[l].rotate_right((window.wrapping_add(1) % SLOTS as u64) as u32);
+                let target = window.checked_add(1 + ahead.trailing_zeros() as u64)?;
+                (target <= u64::MAX >> shift).then(|| target << shift)
+            })
+            .min()
+    }
+
+    // Move to `at`, the start of the nearest occupied slot, and cascade the
+    // slots entered on the way, highest level first
+    fn advance_to(&mut self, at: u64) {
+        let before = self.now_ms;
+        self.now_ms = at;
+        for level in (0..LEVELS).rev() {
+            let shift = level as u32 * LEVEL_BITS;
+            if at >> shift == before >> shift {
+                continue;
+            }
+            let slot = ((at >> shift) as usize) % SLOTS;
+            let i = level * SLOTS + slot;
+            if self.occupied[level] & (1 << slot) == 0 {
+                continue;
+            }
+            self.occupied[level] &= !(1 << slot);
+            for key in std::mem::take(&mut self.slots[i]) {
+                let (deadline, _) = self.timers[&key];
+                self.place(key, deadline);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Step the wheel the way the event loop does, from one next_deadline to
+    // the next, up to `until`; returns each key with the time it came out
+    fn run(wheel: &mut TimerWheel<u32>, until: u64) -> Vec<(u32, u64)> {
+        let mut fired = Vec::new();
+        while let Some(at) = wheel.next_deadline().filter(|&at| at <= until) {
+            fired.extend(wheel.expire(at, usize::MAX).into_iter().map(|key| (key, at)));
+        }
+        fired
+    }
+
+    #[test]
+    fn insert_remove_and_reinsert() {
+        let mut wheel = TimerWheel::default();
+        assert_eq!(wheel.next_deadline(), None);
+        assert_eq!(wheel.insert(1, 100), None);
+        assert_eq!(wheel.insert(1, 200), Some(100));
+        assert_eq!(wheel.remove(&1), Some(200));
+        assert_eq!(wheel.remove(&1), None);
+        assert_eq!(wheel.next_deadline(), None);
+        assert!(wheel.expire(1000, usize::MAX).is_empty());
+
+        // Back in after it was taken out, and moved earlier and later
+        wheel.insert(1, 1500);
+        wheel.insert(2, 1100);
+        wheel.insert(2, 5000);
+        wheel.insert(1, 1200);
+        assert_eq!(run(&mut wheel, u64::MAX), vec![(1, 1200), (2, 5000)]);
+    }
+
+    #[test]
+    fn removing_a_due_timer() {
+        let mut wheel = TimerWheel::default();
+        wheel.insert(1, 10);
+        wheel.insert(2, 10);
+        assert!(wheel.expire(5, usize::MAX).is_empty());
+        wheel.insert(3, 5);
+        assert_eq!(wheel.next_deadline(), Some(5));
+        assert_eq!(wheel.remove(&3), Some(5));
+        assert_eq!(wheel.next_deadline(), Some(10));
+    }
+
+    #[test]
+    fn timers_cascade_down_to_fire_on_time() {
+        // Either side of every level boundary, from a start that isn't
+        // aligned to any of them
+        let mut wheel = TimerWheel::default();
+        let start = 1_000_003;
+        wheel.expire(start, usize::MAX);
+        let mut expected = Vec::new();
+        for level in 0..LEVELS as u32 - 1 {
+            let span = 1u64 << (level * LEVEL_BITS);
+            for delay in [span - 1, span, span + 1, span * 63 + 7] {
+                let key = expected.len() as u32;
+                let deadline = start.saturating_add(delay.max(1));
+                wheel.insert(key, deadline);
+                expected.push((key, deadline));
+            }
+        }
+        expected.sort_by_key(|&(key, deadline)| (deadline, key));
+        let mut fired = run(&mut wheel, u64::MAX);
+        fired.sort_by_key(|&(key, at)| (at, key));
+        assert_eq!(fired, expected);
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn nothing_fires_early_when_time_jumps() {
+        let mut wheel = TimerWheel::default();
+        wheel.insert(1, 4096 + 64 + 5);
+        wheel.insert(2, 300_000);
+        assert!(wheel.expire(4096 + 64 + 4, usize::MAX).is_empty());
+        assert_eq!(wheel.expire(4096 + 64 + 5, usize::MAX), vec![1]);
+        assert!(wheel.expire(299_999, usize::MAX).is_empty());
+        assert_eq!(wheel.expire(1_000_000, usize::MAX), vec![2]);
+    }
+
+    #[test]
+    fn expire_hands_out_at_most_max() {
+        let mut wheel = TimerWheel::default();
+        for key in 0..10 {
+            wheel.insert(key, 50 + key as u64 % 3);
+        }
+        wheel.insert(99, 1000);
+        let mut batches = Vec::new();
+        loop {
+            let batch = wheel.expire(100, 3);
+            if batch.is_empty() {
+                break;
+            }
+            batches.push(batch.len());
+            // What's left is due now; after that, 99 is at most a cascade away
+            let next = wheel.next_deadline().unwrap();
+            if batches.iter().sum::<usize>() < 10 {
+                assert_eq!(next, 100);
+            } else {
+                assert!(next > 100 && next <= 1000);
+            }
+        }
+        assert_eq!(batches, vec![3, 3, 3, 1]);
+        assert_eq!(wheel.expire(1000, 3), vec![99]);
+    }
+
+    #[test]
+    fn deadlines_near_the_end_of_time() {
+        let mut wheel = TimerWheel::default();
+        wheel.insert(1, u64::MAX);
+        wheel.insert(2, u64::MAX - 1);
+        wheel.insert(3, 1 << 63);
+        wheel.insert(4, (15 << 60) + 1);
+        assert_eq!(run(&mut wheel, u64::MAX), vec![(3, 1 << 63), (4, (15 << 60) + 1), (2, u64::MAX - 1), (1, u64::MAX)]);
+
+        // From a start in the top level's last window
+        let mut wheel = TimerWheel::default();
+        wheel.expire(u64::MAX - 100, usize::MAX);
+        wheel.insert(1, u64::MAX);
+        wheel.insert(2, u64::MAX - 64);
+        assert_eq!(wheel.next_deadline(), Some(u64::MAX - 64));
+        assert_eq!(run(&mut wheel, u64::MAX), vec![(2, u64::MAX - 64), (1, u64::MAX)]);
+        wheel.insert(3, u64::MAX);
+        assert_eq!(wheel.expire(u64::MAX, usize::MAX), vec![3]);
+    }
+
+    // xorshift*, so a failing seed replays the same
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % n
+        }
+    }
+
+    // Random inserts, moves, removals and time steps of every size, checked
+    // against a plain map of deadlines: every timer comes out once its
+    // deadline has passed and not before, and the wheel never asks to be
+    // woken later than its earliest deadline
+    #[test]
+    fn matches_a_map_of_deadlines() {
+        for seed in [1, 7, 42, 2024, 0xdead_beef] {
+            let mut rng = Rng(seed);
+            let mut wheel = TimerWheel::default();
+            let mut model: HashMap<u32, u64> = HashMap::new();
+            let mut now = rng.below(1 << 40);
+            wheel.expire(now, usize::MAX);
+
+            for op in 0..20_000 {
+                let key = rng.below(200) as u32;
+                match rng.below(10) {
+                    0..=4 => {
+                        // Near, or a level or several away
+                        let delay = match rng.below(4) {
+                            0 => rng.below(64),
+                            1 => rng.below(1 << 12),
+                            2 => rng.below(1 << 30),
+                            _ => rng.below(u64::MAX - now) + 1,
+                        };
+                        let deadline = now + delay;
+                        assert_eq!(wheel.insert(key, deadline), model.insert(key, deadline), "seed {} op {}", seed, op);
+                    }
+                    5 => assert_eq!(wheel.remove(&key), model.remove(&key), "seed {} op {}", seed, op),
+                    _ => {
+                        let step = match rng.below(3) {
+                            0 => rng.below(64),
+                            1 => rng.below(1 << 16),
+                            _ => rng.below(1 << 32),
+                        };
+                        now = now.saturating_add(step);
+                        let mut fired = wheel.expire(now, usize::MAX);
+                        fired.sort();
+                        let mut due: Vec<u32> = model.iter().filter(|&(_, &at)| at <= now).map(|(&key, _)| key).collect();
+                        due.sort();
+                        assert_eq!(fired, due, "seed {} op {} now {}", seed, op, now);
+                        model.retain(|_, &mut at| at > now);
+                    }
+                }
+                let earliest = model.values().min().copied();
+                match (wheel.next_deadline(), earliest) {
+                    (None, None) => {}
+                    (Some(next), Some(earliest)) => assert!(next <= earliest.max(now), "seed {} op {}", seed, op),
+                    (next, earliest) => panic!("seed {} op {}: next {:?}, earliest {:?}", seed, op, next, earliest),
+                }
+            }
+        }
+    }
+}
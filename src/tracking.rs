@@ -0,0 +1,146 @@
+/* Client tracking */
+//
+// Server-assisted client-side caching. A client that turns on
+//
+//   CLIENT TRACKING ON
+//
+// has the keys it reads remembered; when one of them is modified afterwards,
+// by any client or by expiring, it's sent a push message
+//
+//   ["invalidate", [key, ...]]
+//
+// (RESP3 ">" on text connections) and the key is forgotten until the client
+// reads it again, as in Redis' default mode. Invalidations are collected as
+// commands run and sent together once the event loop has put every
+// connection back in its table.
+
+use std::collections::{HashMap, HashSet};
+use std::os::unix::io::RawFd;
+
+use crate::command::{CMD_READONLY, CMD_WRITE};
+use crate::events::ServerEvent;
+use crate::{blocking, out_arr, out_err, out_push, out_str, with_global_data, Buffer, GData};
+
+#[derive(Debug, Default)]
+pub struct Tracking {
+    // Clients with tracking on
+    clients: HashSet<RawFd>,
+    // key -> tracking clients that read it since it last changed
+    readers: HashMap<String, HashSet<RawFd>>,
+    // Keys to invalidate for each client, not sent yet
+    pending: HashMap<RawFd, Vec<String>>,
+}
+
+impl Tracking {
+    /// Forget a client: tracking turned off, RESET, or disconnected. Its
+    /// fd may be reused, so nothing it read stays behind.
+    pub fn disable(&mut self, fd: RawFd) {
+        if self.clients.remove(&fd) {
+            self.readers.retain(|_, fds| {
+                fds.remove(&fd);
+                !fds.is_empty()
+            });
+            self.pending.remove(&fd);
+        }
+    }
+
+    // Note that `key` changed: every client that read it gets invalidated
+    fn key_modified(&mut self, key: &str) {
+        let Some(readers) = self.readers.remove(key) else {
+            return;
+        };
+        for fd in readers.into_iter().filter(|fd| self.clients.contains(fd)) {
+            self.pending.entry(fd).or_default().push(key.to_string());
+        }
+    }
+
+    /// Whether the running client has tracking on, so the keys it reads
+    /// are to be remembered
+    pub fn tracks_reads(&self) -> bool {
+        blocking::current_client().is_some_and(|fd| self.clients.contains(&fd))
+    }
+
+    fn key_read(&mut self, fd: RawFd, key: &str) {
+        if self.clients.contains(&fd) {
+            self.readers.entry(key.to_string()).or_default().insert(fd);
+        }
+    }
+}
+
+/// Update the tables: keys modified or expired are invalidated, and after a
+/// command ran the keys of a read-only command become tracked for the client
+/// that ran it, those of a write command are invalidated. Commands that
+/// failed change nothing. A client that goes is forgotten.
+pub fn on_event(g_data: &mut GData, event: &ServerEvent) {
+    let (command, args) = match *event {
+        ServerEvent::KeyModified { key } | ServerEvent::KeyExpired { key } => {
+            g_data.tracking.key_modified(key);
+            return;
+        }
+        ServerEvent::ClientDisconnected { fd } => {
+            g_data.tracking.disable(fd);
+            return;
+        }
+        ServerEvent::CommandExecuted { command, args, failed: false, .. } => (command, args),
+        _ => return,
+    };
+    let (reads, writes) = (command.has_flag(CMD_READONLY), command.has_flag(CMD_WRITE));
+    let tracking = &mut g_data.tracking;
+    if !(reads || writes) || (tracking.clients.is_empty() && tracking.readers.is_empty()) {
+        return;
+    }
+    for key in command.keys(args) {
+        if writes {
+            tracking.key_modified(key);
+        } else if let Some(fd) = blocking::current_client() {
+            tracking.key_read(fd, key);
+        }
+    }
+}
+
+/// Write the collected invalidations to their clients' connections
+pub fn send_invalidations(g_data: &mut GData) {
+    let max_reply_size = g_data.config.max_reply_size;
+    for (fd, keys) in g_data.tracking.pending.drain() {
+        let Some(conn) = g_data.fd2conn.get_mut(&fd) else {
+            continue;
+        };
+        let header_pos = conn.outgoing.response_begin();
+        out_push(&mut conn.outgoing, 2);
+        out_str(&mut conn.outgoing, "invalidate");
+        out_arr(&mut conn.outgoing, keys.len() as u32);
+        for key in &keys {
+            out_str(&mut conn.outgoing, key);
+        }
+        conn.finish_reply(header_pos, max_reply_size);
+        conn.replies_queued();
+    }
+}
+
+// CLIENT TRACKING ON|OFF
+pub fn do_tracking(cmd: &[String], out: &mut Buffer) {
+    if cmd.len() != 3 {
+        out_err(out, "ERR wrong number of arguments for 'client|tracking' command");
+        return;
+    }
+    let on = match cmd[2].to_uppercase().as_str() {
+        "ON" => true,
+        "OFF" => false,
+        _ => {
+            out_err(out, "ERR syntax error");
+            return;
+        }
+    };
+    with_global_data(|g_data| {
+        let Some(fd) = blocking::current_client() else {
+            out_err(out, "ERR CLIENT TRACKING can't be used here");
+            return;
+        };
+        if on {
+            g_data.tracking.clients.insert(fd);
+        } else {
+            g_data.tracking.disable(fd);
+        }
+        out_str(out, "OK");
+    });
+}
@@ -0,0 +1,265 @@
+/* Sorted set invariants */
+//
+// A ZSet is an AVL tree of ZNodes linked through parent pointers, plus an
+// index by name. check_invariants() walks a set and reports the first rule it
+// finds broken:
+//
+//   order    an in-order walk is strictly ascending by (score, name)
+//   parents  each child's tree_parent is the node above it; the root has none
+//   heights  tree_height is one more than the taller child's, and the
+//            children's heights differ by at most one
+//   counts   tree_count is one more than the children's counts together
+//   index    name_to_node holds exactly the tree's nodes, under their names
+//
+// Debug builds check a set after every change while it has at most
+// CHECK_MAX_LEN members: small sets go through every rotation case, and big
+// ones don't turn each change into a walk of the whole tree.
+//
+// The tests below hold the property test: random inserts, score updates,
+// deletes and range removals on a few sets, each step checked against the
+// invariants and against a BTreeSet model of what the set should hold. The
+// seeds are fixed, and a failure prints its own; ZSET_FUZZ_SEED runs just one
+// (an old failure, or new ground) and ZSET_FUZZ_OPS sets the length:
+//
+//   ZSET_FUZZ_SEED=42 ZSET_FUZZ_OPS=100000 cargo test zset::
+
+use std::sync::{Arc, Mutex};
+
+use ordered_float::OrderedFloat;
+
+use crate::{ZNode, ZSet};
+
+const CHECK_MAX_LEN: usize = 256;
+
+/// Check `zset`'s tree and index against each other and the AVL rules
+pub fn check_invariants(zset: &ZSet) -> Result<(), String> {
+    let mut walk = Walk { last: None, nodes: 0 };
+    if let Some(root) = &zset.root {
+        if root.lock().unwrap().tree_parent.is_some() {
+            return Err("the root has a parent".to_string());
+        }
+        walk.subtree(zset, root)?;
+    }
+    if walk.nodes != zset.name_to_node.len() {
+        return Err(format!("{} nodes in the tree, {} in the index", walk.nodes, zset.name_to_node.len()));
+    }
+    Ok(())
+}
+
+/// After a change to `zset`: in a debug build, panic if it is broken
+pub fn debug_check(zset: &ZSet) {
+    if cfg!(debug_assertions)
+        && zset.len() <= CHECK_MAX_LEN
+        && let Err(e) = check_invariants(zset)
+    {
+        panic!("sorted set corrupted: {}", e);
+    }
+}
+
+struct Walk {
+    last: Option<(OrderedFloat<f64>, String)>,
+    nodes: usize,
+}
+
+impl Walk {
+    // Check the subtree under `node` in order; returns its height and count
+    fn subtree(&mut self, zset: &ZSet, node: &Arc<Mutex<ZNode>>) -> Result<(u32, u32), String> {
+        let (left, right) = {
+            let n = node.lock().unwrap();
+            (n.tree_left.clone(), n.tree_right.clone())
+        };
+        let (left_height, left_count) = self.child(zset, node, left.as_ref())?;
+
+        let n = node.lock().unwrap();
+        let key = (OrderedFloat(n.score), n.name.clone());
+        if let Some(last) = &self.last
+            && *last >= key
+        {
+            return Err(format!("{:?} comes after {:?}", key, last));
+        }
+        if !zset.name_to_node.get(&n.name).is_some_and(|indexed| Arc::ptr_eq(indexed, node)) {
+            return Err(format!("{:?} is not indexed under its name", n.name));
+        }
+        let (height, count) = (n.tree_height, n.tree_count);
+        drop(n);
+        self.last = Some(key.clone());
+        self.nodes += 1;
+
+        let (right_height, right_count) = self.child(zset, node, right.as_ref())?;
+        if left_height.abs_diff(right_height) > 1 {
+            return Err(format!("{:?} is unbalanced: heights {} and {}", key, left_height, right_height));
+        }
+        if height != 1 + left_height.max(right_height) {
+            return Err(format!("{:?} has height {}, its children {} and {}", key, height, left_height, right_height));
+        }
+        if count != 1 + left_count + right_count {
+            return Err(format!("{:?} has count {}, its children {} and {}", key, count, left_count, right_count));
+        }
+        Ok((height, count))
+    }
+
+    fn child(
+        &mut self,
+        zset: &ZSet,
+        parent: &Arc<Mutex<ZNode>>,
+        child: Option<&Arc<Mutex<ZNode>>>,
+    ) -> Result<(u32, u32), String> {
+        let Some(child) = child else {
+            return Ok((0, 0));
+        };
+        let linked = child.lock().unwrap().tree_parent.as_ref().is_some_and(|p| Arc::ptr_eq(p, parent));
+        if !linked {
+            return Err(format!("{:?} doesn't point back to its parent", child.lock().unwrap().name));
+        }
+        self.subtree(zset, child)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use ordered_float::OrderedFloat;
+
+    use super::check_invariants;
+    use crate::{znode_offset, ScoreBound, ZSet};
+
+    // xorshift64*, as in bench
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    // A set under test, and what it should hold
+    struct Case {
+        zset: ZSet,
+        model: BTreeSet<(OrderedFloat<f64>, String)>,
+    }
+
+    impl Case {
+        fn score_of(&self, name: &str) -> Option<f64> {
+            self.zset.lookup(name).map(|node| node.lock().unwrap().score)
+        }
+
+        // One random operation; returns what it did
+        fn step(&mut self, rng: &mut Rng, members: u64) -> String {
+            // Few distinct scores, so ties are broken by name often
+            let score = match rng.below(4) {
+                0 => rng.below(1000) as f64 / 7.0,
+                _ => rng.below(8) as f64,
+            };
+            let name = format!("m{}", rng.below(members));
+            match rng.below(10) {
+                0..=5 => {
+                    if let Some(old) = self.score_of(&name) {
+                        self.model.remove(&(OrderedFloat(old), name.clone()));
+                    }
+                    self.model.insert((OrderedFloat(score), name.clone()));
+                    self.zset.insert(score, name.clone());
+                    format!("ZADD {} {}", score, name)
+                }
+                6..=8 => {
+                    if let Some(node) = self.zset.lookup(&name) {
+                        let old = node.lock().unwrap().score;
+                        self.model.remove(&(OrderedFloat(old), name.clone()));
+                        self.zset.delete(&node);
+                    }
+                    format!("ZREM {}", name)
+                }
+                _ => {
+                    let max = score + rng.below(3) as f64;
+                    let start = self.zset.seek_score(ScoreBound { score, exclusive: false });
+                    self.zset.remove_range(start, |node| node.score <= max);
+                    self.model.retain(|(s, _)| s.0 < score || s.0 > max);
+                    format!("ZREMRANGEBYSCORE {} {}", score, max)
+                }
+            }
+        }
+
+        // The set against the model, through ranks from both ends
+        fn compare(&self, rng: &mut Rng) -> Result<(), String> {
+            check_invariants(&self.zset)?;
+            if self.zset.len() != self.model.len() {
+                return Err(format!("{} members, expected {}", self.zset.len(), self.model.len()));
+            }
+            let Some(rank) = (!self.model.is_empty()).then(|| rng.below(self.model.len() as u64) as usize) else {
+                return Ok(());
+            };
+            let expected = self.model.iter().nth(rank).map(|(_, name)| name.clone());
+            let from_first = self.zset.at_rank(rank).map(|node| node.lock().unwrap().name.clone());
+            let back = self.model.len() - 1 - rank;
+            let from_last = znode_offset(self.zset.last(), -(back as i64)).map(|node| node.lock().unwrap().name.clone());
+            if from_first != expected || from_last != expected {
+                return Err(format!("rank {}: {:?} from the start, {:?} from the end, expected {:?}", rank, from_first, from_last, expected));
+            }
+            Ok(())
+        }
+    }
+
+    // A number from the environment, to rerun a failure
+    fn env_or(name: &str, default: u64) -> u64 {
+        std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+    }
+
+    // `ops` random operations on a few sets, `members` names to pick from
+    fn fuzz(seed: u64, ops: u64, members: u64) {
+        let mut rng = Rng(seed | 1);
+        let mut cases: Vec<Case> = (0..4).map(|_| Case { zset: ZSet::new(), model: BTreeSet::new() }).collect();
+        for op in 0..ops {
+            let case = &mut cases[rng.below(4) as usize];
+            let done = case.step(&mut rng, members);
+            if let Err(e) = case.compare(&mut rng) {
+                panic!("seed {}, operation {} ({}): {}", seed, op + 1, done, e);
+            }
+        }
+    }
+
+    #[test]
+    fn random_operations_match_a_model() {
+        // Fixed seeds, so a failure comes back on every run; ZSET_FUZZ_SEED
+        // runs just that one
+        let seeds = match std::env::var("ZSET_FUZZ_SEED").ok().and_then(|v| v.parse().ok()) {
+            Some(seed) => vec![seed],
+            None => vec![0x5eed, 42],
+        };
+        for seed in seeds {
+            fuzz(seed, env_or("ZSET_FUZZ_OPS", 30_000), 64);
+        }
+    }
+
+    // Fewer collisions between names, so sets grow deeper
+    #[test]
+    fn random_operations_on_bigger_sets() {
+        for seed in 1..=2 {
+            fuzz(seed, 10_000, 200);
+        }
+    }
+
+    #[test]
+    fn broken_counts_and_links_are_reported() {
+        let mut zset = ZSet::new();
+        for (i, name) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            zset.insert(i as f64, name.to_string());
+        }
+        assert_eq!(check_invariants(&zset), Ok(()));
+
+        let root = zset.root.clone().unwrap();
+        root.lock().unwrap().tree_count += 1;
+        assert!(check_invariants(&zset).unwrap_err().contains("has count"));
+        root.lock().unwrap().tree_count -= 1;
+
+        let leaf = zset.lookup("e").unwrap();
+        leaf.lock().unwrap().tree_parent = None;
+        assert!(check_invariants(&zset).unwrap_err().contains("doesn't point back"));
+    }
+}